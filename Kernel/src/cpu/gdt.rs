@@ -1,3 +1,5 @@
+use super::smp::{cpu_index, MAX_CPUS};
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::{
 	self,
@@ -9,55 +11,53 @@ use x86_64::{
 	VirtAddr,
 };
 
-/// size of the interrupt stack
-const STACK_SIZE: usize = 4096 * 2;
+/// size of the interrupt stack, big enough for fault handlers to write core dumps
+const STACK_SIZE: usize = 4096 * 16;
 
-lazy_static! {
-	static ref TSS: TaskStateSegment = {
-		let mut tss = TaskStateSegment::new();
-		tss.interrupt_stack_table[0] = {
-			/// Stack for interrupt handlers and syscalls
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-			let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-			let stack_end = stack_start + STACK_SIZE;
-			stack_end
-		};
+/// Task state segment of each CPU. privilege_stack_table[0] is set to the kernel stack of each
+/// thread as it is run.
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::new(); MAX_CPUS];
 
-		tss.interrupt_stack_table[1] = {
-			/// Stack for interrupt handlers and syscalls
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Stack for double faults of each CPU
+static mut DOUBLE_FAULT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
 
-			let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-			let stack_end = stack_start + STACK_SIZE;
-			stack_end
-		};
+/// Stack for interrupt handlers of each CPU
+static mut INTERRUPT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
 
-		// tss.privilege_stack_table[0] = VirtAddr::new(0xFFFF900000000000);
+/// Set up the interrupt stacks in the TSS of the CPU
+fn tss(cpu: usize) -> &'static TaskStateSegment {
+	unsafe {
+		let tss = &mut TSS[cpu];
+		tss.interrupt_stack_table[0] = VirtAddr::from_ptr(&DOUBLE_FAULT_STACKS[cpu]) + STACK_SIZE;
+		tss.interrupt_stack_table[1] = VirtAddr::from_ptr(&INTERRUPT_STACKS[cpu]) + STACK_SIZE;
 		tss
-	};
+	}
+}
+
+/// Create the GDT of a CPU. The segments are in the same order for all CPUs, so they all have the
+/// same selectors.
+fn new_gdt(cpu: usize) -> (GlobalDescriptorTable, Selectors) {
+	let mut gdt = GlobalDescriptorTable::new();
+	let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+	let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+	let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss(cpu)));
+	let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+	let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+	(
+		gdt,
+		Selectors {
+			kernel_code_selector,
+			kernel_data_selector,
+			user_code_selector,
+			user_data_selector,
+			tss_selector,
+		},
+	)
 }
 
 lazy_static! {
-	/// GDT
-	pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-		let mut gdt = GlobalDescriptorTable::new();
-		let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-		let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-		let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-		let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-		let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-		(
-			gdt,
-			Selectors {
-				kernel_code_selector,
-				kernel_data_selector,
-				user_code_selector,
-				user_data_selector,
-				tss_selector,
-			},
-		)
-	};
+	/// GDT of the first CPU, and the selectors of its segments, which are the same on all CPUs
+	pub static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(0);
 }
 
 #[allow(dead_code)]
@@ -75,13 +75,32 @@ pub struct Selectors {
 	pub tss_selector: SegmentSelector,
 }
 
+/// Set the stack interrupts from user mode switch to on this CPU, the kernel stack of the thread
+/// about to run
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+	// The CPU reads the TSS from memory whenever it switches stacks, so it can be changed after it
+	// was loaded
+	unsafe {
+		TSS[cpu_index()].privilege_stack_table[0] = stack_top;
+	}
+}
+
 /// Set up global descriptor table, and set code segment register, and task
 /// state segment register.
 pub fn setup() {
+	load(&GDT);
+}
+
+/// Set up a global descriptor table and task state segment for an application processor
+pub fn setup_ap(cpu: usize) {
+	load(Box::leak(Box::new(new_gdt(cpu))));
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
 	unsafe {
-		GDT.0.load();
-		CS::set_reg(GDT.1.kernel_code_selector);
-		DS::set_reg(GDT.1.kernel_data_selector);
-		load_tss(GDT.1.tss_selector);
+		gdt.0.load();
+		CS::set_reg(gdt.1.kernel_code_selector);
+		DS::set_reg(gdt.1.kernel_data_selector);
+		load_tss(gdt.1.tss_selector);
 	}
 }
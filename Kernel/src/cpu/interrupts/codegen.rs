@@ -1,119 +1,28 @@
-use super::{irq_handler, PIC_1_OFFSET};
+use super::irq_handler;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-pub fn set_irq_handlers(idt: &mut InterruptDescriptorTable) {
-	unsafe {
-		// idt[(PIC_1_OFFSET + 0) as usize]
-		// .set_handler_fn(irq_handler_0)
-		// .set_stack_index(0);
-		idt[(PIC_1_OFFSET + 1) as usize]
-			.set_handler_fn(irq_handler_1)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 2) as usize]
-			.set_handler_fn(irq_handler_2)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 3) as usize]
-			.set_handler_fn(irq_handler_3)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 4) as usize]
-			.set_handler_fn(irq_handler_4)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 5) as usize]
-			.set_handler_fn(irq_handler_5)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 6) as usize]
-			.set_handler_fn(irq_handler_6)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 7) as usize]
-			.set_handler_fn(irq_handler_7)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 8) as usize]
-			.set_handler_fn(irq_handler_8)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 9) as usize]
-			.set_handler_fn(irq_handler_9)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 10) as usize]
-			.set_handler_fn(irq_handler_10)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 11) as usize]
-			.set_handler_fn(irq_handler_11)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 12) as usize]
-			.set_handler_fn(irq_handler_12)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 13) as usize]
-			.set_handler_fn(irq_handler_13)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 14) as usize]
-			.set_handler_fn(irq_handler_14)
-			.set_stack_index(0);
-		idt[(PIC_1_OFFSET + 15) as usize]
-			.set_handler_fn(irq_handler_15)
-			.set_stack_index(0);
-	}
+/// Handler of the vector `V`, which calls the callbacks registered on it
+extern "x86-interrupt" fn vector_handler<const V: u8>(stack_frame: InterruptStackFrame) {
+	irq_handler(stack_frame, V);
 }
 
-// extern "x86-interrupt" fn irq_handler_0(stack_frame: InterruptStackFrame) {
-// irq_handler(stack_frame, 0);
-// }
-
-extern "x86-interrupt" fn irq_handler_1(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 1);
+macro_rules! set_vector_handlers {
+	($idt:ident, $($vector:literal)*) => {
+		$(
+			$idt[$vector as usize].set_handler_fn(vector_handler::<$vector>).set_stack_index(0);
+		)*
+	};
 }
 
-extern "x86-interrupt" fn irq_handler_2(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 2);
-}
-
-extern "x86-interrupt" fn irq_handler_3(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 3);
-}
-
-extern "x86-interrupt" fn irq_handler_4(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 4);
-}
-
-extern "x86-interrupt" fn irq_handler_5(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 5);
-}
-
-extern "x86-interrupt" fn irq_handler_6(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 6);
-}
-
-extern "x86-interrupt" fn irq_handler_7(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 7);
-}
-
-extern "x86-interrupt" fn irq_handler_8(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 8);
-}
-
-extern "x86-interrupt" fn irq_handler_9(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 9);
-}
-
-extern "x86-interrupt" fn irq_handler_10(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 10);
-}
-
-extern "x86-interrupt" fn irq_handler_11(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 11);
-}
-
-extern "x86-interrupt" fn irq_handler_12(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 12);
-}
-
-extern "x86-interrupt" fn irq_handler_13(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 13);
-}
-
-extern "x86-interrupt" fn irq_handler_14(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 14);
-}
-
-extern "x86-interrupt" fn irq_handler_15(stack_frame: InterruptStackFrame) {
-	irq_handler(stack_frame, 15);
+/// Set the handlers of the vectors handed out to drivers, [VECTORS](super::VECTORS) of them from
+/// [FIRST_VECTOR](super::FIRST_VECTOR)
+pub fn set_irq_handlers(idt: &mut InterruptDescriptorTable) {
+	unsafe {
+		set_vector_handlers!(idt,
+			0x30 0x31 0x32 0x33 0x34 0x35 0x36 0x37 0x38 0x39 0x3A 0x3B 0x3C 0x3D 0x3E 0x3F
+			0x40 0x41 0x42 0x43 0x44 0x45 0x46 0x47 0x48 0x49 0x4A 0x4B 0x4C 0x4D 0x4E 0x4F
+			0x50 0x51 0x52 0x53 0x54 0x55 0x56 0x57 0x58 0x59 0x5A 0x5B 0x5C 0x5D 0x5E 0x5F
+			0x60 0x61 0x62 0x63 0x64 0x65 0x66 0x67 0x68 0x69 0x6A 0x6B 0x6C 0x6D 0x6E 0x6F
+		);
+	}
 }
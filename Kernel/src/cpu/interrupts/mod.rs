@@ -1,22 +1,31 @@
-use crate::{println, process, serial_println};
-use alloc::vec::Vec;
+use crate::{
+	io::buffer::print_on,
+	println,
+	process::{self, fault::FaultReport, ExitStatus},
+	serial_print, serial_println,
+};
+use alloc::{format, vec::Vec};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+use super::{
+	ioapic, lapic,
+	smp::{self, InterruptGuard},
+};
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
 
-/// Offset of the first pic in the chained pics
-pub const PIC_1_OFFSET: u8 = 32;
-/// Offset of the second pic in the chained pics
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// Offset of the first legacy pic, which is remapped out of the way of the exceptions and masked
+const PIC_1_OFFSET: u8 = 0xE0;
+/// Offset of the second legacy pic
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-/// number of IRQs
-const IRQS: usize = 16;
+/// First vector handed out to drivers
+const FIRST_VECTOR: u8 = 0x30;
+/// Number of vectors handed out to drivers. The handlers for them are in [codegen].
+const VECTORS: usize = 64;
 
-/// Mutex wrapping chained pics. This is the interface for communicating with the pics.
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 mod codegen;
 use codegen::*;
 
@@ -53,37 +62,56 @@ lazy_static! {
 
 		unsafe {
 			use super::pit::handle_timer;
-			idt[(PIC_1_OFFSET + 0) as usize].set_handler_addr(VirtAddr::from_ptr(handle_timer as *const u8)).set_stack_index(1);
-			// idt[(PIC_1_OFFSET + 0) as usize].set_handler_fn(test_handler).set_stack_index(1);
+			idt[lapic::TIMER_VECTOR as usize].set_handler_addr(VirtAddr::from_ptr(handle_timer as *const u8)).set_stack_index(1);
+			// idt[lapic::TIMER_VECTOR as usize].set_handler_fn(test_handler).set_stack_index(1);
+			for vector in PIC_1_OFFSET..PIC_2_OFFSET + 8 {
+				idt[vector as usize].set_handler_fn(legacy_pic_handler).set_stack_index(1);
+			}
+			idt[smp::TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(smp::tlb_shootdown_handler).set_stack_index(1);
+			idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(lapic::spurious_handler).set_stack_index(1);
 		}
 
 		Mutex::new(idt)
 	};
 }
 
-/// Set up interrupt descriptor table, and chained pics
+/// Set up interrupt descriptor table, turn off the legacy pics and set up the I/O APICs, which
+/// devices interrupt through instead
 pub fn setup() {
+	load_idt();
 	unsafe {
-		let idt = &mut *(&mut *IDT.lock() as *mut InterruptDescriptorTable);
-		idt.load();
-	}
-	unsafe {
-		let mut pics = PICS.lock();
+		let mut pics = ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET);
 		pics.initialize();
-		const MASK: u8 = 0b1111_1000;
-		pics.write_masks(MASK, MASK);
+		pics.write_masks(0xFF, 0xFF);
 	};
-	super::pit::setup_pit();
+	ioapic::setup();
 
 	x86_64::instructions::interrupts::enable();
 }
 
+/// Set up the interrupt descriptor table on an application processor
+pub fn setup_ap() {
+	load_idt();
+}
+
+fn load_idt() {
+	unsafe {
+		let idt = &mut *(&mut *IDT.lock() as *mut InterruptDescriptorTable);
+		idt.load();
+	}
+}
+
 const INIT: Option<Vec<fn(&InterruptStackFrame)>> = None;
-static CALLBACKS: Mutex<[Option<Vec<fn(&InterruptStackFrame)>>; IRQS]> = Mutex::new([INIT; IRQS]);
+/// Callbacks of the vectors handed out to drivers, [None] for vectors that are free
+static CALLBACKS: Mutex<[Option<Vec<fn(&InterruptStackFrame)>>; VECTORS]> = Mutex::new([INIT; VECTORS]);
 
-fn irq_handler(stack_frame: InterruptStackFrame, irq: u8) {
-	// serial_println!("Handling irq: {}", irq);
-	match &CALLBACKS.lock()[irq as usize] {
+/// The vector each global system interrupt with callbacks was routed to
+static GSI_VECTORS: Mutex<Vec<(u32, u8)>> = Mutex::new(Vec::new());
+
+fn irq_handler(stack_frame: InterruptStackFrame, vector: u8) {
+	let _guard = InterruptGuard::new(&stack_frame);
+	// serial_println!("Handling vector: {}", vector);
+	match &CALLBACKS.lock()[(vector - FIRST_VECTOR) as usize] {
 		None => {}
 		Some(vec) => {
 			for callback in vec {
@@ -91,24 +119,53 @@ fn irq_handler(stack_frame: InterruptStackFrame, irq: u8) {
 			}
 		}
 	}
-	unsafe {
-		PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
-	}
+	lapic::end_of_interrupt();
 }
 
-/// Register a delegate function to be called when the interrupt with the given irq happens. You
-/// can register multiple functions on the same irq, and they will be called in the order that they
-/// are registered.
-pub fn register_callback(irq: u8, callback: fn(&InterruptStackFrame)) {
-	{
-		x86_64::instructions::interrupts::disable();
-		let callbacks = &mut CALLBACKS.lock();
-		match &mut callbacks[irq as usize] {
-			None => callbacks[irq as usize] = Some(vec![callback]),
-			Some(vec) => vec.push(callback),
+/// Interrupt handler for the legacy pics, which are masked but may still raise spurious interrupts
+extern "x86-interrupt" fn legacy_pic_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Hand out a free vector whose interrupts call `callback`, for interrupts that don't come through
+/// an I/O APIC, like MSIs. [None] if all the vectors are taken.
+pub fn allocate_vector(callback: fn(&InterruptStackFrame)) -> Option<u8> {
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut callbacks = CALLBACKS.lock();
+		let free = callbacks.iter().position(Option::is_none)?;
+		callbacks[free] = Some(vec![callback]);
+		Some(FIRST_VECTOR + free as u8)
+	})
+}
+
+/// Register a delegate function to be called when the global system interrupt `gsi` happens. You
+/// can register multiple functions on the same interrupt, and they will be called in the order
+/// that they are registered. The first time, the interrupt gets a vector and the I/O APIC sends it
+/// to this CPU. Returns false if no I/O APIC has the interrupt or all the vectors are taken.
+pub fn register_callback(gsi: u32, callback: fn(&InterruptStackFrame)) -> bool {
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut gsi_vectors = GSI_VECTORS.lock();
+		if let Some((_, vector)) = gsi_vectors.iter().find(|(routed, _)| *routed == gsi) {
+			let mut callbacks = CALLBACKS.lock();
+			let vec = callbacks[(vector - FIRST_VECTOR) as usize].as_mut().expect("routed vector is free");
+			vec.push(callback);
+			return true;
 		}
-	}
-	x86_64::instructions::interrupts::enable();
+		let vector = match allocate_vector(callback) {
+			Some(vector) => vector,
+			None => return false,
+		};
+		if !ioapic::route(gsi, vector, lapic::id()) {
+			CALLBACKS.lock()[(vector - FIRST_VECTOR) as usize] = None;
+			return false;
+		}
+		gsi_vectors.push((gsi, vector));
+		true
+	})
+}
+
+/// Register a delegate function to be called when the ISA interrupt `irq` happens, like 1 for the
+/// PS/2 keyboard. See [register_callback].
+pub fn register_isa_callback(irq: u8, callback: fn(&InterruptStackFrame)) -> bool {
+	register_callback(ioapic::isa_gsi(irq), callback)
 }
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
@@ -166,15 +223,23 @@ extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: Interrup
 	exception_error("general protection fault", stack_frame, error_code)
 }
 
-/// Interrupt handler for page faults. Currenty it **does not** solve the page fault (by swapping
-/// pages, etc...), rather just prints some information about the fault.
+/// Interrupt handler for page faults. The only page faults it solves are the ones that touch a
+/// page of the running process that isn't mapped yet, for the rest it prints some information
+/// about the fault.
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
 	use x86_64::registers::control::Cr2;
+	let _guard = InterruptGuard::new(&stack_frame);
+	// Pages of processes are mapped the first time they are touched, which may be in a syscall
+	// using a buffer of the process
+	let not_present = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+	if not_present && process::memory::handle_page_fault(Cr2::read()) {
+		return;
+	}
 	serial_println!("EXCEPTION: page fault");
 	serial_println!(" - Accessed Address: {:?}", Cr2::read());
 	serial_println!(" - Error Code: {:?}", error_code);
 	serial_println!(" - {:#?}", stack_frame);
-	try_recover("page fault", stack_frame);
+	try_recover("page fault", stack_frame, Some((Cr2::read(), error_code)));
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
@@ -200,29 +265,51 @@ extern "x86-interrupt" fn security_exception_handler(stack_frame: InterruptStack
 	exception_error("security exception", stack_frame, error_code)
 }
 
-fn exception(string: &str, stack_frame: InterruptStackFrame) -> ! {
+fn exception(string: &'static str, stack_frame: InterruptStackFrame) -> ! {
+	let _guard = InterruptGuard::new(&stack_frame);
 	serial_println!("EXCEPTION: {} \n - {:#?}", string, stack_frame);
-	try_recover(string, stack_frame)
+	try_recover(string, stack_frame, None)
 }
 
-fn exception_error(string: &str, stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+fn exception_error(string: &'static str, stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+	let _guard = InterruptGuard::new(&stack_frame);
 	serial_println!(
 		"EXCEPTION: {} \n - ERRORCODE: {} \n - {:#?}",
 		string,
 		error_code,
 		stack_frame
 	);
-	try_recover(string, stack_frame)
+	try_recover(string, stack_frame, None)
 }
 
-fn try_recover(string: &str, stack_frame: InterruptStackFrame) -> ! {
-	println!("\x1b[31m\x1b[4mEXCEPTION:\x1b[0m {}", string);
+fn try_recover(
+	string: &'static str,
+	stack_frame: InterruptStackFrame,
+	page_fault: Option<(VirtAddr, PageFaultErrorCode)>,
+) -> ! {
 	use x86_64::{registers::segmentation::SegmentSelector, PrivilegeLevel};
 	let code_selector = SegmentSelector(stack_frame.code_segment as u16);
 	let from_userspace = code_selector.rpl() == PrivilegeLevel::Ring3;
 	if from_userspace {
-		process::remove_current_process();
+		process::leave_if_removed();
+		let report = FaultReport::new(string, stack_frame.instruction_pointer, page_fault);
+		let pid = process::running_process();
+		let (command, terminal) = {
+			let processes = process::MAP.lock();
+			let process = processes.get(&pid).expect("running process not in hashmap");
+			(process.command.clone(), process.terminal)
+		};
+		let details = report.details(&command, pid);
+		serial_print!("{}", details);
+		print_on(&details, terminal);
+		match process::core_dump::dump_running(report.signal, &stack_frame) {
+			Ok(Some(path)) => print_on(&format!("Core dumped to {}\n", path), terminal),
+			Ok(None) => {}
+			Err(e) => print_on(&format!("Can't write core dump: {:?}\n", e), terminal),
+		}
+		process::remove_current_process(ExitStatus::Crashed(report));
 	} else {
+		println!("\x1b[31m\x1b[4mEXCEPTION:\x1b[0m {}", string);
 		loop {}
 	}
 }
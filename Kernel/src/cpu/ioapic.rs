@@ -0,0 +1,167 @@
+use crate::{drivers::acpi::madt, mem::paging};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+/// Where the I/O APIC usually is, used if the firmware has no MADT
+const DEFAULT_ADDRESS: u32 = 0xFEC0_0000;
+
+// A register is accessed by writing its index to the select register, then using the window
+const SELECT: usize = 0x00;
+const WINDOW: usize = 0x10;
+
+// Register indices
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+// Redirection entry fields, fixed delivery to a physical destination is 0
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+// Polarity and trigger mode in the flags of an interrupt override. 0 means the bus default.
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_HIGH: u16 = 0b01;
+const POLARITY_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_EDGE: u16 = 0b01 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// ISA interrupts are the first global system interrupts, unless they are overridden
+const ISA_IRQS: u32 = 16;
+
+/// An I/O APIC, and the global system interrupts it has inputs for
+struct IoApic {
+	/// Virtual address of its registers
+	base: usize,
+	/// The global system interrupt of its first input
+	gsi_base: u32,
+	/// Number of inputs
+	inputs: u32,
+}
+
+impl IoApic {
+	fn new(address: u32, gsi_base: u32) -> Self {
+		let mut io_apic = Self {
+			base: paging::phys_to_virt(PhysAddr::new(address as u64)).as_u64() as usize,
+			gsi_base,
+			inputs: 0,
+		};
+		// The version register holds the index of the last redirection entry
+		io_apic.inputs = ((io_apic.read(VERSION) >> 16) & 0xFF) + 1;
+		io_apic
+	}
+
+	fn read(&self, register: u32) -> u32 {
+		unsafe {
+			((self.base + SELECT) as *mut u32).write_volatile(register);
+			((self.base + WINDOW) as *const u32).read_volatile()
+		}
+	}
+
+	fn write(&self, register: u32, value: u32) {
+		unsafe {
+			((self.base + SELECT) as *mut u32).write_volatile(register);
+			((self.base + WINDOW) as *mut u32).write_volatile(value);
+		}
+	}
+
+	fn handles(&self, gsi: u32) -> bool {
+		(self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+	}
+
+	/// Set the redirection entry of an input. The high half holds the destination, so it is written
+	/// while the entry is masked.
+	fn set_entry(&self, input: u32, entry: u64) {
+		let register = REDIRECTION_TABLE + input * 2;
+		self.write(register, MASKED as u32);
+		self.write(register + 1, (entry >> 32) as u32);
+		self.write(register, entry as u32);
+	}
+}
+
+/// The I/O APICs, and how ISA interrupts are wired to them
+struct Routing {
+	io_apics: Vec<IoApic>,
+	overrides: Vec<madt::InterruptOverride>,
+}
+
+static ROUTING: Mutex<Routing> = Mutex::new(Routing {
+	io_apics: Vec::new(),
+	overrides: Vec::new(),
+});
+
+/// Find the I/O APICs in the MADT and mask all of their inputs. The ACPI tables must be set up.
+pub fn setup() {
+	let mut routing = ROUTING.lock();
+	match madt::read() {
+		Some(madt) => {
+			routing.io_apics = madt
+				.io_apics
+				.iter()
+				.map(|io_apic| IoApic::new(io_apic.address, io_apic.gsi_base))
+				.collect();
+			routing.overrides = madt.overrides;
+		}
+		None => {
+			serial_println!("IOAPIC: no MADT, assuming one I/O APIC at {:#x}", DEFAULT_ADDRESS);
+			routing.io_apics.push(IoApic::new(DEFAULT_ADDRESS, 0));
+		}
+	}
+	for io_apic in routing.io_apics.iter() {
+		serial_println!(
+			"IOAPIC: global system interrupts {}-{}",
+			io_apic.gsi_base,
+			io_apic.gsi_base + io_apic.inputs - 1
+		);
+		for input in 0..io_apic.inputs {
+			io_apic.set_entry(input, MASKED);
+		}
+	}
+}
+
+/// The global system interrupt an ISA IRQ arrives on
+pub fn isa_gsi(irq: u8) -> u32 {
+	ROUTING
+		.lock()
+		.overrides
+		.iter()
+		.find(|o| o.source == irq)
+		.map_or(irq as u32, |o| o.gsi)
+}
+
+/// Deliver a global system interrupt to the CPU with the local APIC `apic_id`, as `vector`.
+/// Returns false if no I/O APIC has the interrupt.
+///
+/// Interrupts that an override points to get its polarity and trigger mode. Otherwise the ISA
+/// interrupts are active high and edge triggered, and the rest are PCI interrupts, which are active
+/// low and level triggered.
+pub fn route(gsi: u32, vector: u8, apic_id: u32) -> bool {
+	let routing = ROUTING.lock();
+	let io_apic = match routing.io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+		Some(io_apic) => io_apic,
+		None => return false,
+	};
+	let flags = routing.overrides.iter().find(|o| o.gsi == gsi).map_or(0, |o| o.flags);
+	let pci = gsi >= ISA_IRQS;
+	let active_low = match flags & POLARITY_MASK {
+		POLARITY_HIGH => false,
+		POLARITY_LOW => true,
+		_ => pci,
+	};
+	let level_triggered = match flags & TRIGGER_MASK {
+		TRIGGER_EDGE => false,
+		TRIGGER_LEVEL => true,
+		_ => pci,
+	};
+
+	let mut entry = vector as u64 | (apic_id as u64) << 56;
+	if active_low {
+		entry |= ACTIVE_LOW;
+	}
+	if level_triggered {
+		entry |= LEVEL_TRIGGERED;
+	}
+	io_apic.set_entry(gsi - io_apic.gsi_base, entry);
+	true
+}
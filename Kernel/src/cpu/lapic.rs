@@ -0,0 +1,157 @@
+use super::pit;
+use crate::mem::paging;
+use core::{
+	hint::spin_loop,
+	ptr::{read_volatile, write_volatile},
+	sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use x86_64::{
+	instructions::interrupts::without_interrupts, registers::model_specific::Msr,
+	structures::idt::InterruptStackFrame, PhysAddr,
+};
+
+/// MSR holding the physical address of the local APIC registers
+const APIC_BASE_MSR: u32 = 0x1B;
+/// Bits of [APIC_BASE_MSR] that hold the address
+const APIC_BASE_MASK: u64 = 0xF_FFFF_F000;
+
+// Registers, as offsets from the base address
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const END_OF_INTERRUPT: usize = 0xB0;
+const SPURIOUS_INTERRUPT: usize = 0xF0;
+const ERROR_STATUS: usize = 0x280;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+/// Bit of [SPURIOUS_INTERRUPT] that enables the local APIC
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Vector of interrupts the local APIC raises that turn out to have nothing to deliver. They don't
+/// need an end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Interrupt command fields, fixed delivery is 0
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Vector of the local APIC timer, which ticks every [pit::TIMER_MILLIS] on every CPU
+pub const TIMER_VECTOR: u8 = 0x20;
+
+// Timer fields
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration for counting down once every 16 bus cycles
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Physical address that writes of MSIs go to, with the destination local APIC ID in bits 12-19
+const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+/// Virtual address of the local APIC registers. Every CPU sees its own local APIC at the same
+/// address.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// What the timer counts down from between ticks, measured by [setup_timer]
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn read(register: usize) -> u32 {
+	let base = BASE.load(Ordering::Relaxed) as usize;
+	unsafe { read_volatile((base + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+	let base = BASE.load(Ordering::Relaxed) as usize;
+	unsafe { write_volatile((base + register) as *mut u32, value) }
+}
+
+/// Find the local APIC registers, and enable the local APIC of this CPU
+pub fn setup() {
+	let base = unsafe { Msr::new(APIC_BASE_MSR).read() } & APIC_BASE_MASK;
+	BASE.store(paging::phys_to_virt(PhysAddr::new(base)).as_u64(), Ordering::Relaxed);
+	enable();
+}
+
+/// Enable the local APIC of this CPU, accepting interrupts of every priority
+pub fn enable() {
+	write(SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+	write(TASK_PRIORITY, 0);
+	// The error status register is cleared by writing to it
+	write(ERROR_STATUS, 0);
+}
+
+/// ID of the local APIC of this CPU
+pub fn id() -> u32 {
+	read(ID) >> 24
+}
+
+/// Tell the local APIC the interrupt it delivered was handled
+pub fn end_of_interrupt() {
+	write(END_OF_INTERRUPT, 0);
+}
+
+/// Send an interrupt with the given vector to the CPU with the local APIC `apic_id`
+pub fn send_ipi(apic_id: u32, vector: u8) {
+	send(apic_id, LEVEL_ASSERT | vector as u32);
+}
+
+/// Reset the CPU with the local APIC `apic_id`, making it wait for a [send_startup]
+pub fn send_init(apic_id: u32) {
+	send(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Start a CPU that was reset with [send_init] in real mode, at the start of the physical page
+/// `page`
+pub fn send_startup(apic_id: u32, page: u8) {
+	send(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+}
+
+fn send(apic_id: u32, command: u32) {
+	// A timer interrupt in between could send its own interrupt to another CPU
+	without_interrupts(|| {
+		write(INTERRUPT_COMMAND_HIGH, apic_id << 24);
+		// Writing the low half sends the interrupt
+		write(INTERRUPT_COMMAND_LOW, command);
+		while read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+			spin_loop();
+		}
+	});
+}
+
+/// Measure how fast the timer of this CPU counts against the PIT, and start it. The other CPUs
+/// are assumed to count as fast, as their timers run on the same bus clock.
+pub fn setup_timer() {
+	const MEASURE_MILLIS: u64 = 10;
+	write(TIMER_DIVIDE, DIVIDE_BY_16);
+	write(TIMER, TIMER_MASKED);
+	write(TIMER_INITIAL_COUNT, u32::MAX);
+	pit::poll_wait(MEASURE_MILLIS);
+	let counted = u32::MAX - read(TIMER_CURRENT_COUNT);
+	write(TIMER_INITIAL_COUNT, 0);
+	let count = counted as u64 * pit::TIMER_MILLIS / MEASURE_MILLIS;
+	serial_println!("LAPIC: timer counts {} per tick", count);
+	TIMER_COUNT.store(count as u32, Ordering::Relaxed);
+	start_timer();
+}
+
+/// Make the timer of this CPU interrupt with [TIMER_VECTOR] every tick. [setup_timer] must have
+/// run on the first CPU.
+pub fn start_timer() {
+	write(TIMER_DIVIDE, DIVIDE_BY_16);
+	write(TIMER, TIMER_PERIODIC | TIMER_VECTOR as u32);
+	write(TIMER_INITIAL_COUNT, TIMER_COUNT.load(Ordering::Relaxed));
+}
+
+/// Address and data a PCI device writes to send `vector` to the CPU with the local APIC
+/// `apic_id` as a message signaled interrupt. They are edge triggered with fixed delivery.
+pub fn msi_message(apic_id: u32, vector: u8) -> (u64, u16) {
+	(MSI_ADDRESS | (apic_id as u64) << 12, vector as u16)
+}
+
+/// Interrupt handler for spurious interrupts, there is nothing to do
+pub extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
@@ -9,3 +9,12 @@ pub mod syscalls;
 
 /// Programmable Interval Timer
 pub mod pit;
+
+/// Local APIC of each CPU, which receives interrupts and sends them to other CPUs
+pub mod lapic;
+
+/// I/O APICs, which route the interrupts of devices to the local APICs
+pub mod ioapic;
+
+/// Running on multiple CPUs: per-CPU data, the kernel lock and starting the other CPUs
+pub mod smp;
@@ -1,24 +1,34 @@
-use super::interrupts::{PICS, PIC_1_OFFSET};
+use super::{
+	lapic,
+	smp::{self, PerCpu, MAX_CPUS},
+};
 use crate::{cpu::syscalls::Registers, process, serial_println};
 use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use core::hint::spin_loop;
 use x86_64::{
 	instructions::{hlt, port::*},
 	structures::idt::InterruptStackFrame,
 };
 
-const CHANNEL_0: u16 = 0x40; // Read/Write
 const CHANNEL_2: u16 = 0x42; // Read/Write
 const MODE_COMMAND: u16 = 0x43; // Write
 const PORT: u16 = 0x61; // Read/Write
 
 const PIT_BASE_FREQ: u64 = 1193182;
 
-/// Milliseconds per PIT
+/// Bit of [PORT] that lets channel 2 count
+const CHANNEL_2_GATE: u8 = 1 << 0;
+/// Bit of [PORT] that connects channel 2 to the speaker
+const SPEAKER: u8 = 1 << 1;
+/// Bit of [PORT] that is the output of channel 2
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+/// Milliseconds per timer tick
 pub const TIMER_MILLIS: u64 = 10;
 const TIMER_NANOS: u64 = TIMER_MILLIS * 1_000_000;
-const QUANTA: usize = 5;
 
 /// A Note or a rest
 pub struct Note {
@@ -94,17 +104,27 @@ fn handle_queue() {
 	}
 }
 
-/// setup PIT
-pub fn setup_pit() {
-	let freq_hz = 1_000_000_000 / TIMER_NANOS;
-	let divisor = (PIT_BASE_FREQ / freq_hz) as u16;
+/// Busy wait for some milliseconds (up to 54) by counting down channel 2 once, with the speaker
+/// off. It works without interrupts, so other clocks can be measured against it before ticks start.
+pub fn poll_wait(millis: u64) {
+	let count = (PIT_BASE_FREQ * millis / 1000) as u16;
 
-	let mut data: PortGeneric<u8, ReadWriteAccess> = Port::new(CHANNEL_0);
+	let mut port: PortGeneric<u8, ReadWriteAccess> = Port::new(PORT);
+	let mut data: PortGeneric<u8, ReadWriteAccess> = Port::new(CHANNEL_2);
 	let mut command: PortGeneric<u8, ReadWriteAccess> = Port::new(MODE_COMMAND);
 	unsafe {
-		command.write(0x36);
-		data.write((divisor & 0xff) as u8);
-		data.write(((divisor >> 8) & 0xff) as u8);
+		let value = port.read() & !(SPEAKER | CHANNEL_2_GATE);
+		port.write(value);
+		// Channel 2, low byte then high byte, interrupt on terminal count
+		command.write(0xb0);
+		data.write((count & 0xff) as u8);
+		data.write(((count >> 8) & 0xff) as u8);
+		// Counting starts when the gate goes up, and the output goes up when it reaches 0
+		port.write(value | CHANNEL_2_GATE);
+		while port.read() & CHANNEL_2_OUTPUT == 0 {
+			spin_loop();
+		}
+		port.write(value);
 	}
 }
 
@@ -148,7 +168,9 @@ pub fn stop_sound() {
 	}
 }
 
-/// Number of ticks
+/// Number of ticks of the time stamp counter, which counts CPU cycles at a constant rate. Fine
+/// grained enough to time a single syscall.
+#[derive(Debug, Copy, Clone)]
 pub struct Ticks(pub u64);
 
 impl Sub for Ticks {
@@ -169,7 +191,8 @@ impl Add for Ticks {
 
 impl Into<Duration> for Ticks {
 	fn into(self) -> Duration {
-		Duration::from_nanos(self.0 * 1_000_000 / unsafe { TICKS_PER_MILISEC })
+		// In 128 bits, as the ticks times a million overflow 64 bits within hours
+		Duration::from_nanos((self.0 as u128 * 1_000_000 / unsafe { TICKS_PER_MILISEC } as u128) as u64)
 	}
 }
 
@@ -196,7 +219,7 @@ pub fn wait(duration: Duration) {
 	}
 }
 
-/// wait for timer interrupt
+/// wait for a timer tick of the first CPU
 pub fn wait_for_pit() {
 	let start_count = get_pit_count();
 	while get_pit_count() == start_count {
@@ -204,27 +227,21 @@ pub fn wait_for_pit() {
 	}
 }
 
-fn measure_ticks_per_pit() -> u64 {
-	let n = 5;
-	wait_for_pit();
+/// setup time: measure the time stamp counter against the PIT, then start the timer ticks of the
+/// local APIC
+pub fn setup_time() {
+	const MEASURE_MILLIS: u64 = 50;
 	let start_ticks = get_ticks().0;
-	for _ in 0..n {
-		wait_for_pit();
-	}
+	poll_wait(MEASURE_MILLIS);
 	let end_ticks = get_ticks().0;
-	(end_ticks - start_ticks) / n
-}
-
-/// setup time
-pub fn setup_time() {
-	let ticks_per_pit = measure_ticks_per_pit();
 	unsafe {
-		TICKS_PER_MILISEC = ticks_per_pit / TIMER_MILLIS;
+		TICKS_PER_MILISEC = (end_ticks - start_ticks) / MEASURE_MILLIS;
 		serial_println!("Ticks per milisec: {}", TICKS_PER_MILISEC);
 	}
+	lapic::setup_timer();
 }
 
-/// get the current time
+/// get the current time, in ticks of the time stamp counter
 pub fn get_ticks() -> Ticks {
 	let time_low: u32;
 	let time_high: u32;
@@ -244,33 +261,48 @@ pub fn get_time() -> Duration {
 	ticks.into()
 }
 
-/// count of pits since starting current process
-pub static mut PROC_COUNTER: usize = 0;
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// count of ticks since each CPU started its current process
+pub static PROC_COUNTER: PerCpu<AtomicUsize> = PerCpu::new([ZERO; MAX_CPUS]);
 
-/// total count of pits
+/// count of ticks the current process of each CPU may run for before it is preempted
+pub static PROC_QUANTUM: PerCpu<AtomicUsize> = PerCpu::new([ZERO; MAX_CPUS]);
+
+/// total count of timer ticks of the first CPU
 static mut PIT_COUNTER: usize = 0;
 
-/// get total count of PIT interrupts
+/// get total count of timer ticks of the first CPU
 #[inline(never)]
 pub fn get_pit_count() -> usize {
 	unsafe { PIT_COUNTER }
 }
 
+/// Handles the local APIC timer tick of each CPU
 #[allow(dead_code)] // called from asm
 #[no_mangle] // called from asm
 extern "C" fn handle_timer_inner(registers_ptr: *mut Registers) -> *const u8 {
-	let proc_count;
-	unsafe {
-		proc_count = PROC_COUNTER;
-		PROC_COUNTER += 1;
-		PIT_COUNTER += 1;
-	};
-	handle_queue();
-	unsafe {
-		PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 0);
+	let first_cpu = smp::cpu_index() == 0;
+	if first_cpu {
+		// Counted before waiting for the kernel lock, the CPU holding it may be waiting for a tick
+		unsafe {
+			PIT_COUNTER += 1;
+		}
+	}
+	lapic::end_of_interrupt();
+	let locked = smp::lock_kernel();
+	let proc_count = PROC_COUNTER.get().fetch_add(1, Ordering::Relaxed);
+	if first_cpu {
+		handle_queue();
+		process::wait_queue::check_deadlines();
+	}
+	let running = process::RUNNING.get().load(Ordering::Relaxed);
+	if running {
+		process::leave_if_removed();
+		process::account_user_time();
 	}
-	let running = unsafe { process::RUNNING };
-	if running && proc_count >= QUANTA {
+	if running && proc_count >= PROC_QUANTUM.get().load(Ordering::Relaxed) {
 		serial_println!("The clock's run out, time's up, over, blaow");
 
 		let registers: &mut Registers;
@@ -291,18 +323,23 @@ extern "C" fn handle_timer_inner(registers_ptr: *mut Registers) -> *const u8 {
 			rflags,
 		});
 	} else {
+		if locked {
+			smp::unlock_kernel();
+		}
 		return registers_ptr as *const _;
 	}
 }
 
 const STACK_SIZE: usize = 4096 * 8;
-static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+/// Stack of each CPU that the timer handler switches to
+static mut STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
 
 #[allow(dead_code)] // called from asm
 #[no_mangle] // called from asm
 extern "C" fn get_timer_stack_addr() -> *const u8 {
 	// Switch to kernel stack
-	let temp_stack: *const u8 = unsafe { STACK.as_ptr().add(STACK_SIZE) };
+	let temp_stack: *const u8 = unsafe { STACKS[smp::cpu_index()].as_ptr().add(STACK_SIZE) };
 	return temp_stack;
 }
 
@@ -311,9 +348,15 @@ extern "C" fn get_timer_stack_addr() -> *const u8 {
 pub extern "C" fn handle_timer() {
 	unsafe {
 		asm!(
-			// Push scratch registers
+			// Switch to the kernel's GS if the tick interrupted user mode
 			"
 			cli
+			test byte ptr [rsp + 8], 3
+			jz 2f
+			swapgs
+			2:",
+			// Push scratch registers
+			"
 			push rsp
 			push rax
 			push rcx
@@ -359,7 +402,13 @@ pub extern "C" fn handle_timer() {
 			pop rcx
 			pop rax
 			pop rsp",
-			"iretq",
+			// Switch back to the user's GS if returning to user mode
+			"
+			test byte ptr [rsp + 8], 3
+			jz 3f
+			swapgs
+			3:
+			iretq",
 			options(noreturn)
 		);
 	}
@@ -0,0 +1,452 @@
+use super::{gdt, interrupts, lapic, pit, syscalls};
+use crate::{drivers::acpi::madt, mem::paging, process};
+use alloc::vec;
+use core::{
+	hint::spin_loop,
+	ptr::copy_nonoverlapping,
+	sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+	time::Duration,
+};
+use x86_64::{
+	instructions::tlb,
+	registers::{
+		control::{Cr0, Cr3, Cr4},
+		model_specific::{Efer, GsBase, KernelGsBase},
+	},
+	structures::{idt::InterruptStackFrame, paging::PhysFrame},
+	PhysAddr, VirtAddr,
+};
+
+/// Most CPUs that are used, the rest are left alone
+pub const MAX_CPUS: usize = 8;
+
+/// Interrupt vector of TLB shootdowns
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+
+/// Data of a CPU, which the CPU finds through its GS segment while in the kernel. The syscall
+/// entry uses `kernel_stack` and `user_stack` before it has a stack, and [cpu_index] reads
+/// `index`, so their offsets must stay the same.
+#[repr(C)]
+struct Cpu {
+	/// Top of the kernel stack of the thread running on the CPU, at offset 0
+	kernel_stack: AtomicU64,
+	/// Stack pointer of the thread making a syscall while the stack is switched, at offset 8
+	#[allow(dead_code)] // used from asm
+	user_stack: AtomicU64,
+	/// Index of the CPU, at offset 16
+	index: AtomicUsize,
+	/// ID of the local APIC of the CPU
+	apic_id: AtomicU32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU: Cpu = Cpu {
+	kernel_stack: AtomicU64::new(0),
+	user_stack: AtomicU64::new(0),
+	index: AtomicUsize::new(0),
+	apic_id: AtomicU32::new(0),
+};
+
+static CPUS: [Cpu; MAX_CPUS] = [CPU; MAX_CPUS];
+
+/// Number of CPUs that were started, including the first one
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// A value for each CPU, of which each CPU uses its own
+pub struct PerCpu<T>([T; MAX_CPUS]);
+
+impl<T> PerCpu<T> {
+	/// Create the values of all CPUs
+	pub const fn new(values: [T; MAX_CPUS]) -> Self {
+		Self(values)
+	}
+
+	/// The value of the CPU running this
+	pub fn get(&self) -> &T {
+		&self.0[cpu_index()]
+	}
+
+	/// The value of the given CPU
+	pub fn of(&self, cpu: usize) -> &T {
+		&self.0[cpu]
+	}
+}
+
+/// Index of the CPU running this, from 0 for the one the machine booted on to [cpu_count]
+pub fn cpu_index() -> usize {
+	let index: usize;
+	unsafe {
+		asm!("mov {}, qword ptr gs:[16]", out(reg) index, options(nostack, readonly, preserves_flags));
+	}
+	index
+}
+
+/// Number of CPUs running
+pub fn cpu_count() -> usize {
+	CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Point GS at the data of the CPU. The kernel runs with this GS, user mode with a GS of 0, and
+/// `swapgs` switches between them on every entry to and exit from the kernel.
+fn set_cpu_data(cpu: usize) {
+	CPUS[cpu].index.store(cpu, Ordering::SeqCst);
+	GsBase::write(VirtAddr::from_ptr(&CPUS[cpu]));
+	KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Set up the data of the CPU the machine booted on, and take the kernel lock for the rest of the
+/// boot. Must come before anything uses [cpu_index].
+pub fn setup() {
+	set_cpu_data(0);
+	lock_kernel();
+}
+
+/// Set the stack syscalls and interrupts from user mode switch to on this CPU, the kernel stack of
+/// the thread about to run
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+	CPUS[cpu_index()].kernel_stack.store(stack_top.as_u64(), Ordering::SeqCst);
+	gdt::set_kernel_stack(stack_top);
+}
+
+/// No CPU holds the kernel lock
+const NO_CPU: usize = usize::MAX;
+
+/// The CPU running kernel code. Only one CPU is in the kernel at a time, the others wait for it
+/// where they enter the kernel: syscalls, interrupts and the scheduler. Waiting CPUs still
+/// handle TLB shootdowns, so the CPU in the kernel can wait for them.
+static KERNEL_LOCK: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+/// Wait until no other CPU is in the kernel, and take the kernel lock. Returns false if this CPU
+/// already held it.
+pub fn lock_kernel() -> bool {
+	let cpu = cpu_index();
+	loop {
+		match KERNEL_LOCK.compare_exchange_weak(NO_CPU, cpu, Ordering::Acquire, Ordering::Relaxed) {
+			Ok(_) => return true,
+			Err(owner) if owner == cpu => return false,
+			Err(_) => {
+				flush_if_shot_down();
+				spin_loop();
+			}
+		}
+	}
+}
+
+/// Release the kernel lock, if this CPU holds it
+pub fn unlock_kernel() {
+	let _ = KERNEL_LOCK.compare_exchange(cpu_index(), NO_CPU, Ordering::Release, Ordering::Relaxed);
+}
+
+/// Switches to the kernel's GS when an interrupt came from user mode, and back when dropped
+pub struct GsGuard {
+	from_user: bool,
+}
+
+impl GsGuard {
+	/// Switch to the kernel's GS, if the interrupt with the stack frame came from user mode
+	pub fn new(stack_frame: &InterruptStackFrame) -> Self {
+		let from_user = stack_frame.code_segment & 3 == 3;
+		if from_user {
+			unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+		}
+		Self { from_user }
+	}
+}
+
+impl Drop for GsGuard {
+	fn drop(&mut self) {
+		if self.from_user {
+			unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+		}
+	}
+}
+
+/// Entry into the kernel from an interrupt or exception. Switches to the kernel's GS and takes
+/// the kernel lock, and undoes both when dropped. Handlers that never return leave it to the
+/// scheduler.
+pub struct InterruptGuard {
+	_gs: GsGuard,
+	locked: bool,
+}
+
+impl InterruptGuard {
+	/// Enter the kernel for the interrupt with the stack frame
+	pub fn new(stack_frame: &InterruptStackFrame) -> Self {
+		let gs = GsGuard::new(stack_frame);
+		Self {
+			_gs: gs,
+			locked: lock_kernel(),
+		}
+	}
+}
+
+impl Drop for InterruptGuard {
+	fn drop(&mut self) {
+		if self.locked {
+			unlock_kernel();
+		}
+	}
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TABLE: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FLUSH: AtomicBool = AtomicBool::new(false);
+
+/// Physical address of the page table each CPU has loaded
+static LOADED_TABLE: PerCpu<AtomicU64> = PerCpu::new([NO_TABLE; MAX_CPUS]);
+
+/// Set for CPUs that have to flush their TLB, cleared once they did
+static FLUSH_TLB: PerCpu<AtomicBool> = PerCpu::new([NO_FLUSH; MAX_CPUS]);
+
+/// Remember that this CPU loaded the page table at `table`
+pub fn set_loaded_table(table: PhysAddr) {
+	LOADED_TABLE.get().store(table.as_u64(), Ordering::SeqCst);
+}
+
+/// Make the other CPUs that have the page table at `table` loaded flush their TLB, after
+/// mappings were removed from it. Returns once all of them did.
+pub fn shoot_down(table: PhysAddr) {
+	let me = cpu_index();
+	for cpu in (0..cpu_count()).filter(|cpu| *cpu != me) {
+		if LOADED_TABLE.of(cpu).load(Ordering::SeqCst) == table.as_u64() {
+			FLUSH_TLB.of(cpu).store(true, Ordering::SeqCst);
+			lapic::send_ipi(CPUS[cpu].apic_id.load(Ordering::SeqCst), TLB_SHOOTDOWN_VECTOR);
+		}
+	}
+	for cpu in 0..cpu_count() {
+		while FLUSH_TLB.of(cpu).load(Ordering::SeqCst) {
+			spin_loop();
+		}
+	}
+}
+
+fn flush_if_shot_down() {
+	if FLUSH_TLB.get().load(Ordering::SeqCst) {
+		tlb::flush_all();
+		FLUSH_TLB.get().store(false, Ordering::SeqCst);
+	}
+}
+
+/// Interrupt handler for TLB shootdowns. Doesn't take the kernel lock, the CPU that sent it holds
+/// it while waiting.
+pub extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: InterruptStackFrame) {
+	let _gs = GsGuard::new(&stack_frame);
+	flush_if_shot_down();
+	lapic::end_of_interrupt();
+}
+
+/// Physical address application processors start at. They start in real mode, so it has to be a
+/// page in the first MiB, and it has to match `SMP_TRAMPOLINE` in the trampoline.
+const TRAMPOLINE: u64 = 0x8000;
+
+/// Size of the stack application processors set themselves up on
+const BOOT_STACK_SIZE: usize = 4096 * 4;
+
+// Code application processors start at, copied to TRAMPOLINE. It goes from real mode to long mode
+// with the kernel page table, and calls the entry with the cpu index on the stack it is given. It
+// runs before paging is enabled, so it is identity mapped while processors start.
+global_asm!(
+	"
+	.equ SMP_TRAMPOLINE, 0x8000
+	.pushsection .rodata.smp_trampoline, \"a\"
+	.global smp_trampoline_start
+	.global smp_trampoline_end
+	.global smp_trampoline_cr3
+	.global smp_trampoline_stack
+	.global smp_trampoline_entry
+	.global smp_trampoline_cpu
+
+	.code16
+smp_trampoline_start:
+	cli
+	cld
+	xor ax, ax
+	mov ds, ax
+	lgdt [SMP_TRAMPOLINE + smp_trampoline_gdt_pointer - smp_trampoline_start]
+	mov eax, cr0
+	or eax, 1
+	mov cr0, eax
+	# jmp 0x08:smp_trampoline_protected
+	.byte 0x66, 0xea
+	.long SMP_TRAMPOLINE + smp_trampoline_protected - smp_trampoline_start
+	.word 0x08
+
+	.code32
+smp_trampoline_protected:
+	mov ax, 0x10
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	# Physical address extension
+	mov eax, cr4
+	or eax, 1 << 5
+	mov cr4, eax
+	mov eax, [SMP_TRAMPOLINE + smp_trampoline_cr3 - smp_trampoline_start]
+	mov cr3, eax
+	# Long mode and no execute in EFER
+	mov ecx, 0xC0000080
+	rdmsr
+	or eax, (1 << 8) | (1 << 11)
+	wrmsr
+	# Paging and write protect
+	mov eax, cr0
+	or eax, (1 << 31) | (1 << 16)
+	mov cr0, eax
+	# jmp 0x18:smp_trampoline_long
+	.byte 0xea
+	.long SMP_TRAMPOLINE + smp_trampoline_long - smp_trampoline_start
+	.word 0x18
+
+	.code64
+smp_trampoline_long:
+	xor ax, ax
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	mov rsp, [SMP_TRAMPOLINE + smp_trampoline_stack - smp_trampoline_start]
+	mov rdi, [SMP_TRAMPOLINE + smp_trampoline_cpu - smp_trampoline_start]
+	mov rax, [SMP_TRAMPOLINE + smp_trampoline_entry - smp_trampoline_start]
+	call rax
+	ud2
+
+	.align 8
+smp_trampoline_gdt:
+	.quad 0
+	.quad 0x00CF9A000000FFFF
+	.quad 0x00CF92000000FFFF
+	.quad 0x00AF9A000000FFFF
+smp_trampoline_gdt_pointer:
+	.word smp_trampoline_gdt_pointer - smp_trampoline_gdt - 1
+	.long SMP_TRAMPOLINE + smp_trampoline_gdt - smp_trampoline_start
+	.align 8
+smp_trampoline_cr3:
+	.quad 0
+smp_trampoline_stack:
+	.quad 0
+smp_trampoline_entry:
+	.quad 0
+smp_trampoline_cpu:
+	.quad 0
+smp_trampoline_end:
+	.popsection
+	"
+);
+
+extern "C" {
+	static smp_trampoline_start: u8;
+	static smp_trampoline_end: u8;
+	static smp_trampoline_cr3: u8;
+	static smp_trampoline_stack: u8;
+	static smp_trampoline_entry: u8;
+	static smp_trampoline_cpu: u8;
+}
+
+/// Set a parameter of the trampoline copied to [TRAMPOLINE], `field` is where it is in the original
+unsafe fn set_trampoline_field(field: &u8, value: u64) {
+	let offset = field as *const u8 as u64 - &smp_trampoline_start as *const u8 as u64;
+	*paging::phys_to_virt(PhysAddr::new(TRAMPOLINE + offset)).as_mut_ptr::<u64>() = value;
+}
+
+/// Set once the application processor being started runs in the kernel
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Control registers of the first CPU, which application processors copy
+static CR0: AtomicU64 = AtomicU64::new(0);
+static CR4: AtomicU64 = AtomicU64::new(0);
+static EFER: AtomicU64 = AtomicU64::new(0);
+
+/// Start the other CPUs listed in the MADT, one after the other with INIT and STARTUP interrupts.
+/// Each sets itself up and waits in the scheduler for threads to run. Must come before any user
+/// page table is created, since the trampoline is mapped in the kernel page table meanwhile.
+pub fn start_application_processors() {
+	let bsp_apic_id = lapic::id();
+	CPUS[0].apic_id.store(bsp_apic_id, Ordering::SeqCst);
+	let madt = match madt::read() {
+		Some(madt) => madt,
+		None => {
+			serial_println!("SMP: no MADT, running on one CPU");
+			return;
+		}
+	};
+
+	let kernel_table = Cr3::read().0.start_address().as_u64();
+	assert!(kernel_table < 1 << 32, "The trampoline can only load a page table in the first 4GiB");
+	CR0.store(Cr0::read_raw(), Ordering::SeqCst);
+	CR4.store(Cr4::read_raw(), Ordering::SeqCst);
+	EFER.store(Efer::read_raw(), Ordering::SeqCst);
+	unsafe {
+		let start = &smp_trampoline_start as *const u8;
+		let size = &smp_trampoline_end as *const u8 as usize - start as usize;
+		copy_nonoverlapping(start, paging::phys_to_virt(PhysAddr::new(TRAMPOLINE)).as_mut_ptr(), size);
+		set_trampoline_field(&smp_trampoline_cr3, kernel_table);
+		set_trampoline_field(&smp_trampoline_entry, ap_main as usize as u64);
+	}
+	paging::map_identity(PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE)));
+
+	for processor in madt.processors.iter() {
+		let apic_id = processor.apic_id as u32;
+		if !processor.enabled || apic_id == bsp_apic_id {
+			continue;
+		}
+		let cpu = cpu_count();
+		if cpu == MAX_CPUS {
+			serial_println!("SMP: only {} CPUs are used", MAX_CPUS);
+			break;
+		}
+		CPUS[cpu].apic_id.store(apic_id, Ordering::SeqCst);
+		let stack = vec![0u8; BOOT_STACK_SIZE].leak();
+		let stack_top = (VirtAddr::from_ptr(stack.as_ptr()) + BOOT_STACK_SIZE).align_down(16u64);
+		unsafe {
+			set_trampoline_field(&smp_trampoline_stack, stack_top.as_u64());
+			set_trampoline_field(&smp_trampoline_cpu, cpu as u64);
+		}
+		STARTED.store(false, Ordering::SeqCst);
+
+		lapic::send_init(apic_id);
+		pit::wait(Duration::from_millis(10));
+		// The second STARTUP is only needed if the first was missed
+		for _ in 0..2 {
+			if STARTED.load(Ordering::SeqCst) {
+				break;
+			}
+			lapic::send_startup(apic_id, (TRAMPOLINE >> 12) as u8);
+			pit::wait(Duration::from_millis(10));
+		}
+		for _ in 0..10 {
+			if STARTED.load(Ordering::SeqCst) {
+				break;
+			}
+			pit::wait_for_pit();
+		}
+
+		if STARTED.load(Ordering::SeqCst) {
+			CPU_COUNT.store(cpu + 1, Ordering::SeqCst);
+			serial_println!("SMP: started CPU {} (APIC {})", cpu, apic_id);
+		} else {
+			serial_println!("SMP: APIC {} didn't start", apic_id);
+		}
+	}
+
+	paging::unmap_identity();
+	serial_println!("SMP: running on {} CPUs", cpu_count());
+}
+
+/// Where application processors continue from the trampoline, on their boot stack
+extern "C" fn ap_main(cpu: usize) -> ! {
+	unsafe {
+		Cr0::write_raw(CR0.load(Ordering::SeqCst));
+		Cr4::write_raw(CR4.load(Ordering::SeqCst));
+		Efer::write_raw(EFER.load(Ordering::SeqCst));
+	}
+	set_cpu_data(cpu);
+	gdt::setup_ap(cpu);
+	interrupts::setup_ap();
+	syscalls::setup();
+	lapic::enable();
+	lapic::start_timer();
+	STARTED.store(true, Ordering::SeqCst);
+
+	lock_kernel();
+	process::run_next_process();
+}
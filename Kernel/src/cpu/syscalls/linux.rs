@@ -0,0 +1,924 @@
+use super::{user_mut, user_ref, user_slice, user_slice_mut, OpenFlags, Syscall, SyscallResult, SyscallResult::*};
+use crate::{
+	cpu::pit::get_time,
+	fs::ext2::{self, Access, Ext2Err, Metadata, Type},
+	process::{self, layout::USER_END, Handle, PCB},
+	serial_println,
+	util::io::{IOError, SeekFrom},
+};
+use alloc::{format, string::String};
+use core::{str, time::Duration};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+// Error numbers, which syscalls return negated
+const EPERM: i64 = 1;
+const ENOENT: i64 = 2;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const ENODEV: i64 = 19;
+const ENOTDIR: i64 = 20;
+const EISDIR: i64 = 21;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ETXTBSY: i64 = 26;
+const ENOSPC: i64 = 28;
+const ESPIPE: i64 = 29;
+const EPIPE: i64 = 32;
+const ERANGE: i64 = 34;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+const ENOTEMPTY: i64 = 39;
+
+// Flags of open
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+
+// The *at syscalls
+const AT_FDCWD: i32 = -100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// Whence of lseek
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+// mmap
+const PROT_READ: u64 = 0x1;
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// ioctl requests of terminals
+const TCGETS: u64 = 0x5401;
+const TIOCGWINSZ: u64 = 0x5413;
+
+// Codes of arch_prctl
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+
+/// Paths can't be longer than this, including the NUL
+const PATH_MAX: usize = 4096;
+/// readv and writev can't be given more vectors than this
+const IOV_MAX: u64 = 1024;
+
+/// Get the function handling a Linux syscall. The ones that aren't implemented return -ENOSYS.
+pub fn syscall(number: u64) -> Syscall {
+	match number {
+		0 => sys_read,
+		1 => sys_write,
+		2 => sys_open,
+		3 => sys_close,
+		4 => sys_stat,
+		5 => sys_fstat,
+		6 => sys_stat, // There are no symbolic links to not follow
+		8 => sys_lseek,
+		9 => sys_mmap,
+		10 => sys_mprotect,
+		11 => sys_munmap,
+		12 => sys_brk,
+		13 => sys_ignored, // rt_sigaction, there are no signals
+		14 => sys_rt_sigprocmask,
+		16 => sys_ioctl,
+		19 => sys_readv,
+		20 => sys_writev,
+		21 => sys_access,
+		35 => sys_nanosleep,
+		39 => super::sys_getpid,
+		60 => sys_exit, // Processes can't start threads with clone, so the thread is the process
+		63 => sys_uname,
+		79 => sys_getcwd,
+		83 => sys_mkdir,
+		84 => sys_rmdir,
+		87 => sys_unlink,
+		90 => sys_chmod,
+		95 => super::sys_umask,
+		102 => sys_getuid,
+		104 => sys_getgid,
+		107 => sys_getuid, // There are no set-user-ID executables, so the effective user is the user
+		108 => sys_getgid,
+		158 => sys_arch_prctl,
+		186 => super::sys_gettid,
+		217 => sys_getdents64,
+		218 => super::sys_gettid, // set_tid_address, there is nobody to clear the address for
+		228 => sys_clock_gettime,
+		231 => sys_exit,
+		257 => sys_openat,
+		258 => sys_mkdirat,
+		262 => sys_newfstatat,
+		263 => sys_unlinkat,
+		269 => sys_faccessat,
+		273 => sys_ignored, // set_robust_list, nobody handles the futexes of dead threads
+		_ => {
+			serial_println!("Unimplemented Linux syscall {}", number);
+			sys_unimplemented
+		}
+	}
+}
+
+/// Where a Linux file descriptor leads. The first three are the terminal of the process, the rest
+/// are its handles.
+enum Descriptor {
+	Input,
+	Output,
+	Handle(Handle),
+}
+
+fn descriptor(fd: u64) -> Option<Descriptor> {
+	match fd {
+		0 => Some(Descriptor::Input),
+		1 | 2 => Some(Descriptor::Output),
+		fd => fd.try_into().ok().map(Descriptor::Handle),
+	}
+}
+
+/// Run `f` on the running process, with [process::MAP] locked
+fn with_running<T>(f: impl FnOnce(&mut PCB) -> T) -> T {
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	f(lock.get_mut(&running).expect("running process not in hashmap"))
+}
+
+/// The Linux error number of a file system error, negated
+fn errno(err: Ext2Err) -> i64 {
+	-match err {
+		Ext2Err::FileNotFound | Ext2Err::NoParentDir => ENOENT,
+		Ext2Err::FileAlreadyExists => EEXIST,
+		Ext2Err::NotADir => ENOTDIR,
+		Ext2Err::NotAFile => EISDIR,
+		Ext2Err::DirNotEmpty => ENOTEMPTY,
+		Ext2Err::PermissionDenied => EACCES,
+		Ext2Err::TextBusy => ETXTBSY,
+		Ext2Err::NoHandle | Ext2Err::IO(IOError::PermissionDenied) => EBADF,
+		Ext2Err::NoInodes | Ext2Err::NoBlocks => ENOSPC,
+		Ext2Err::NotAbsolute | Ext2Err::Utf8Error | Ext2Err::IO(IOError::BufferTooSmall) => EINVAL,
+		Ext2Err::IO(IOError::BrokenPipe) => EPIPE,
+		_ => EIO,
+	}
+}
+
+/// Read the NUL terminated path at `ptr`. The working directory is always the root, so relative
+/// paths are taken from it.
+fn path(ptr: u64) -> core::result::Result<String, i64> {
+	let mut len = 0;
+	loop {
+		match user_ref::<u8>(ptr.wrapping_add(len as u64)) {
+			Some(0) => break,
+			Some(_) => {}
+			None => return Err(-EFAULT),
+		}
+		len += 1;
+		if len == PATH_MAX {
+			return Err(-ENAMETOOLONG);
+		}
+	}
+	let bytes = user_slice(ptr, len as u64).ok_or(-EFAULT)?;
+	match str::from_utf8(bytes) {
+		Ok("") => Err(-ENOENT),
+		Ok(path) if path.starts_with('/') => Ok(String::from(path)),
+		Ok(path) => Ok(format!("/{}", path)),
+		Err(_) => Err(-EINVAL),
+	}
+}
+
+/// Read the path at `ptr` for one of the *at syscalls. Open directories don't know their paths,
+/// so relative paths can only be taken from the working directory.
+fn path_at(dirfd: u64, ptr: u64) -> core::result::Result<String, i64> {
+	let path = path(ptr)?;
+	let relative = user_ref::<u8>(ptr) != Some(&b'/');
+	if relative && dirfd as i32 != AT_FDCWD {
+		return Err(-EINVAL);
+	}
+	Ok(path)
+}
+
+/// Change the permissions of a file that was just created to `mode`, without the permissions the
+/// umask of the process clears
+fn set_mode(path: &str, mode: u64, process: &PCB) {
+	let permissions = mode as u16 & 0o777 & !process.credentials.umask;
+	// The creator owns the file, so this can't fail
+	let _ = ext2::chmod(path, permissions, &process.credentials);
+}
+
+fn sys_unimplemented(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(-ENOSYS)
+}
+
+/// Syscalls that change nothing here, but that succeed
+fn sys_ignored(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(0)
+}
+
+fn sys_read(fd: u64, ptr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	read(fd, ptr, len, true)
+}
+
+/// Read from a file descriptor. If nothing can be read yet, this blocks or fails with EAGAIN.
+fn read(fd: u64, ptr: u64, len: u64, block: bool) -> SyscallResult {
+	let slice = match user_slice_mut(ptr, len) {
+		Some(slice) => slice,
+		None => return Result(-EFAULT),
+	};
+	let handle = match descriptor(fd) {
+		Some(Descriptor::Input) if !block && with_running(|process| process.input_buffer.is_empty()) => {
+			return Result(-EAGAIN)
+		}
+		Some(Descriptor::Input) => return super::sys_input(ptr, len, 0, 0, 0, 0),
+		Some(Descriptor::Output) | None => return Result(-EBADF),
+		Some(Descriptor::Handle(handle)) => handle,
+	};
+	loop {
+		let read = with_running(|process| {
+			if process.open_files.is_dir(handle) {
+				return Some(Result(-EISDIR));
+			}
+			match process.open_files.read(handle, slice) {
+				Ok(count) => Some(Result(count as i64)),
+				Err(Ext2Err::IO(IOError::WouldBlock)) => None,
+				Err(e) => Some(Result(errno(e))),
+			}
+		});
+		match read {
+			Some(result) => return result,
+			None if block => {
+				process::block_current("Pipe", None);
+			}
+			None => return Result(-EAGAIN),
+		}
+	}
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	write(fd, ptr, len, true)
+}
+
+/// Write to a file descriptor. If nothing can be written yet, this blocks or fails with EAGAIN.
+fn write(fd: u64, ptr: u64, len: u64, block: bool) -> SyscallResult {
+	let slice = match user_slice(ptr, len) {
+		Some(slice) => slice,
+		None => return Result(-EFAULT),
+	};
+	let handle = match descriptor(fd) {
+		Some(Descriptor::Output) => {
+			let term = with_running(|process| process.terminal);
+			crate::io::buffer::print_on(&String::from_utf8_lossy(slice), term);
+			return Result(len as i64);
+		}
+		Some(Descriptor::Input) | None => return Result(-EBADF),
+		Some(Descriptor::Handle(handle)) => handle,
+	};
+	loop {
+		let written = with_running(|process| match process.open_files.write(handle, slice) {
+			Ok(count) => Some(Result(count as i64)),
+			Err(Ext2Err::IO(IOError::WouldBlock)) => None,
+			Err(e) => Some(Result(errno(e))),
+		});
+		match written {
+			Some(result) => return result,
+			None if block => {
+				process::block_current("Pipe", None);
+			}
+			None => return Result(-EAGAIN),
+		}
+	}
+}
+
+/// An element of the vectors readv and writev are given
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct IoVec {
+	base: u64,
+	len: u64,
+}
+
+/// Read or write the buffers of a vector one after another, until one of them is cut short. Only
+/// the first buffer may block, as what was transferred before would be lost.
+fn vectored(fd: u64, ptr: u64, count: u64, transfer: fn(u64, u64, u64, bool) -> SyscallResult) -> SyscallResult {
+	if count > IOV_MAX {
+		return Result(-EINVAL);
+	}
+	let vectors = match user_slice::<IoVec>(ptr, count) {
+		Some(vectors) => vectors,
+		None => return Result(-EFAULT),
+	};
+	let mut total = 0;
+	for vector in vectors {
+		match transfer(fd, vector.base, vector.len, total == 0) {
+			Result(result) if result < 0 && total == 0 => return Result(result),
+			Result(result) if result >= 0 => {
+				total += result;
+				if (result as u64) < vector.len {
+					break;
+				}
+			}
+			_ => break,
+		}
+	}
+	Result(total)
+}
+
+fn sys_readv(fd: u64, ptr: u64, count: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	vectored(fd, ptr, count, read)
+}
+
+fn sys_writev(fd: u64, ptr: u64, count: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	vectored(fd, ptr, count, write)
+}
+
+fn sys_open(ptr: u64, flags: u64, mode: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	sys_openat(AT_FDCWD as u64, ptr, flags, mode, 0, 0)
+}
+
+/// Open a file or a directory. Creating a file gives it the permissions of `mode`.
+fn sys_openat(dirfd: u64, ptr: u64, flags: u64, mode: u64, _: u64, _: u64) -> SyscallResult {
+	let path = match path_at(dirfd, ptr) {
+		Ok(path) => path,
+		Err(e) => return Result(e),
+	};
+	let existing = ext2::inode_type(&path, &process::running_credentials()).map(|(_, inode_type)| inode_type);
+	let is_dir = matches!(existing, Ok(Type::Directory));
+	if flags & O_DIRECTORY != 0 && existing.is_ok() && !is_dir {
+		return Result(-ENOTDIR);
+	}
+	// Waiting for the other end of a FIFO is left until the process isn't locked
+	let opened = with_running(|process| {
+		if is_dir {
+			if flags & O_ACCMODE != O_RDONLY {
+				return Ok(Result(-EISDIR));
+			}
+			return Ok(match process.open_files.open_dir(&path, &process.credentials) {
+				Ok(handle) => Result(handle as i64),
+				Err(e) => Result(errno(e)),
+			});
+		}
+		let mut open_flags = match flags & O_ACCMODE {
+			O_RDONLY => OpenFlags::READ,
+			O_WRONLY => OpenFlags::WRITE,
+			_ => OpenFlags::empty(),
+		};
+		open_flags.set(OpenFlags::CREATE, flags & O_CREAT != 0);
+		open_flags.set(OpenFlags::EXCLUSIVE, flags & O_EXCL != 0);
+		open_flags.set(OpenFlags::TRUNCATE, flags & O_TRUNC != 0);
+		open_flags.set(OpenFlags::APPEND, flags & O_APPEND != 0);
+		match process.open_files.open_file(&path, open_flags, &process.credentials) {
+			Ok(handle) => {
+				if existing.is_err() {
+					set_mode(&path, mode, process);
+				}
+				// A FIFO waits for its other end, without the lock
+				Err(handle)
+			}
+			Err(e) => Ok(Result(errno(e))),
+		}
+	});
+	match opened {
+		Ok(result) => result,
+		Err(handle) => {
+			process::wait_for_peer(handle);
+			Result(handle as i64)
+		}
+	}
+}
+
+fn sys_close(fd: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match descriptor(fd) {
+		// The terminal stays where it is
+		Some(Descriptor::Input | Descriptor::Output) => Result(0),
+		Some(Descriptor::Handle(handle)) => with_running(|process| match process.open_files.close(handle) {
+			Ok(()) => Result(0),
+			Err(e) => Result(errno(e)),
+		}),
+		None => Result(-EBADF),
+	}
+}
+
+/// A time as Linux passes it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Timespec {
+	seconds: i64,
+	nanoseconds: i64,
+}
+
+impl From<Duration> for Timespec {
+	fn from(duration: Duration) -> Self {
+		Self {
+			seconds: duration.as_secs() as i64,
+			nanoseconds: duration.subsec_nanos() as i64,
+		}
+	}
+}
+
+/// What stat and its variations write, laid out like Linux does on x86-64
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Stat {
+	device: u64,
+	inode: u64,
+	links: u64,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	_padding: u32,
+	represented_device: u64,
+	size: i64,
+	block_size: i64,
+	blocks: i64,
+	access_time: Timespec,
+	modification_time: Timespec,
+	change_time: Timespec,
+	_reserved: [i64; 3],
+}
+
+impl From<Metadata> for Stat {
+	fn from(metadata: Metadata) -> Self {
+		let kind = match metadata.inode_type {
+			Type::Fifo => 0o010000,
+			Type::CharacterDevice => 0o020000,
+			Type::Directory => 0o040000,
+			Type::BlockDevice => 0o060000,
+			Type::RegularFile => 0o100000,
+			Type::SymbolicLink => 0o120000,
+			Type::UnixSocket => 0o140000,
+			Type::Other => 0,
+		};
+		let time = |seconds: u32| Timespec {
+			seconds: seconds as i64,
+			nanoseconds: 0,
+		};
+		Self {
+			inode: metadata.inode as u64,
+			links: metadata.links as u64,
+			mode: kind | metadata.permissions as u32,
+			uid: metadata.uid as u32,
+			gid: metadata.gid as u32,
+			size: metadata.size as i64,
+			block_size: 4096,
+			blocks: metadata.sectors as i64,
+			access_time: time(metadata.access_time),
+			modification_time: time(metadata.modification_time),
+			change_time: time(metadata.change_time),
+			..Self::default()
+		}
+	}
+}
+
+/// Write the stat of the metadata to `ptr`
+fn write_stat(ptr: u64, metadata: core::result::Result<Metadata, Ext2Err>) -> SyscallResult {
+	let metadata = match metadata {
+		Ok(metadata) => metadata,
+		Err(e) => return Result(errno(e)),
+	};
+	match user_mut::<Stat>(ptr) {
+		Some(stat) => {
+			*stat = Stat::from(metadata);
+			Result(0)
+		}
+		None => Result(-EFAULT),
+	}
+}
+
+/// The metadata of the file at the path. Only reads the file system, so the thread may sleep while
+/// it waits for the disk.
+fn metadata(path: &str) -> Result<Metadata, Ext2Err> {
+	let credentials = process::running_credentials();
+	process::with_blocking_io(|| ext2::metadata(path, &credentials))
+}
+
+fn sys_stat(ptr: u64, stat: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match path(ptr) {
+		Ok(path) => write_stat(stat, metadata(&path)),
+		Err(e) => Result(e),
+	}
+}
+
+fn sys_fstat(fd: u64, stat: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let metadata = match descriptor(fd) {
+		Some(Descriptor::Input | Descriptor::Output) => {
+			let mut terminal = Metadata::anonymous(Type::CharacterDevice);
+			terminal.permissions = 0o620;
+			Ok(terminal)
+		}
+		Some(Descriptor::Handle(handle)) => with_running(|process| process.open_files.metadata(handle)),
+		None => Err(Ext2Err::NoHandle),
+	};
+	write_stat(stat, metadata)
+}
+
+fn sys_newfstatat(dirfd: u64, ptr: u64, stat: u64, flags: u64, _: u64, _: u64) -> SyscallResult {
+	if flags & AT_EMPTY_PATH != 0 && user_ref::<u8>(ptr) == Some(&0) {
+		return sys_fstat(dirfd, stat, 0, 0, 0, 0);
+	}
+	match path_at(dirfd, ptr) {
+		Ok(path) => write_stat(stat, metadata(&path)),
+		Err(e) => Result(e),
+	}
+}
+
+fn sys_lseek(fd: u64, offset: u64, whence: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let offset = offset as i64;
+	let pos = match whence {
+		SEEK_SET if offset >= 0 => SeekFrom::Start(offset as usize),
+		SEEK_CUR => SeekFrom::Current(offset as isize),
+		SEEK_END => SeekFrom::End(offset as isize),
+		_ => return Result(-EINVAL),
+	};
+	match descriptor(fd) {
+		Some(Descriptor::Input | Descriptor::Output) => Result(-ESPIPE),
+		Some(Descriptor::Handle(handle)) => with_running(|process| match process.open_files.seek(handle, pos) {
+			Ok(position) => Result(position as i64),
+			Err(Ext2Err::NotAFile) => Result(-ESPIPE),
+			// Seeking before the start
+			Err(Ext2Err::IO(IOError::Other)) => Result(-EINVAL),
+			Err(e) => Result(errno(e)),
+		}),
+		None => Result(-EBADF),
+	}
+}
+
+/// Map anonymous memory, somewhere of the kernel's choosing. Files can't be mapped.
+fn sys_mmap(_: u64, len: u64, protection: u64, flags: u64, _: u64, _: u64) -> SyscallResult {
+	if flags & MAP_ANONYMOUS == 0 {
+		return Result(-ENODEV);
+	}
+	if flags & MAP_FIXED != 0 || len == 0 {
+		return Result(-EINVAL);
+	}
+	if len >= USER_END {
+		return Result(-ENOMEM);
+	}
+	let memory = with_running(|process| process.memory());
+	let mapped = memory.lock().map_anonymous(len, protection_flags(protection));
+	match mapped {
+		Some(addr) => Result(addr.as_u64() as i64),
+		None => Result(-ENOMEM),
+	}
+}
+
+/// Change the protection of the pages in a range, all of which must be mapped
+fn sys_mprotect(addr: u64, len: u64, protection: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let start = match VirtAddr::try_new(addr) {
+		Ok(start) if start.is_aligned(4096u64) && addr < USER_END => start,
+		_ => return Result(-EINVAL),
+	};
+	if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+		return Result(-EINVAL);
+	}
+	if len == 0 {
+		return Result(0);
+	}
+	if len > USER_END - addr {
+		return Result(-ENOMEM);
+	}
+	let memory = with_running(|process| process.memory());
+	let protected = memory.lock().protect(start, len, protection_flags(protection));
+	Result(if protected { 0 } else { -ENOMEM })
+}
+
+/// The flags of pages with the protection of mmap and mprotect. Pages can't be write or execute
+/// only, so they are readable unless the protection is none, which leaves them to the kernel.
+fn protection_flags(protection: u64) -> PageTableFlags {
+	let mut flags = PageTableFlags::PRESENT;
+	if protection != 0 {
+		flags |= PageTableFlags::USER_ACCESSIBLE;
+	}
+	if protection & PROT_WRITE != 0 {
+		flags |= PageTableFlags::WRITABLE;
+	}
+	if protection & PROT_EXEC == 0 {
+		flags |= PageTableFlags::NO_EXECUTE;
+	}
+	flags
+}
+
+fn sys_munmap(addr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let start = match VirtAddr::try_new(addr) {
+		Ok(start) if start.is_aligned(4096u64) && addr < USER_END && len > 0 => start,
+		_ => return Result(-EINVAL),
+	};
+	let memory = with_running(|process| process.memory());
+	memory.lock().unmap(start, len.min(USER_END - addr));
+	Result(0)
+}
+
+/// Move the end of the heap to `addr`, and return where it is. Returns where it is without moving
+/// it if it can't be moved there, so brk(0) gets it.
+fn sys_brk(addr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let addr = VirtAddr::try_new(addr).unwrap_or_else(|_| VirtAddr::zero());
+	let memory = with_running(|process| process.memory());
+	let program_break = memory.lock().set_break(addr);
+	Result(program_break.as_u64() as i64)
+}
+
+/// Nothing is ever blocked, so the old mask is empty
+fn sys_rt_sigprocmask(_: u64, _: u64, old: u64, size: u64, _: u64, _: u64) -> SyscallResult {
+	if let Some(old) = user_slice_mut::<u8>(old, size) {
+		old.fill(0);
+	}
+	Result(0)
+}
+
+/// Size of a terminal, as TIOCGWINSZ writes it
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct WindowSize {
+	rows: u16,
+	columns: u16,
+	width: u16,
+	height: u16,
+}
+
+/// Only the terminal supports ioctl, with TCGETS for checking that it is a terminal, and
+/// TIOCGWINSZ for its size
+fn sys_ioctl(fd: u64, request: u64, arg: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match descriptor(fd) {
+		Some(Descriptor::Input | Descriptor::Output) => {}
+		Some(Descriptor::Handle(handle)) => {
+			let exists = with_running(|process| process.open_files.metadata(handle).is_ok());
+			return Result(if exists { -ENOTTY } else { -EBADF });
+		}
+		None => return Result(-EBADF),
+	}
+	match request {
+		TCGETS => {
+			// The settings of the terminal can't be changed, so they are all 0
+			const TERMIOS_SIZE: u64 = 36;
+			match user_slice_mut::<u8>(arg, TERMIOS_SIZE) {
+				Some(termios) => {
+					termios.fill(0);
+					Result(0)
+				}
+				None => Result(-EFAULT),
+			}
+		}
+		TIOCGWINSZ => {
+			let term = with_running(|process| process.terminal);
+			let (columns, rows) = crate::io::buffer::size_of_term(term);
+			match user_mut::<WindowSize>(arg) {
+				Some(size) => {
+					*size = WindowSize {
+						rows: rows as u16,
+						columns: columns as u16,
+						width: 0,
+						height: 0,
+					};
+					Result(0)
+				}
+				None => Result(-EFAULT),
+			}
+		}
+		_ => Result(-EINVAL),
+	}
+}
+
+fn sys_access(ptr: u64, mode: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	sys_faccessat(AT_FDCWD as u64, ptr, mode, 0, 0, 0)
+}
+
+/// Check that the process may access the file at the path. The access bits of Linux are laid out
+/// like [Access], and without any only checks that the file exists.
+fn sys_faccessat(dirfd: u64, ptr: u64, mode: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let path = match path_at(dirfd, ptr) {
+		Ok(path) => path,
+		Err(e) => return Result(e),
+	};
+	let wanted = Access::from_bits_truncate(mode as u16);
+	let credentials = process::running_credentials();
+	match process::with_blocking_io(|| ext2::access(&path, &credentials, wanted)) {
+		Ok(()) => Result(0),
+		Err(e) => Result(errno(e)),
+	}
+}
+
+fn sys_exit(status: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	// Only the lowest byte of the status is kept, like Linux does
+	super::sys_exit(status & 0xFF, 0, 0, 0, 0, 0)
+}
+
+/// What uname writes, each field a NUL terminated string
+#[repr(C)]
+struct UtsName {
+	system: [u8; 65],
+	node: [u8; 65],
+	release: [u8; 65],
+	version: [u8; 65],
+	machine: [u8; 65],
+	domain: [u8; 65],
+}
+
+fn sys_uname(ptr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let field = |value: &str| {
+		let mut field = [0u8; 65];
+		field[..value.len()].copy_from_slice(value.as_bytes());
+		field
+	};
+	match user_mut::<UtsName>(ptr) {
+		Some(name) => {
+			*name = UtsName {
+				system: field("GuyOS"),
+				node: field("guyos"),
+				// C libraries refuse to run on versions of Linux that are too old
+				release: field("5.15.0"),
+				version: field(env!("CARGO_PKG_VERSION")),
+				machine: field("x86_64"),
+				domain: field("(none)"),
+			};
+			Result(0)
+		}
+		None => Result(-EFAULT),
+	}
+}
+
+/// The working directory is always the root
+fn sys_getcwd(ptr: u64, size: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	const CWD: &[u8] = b"/\0";
+	if size < CWD.len() as u64 {
+		return Result(-ERANGE);
+	}
+	match user_slice_mut::<u8>(ptr, CWD.len() as u64) {
+		Some(buffer) => {
+			buffer.copy_from_slice(CWD);
+			Result(CWD.len() as i64)
+		}
+		None => Result(-EFAULT),
+	}
+}
+
+fn sys_mkdir(ptr: u64, mode: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	sys_mkdirat(AT_FDCWD as u64, ptr, mode, 0, 0, 0)
+}
+
+fn sys_mkdirat(dirfd: u64, ptr: u64, mode: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let path = match path_at(dirfd, ptr) {
+		Ok(path) => path,
+		Err(e) => return Result(e),
+	};
+	with_running(|process| match ext2::mkdir(&path, &process.credentials) {
+		Ok(_) => {
+			set_mode(&path, mode, process);
+			Result(0)
+		}
+		Err(e) => Result(errno(e)),
+	})
+}
+
+fn sys_rmdir(ptr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	sys_unlinkat(AT_FDCWD as u64, ptr, AT_REMOVEDIR, 0, 0, 0)
+}
+
+fn sys_unlink(ptr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	sys_unlinkat(AT_FDCWD as u64, ptr, 0, 0, 0, 0)
+}
+
+fn sys_unlinkat(dirfd: u64, ptr: u64, flags: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let path = match path_at(dirfd, ptr) {
+		Ok(path) => path,
+		Err(e) => return Result(e),
+	};
+	let credentials = process::running_credentials();
+	let result = if flags & AT_REMOVEDIR != 0 {
+		ext2::rmdir(&path, &credentials)
+	} else {
+		ext2::unlink(&path, false, &credentials)
+	};
+	match result {
+		Ok(()) => Result(0),
+		Err(e) => Result(errno(e)),
+	}
+}
+
+fn sys_chmod(ptr: u64, mode: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let path = match path(ptr) {
+		Ok(path) => path,
+		Err(e) => return Result(e),
+	};
+	match ext2::chmod(&path, mode as u16 & 0o7777, &process::running_credentials()) {
+		Ok(()) => Result(0),
+		Err(Ext2Err::PermissionDenied) => Result(-EPERM),
+		Err(e) => Result(errno(e)),
+	}
+}
+
+fn sys_getuid(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(process::running_credentials().uid as i64)
+}
+
+fn sys_getgid(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(process::running_credentials().gid as i64)
+}
+
+/// Set or get the base of the FS segment, used for thread local storage
+fn sys_arch_prctl(code: u64, addr: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match code {
+		ARCH_SET_FS => match super::sys_set_fs_base(addr, 0, 0, 0, 0, 0) {
+			Result(0) => Result(0),
+			_ => Result(-EPERM),
+		},
+		ARCH_GET_FS => {
+			let running = process::running_thread();
+			let base = process::THREADS
+				.lock()
+				.get(&running)
+				.expect("running thread not in hashmap")
+				.fs_base;
+			match user_mut::<u64>(addr) {
+				Some(addr) => {
+					*addr = base.as_u64();
+					Result(0)
+				}
+				None => Result(-EFAULT),
+			}
+		}
+		_ => Result(-EINVAL),
+	}
+}
+
+/// Size of the header of the records getdents64 writes: the inode, the offset of the next record,
+/// the size of the record and the type
+const DIRENT_HEADER_SIZE: usize = 19;
+
+/// Write as many directory entries as fit, as Linux records padded to 8 bytes
+fn sys_getdents64(fd: u64, ptr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle = match descriptor(fd) {
+		Some(Descriptor::Handle(handle)) => handle,
+		Some(_) => return Result(-ENOTDIR),
+		None => return Result(-EBADF),
+	};
+	let slice = match user_slice_mut(ptr, len) {
+		Some(slice) => slice,
+		None => return Result(-EFAULT),
+	};
+	let mut offset = 0u64;
+	let written = with_running(|process| {
+		process.open_files.read_dir_with(handle, slice, |entry, slice| {
+			let name = entry.name.as_bytes();
+			let record_length = (DIRENT_HEADER_SIZE + name.len() + 1 + 7) & !7;
+			if record_length > slice.len() {
+				return None;
+			}
+			// The types of directory entries of Linux
+			let entry_type: u8 = match entry.type_indicator() {
+				1 => 8, // Regular file
+				2 => 4, // Directory
+				3 => 2, // Character device
+				4 => 6, // Block device
+				5 => 1, // FIFO
+				6 => 12, // Socket
+				7 => 10, // Symbolic link
+				_ => 0,
+			};
+			offset += record_length as u64;
+			slice[..8].copy_from_slice(&(entry.inode() as u64).to_le_bytes());
+			slice[8..16].copy_from_slice(&offset.to_le_bytes());
+			slice[16..18].copy_from_slice(&(record_length as u16).to_le_bytes());
+			slice[18] = entry_type;
+			slice[DIRENT_HEADER_SIZE..DIRENT_HEADER_SIZE + name.len()].copy_from_slice(name);
+			slice[DIRENT_HEADER_SIZE + name.len()..record_length].fill(0);
+			Some(record_length)
+		})
+	});
+	match written {
+		Ok(written) => Result(written as i64),
+		Err(e) => Result(errno(e)),
+	}
+}
+
+/// There is no real time clock, so every clock counts from boot
+fn sys_clock_gettime(_: u64, ptr: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match user_mut::<Timespec>(ptr) {
+		Some(time) => {
+			*time = Timespec::from(get_time());
+			Result(0)
+		}
+		None => Result(-EFAULT),
+	}
+}
+
+/// Sleep for the duration at `ptr`. Nothing interrupts a sleep, so the remaining time is never
+/// written.
+fn sys_nanosleep(ptr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let time = match user_ref::<Timespec>(ptr) {
+		Some(time) => *time,
+		None => return Result(-EFAULT),
+	};
+	if time.seconds < 0 || !(0..1_000_000_000).contains(&time.nanoseconds) {
+		return Result(-EINVAL);
+	}
+	let deadline = get_time() + Duration::new(time.seconds as u64, time.nanoseconds as u32);
+	while get_time() < deadline {
+		process::block_current("Sleep", Some(deadline));
+	}
+	Result(0)
+}
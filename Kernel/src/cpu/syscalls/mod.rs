@@ -1,15 +1,22 @@
-use alloc::{string::String, vec::Vec};
+use alloc::{format, string::String, vec::Vec};
 use core::{
 	cmp::min,
-	ptr::{slice_from_raw_parts, slice_from_raw_parts_mut},
-	str,
+	mem::{align_of, size_of},
+	slice, str,
+	time::Duration,
 };
 
 use crate::{
-	cpu::gdt::GDT,
+	cpu::{gdt::GDT, pit::get_time, smp},
+	fs::ext2::{Ext2Err, File},
+	ipc::{
+		futex, shm,
+		socket::{self, Socket, SocketErr},
+	},
 	println, process,
-	process::{Handle, Pid},
+	process::{scheduler, Handle, Pid, Tid},
 	serial_print, serial_println,
+	util::io::IOError,
 };
 use bitflags::bitflags;
 use x86_64::{
@@ -34,20 +41,56 @@ use x86_64::{
 // R11 saved rflags
 // RDI arg
 
-use process::BlockData;
+/// The syscalls of Linux, made by Linux executables
+pub mod linux;
+
+use process::{wait_queue::Waiter, Abi};
 use SyscallResult::*;
-/// Result of a syscall
+/// Result of a syscall. Syscalls that have to wait block with [process::block_current], and
+/// return once they are done.
 pub enum SyscallResult {
 	/// The syscall has finished and this is the result
 	Result(i64),
-	/// The syscall has not finished and must be blocked
-	Blocked(BlockData),
 }
 
 /// A system call function
 pub type Syscall = fn(arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> SyscallResult;
 
-const SYSCALLS: [Syscall; 18] = [
+/// The `count` values at `ptr` in the running process, if they are aligned and all in regions it
+/// can read, and write if `write`. Memory a process passes is checked before the kernel touches
+/// it, as a fault in the kernel isn't recovered from.
+fn user_pointer<T>(ptr: u64, count: u64, write: bool) -> Option<*mut T> {
+	let size = count.checked_mul(size_of::<T>() as u64)?;
+	let addr = VirtAddr::try_new(ptr).ok()?;
+	if addr.is_null() || !addr.is_aligned(align_of::<T>() as u64) {
+		return None;
+	}
+	process::memory::running_can_access(addr, size, write).then(|| addr.as_mut_ptr())
+}
+
+/// The `count` values at `ptr` in the running process, if it can read them
+fn user_slice<'a, T>(ptr: u64, count: u64) -> Option<&'a [T]> {
+	let ptr = user_pointer(ptr, count, false)?;
+	Some(unsafe { slice::from_raw_parts(ptr, count as usize) })
+}
+
+/// The `count` values at `ptr` in the running process, if it can write them
+fn user_slice_mut<'a, T>(ptr: u64, count: u64) -> Option<&'a mut [T]> {
+	let ptr = user_pointer(ptr, count, true)?;
+	Some(unsafe { slice::from_raw_parts_mut(ptr, count as usize) })
+}
+
+/// The value at `ptr` in the running process, if it can read it
+fn user_ref<'a, T>(ptr: u64) -> Option<&'a T> {
+	user_slice(ptr, 1).map(|slice| &slice[0])
+}
+
+/// The value at `ptr` in the running process, if it can write it
+fn user_mut<'a, T>(ptr: u64) -> Option<&'a mut T> {
+	user_slice_mut(ptr, 1).map(|slice| &mut slice[0])
+}
+
+const SYSCALLS: [Syscall; 45] = [
 	sys_debug,
 	sys_print,
 	sys_exit,
@@ -66,6 +109,33 @@ const SYSCALLS: [Syscall; 18] = [
 	sys_mkdir,
 	sys_info,
 	sys_paint,
+	sys_chmod,
+	sys_chown,
+	sys_umask,
+	sys_truncate,
+	sys_ftruncate,
+	sys_read_dir,
+	sys_usage,
+	sys_nice,
+	sys_getpid,
+	sys_scheduler,
+	sys_get_nice,
+	sys_thread_create,
+	sys_thread_exit,
+	sys_thread_join,
+	sys_set_fs_base,
+	sys_gettid,
+	sys_futex,
+	sys_shm_map,
+	sys_shm_unmap,
+	sys_mkfifo,
+	sys_socket,
+	sys_bind,
+	sys_listen,
+	sys_accept,
+	sys_connect,
+	sys_limit,
+	sys_personality,
 ];
 
 // 0 - procs
@@ -89,10 +159,22 @@ fn sys_info(info_type: u64, arg0: u64, _: u64, _: u64, _: u64, _: u64) -> Syscal
 		0 => {
 			// procs
 			let map = process::MAP.lock();
-			println!("{}\t{}\t{}", "PID", "TTY", "CMD");
-			for pid in process::QUEUE.lock().iter() {
-				let pcb = map.get(pid).expect("process from queue not in map");
-				println!("{}\t{}\t{}", pid, pcb.terminal, pcb.command);
+			println!("Scheduler: {}", process::scheduler::SCHEDULER.lock().name());
+			println!("CPUs: {}", smp::cpu_count());
+			println!("{}\t{}\t{}\t{}\t{}\t{}", "PID", "UID", "NI", "TTY", "TIME(ms)", "CMD");
+			let mut pids: Vec<&Pid> = map.keys().collect();
+			pids.sort();
+			for pid in pids {
+				let pcb = &map[pid];
+				println!(
+					"{}\t{}\t{}\t{}\t{}\t{}",
+					pid,
+					pcb.credentials.uid,
+					pcb.nice,
+					pcb.terminal,
+					pcb.usage.cpu_time().as_millis(),
+					pcb.command
+				);
 			}
 		}
 		1 => {
@@ -137,17 +219,12 @@ fn sys_info(info_type: u64, arg0: u64, _: u64, _: u64, _: u64, _: u64) -> Syscal
 }
 
 fn sys_rm(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let a = str::from_utf8(slice);
 		if let Ok(path) = a {
-			match crate::fs::ext2::unlink(path, false) {
+			match crate::fs::ext2::unlink(path, false, &process::running_credentials()) {
 				Ok(_) => Result(0),
 				Err(_) => Result(-1),
 			}
@@ -160,17 +237,12 @@ fn sys_rm(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
 }
 
 fn sys_mkdir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let a = str::from_utf8(slice);
 		if let Ok(path) = a {
-			match crate::fs::ext2::mkdir(path) {
+			match crate::fs::ext2::mkdir(path, &process::running_credentials()) {
 				Ok(_) => Result(0),
 				Err(_) => Result(-1),
 			}
@@ -183,17 +255,49 @@ fn sys_mkdir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResul
 }
 
 fn sys_rmdir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
+	let opt_slice = user_slice(ptr, len);
+
+	if let Some(slice) = opt_slice {
+		let a = str::from_utf8(slice);
+		if let Ok(path) = a {
+			match crate::fs::ext2::rmdir(path, &process::running_credentials()) {
+				Ok(_) => Result(0),
+				Err(_) => Result(-1),
+			}
+		} else {
+			Result(-1)
+		}
+	} else {
+		Result(-1)
+	}
+}
+
+/// Create a FIFO (named pipe) at the path
+fn sys_mkfifo(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_slice = user_slice(ptr, len);
+
+	if let Some(slice) = opt_slice {
+		let a = str::from_utf8(slice);
+		if let Ok(path) = a {
+			match crate::fs::ext2::mkfifo(path, &process::running_credentials()) {
+				Ok(_) => Result(0),
+				Err(_) => Result(-1),
+			}
+		} else {
+			Result(-1)
+		}
+	} else {
+		Result(-1)
 	}
+}
+
+fn sys_chmod(ptr: u64, len: u64, permissions: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_slice = user_slice(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let a = str::from_utf8(slice);
 		if let Ok(path) = a {
-			match crate::fs::ext2::rmdir(path) {
+			match crate::fs::ext2::chmod(path, permissions as u16, &process::running_credentials()) {
 				Ok(_) => Result(0),
 				Err(_) => Result(-1),
 			}
@@ -205,33 +309,555 @@ fn sys_rmdir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResul
 	}
 }
 
-fn sys_quit(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	crate::end();
+fn sys_chown(ptr: u64, len: u64, uid: u64, gid: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_slice = user_slice(ptr, len);
+	let (uid, gid): (process::Uid, process::Gid) = match (uid.try_into(), gid.try_into()) {
+		(Ok(uid), Ok(gid)) => (uid, gid),
+		_ => return Result(-1),
+	};
+
+	if let Some(slice) = opt_slice {
+		let a = str::from_utf8(slice);
+		if let Ok(path) = a {
+			match crate::fs::ext2::chown(path, uid, gid, &process::running_credentials()) {
+				Ok(_) => Result(0),
+				Err(_) => Result(-1),
+			}
+		} else {
+			Result(-1)
+		}
+	} else {
+		Result(-1)
+	}
 }
 
-/// Block the process until the process with pid exits
-fn sys_kill(pid: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let pid = pid as Pid;
-	if pid != process::running_process() {
-		process::remove_process(pid)
+/// Set the file mode creation mask of the running process, returning the previous one
+fn sys_umask(umask: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	let old = process.credentials.umask;
+	process.credentials.umask = umask as u16 & 0o777;
+	Result(old as i64)
+}
+
+fn sys_truncate(ptr: u64, len: u64, size: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_slice = user_slice(ptr, len);
+
+	if let Some(slice) = opt_slice {
+		let a = str::from_utf8(slice);
+		if let Ok(path) = a {
+			let credentials = process::running_credentials();
+			let res = File::from_path(path, OpenFlags::empty(), &credentials).and_then(|mut file| file.set_len(size as usize));
+			match res {
+				Ok(_) => Result(0),
+				Err(_) => Result(-1),
+			}
+		} else {
+			Result(-1)
+		}
+	} else {
+		Result(-1)
+	}
+}
+
+fn sys_ftruncate(handle: u64, size: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	match process.open_files.truncate(handle, size as usize) {
+		Ok(_) => Result(0),
+		Err(_) => Result(-1),
+	}
+}
+
+/// Resource usage as written by the usage syscall. Times are in nanoseconds.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct UsageRecord {
+	/// Time spent in user mode
+	pub user_nanos: u64,
+	/// Time spent in the kernel
+	pub kernel_nanos: u64,
+	/// Number of times switched out
+	pub context_switches: u64,
+	/// Number of syscalls made
+	pub syscalls: u64,
+}
+
+impl From<process::Usage> for UsageRecord {
+	fn from(usage: process::Usage) -> Self {
+		Self {
+			user_nanos: usage.user_time.as_nanos() as u64,
+			kernel_nanos: usage.kernel_time.as_nanos() as u64,
+			context_switches: usage.context_switches,
+			syscalls: usage.syscalls,
+		}
+	}
+}
+
+/// How a process ended, as written by the wait syscall
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct WaitRecord {
+	/// 0 - exited, 1 - killed, 2 - crashed
+	pub kind: u64,
+	/// The exit code if the process exited, the signal if it crashed
+	pub code: i64,
+	/// Length of the description
+	pub description_len: u64,
+	/// Description of the fault that crashed the process
+	pub description: [u8; 120],
+}
+
+impl From<&process::ExitStatus> for WaitRecord {
+	fn from(status: &process::ExitStatus) -> Self {
+		let mut record = Self {
+			kind: 0,
+			code: 0,
+			description_len: 0,
+			description: [0; 120],
+		};
+		match status {
+			process::ExitStatus::Exited(code) => record.code = *code,
+			process::ExitStatus::Killed => record.kind = 1,
+			process::ExitStatus::Crashed(report) => {
+				record.kind = 2;
+				record.code = report.signal as i64;
+				let description = alloc::format!("{}", report);
+				let len = min(description.len(), record.description.len());
+				record.description[..len].copy_from_slice(&description.as_bytes()[..len]);
+				record.description_len = len as u64;
+			}
+		}
+		record
+	}
+}
+
+// 0 - the calling process
+// 1 - the processes it has waited for
+
+fn sys_usage(who: u64, ptr: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_record = user_mut::<UsageRecord>(ptr);
+
+	if let Some(record) = opt_record {
+		let running = process::running_process();
+		let lock = process::MAP.lock();
+		let process = lock.get(&running).expect("running process not in hashmap");
+		let usage = match who {
+			0 => process.usage,
+			1 => process.children_usage,
+			_ => return Result(-1),
+		};
+		*record = usage.into();
+		Result(0)
+	} else {
+		Result(-1)
 	}
-	Result(0)
 }
 
-/// Block the process until the process with pid exits
-fn sys_wait(pid: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+/// Set the niceness of a process. Only root may lower the niceness of a process, or change the
+/// niceness of another user's process. Returns the previous niceness minus [scheduler::MIN_NICE],
+/// so that it can't be confused with a failiure.
+fn sys_nice(pid: u64, nice: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
 	let pid = pid as Pid;
+	let nice = scheduler::clamp_nice(nice as i64);
+	let credentials = process::running_credentials();
 	let mut lock = process::MAP.lock();
 	match lock.get_mut(&pid) {
 		Some(process) => {
-			let running = process::running_process();
-			process.append_waiting(running);
+			let old = process.nice;
+			if credentials.uid != process::ROOT_UID && (credentials.uid != process.credentials.uid || nice < old) {
+				return Result(-1);
+			}
+			process.nice = nice;
+			let mut scheduler = scheduler::SCHEDULER.lock();
+			for tid in process.threads.iter() {
+				scheduler.set_nice(*tid, nice);
+			}
+			Result((old - scheduler::MIN_NICE) as i64)
+		}
+		None => Result(-1),
+	}
+}
+
+/// Get the niceness of a process, minus [scheduler::MIN_NICE] like [sys_nice]
+fn sys_get_nice(pid: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match process::MAP.lock().get(&(pid as Pid)) {
+		Some(process) => Result((process.nice - scheduler::MIN_NICE) as i64),
+		None => Result(-1),
+	}
+}
+
+fn sys_getpid(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(process::running_process() as i64)
+}
+
+// 0 - round robin
+// 1 - multi level feedback queue
+// The policy is shared by every CPU, so only root may change it
 
-			Blocked(BlockData::Wait(pid))
+fn sys_scheduler(policy: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	if process::running_credentials().uid != process::ROOT_UID {
+		return Result(-1);
+	}
+	match scheduler::Policy::from_number(policy) {
+		Some(policy) => {
+			scheduler::set_policy(policy);
+			Result(0)
 		}
 		None => Result(-1),
 	}
 }
+
+/// Start a new thread in the calling process, at `entry` with `arg` as its first arguement. The
+/// kernel maps a stack of `stack_size` bytes for it, with a guard region below, and frees it once
+/// the thread exits.
+fn sys_thread_create(entry: u64, stack_size: u64, arg: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let entry = match VirtAddr::try_new(entry) {
+		Ok(entry) => entry,
+		Err(_) => return Result(-1),
+	};
+	match process::add_thread(entry, stack_size, arg as usize) {
+		Some(tid) => Result(tid as i64),
+		None => Result(-1),
+	}
+}
+
+/// Exit the calling thread. Unless `exited` is 0, 1 is stored in the futex at that address and
+/// the threads waiting on it are woken, once the thread no longer runs in userspace.
+fn sys_thread_exit(exited: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	if let Some(flag) = user_mut::<u32>(exited) {
+		*flag = 1;
+		if let Some(key) = futex::FutexKey::new(VirtAddr::new(exited), true) {
+			futex::wake(key, usize::MAX);
+		}
+	}
+	process::remove_current_thread();
+}
+
+/// Block the thread until the thread with tid, of the same process, exits
+fn sys_thread_join(tid: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let tid = tid as Tid;
+	if tid == process::running_thread() {
+		return Result(-1);
+	}
+	let mut joined = false;
+	loop {
+		let waiter = Waiter::current();
+		match process::THREADS.lock().get_mut(&tid) {
+			Some(thread) if thread.process == process::running_process() => thread.append_joining(waiter),
+			// The thread exited while this one was blocked
+			_ if joined => return Result(0),
+			_ => return Result(-1),
+		}
+		process::block_current(format!("Thread {} termination", tid), None);
+		joined = true;
+	}
+}
+
+/// Set the base address of the FS segment of the calling thread
+fn sys_set_fs_base(base: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	match VirtAddr::try_new(base) {
+		Ok(base) => {
+			let running = process::running_thread();
+			process::THREADS
+				.lock()
+				.get_mut(&running)
+				.expect("running thread not in hashmap")
+				.fs_base = base;
+			Result(0)
+		}
+		Err(_) => Result(-1),
+	}
+}
+
+fn sys_gettid(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	Result(process::running_thread() as i64)
+}
+
+/// Wait on or wake a futex, the 32 bit word at `addr`. See [futex] for the operations.
+///
+/// FUTEX_WAIT blocks if the futex holds `val`, until woken or until `timeout` milliseconds pass (0
+/// waits forever). It returns 0 when woken, and -1 if the futex didn't hold `val` or it timed out.
+/// FUTEX_WAKE wakes up to `val` threads, and returns how many were woken.
+fn sys_futex(addr: u64, op: u64, val: u64, timeout: u64, _: u64, _: u64) -> SyscallResult {
+	let addr = match VirtAddr::try_new(addr) {
+		Ok(addr) if addr.is_aligned(4u64) => addr,
+		_ => return Result(-1),
+	};
+	let key = match futex::FutexKey::new(addr, op & futex::FUTEX_PRIVATE != 0) {
+		Some(key) => key,
+		None => return Result(-1),
+	};
+
+	match op & !futex::FUTEX_PRIVATE {
+		futex::FUTEX_WAIT => {
+			match user_ref::<u32>(addr.as_u64()) {
+				Some(&value) if value == val as u32 => {}
+				_ => return Result(-1),
+			}
+			let deadline = match timeout {
+				0 => None,
+				millis => Some(get_time() + Duration::from_millis(millis)),
+			};
+			futex::wait(key);
+			Result(process::block_current(format!("Futex {:?}", key), deadline))
+		}
+		futex::FUTEX_WAKE => Result(futex::wake(key, val as usize) as i64),
+		_ => Result(-1),
+	}
+}
+
+/// Map the shared memory object with the name, creating it with `size` bytes if it doesn't exist
+/// (a size of 0 only opens an existing one). It is mapped at `addr`, or at an address picked by
+/// the kernel if it is 0. Returns the address it was mapped at.
+fn sys_shm_map(ptr: u64, len: u64, size: u64, addr: u64, _: u64, _: u64) -> SyscallResult {
+	let opt_slice = user_slice(ptr, len);
+	let name = match opt_slice.map(str::from_utf8) {
+		Some(Ok(name)) if !name.is_empty() => name,
+		_ => return Result(-1),
+	};
+	let addr = match addr {
+		0 => None,
+		addr => match VirtAddr::try_new(addr) {
+			Ok(addr) => Some(addr),
+			Err(_) => return Result(-1),
+		},
+	};
+
+	let object = match shm::open(name, size) {
+		Some(object) => object,
+		None => return Result(-1),
+	};
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	match process.map_shared_memory(object, addr) {
+		Some(start) => Result(start.as_u64() as i64),
+		None => Result(-1),
+	}
+}
+
+/// Unmap the shared memory object mapped at `addr`
+fn sys_shm_unmap(addr: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let addr = match VirtAddr::try_new(addr) {
+		Ok(addr) => addr,
+		Err(_) => return Result(-1),
+	};
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	if process.unmap_shared_memory(addr) {
+		Result(0)
+	} else {
+		Result(-1)
+	}
+}
+
+/// Create a socket of the family and kind, see [socket] for the ones there are
+fn sys_socket(family: u64, kind: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	if family != socket::FAMILY_LOCAL || kind != socket::KIND_STREAM {
+		return Result(-1);
+	}
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	Result(process.open_files.add_socket(Socket::Unbound) as i64)
+}
+
+/// Bind the socket to a path, creating a socket file there
+fn sys_bind(handle: u64, ptr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+	let opt_slice = user_slice(ptr, len);
+	let path = match opt_slice.map(str::from_utf8) {
+		Some(Ok(path)) => path,
+		_ => return Result(-1),
+	};
+
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	let credentials = process.credentials;
+	match process
+		.open_files
+		.socket_mut(handle)
+		.map_err(SocketErr::from)
+		.and_then(|socket| socket.bind(path, &credentials))
+	{
+		Ok(_) => Result(0),
+		Err(_) => Result(-1),
+	}
+}
+
+/// Start accepting connections on a bound socket
+fn sys_listen(handle: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	match process.open_files.socket_mut(handle).map(|socket| socket.listen()) {
+		Ok(Ok(_)) => Result(0),
+		_ => Result(-1),
+	}
+}
+
+/// Accept a connection on a listening socket, blocking until there is one. Returns a handle to
+/// a new socket for the connection.
+fn sys_accept(handle: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+	let running = process::running_process();
+	loop {
+		let mut lock = process::MAP.lock();
+		let process = lock.get_mut(&running).expect("running process not in hashmap");
+		let accepted = match process.open_files.socket_mut(handle) {
+			Ok(socket) => socket.accept(),
+			Err(_) => return Result(-1),
+		};
+		match accepted {
+			Ok(connection) => return Result(process.open_files.add_socket(connection) as i64),
+			Err(SocketErr::WouldBlock) => {}
+			Err(_) => return Result(-1),
+		}
+		drop(lock);
+		process::block_current("Connection", None);
+	}
+}
+
+/// Connect a socket to the socket listening at a path
+fn sys_connect(handle: u64, ptr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+	let opt_slice = user_slice(ptr, len);
+	let path = match opt_slice.map(str::from_utf8) {
+		Some(Ok(path)) => path,
+		_ => return Result(-1),
+	};
+
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	let credentials = process.credentials;
+	match process
+		.open_files
+		.socket_mut(handle)
+		.map_err(SocketErr::from)
+		.and_then(|socket| socket.connect(path, &credentials))
+	{
+		Ok(_) => Result(0),
+		Err(_) => Result(-1),
+	}
+}
+
+// 0 - core dump size
+// 1 - stack size, for processes executed after it is set
+
+/// Set a resource limit of the running process, returns the old value. A value of u64::MAX only
+/// reads the limit.
+fn sys_limit(resource: u64, value: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	let limit = match resource {
+		0 => &mut process.limits.core_size,
+		1 => &mut process.limits.stack_size,
+		_ => return Result(-1),
+	};
+	let old = *limit;
+	if value != u64::MAX {
+		*limit = value;
+	}
+	Result(min(old, i64::MAX as u64) as i64)
+}
+
+/// Set the personality of the running process and the processes it executes, or just read it if
+/// the value is u64::MAX. Returns the old personality.
+fn sys_personality(value: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let running = process::running_process();
+	let mut lock = process::MAP.lock();
+	let process = lock.get_mut(&running).expect("running process not in hashmap");
+	let old = process.personality;
+	if value != u64::MAX {
+		match process::Personality::from_bits(value as u32) {
+			Some(personality) if value <= u32::MAX as u64 => process.personality = personality,
+			_ => return Result(-1),
+		}
+	}
+	Result(old.bits() as i64)
+}
+
+/// End the os, powering off the computer if how is 0 or rebooting it if how is 1. Only root may
+/// do this.
+fn sys_quit(how: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	if process::running_credentials().uid != process::ROOT_UID {
+		return Result(-1);
+	}
+	match how {
+		0 => crate::end(crate::Shutdown::PowerOff),
+		1 => crate::end(crate::Shutdown::Reboot),
+		_ => Result(-1),
+	}
+}
+
+/// Kill the process with pid. Only root may kill another user's process. A process can't kill
+/// itself this way.
+fn sys_kill(pid: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let pid = pid as Pid;
+	let credentials = process::running_credentials();
+	let owner = match process::MAP.lock().get(&pid) {
+		Some(process) => process.credentials.uid,
+		None => return Result(-1),
+	};
+	if credentials.uid != process::ROOT_UID && credentials.uid != owner {
+		return Result(-1);
+	}
+	if pid != process::running_process() {
+		process::remove_process(pid, process::ExitStatus::Killed)
+	}
+	Result(0)
+}
+
+/// Block the process until the process with pid exits, then write how it ended to ptr (if not null)
+fn sys_wait(pid: u64, ptr: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let pid = pid as Pid;
+	let running = process::running_thread();
+	loop {
+		let waiter = Waiter::current();
+		let mut lock = process::MAP.lock();
+		let status = lock
+			.get_mut(&process::running_process())
+			.expect("running process not in hashmap")
+			.take_exit_record(running);
+		if let Some(status) = status {
+			if let Some(record) = user_mut::<WaitRecord>(ptr) {
+				*record = status;
+			}
+			return Result(0);
+		}
+		match lock.get_mut(&pid) {
+			Some(process) => process.append_waiting(waiter),
+			None => return Result(-1),
+		}
+		drop(lock);
+		process::block_current(format!("Process {} termination", pid), None);
+	}
+}
 fn sys_close(handle: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
 	let handle: Handle = match handle.try_into() {
 		Ok(h) => h,
@@ -256,16 +882,35 @@ bitflags! {
 		const CREATE = 0b0001;
 		/// Truncate file
 		const TRUNCATE = 0b0010;
+		/// Every write goes to the end of the file
+		const APPEND = 0b0100;
+		/// Together with CREATE, fail if the file already exists
+		const EXCLUSIVE = 0b1000;
+		/// Only open for reading. Opening a FIFO like this waits for a writer.
+		const READ = 0b1_0000;
+		/// Only open for writing. Opening a FIFO like this waits for a reader.
+		const WRITE = 0b10_0000;
 	}
 }
 
+/// Header of a directory entry record, as written by the read_dir syscall. It is followed by
+/// `name_length` bytes of name, and the whole record is padded to `record_length` bytes, so the
+/// next record starts 4 byte aligned.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DirRecord {
+	/// Inode the entry points to
+	pub inode: u32,
+	/// Size of the whole record, including the header, the name and the padding
+	pub record_length: u16,
+	/// Length of the name in bytes
+	pub name_length: u8,
+	/// Type of the inode (same values as the ext2 directory entry type indicator)
+	pub type_indicator: u8,
+}
+
 fn sys_open_dir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let a = str::from_utf8(slice);
@@ -273,7 +918,7 @@ fn sys_open_dir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallRe
 			let running = process::running_process();
 			let mut lock = process::MAP.lock();
 			let process = lock.get_mut(&running).expect("running process not in hashmap");
-			let res = process.open_files.open_dir(path);
+			let res = process.open_files.open_dir(path, &process.credentials);
 			if let Ok(handle) = res {
 				Result(handle as i64)
 			} else {
@@ -288,12 +933,7 @@ fn sys_open_dir(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallRe
 	}
 }
 fn sys_open(ptr: u64, len: u64, flags: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 	let flags = match OpenFlags::from_bits(flags) {
 		Some(f) => f,
 		None => return Result(-1),
@@ -305,11 +945,14 @@ fn sys_open(ptr: u64, len: u64, flags: u64, _: u64, _: u64, _: u64) -> SyscallRe
 			let running = process::running_process();
 			let mut lock = process::MAP.lock();
 			let process = lock.get_mut(&running).expect("running process not in hashmap");
-			let res = process.open_files.open_file(path, flags);
-			if let Ok(handle) = res {
-				Result(handle as i64)
-			} else {
-				Result(-1)
+			let res = process.open_files.open_file(path, flags, &process.credentials);
+			match res {
+				Ok(handle) => {
+					drop(lock);
+					process::wait_for_peer(handle);
+					Result(handle as i64)
+				}
+				Err(_) => Result(-1),
 			}
 		} else {
 			serial_println!("Invalid UTF path");
@@ -325,22 +968,22 @@ fn sys_write(ptr: u64, len: u64, handle: u64, _: u64, _: u64, _: u64) -> Syscall
 		Ok(h) => h,
 		Err(_) => return Result(-1),
 	};
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let running = process::running_process();
-		let mut lock = process::MAP.lock();
-		let process = lock.get_mut(&running).expect("running process not in hashmap");
+		loop {
+			let mut lock = process::MAP.lock();
+			let process = lock.get_mut(&running).expect("running process not in hashmap");
 
-		let write_res = process.open_files.write(handle, slice);
-		match write_res {
-			Ok(count) => Result(count as i64),
-			Err(_) => Result(-1),
+			let write_res = process.open_files.write(handle, slice);
+			match write_res {
+				Ok(count) => return Result(count as i64),
+				Err(Ext2Err::IO(IOError::WouldBlock)) => {}
+				Err(_) => return Result(-1),
+			}
+			drop(lock);
+			process::block_current("Pipe", None);
 		}
 	} else {
 		Result(-1) // Failiure
@@ -352,20 +995,41 @@ fn sys_read(ptr: u64, len: u64, handle: u64, _: u64, _: u64, _: u64) -> SyscallR
 		Ok(h) => h,
 		Err(_) => return Result(-1),
 	};
-	let ptr = ptr as *mut u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts_mut(ptr, len as usize).as_mut();
+	let opt_slice = user_slice_mut(ptr, len);
+
+	if let Some(slice) = opt_slice {
+		let running = process::running_process();
+		loop {
+			let mut lock = process::MAP.lock();
+			let process = lock.get_mut(&running).expect("running process not in hashmap");
+
+			let read_res = process.open_files.read(handle, slice);
+			match read_res {
+				Ok(count) => return Result(count as i64),
+				Err(Ext2Err::IO(IOError::WouldBlock)) => {}
+				Err(_) => return Result(-1),
+			}
+			drop(lock);
+			process::block_current("Pipe", None);
+		}
+	} else {
+		Result(-1) // Failiure
 	}
+}
+
+fn sys_read_dir(ptr: u64, len: u64, handle: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+	let handle: Handle = match handle.try_into() {
+		Ok(h) => h,
+		Err(_) => return Result(-1),
+	};
+	let opt_slice = user_slice_mut(ptr, len);
 
 	if let Some(slice) = opt_slice {
 		let running = process::running_process();
 		let mut lock = process::MAP.lock();
 		let process = lock.get_mut(&running).expect("running process not in hashmap");
 
-		let read_res = process.open_files.read(handle, slice);
-		match read_res {
+		match process.open_files.read_dir(handle, slice) {
 			Ok(count) => Result(count as i64),
 			Err(_) => Result(-1),
 		}
@@ -375,26 +1039,24 @@ fn sys_read(ptr: u64, len: u64, handle: u64, _: u64, _: u64, _: u64) -> SyscallR
 }
 
 fn sys_input(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *mut u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts_mut(ptr, len as usize).as_mut();
-	}
+	let opt_slice = user_slice_mut(ptr, len);
 	if let Some(slice) = opt_slice {
 		let running = process::running_process();
-		let mut lock = process::MAP.lock();
-		let process = lock.get_mut(&running).expect("running process not in hashmap");
-		let buffer: &mut String = &mut process.input_buffer;
+		loop {
+			let mut lock = process::MAP.lock();
+			let process = lock.get_mut(&running).expect("running process not in hashmap");
+			let buffer: &mut String = &mut process.input_buffer;
 
-		if buffer.len() > 0 {
-			let amount_to_take = min(buffer.len(), slice.len());
-			slice[..amount_to_take].copy_from_slice(&buffer.as_bytes()[..amount_to_take]);
+			if buffer.len() > 0 {
+				let amount_to_take = min(buffer.len(), slice.len());
+				slice[..amount_to_take].copy_from_slice(&buffer.as_bytes()[..amount_to_take]);
 
-			buffer.drain(0..amount_to_take);
-			return Result(amount_to_take as i64);
-		} else {
-			return Blocked(BlockData::Input { slice });
+				buffer.drain(0..amount_to_take);
+				return Result(amount_to_take as i64);
+			}
+			let terminal = process.terminal;
+			drop(lock);
+			process::wait_for_input(terminal);
 		}
 	} else {
 		return Result(-1); // Failiure
@@ -402,12 +1064,7 @@ fn sys_input(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResul
 }
 
 fn sys_print(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let opt_slice = user_slice(ptr, len);
 	if let Some(slice) = opt_slice {
 		let a = str::from_utf8(slice);
 		if let Ok(s) = a {
@@ -429,20 +1086,29 @@ fn sys_print(ptr: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResul
 	}
 }
 
+/// An argument of exec, a string as the standard library passes it
+#[repr(C)]
+struct StrArg {
+	ptr: u64,
+	len: u64,
+}
+
 fn sys_exec(ptr: u64, len: u64, argv: u64, argc: u64, _: u64, _: u64) -> SyscallResult {
-	let ptr = ptr as *const u8;
-	let executable_opt_slice;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		executable_opt_slice = slice_from_raw_parts(ptr, len as usize).as_ref();
-	}
+	let executable_opt_slice = user_slice(ptr, len);
 
-	let argc = argc as usize;
-	let ptr = argv as *const &str;
-	let args;
-	unsafe {
-		// This is not sound. Who knows what the user put as the pointer
-		args = slice_from_raw_parts(ptr, argc).as_ref().unwrap();
+	let arg_slices: &[StrArg] = match argc {
+		0 => &[],
+		_ => match user_slice(argv, argc) {
+			Some(args) => args,
+			None => return Result(-1),
+		},
+	};
+	let mut args = Vec::new();
+	for arg in arg_slices {
+		match user_slice(arg.ptr, arg.len).map(str::from_utf8) {
+			Some(Ok(arg)) => args.push(arg),
+			_ => return Result(-1),
+		}
 	}
 
 	if let Some(slice) = executable_opt_slice {
@@ -450,17 +1116,24 @@ fn sys_exec(ptr: u64, len: u64, argv: u64, argc: u64, _: u64, _: u64) -> Syscall
 		if let Ok(s) = a {
 			let mut owning_string = String::new();
 			let mut local_args: Vec<&str> = Vec::new();
-			for arg in args {
+			for arg in &args {
 				owning_string.push_str(arg);
 			}
 			let mut start = 0;
-			for arg in args {
+			for arg in &args {
 				let len = arg.len();
 				local_args.push(&owning_string[start..start + len]);
 				start += len;
 			}
 
-			let res = crate::process::add_process(s, &local_args, None);
+			let (credentials, nice, limits, personality) = {
+				let lock = process::MAP.lock();
+				let running = lock
+					.get(&process::running_process())
+					.expect("running process not in hashmap");
+				(running.credentials, running.nice, running.limits, running.personality)
+			};
+			let res = crate::process::add_process(s, &local_args, None, credentials, nice, limits, personality);
 			match res {
 				Ok(pid) => Result(pid as u32 as i64),
 				Err(e) => {
@@ -502,7 +1175,7 @@ fn sys_exec(ptr: u64, len: u64, argv: u64, argc: u64, _: u64, _: u64) -> Syscall
 fn sys_exit(status: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
 	let status = status as i64;
 	serial_println!("Process exited with status: {}", status);
-	process::remove_current_process();
+	process::remove_current_process(process::ExitStatus::Exited(status));
 }
 
 fn sys_debug(arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> SyscallResult {
@@ -563,9 +1236,6 @@ pub struct PreservedRegisters {
 	pub rbx: u64,
 }
 
-const STACK_SIZE: usize = 4096 * 8;
-static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 /// Registers
@@ -576,18 +1246,6 @@ pub struct Registers {
 	pub scratch: ScratchRegisters,
 }
 
-#[allow(dead_code)] // called from asm
-#[no_mangle] // called from asm
-extern "C" fn get_syscall_stack_addr() -> *const u8 {
-	// Switch to kernel stack
-	let temp_stack: *const u8 = unsafe { STACK.as_ptr().add(STACK_SIZE) };
-	// unsafe {
-	// asm!("mov rsp, {stack}",
-	// stack = in(reg) temp_stack)
-	// }
-	return temp_stack;
-}
-
 #[allow(dead_code)] // called from asm
 #[no_mangle] // called from asm
 extern "C" fn handle_syscall_inner(registers_ptr: *mut Registers) {
@@ -597,7 +1255,15 @@ extern "C" fn handle_syscall_inner(registers_ptr: *mut Registers) {
 		registers = &mut *registers_ptr;
 	}
 
-	let function = SYSCALLS.get(registers.scratch.rax as usize);
+	smp::lock_kernel();
+	process::leave_if_removed();
+	process::account_user_time();
+
+	let number = registers.scratch.rax as u64;
+	let function = match process::running_abi() {
+		Abi::Native => SYSCALLS.get(number as usize).copied(),
+		Abi::Linux => Some(linux::syscall(number)),
+	};
 	match function {
 		Some(func) => {
 			let scratch = &mut registers.scratch;
@@ -607,22 +1273,16 @@ extern "C" fn handle_syscall_inner(registers_ptr: *mut Registers) {
 			let r8 = scratch.r8;
 			let r9 = scratch.r9;
 			let r10 = scratch.r10;
-			let result = func(rdi, rsi, rdx, r10, r8, r9);
-
-			match result {
-				Result(r) => {
-					scratch.rax = r;
-				}
-				Blocked(data) => {
-					crate::process::block_current(data);
-				}
-			}
+			let Result(result) = func(rdi, rsi, rdx, r10, r8, r9);
+			scratch.rax = result;
+			process::account_syscall();
 			crate::process::context_switch(process::State::Syscall { registers: *registers });
 		}
 		None => {
 			// No syscall with that id
 			let scratch = &mut registers.scratch;
 			scratch.rax = -1;
+			process::account_syscall();
 			crate::process::context_switch(process::State::Syscall { registers: *registers });
 		}
 	}
@@ -637,10 +1297,15 @@ extern "C" fn do_nothing() {
 extern "C" fn handle_syscall() {
 	unsafe {
 		asm!(
+			// Switch to the kernel's GS, and through it to the kernel stack of the thread (see
+			// smp::Cpu). Interrupts are masked by SFMask.
+			"
+			swapgs
+			mov gs:[8], rsp
+			mov rsp, gs:[0]",
 			// Push scratch registers
 			"
-			cli
-			push rsp
+			push qword ptr gs:[8]
 			push rax
 			push rcx
 			push rdx
@@ -661,10 +1326,7 @@ extern "C" fn handle_syscall() {
 			",
 			// "add rsp, 8",
 			// "call do_nothing",
-			"mov rbx, rsp", // C calling convention first variable
-			"call get_syscall_stack_addr",
-			"mov rsp, rax",
-			"mov rdi, rbx", // C calling convention first variable
+			"mov rdi, rsp", // C calling convention first variable
 			"call handle_syscall_inner",
 			// Pop preserved registers
 			"
@@ -686,6 +1348,7 @@ extern "C" fn handle_syscall() {
 			pop rcx
 			pop rax
 			pop rsp",
+			"swapgs",
 			"sysretq",
 			options(noreturn)
 		);
@@ -713,7 +1376,7 @@ pub fn setup() {
 }
 
 /// Go to ring 3 with given code and stack addresses
-pub unsafe fn go_to_ring3(code: VirtAddr, stack_end: VirtAddr, arg0: usize, arg1: usize) -> ! {
+pub unsafe fn go_to_ring3(code: VirtAddr, stack_end: VirtAddr, arg0: usize, arg1: usize, arg2: usize) -> ! {
 	let cs_idx: u16 = GDT.1.user_code_selector.0;
 	let ds_idx: u16 = GDT.1.user_data_selector.0;
 	// serial_println!("{:?}, {:?}", cs_idx, ds_idx);
@@ -722,18 +1385,18 @@ pub unsafe fn go_to_ring3(code: VirtAddr, stack_end: VirtAddr, arg0: usize, arg1
 	DS::set_reg(GDT.1.user_data_selector);
 	asm!(
 	"push rax",
-	"push rsi",
+	"push r9",
 	"push 0x200",
-	"push rdx",
-	"push rdi",
-	"mov rdi, {arg0}",
-	"mov rsi, {arg1}",
+	"push rcx",
+	"push r8",
+	"swapgs",
 	"iretq",
-	arg0 = in(reg) arg0,
-	arg1 = in(reg) arg1,
-	in("rdi") code.as_u64(),
-	in("rsi") stack_end.as_u64(),
-	in("dx") cs_idx,
+	in("rdi") arg0,
+	in("rsi") arg1,
+	in("rdx") arg2,
+	in("r8") code.as_u64(),
+	in("r9") stack_end.as_u64(),
+	in("cx") cs_idx,
 	in("ax") ds_idx,
 	options(noreturn),
 	);
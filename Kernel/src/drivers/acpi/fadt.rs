@@ -0,0 +1,128 @@
+use super::{find_table, table_at, table_body};
+use x86_64::PhysAddr;
+
+// Offsets of the fields in the FADT body, after the table header
+const DSDT: usize = 4;
+const SMI_COMMAND: usize = 12;
+const ACPI_ENABLE: usize = 16;
+const PM1A_CONTROL_BLOCK: usize = 28;
+const PM1B_CONTROL_BLOCK: usize = 32;
+const FLAGS: usize = 76;
+const RESET_REGISTER: usize = 80;
+const RESET_VALUE: usize = 92;
+const X_DSDT: usize = 104;
+
+/// Bit of the flags saying the reset register can be used
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+// AML opcodes, for finding the sleep types of S5 in the DSDT
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0A;
+
+// Address spaces of a generic address structure
+const SYSTEM_MEMORY: u8 = 0;
+const SYSTEM_IO: u8 = 1;
+
+/// A register, in memory or in I/O space
+#[derive(Debug, Copy, Clone)]
+pub enum Register {
+	/// A register in physical memory
+	Memory(PhysAddr),
+	/// An I/O port
+	Io(u16),
+}
+
+/// What the Fixed ACPI Description Table and the DSDT say about power management
+#[derive(Debug)]
+pub struct Fadt {
+	/// I/O port of the system management interrupt commands, 0 if the machine is always in ACPI
+	/// mode
+	pub smi_command: u16,
+	/// Value to write to [Fadt::smi_command] to switch to ACPI mode
+	pub acpi_enable: u8,
+	/// I/O ports of the PM1a and PM1b control registers, which put the machine to sleep. PM1b is
+	/// 0 if there isn't one.
+	pub pm1_control: (u16, u16),
+	/// The sleep types to write to the PM1a and PM1b control registers for S5 (soft off), if the
+	/// DSDT has them
+	pub s5_sleep_types: Option<(u8, u8)>,
+	/// The register to write [Fadt::reset_value] to for resetting the machine, if it has one
+	pub reset_register: Option<Register>,
+	/// Value that resets the machine when written to [Fadt::reset_register]
+	pub reset_value: u8,
+}
+
+/// Read the FADT, [None] if the firmware doesn't have one
+pub fn read() -> Option<Fadt> {
+	let body = table_body(find_table(b"FACP")?);
+	let u8_at = |offset: usize| body.get(offset).copied().unwrap_or(0);
+	let u32_at = |offset: usize| match body.get(offset..offset + 4) {
+		Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+		None => 0,
+	};
+	let u64_at = |offset: usize| u32_at(offset) as u64 | (u32_at(offset + 4) as u64) << 32;
+
+	// The reset register is a generic address structure: address space, bit width, bit offset,
+	// access size, then the address. Older FADTs end before it.
+	let reset_register = if u32_at(FLAGS) & RESET_REGISTER_SUPPORTED != 0 {
+		let address = u64_at(RESET_REGISTER + 4);
+		match u8_at(RESET_REGISTER) {
+			SYSTEM_MEMORY if address != 0 => Some(Register::Memory(PhysAddr::new(address))),
+			SYSTEM_IO if address != 0 => Some(Register::Io(address as u16)),
+			_ => None,
+		}
+	} else {
+		None
+	};
+	let dsdt = match u64_at(X_DSDT) {
+		0 => u32_at(DSDT) as u64,
+		address => address,
+	};
+
+	Some(Fadt {
+		smi_command: u32_at(SMI_COMMAND) as u16,
+		acpi_enable: u8_at(ACPI_ENABLE),
+		pm1_control: (u32_at(PM1A_CONTROL_BLOCK) as u16, u32_at(PM1B_CONTROL_BLOCK) as u16),
+		s5_sleep_types: s5_sleep_types(PhysAddr::new(dsdt)),
+		reset_register,
+		reset_value: u8_at(RESET_VALUE),
+	})
+}
+
+/// Find the sleep types of S5 in the DSDT at `dsdt`. Instead of running its AML, this looks for
+/// the `\_S5_` object, which firmware writes as a package of constants:
+/// `Name(_S5_, Package() {SLP_TYPa, SLP_TYPb, ...})`.
+fn s5_sleep_types(dsdt: PhysAddr) -> Option<(u8, u8)> {
+	if dsdt.is_null() {
+		return None;
+	}
+	let aml = table_body(unsafe { table_at(dsdt)? });
+	let start = (0..aml.len()).find(|i| {
+		aml[*i..].starts_with(b"_S5_")
+			&& aml.get(i + 4) == Some(&PACKAGE_OP)
+			// The name may have a root prefix before it
+			&& aml[..*i].iter().rev().find(|byte| **byte != b'\\') == Some(&NAME_OP)
+	})?;
+	let mut offset = start + 5;
+	// The top two bits of the first byte of the package length are how many bytes follow it
+	offset += 1 + (*aml.get(offset)? >> 6) as usize;
+	// Skip the number of elements
+	offset += 1;
+
+	let mut read_constant = || {
+		let value = match *aml.get(offset)? {
+			BYTE_PREFIX => {
+				offset += 1;
+				*aml.get(offset)?
+			}
+			// ZeroOp and OneOp are 0 and 1
+			value => value,
+		};
+		offset += 1;
+		Some(value)
+	};
+	let a = read_constant()?;
+	let b = read_constant()?;
+	Some((a, b))
+}
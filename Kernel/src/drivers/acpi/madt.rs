@@ -0,0 +1,107 @@
+use super::{find_table, table_body};
+use alloc::vec::Vec;
+
+/// Entry types of the MADT
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// The processor can be started
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+/// The processor is disabled, but can be brought online by the firmware
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A processor, with its local APIC
+#[derive(Debug, Copy, Clone)]
+pub struct Processor {
+	/// ACPI id of the processor
+	pub processor_id: u8,
+	/// ID of the processor's local APIC, which interrupts are sent to
+	pub apic_id: u8,
+	/// Whether the processor can be started
+	pub enabled: bool,
+}
+
+/// An I/O APIC, routing interrupts of devices to processors
+#[derive(Debug, Copy, Clone)]
+pub struct IoApic {
+	/// ID of the I/O APIC
+	pub id: u8,
+	/// Physical address of its registers
+	pub address: u32,
+	/// The global system interrupt its first input is
+	pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't connected to the I/O APIC input of the same number
+#[derive(Debug, Copy, Clone)]
+pub struct InterruptOverride {
+	/// The ISA IRQ
+	pub source: u8,
+	/// The global system interrupt it is connected to
+	pub gsi: u32,
+	/// Polarity and trigger mode of the interrupt
+	pub flags: u16,
+}
+
+/// What the Multiple APIC Description Table says about the interrupt controllers
+#[derive(Debug)]
+pub struct Madt {
+	/// Physical address of the local APIC registers of every processor
+	pub local_apic_address: u64,
+	/// The processors, the first is the one the machine booted on
+	pub processors: Vec<Processor>,
+	/// The I/O APICs
+	pub io_apics: Vec<IoApic>,
+	/// ISA interrupts wired differently than the I/O APIC inputs of the same number
+	pub overrides: Vec<InterruptOverride>,
+}
+
+/// Read the MADT, [None] if the firmware doesn't have one
+pub fn read() -> Option<Madt> {
+	let body = table_body(find_table(b"APIC")?);
+	let u16_at = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+	let u32_at = |offset: usize| u32::from_le_bytes([body[offset], body[offset + 1], body[offset + 2], body[offset + 3]]);
+
+	let mut madt = Madt {
+		local_apic_address: u32_at(0) as u64,
+		processors: Vec::new(),
+		io_apics: Vec::new(),
+		overrides: Vec::new(),
+	};
+	// The local APIC address and flags come before the entries
+	let mut offset = 8;
+	while offset + 2 <= body.len() {
+		let (kind, length) = (body[offset], body[offset + 1] as usize);
+		if length < 2 || offset + length > body.len() {
+			break;
+		}
+		match kind {
+			PROCESSOR_LOCAL_APIC => {
+				let flags = u32_at(offset + 4);
+				madt.processors.push(Processor {
+					processor_id: body[offset + 2],
+					apic_id: body[offset + 3],
+					enabled: flags & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0,
+				});
+			}
+			IO_APIC => madt.io_apics.push(IoApic {
+				id: body[offset + 2],
+				address: u32_at(offset + 4),
+				gsi_base: u32_at(offset + 8),
+			}),
+			INTERRUPT_SOURCE_OVERRIDE => madt.overrides.push(InterruptOverride {
+				source: body[offset + 3],
+				gsi: u32_at(offset + 4),
+				flags: u16_at(offset + 8),
+			}),
+			LOCAL_APIC_ADDRESS_OVERRIDE => {
+				madt.local_apic_address = u32_at(offset + 4) as u64 | (u32_at(offset + 8) as u64) << 32;
+			}
+			_ => {}
+		}
+		offset += length;
+	}
+	Some(madt)
+}
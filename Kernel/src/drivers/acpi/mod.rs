@@ -0,0 +1,162 @@
+use crate::mem::paging;
+use alloc::vec::Vec;
+use core::{mem::size_of, slice};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+/// Module reading the Multiple APIC Description Table, which lists the processors and interrupt
+/// controllers
+pub mod madt;
+
+/// Module reading the Fixed ACPI Description Table, and the parts of the DSDT needed for powering
+/// off
+pub mod fadt;
+
+/// Root System Description Pointer, which the firmware leaves in low memory to point at the other
+/// tables. The fields after `rsdt_address` only exist from revision 2.
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Rsdp {
+	signature: [u8; 8],
+	checksum: u8,
+	oem_id: [u8; 6],
+	revision: u8,
+	rsdt_address: u32,
+	length: u32,
+	xsdt_address: u64,
+	extended_checksum: u8,
+	reserved: [u8; 3],
+}
+
+/// Size of the revision 0 [Rsdp], which is all the checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header every ACPI table (besides the [Rsdp]) starts with
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SdtHeader {
+	/// Four letters naming the table
+	pub signature: [u8; 4],
+	/// Length of the table, including the header
+	pub length: u32,
+	/// Revision of the table's layout
+	pub revision: u8,
+	/// Makes all the bytes of the table sum to 0
+	pub checksum: u8,
+	/// Who made the table
+	pub oem_id: [u8; 6],
+	/// Which of the OEM's tables this is
+	pub oem_table_id: [u8; 8],
+	/// Revision of the OEM's table
+	pub oem_revision: u32,
+	/// The tool that made the table
+	pub creator_id: u32,
+	/// Revision of the tool that made the table
+	pub creator_revision: u32,
+}
+
+/// Physical addresses of the tables listed in the RSDT or XSDT
+static TABLES: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+
+/// Where the BIOS leaves the RSDP if it isn't in the extended BIOS data area
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Find the ACPI tables, starting from the RSDP at `rsdp_addr` if the bootloader found it. Tables
+/// with bad checksums are ignored.
+pub fn setup(rsdp_addr: Option<u64>) {
+	let rsdp = match rsdp_addr.map(PhysAddr::new).or_else(search_rsdp) {
+		Some(addr) => unsafe { &*paging::phys_to_virt(addr).as_ptr::<Rsdp>() },
+		None => {
+			serial_println!("ACPI: no RSDP found");
+			return;
+		}
+	};
+	let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+		(PhysAddr::new(rsdp.xsdt_address), size_of::<u64>())
+	} else {
+		(PhysAddr::new(rsdp.rsdt_address as u64), size_of::<u32>())
+	};
+	let header = match unsafe { table_at(root) } {
+		Some(header) => header,
+		None => {
+			serial_println!("ACPI: bad root table at {:?}", root);
+			return;
+		}
+	};
+
+	let entries_start = paging::phys_to_virt(root).as_u64() as usize + size_of::<SdtHeader>();
+	let count = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+	let mut tables = TABLES.lock();
+	for i in 0..count {
+		let entry = (entries_start + i * entry_size) as *const u8;
+		let addr = unsafe {
+			match entry_size {
+				4 => (entry as *const u32).read_unaligned() as u64,
+				_ => (entry as *const u64).read_unaligned(),
+			}
+		};
+		if let Some(table) = unsafe { table_at(PhysAddr::new(addr)) } {
+			serial_println!(
+				"ACPI: found {} at {:#x}",
+				core::str::from_utf8(&table.signature).unwrap_or("????"),
+				addr
+			);
+			tables.push(PhysAddr::new(addr));
+		}
+	}
+}
+
+/// Find the first table with the given signature, like `b"APIC"` for the MADT. The table is the
+/// header followed by the rest of its bytes.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+	TABLES
+		.lock()
+		.iter()
+		.map(|addr| unsafe { &*paging::phys_to_virt(*addr).as_ptr::<SdtHeader>() })
+		.find(|header| &header.signature == signature)
+}
+
+/// The bytes of a table after its header
+pub fn table_body(header: &'static SdtHeader) -> &'static [u8] {
+	let start = header as *const SdtHeader as *const u8;
+	unsafe {
+		slice::from_raw_parts(
+			start.add(size_of::<SdtHeader>()),
+			header.length as usize - size_of::<SdtHeader>(),
+		)
+	}
+}
+
+/// Get the table at `addr`, if its checksum is right
+unsafe fn table_at(addr: PhysAddr) -> Option<&'static SdtHeader> {
+	let header = &*paging::phys_to_virt(addr).as_ptr::<SdtHeader>();
+	if (header.length as usize) < size_of::<SdtHeader>() {
+		return None;
+	}
+	let bytes = slice::from_raw_parts(header as *const SdtHeader as *const u8, header.length as usize);
+	checksum_ok(bytes).then(|| header)
+}
+
+/// ACPI structures are valid if all their bytes sum to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Look for the RSDP where the BIOS puts it, on a 16 byte boundary in the first KiB of the
+/// extended BIOS data area or in the BIOS area
+fn search_rsdp() -> Option<PhysAddr> {
+	let ebda_segment = unsafe { *paging::phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>() };
+	let ebda = (ebda_segment as u64) << 4;
+	let areas = [(ebda, ebda + 1024), (BIOS_AREA_START, BIOS_AREA_END)];
+	areas
+		.iter()
+		.filter(|(start, _)| *start != 0)
+		.flat_map(|(start, end)| (*start..*end).step_by(16))
+		.map(PhysAddr::new)
+		.find(|addr| {
+			let bytes =
+				unsafe { slice::from_raw_parts(paging::phys_to_virt(*addr).as_ptr::<u8>(), RSDP_V1_SIZE) };
+			&bytes[..8] == b"RSD PTR " && checksum_ok(bytes)
+		})
+}
@@ -6,7 +6,7 @@ use alloc::boxed::Box;
 use core::{cmp::min, ptr::slice_from_raw_parts_mut, slice};
 use spin::Mutex;
 
-use super::{Port, Sector, SECTOR_SIZE};
+use super::{Command, Port, Sector, SECTOR_SIZE};
 
 /// A struct that can browse and read sectors, with a similair interface to std::io
 #[derive(Clone)]
@@ -16,6 +16,8 @@ pub struct BlockReader {
 	sectors_per_block: usize,
 	buffer: UBuffer,
 	block_in_buffer: Option<usize>,
+	/// Whether the buffer was changed since it was read or written
+	dirty: bool,
 	block_device: &'static Mutex<dyn BlockDevice>,
 }
 
@@ -40,6 +42,7 @@ impl BlockReader {
 			sectors_per_block,
 			block_device,
 			block_in_buffer: None,
+			dirty: false,
 			buffer: UBuffer::new(sectors_per_block * SECTOR_SIZE),
 		}
 	}
@@ -72,10 +75,14 @@ impl BlockReader {
 				.as_mut()
 				.unwrap();
 		}
-		self.block_device
+		// The device is unlocked before waiting, so others can use it while this thread sleeps
+		let command = self
+			.block_device
 			.lock()
-			.read_sectors(self.block * self.sectors_per_block, slice);
+			.start_read_sectors(self.block * self.sectors_per_block, slice);
+		command.wait();
 		self.block_in_buffer = Some(self.block);
+		self.dirty = false;
 	}
 
 	/// Write the current block to the disk
@@ -87,18 +94,30 @@ impl BlockReader {
 				.as_mut()
 				.unwrap();
 		}
-		self.block_device
+		let command = self
+			.block_device
 			.lock()
-			.write_sectors(self.block * self.sectors_per_block, slice);
+			.start_write_sectors(self.block * self.sectors_per_block, slice);
+		command.wait();
+		self.dirty = false;
 	}
 
-	/// Read the block into the buffer and return a slice to it
+	/// Read the block into the buffer and return a slice to it, which is written back to the disk
+	/// once the reader moves on
 	pub fn read_block(&mut self, block: u32) -> Result<&mut [u8], IOError> {
 		self.move_to_block(block)?;
 		self.get_current_block();
+		self.dirty = true;
 		Ok(self.mut_slice())
 	}
 
+	/// Read the block into the buffer and return a slice to it, for reading only
+	pub fn read_block_ref(&mut self, block: u32) -> Result<&[u8], IOError> {
+		self.move_to_block(block)?;
+		self.get_current_block();
+		Ok(self.slice())
+	}
+
 	/// Move to block
 	pub fn move_to_block(&mut self, block: u32) -> Result<(), IOError> {
 		self.flush()?;
@@ -187,6 +206,7 @@ impl Write for BlockReader {
 				self.buffer.slice.as_mut().unwrap()[self.offset..(self.offset + len_to_take)]
 					.copy_from_slice(&buffer[..len_to_take]);
 			}
+			self.dirty = true;
 			buffer = &buffer[len_to_take..];
 			// self.offset += len_to_take;
 			self.offset = if len_to_take == len_available {
@@ -204,7 +224,8 @@ impl Write for BlockReader {
 		match self.block_in_buffer {
 			None => Ok(()),
 			Some(block) => {
-				if block == self.block {
+				// Blocks that were only read are not written, they may have changed on the disk since
+				if block == self.block && self.dirty {
 					self.write_current_block();
 				}
 				Ok(())
@@ -243,10 +264,20 @@ pub trait BlockDevice {
 	}
 
 	/// Read sectors at LBA
-	fn read_sectors(&mut self, lba: usize, buffer: &mut [Sector]);
+	fn read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) {
+		self.start_read_sectors(lba, buffer).wait();
+	}
 
 	/// Write sectors at LBA
-	fn write_sectors(&mut self, lba: usize, buffer: &[Sector]);
+	fn write_sectors(&mut self, lba: usize, buffer: &[Sector]) {
+		self.start_write_sectors(lba, buffer).wait();
+	}
+
+	/// Start reading sectors at LBA. The buffer is filled once the command is done.
+	fn start_read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) -> Command;
+
+	/// Start writing sectors at LBA. The buffer may not change until the command is done.
+	fn start_write_sectors(&mut self, lba: usize, buffer: &[Sector]) -> Command;
 }
 
 /// Struct represnting a partition on some block device
@@ -261,12 +292,12 @@ impl BlockDevice for Partition {
 		self.length
 	}
 
-	fn read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) {
-		self.disk.read_sectors(lba + self.start_sector, buffer);
+	fn start_read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) -> Command {
+		self.disk.start_read_sectors(lba + self.start_sector, buffer)
 	}
 
-	fn write_sectors(&mut self, lba: usize, buffer: &[Sector]) {
-		self.disk.write_sectors(lba + self.start_sector, buffer);
+	fn start_write_sectors(&mut self, lba: usize, buffer: &[Sector]) -> Command {
+		self.disk.start_write_sectors(lba + self.start_sector, buffer)
 	}
 }
 
@@ -287,12 +318,14 @@ impl Partition {
 pub struct AtaDisk {
 	num_sectors: usize,
 	port: &'static mut Port,
+	/// Index of the port in the HBA
+	index: usize,
 }
 
 impl AtaDisk {
-	/// Create a new ATA disk around a port. Calling this multiple times for a given port will
-	/// cause UB
-	pub unsafe fn new(port: &'static mut Port) -> Self {
+	/// Create a new ATA disk around the port at index. Calling this multiple times for a given port
+	/// will cause UB
+	pub unsafe fn new(port: &'static mut Port, index: usize) -> Self {
 		let disk_data;
 		port.rebase();
 		disk_data = port.ata_identify().expect("Failed to Identify disk");
@@ -303,6 +336,7 @@ impl AtaDisk {
 		// port.ata_dma(0, &mut *buffer, ReadWrite::Read).expect("Failed to read");
 		Self {
 			port,
+			index,
 			num_sectors: disk_data.sector_count,
 		}
 	}
@@ -314,27 +348,29 @@ impl BlockDevice for AtaDisk {
 		self.num_sectors
 	}
 
-	fn read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) {
+	fn start_read_sectors(&mut self, lba: usize, buffer: &mut [Sector]) -> Command {
 		assert!(
 			lba + buffer.len() < self.num_sectors,
 			"Trying to read outside of sector"
 		);
-		unsafe {
+		let slot = unsafe {
 			self.port
 				.ata_dma(lba as u64, buffer, Read)
-				.expect("Failed to read sector");
-		}
+				.expect("Failed to read sector")
+		};
+		Command::new(self.index, slot)
 	}
 
-	fn write_sectors(&mut self, lba: usize, buffer: &[Sector]) {
+	fn start_write_sectors(&mut self, lba: usize, buffer: &[Sector]) -> Command {
 		assert!(
 			lba + buffer.len() < self.num_sectors,
 			"Trying to write outside of sector"
 		);
-		unsafe {
+		let slot = unsafe {
 			self.port
 				.ata_dma(lba as u64, &mut *(buffer as *const [Sector] as *mut [Sector]), Write)
-				.expect("Failed to write sector");
-		}
+				.expect("Failed to write sector")
+		};
+		Command::new(self.index, slot)
 	}
 }
@@ -3,8 +3,10 @@ use bitflags::bitflags;
 use core::{
 	fmt::{Debug, Display},
 	marker::PhantomData,
+	hint::spin_loop,
 	mem::{align_of, size_of},
 	ops::{Deref, DerefMut},
+	sync::atomic::{AtomicU64, Ordering},
 };
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
@@ -13,10 +15,13 @@ use spin::Mutex;
 use x86_64::{structures::paging::mapper::Translate, VirtAddr};
 
 use super::pci;
-use crate::mem::{
-	heap::{uncached_allocate_value, uncached_allocate_zeroed, UBox},
-	paging,
-	volatile::V,
+use crate::{
+	mem::{
+		heap::{uncached_allocate_value, uncached_allocate_zeroed, UBox},
+		paging,
+		volatile::V,
+	},
+	process::{self, wait_queue::WaitQueue},
 };
 
 mod fis;
@@ -36,6 +41,13 @@ lazy_static! {
 	static ref PHYS_TO_VIRT: Mutex<HashMap<u64, VirtAddr>> = Mutex::new(HashMap::new());
 }
 
+/// Virtual address of the HBA memory, 0 until [get_disks] finds it
+static HBA: AtomicU64 = AtomicU64::new(0);
+
+const NO_WAITERS: WaitQueue = WaitQueue::new();
+/// Threads waiting for a command on each port to finish, woken by the interrupt
+static PORT_WAITERS: Mutex<[WaitQueue; 32]> = Mutex::new([NO_WAITERS; 32]);
+
 /// Error when trying to find a disk through AHCI
 #[derive(Debug)]
 pub enum AhciError {
@@ -55,9 +67,21 @@ pub unsafe fn get_disks() -> Result<Vec<Box<dyn BlockDevice>>, AhciError> {
 		Some(function) => {
 			serial_println!("Found AHCI device: {:?}", function);
 			let abar = pci::get_bars(*function)[5];
-			let interrupt = pci::get_interrupt(*function);
-			use crate::cpu::interrupts::register_callback;
-			register_callback(8 + interrupt.line, interrupt_handler);
+			use crate::cpu::{interrupts, lapic};
+			let vector = pci::has_msi(*function)
+				.then(|| interrupts::allocate_vector(interrupt_handler))
+				.flatten();
+			match vector {
+				Some(vector) => {
+					let (address, data) = lapic::msi_message(lapic::id(), vector);
+					pci::enable_msi(*function, address, data);
+				}
+				None => {
+					// The interrupt line is the ISA interrupt the firmware connected the pin to
+					let interrupt = pci::get_interrupt(*function);
+					interrupts::register_isa_callback(interrupt.line, interrupt_handler);
+				}
+			}
 
 			let address;
 			match abar {
@@ -74,6 +98,7 @@ pub unsafe fn get_disks() -> Result<Vec<Box<dyn BlockDevice>>, AhciError> {
 			let virt_addr = paging::phys_to_virt(address);
 			let hba_memory: &mut Memory;
 			hba_memory = &mut *(virt_addr.as_mut_ptr());
+			HBA.store(virt_addr.as_u64(), Ordering::SeqCst);
 
 			hba_memory.global_host_control.write(1 << 31 | 1 << 1);
 
@@ -84,7 +109,7 @@ pub unsafe fn get_disks() -> Result<Vec<Box<dyn BlockDevice>>, AhciError> {
 			let mut vec: Vec<Box<dyn BlockDevice>> = Vec::new();
 			for port in ports {
 				if hba_memory.ports[port].get_device_type() == DeviceType::Sata {
-					let disk = AtaDisk::new(&mut *(&mut hba_memory.ports[port] as *mut _));
+					let disk = AtaDisk::new(&mut *(&mut hba_memory.ports[port] as *mut _), port);
 					vec.push(Box::new(disk));
 				}
 			}
@@ -97,6 +122,39 @@ pub unsafe fn get_disks() -> Result<Vec<Box<dyn BlockDevice>>, AhciError> {
 /// An array of bytes, the size of one sector
 pub type Sector = [u8; SECTOR_SIZE];
 
+/// A command issued to a port, which may still be running
+#[must_use]
+pub struct Command {
+	port: usize,
+	slot: u32,
+}
+
+impl Command {
+	fn new(port: usize, slot: u32) -> Self {
+		Self { port, slot }
+	}
+
+	/// Wait until the command is done. The running thread sleeps until the interrupt of the port
+	/// if [process::can_block_io] allows it, and otherwise waits on the CPU.
+	pub fn wait(self) {
+		let hba = unsafe { &*(HBA.load(Ordering::SeqCst) as *const Memory) };
+		let port = &hba.ports[self.port];
+		while unsafe { !port.is_done(self.slot) } {
+			if !process::can_block_io() {
+				spin_loop();
+				continue;
+			}
+			// Added before checking again, so the interrupt of a command that is done in between
+			// still wakes the thread
+			PORT_WAITERS.lock()[self.port].add_current();
+			if unsafe { port.is_done(self.slot) } {
+				break;
+			}
+			process::block_current("Disk", None);
+		}
+	}
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 struct AHCIVersion {
@@ -193,7 +251,13 @@ enum ReadWrite {
 use AtaError::*;
 
 impl Port {
-	unsafe fn ata_dma(&mut self, start_sector: u64, buf: &mut [Sector], read_write: ReadWrite) -> Result<(), AtaError> {
+	/// Start a DMA read or write, returns the bit of the command slot in `command_issue`
+	unsafe fn ata_dma(
+		&mut self,
+		start_sector: u64,
+		buf: &mut [Sector],
+		read_write: ReadWrite,
+	) -> Result<u32, AtaError> {
 		let count = buf.len();
 		if count == 0 || count >= 256 {
 			return Err(AtaError::InvalidSectorCount);
@@ -227,17 +291,13 @@ impl Port {
 				sector_count,
 				1 << 6,
 			);
-		})?;
-
-		// serial_println!("{:?}", buf);
-
-		Ok(())
+		})
 	}
 
 	unsafe fn ata_identify(&mut self) -> Result<DiskData, AtaError> {
 		let mut buffer = UBox::new([[0; 512]; 1]);
 
-		self.ata_start(|cmdheader, cmdfis, prdt_entries| {
+		let ci = self.ata_start(|cmdheader, cmdfis, prdt_entries| {
 			cmdheader.prdt_length.write(1);
 			let entry = &mut prdt_entries[0];
 
@@ -250,11 +310,17 @@ impl Port {
 			*cmdfis = FisRegHostToDevice::new(FisRegH2DBits::new().with_command_or_control(true), 0xEC, 0, 0, 1, 0);
 		})?;
 
+		// Disks are identified while booting, before any thread runs
+		while !self.is_done(ci) {
+			spin_loop();
+		}
+
 		let data: &mut IdentifyData = &mut *(buffer.as_mut_ptr() as *mut Sector as *mut IdentifyData);
 		Ok(DiskData::new(data))
 	}
 
-	unsafe fn ata_start<F>(&mut self, callback: F) -> Result<(), AtaError>
+	/// Issue a command without waiting for it, returns the bit of its slot in `command_issue`
+	unsafe fn ata_start<F>(&mut self, callback: F) -> Result<u32, AtaError>
 	where
 		F: FnOnce(&mut CommandHeader, &mut FisRegHostToDevice, &mut [PrdtEntry; PRDTL]),
 	{
@@ -298,12 +364,12 @@ impl Port {
 		// Issue command
 		self.command_issue.write(ci);
 
-		// Wait for completion
-		while self.command_issue.read() & ci != 0 {
-			// unsafe { asm!("nop") };
-		}
+		Ok(ci)
+	}
 
-		Ok(())
+	/// Whether the command in the slot with the bit is done
+	unsafe fn is_done(&self, ci: u32) -> bool {
+		self.command_issue.read() & ci == 0
 	}
 
 	unsafe fn find_command_slot(&self) -> Result<usize, AtaError> {
@@ -520,7 +586,25 @@ const _: () = {
 
 use x86_64::structures::idt::InterruptStackFrame;
 fn interrupt_handler(_stack_frame: &InterruptStackFrame) {
-	// serial_println!("Caught interrupt from ahci!");
+	let hba = HBA.load(Ordering::SeqCst) as *mut Memory;
+	if hba.is_null() {
+		return;
+	}
+	let hba = unsafe { &mut *hba };
+	unsafe {
+		let pending = hba.interrupt_status.read();
+		for (index, port) in hba.ports.iter_mut().enumerate() {
+			if pending & 1 << index != 0 {
+				port.interrupt_status.write(port.interrupt_status.read());
+			}
+		}
+		hba.interrupt_status.write(pending);
+	}
+	// Issuing a command clears the status of the port, so all the waiters are woken, and they check
+	// their own command
+	for waiters in PORT_WAITERS.lock().iter_mut() {
+		waiters.wake_all(0);
+	}
 }
 
 #[repr(transparent)]
@@ -4,3 +4,6 @@ pub mod pci;
 /// Module for working with ahci
 #[allow(dead_code)]
 pub mod ahci;
+
+/// Module for reading the ACPI tables the firmware describes the machine with
+pub mod acpi;
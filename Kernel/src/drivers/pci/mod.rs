@@ -100,6 +100,23 @@ fn pci_config_read(func: Function, register: u8) -> u32 {
 	}
 }
 
+// Write a word at a certain bus, slot, func, offset
+fn pci_config_write(func: Function, register: u8, value: u32) {
+	let lbus = func.bus as u32;
+	let lslot = func.slot as u32;
+	let lfunc = func.function as u32;
+	let lregister = register as u32;
+
+	let address: u32 = lbus << 16 | lslot << 11 | lfunc << 8 | lregister << 2 | 0x80000000;
+
+	unsafe {
+		#[allow(const_item_mutation)]
+		CONFIG_ADDRESS.write(address);
+		#[allow(const_item_mutation)]
+		CONFIG_DATA.write(value);
+	}
+}
+
 /// Recursively scan PCI buses and find all available functions. This starts at the ['function']
 /// (0,0,0) and through buses and bridges finds the rest of the functions recursively.
 pub fn recursive_scan() -> Vec<Function> {
@@ -207,6 +224,78 @@ pub fn get_interrupt(func: Function) -> Interrupt {
 	}
 }
 
+/// Bit of the status register (high half of register 0x1) saying there is a capabilities list
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+/// Bit of the command register (low half of register 0x1) that turns off the interrupt pin
+const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+/// Capability ID of message signaled interrupts
+const CAPABILITY_MSI: u8 = 0x05;
+
+// MSI capability layout, in registers from the capability
+// ╔════════════╦═══════════════════════════════╦═════════════════╦════════════════════════╗
+// ║  Register  ║           Bits 31-16          ║    Bits 15-8    ║        Bits 7-0        ║
+// ╠════════════╬═══════════════════════════════╬═════════════════╬════════════════════════╣
+// ║     0x0    ║        Message Control        ║  Next Pointer   ║     Capability ID      ║
+// ╠════════════╬═══════════════════════════════╩═════════════════╩════════════════════════╣
+// ║     0x1    ║                          Message Address                                 ║
+// ╠════════════╬══════════════════════════════════════════════════════════════════════════╣
+// ║     0x2    ║              Message Upper Address (if 64 bit capable)                   ║
+// ╠════════════╬═══════════════════════════════╦══════════════════════════════════════════╣
+// ║  0x2/0x3   ║            Reserved           ║               Message Data               ║
+// ╚════════════╩═══════════════════════════════╩══════════════════════════════════════════╝
+/// Message control bit that enables MSI
+const MSI_ENABLE: u32 = 1 << 16;
+/// Message control bits of how many vectors are enabled, 0 for one
+const MSI_MULTIPLE_ENABLE: u32 = 0b111 << 20;
+/// Message control bit saying the message address is 64 bit
+const MSI_64_BIT: u32 = 1 << 23;
+
+/// Find the register of the function's capability with the given ID
+fn find_capability(func: Function, id: u8) -> Option<u8> {
+	if pci_config_read(func, 1) & STATUS_CAPABILITIES == 0 {
+		return None;
+	}
+	// Pointers are byte offsets, the bottom two bits are reserved
+	let mut pointer = (pci_config_read(func, 0xD) as u8) & !0b11;
+	while pointer != 0 {
+		let reg = pci_config_read(func, pointer / 4);
+		if reg as u8 == id {
+			return Some(pointer / 4);
+		}
+		pointer = ((reg >> 8) as u8) & !0b11;
+	}
+	None
+}
+
+/// Check if the function can send message signaled interrupts
+pub fn has_msi(func: Function) -> bool {
+	find_capability(func, CAPABILITY_MSI).is_some()
+}
+
+/// Make the function send message signaled interrupts instead of using its interrupt pin. It
+/// writes `data` to the physical `address`, see [crate::cpu::lapic::msi_message]. Returns false if
+/// the function doesn't support MSI.
+pub fn enable_msi(func: Function, address: u64, data: u16) -> bool {
+	let capability = match find_capability(func, CAPABILITY_MSI) {
+		Some(capability) => capability,
+		None => return false,
+	};
+	let control = pci_config_read(func, capability);
+	pci_config_write(func, capability + 1, address as u32);
+	if control & MSI_64_BIT != 0 {
+		pci_config_write(func, capability + 2, (address >> 32) as u32);
+		pci_config_write(func, capability + 3, data as u32);
+	} else {
+		pci_config_write(func, capability + 2, data as u32);
+	}
+	pci_config_write(func, capability, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+
+	// The status half is cleared by writing ones, so only the command half is written back
+	let command = pci_config_read(func, 1) & 0xFFFF;
+	pci_config_write(func, 1, command | COMMAND_INTERRUPT_DISABLE);
+	true
+}
+
 // Memory space BAR layout
 // ╔════════════════════════════════╦════════════════╦════════════╦════════════╗
 // ║            Bits 31-4           ║      Bit 3     ║  Bits 2-1  ║    Bit 0   ║
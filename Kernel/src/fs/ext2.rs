@@ -2,6 +2,7 @@ use super::partitions;
 use crate::{
 	cpu::syscalls::OpenFlags,
 	drivers::ahci::disk::{BlockReader, Partition},
+	process::{Credentials, Gid, Uid, ROOT_CREDENTIALS, ROOT_UID},
 	util::io::*,
 };
 use alloc::{
@@ -9,6 +10,7 @@ use alloc::{
 	string::{FromUtf8Error, String, ToString},
 	vec::Vec,
 };
+use bitflags::bitflags;
 use core::{
 	cmp::min,
 	mem::size_of,
@@ -16,6 +18,7 @@ use core::{
 	ptr::slice_from_raw_parts_mut,
 	slice,
 };
+use hashbrown::HashMap;
 use spin::Mutex;
 
 static mut DEVICE: Option<Mutex<Partition>> = None;
@@ -151,16 +154,25 @@ struct BlockGroupDescriptor {
 	_unused: [u8; 32 - 18],
 }
 
+/// Type of an inode, with the values used by directory entries
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum Type {
+pub enum Type {
+	/// Named pipe
 	Fifo = 5,
+	/// Character device
 	CharacterDevice = 3,
+	/// Directory
 	Directory = 2,
+	/// Block device
 	BlockDevice = 4,
+	/// Regular file
 	RegularFile = 1,
+	/// Symbolic link
 	SymbolicLink = 7,
+	/// Unix domain socket
 	UnixSocket = 6,
+	/// Unknown type
 	Other = 0,
 }
 
@@ -194,6 +206,26 @@ impl TypeAndPermissions {
 		};
 		TypeAndPermissions(val << 12 | (permissions & 0xFFF))
 	}
+
+	fn permissions(&self) -> u16 {
+		self.0 & 0xFFF
+	}
+
+	fn set_permissions(&mut self, permissions: u16) {
+		self.0 = (self.0 & !0xFFF) | (permissions & 0xFFF);
+	}
+}
+
+bitflags! {
+	/// Kinds of access to an inode, laid out like a single rwx triplet of the permission bits
+	pub struct Access: u16 {
+		/// Execute a file, or search a directory
+		const EXECUTE = 0b001;
+		/// Write to a file, or add and remove entries of a directory
+		const WRITE = 0b010;
+		/// Read a file, or list a directory
+		const READ = 0b100;
+	}
 }
 
 #[repr(C)]
@@ -223,6 +255,101 @@ struct InodeData {
 	os_specific_val2: [u8; 12],
 }
 
+impl InodeData {
+	/// Get the access that the permission bits of this inode grant to the given credentials.
+	fn granted(&self, credentials: &Credentials) -> Access {
+		let permissions = self.type_and_permissions.permissions();
+		if credentials.uid == ROOT_UID {
+			// Root may read and write anything, but only execute things that are executable by someone
+			let mut granted = Access::READ | Access::WRITE;
+			if permissions & 0o111 != 0 {
+				granted |= Access::EXECUTE;
+			}
+			return granted;
+		}
+		let shift = if credentials.uid == self.user_id {
+			6
+		} else if credentials.gid == self.group_id {
+			3
+		} else {
+			0
+		};
+		Access::from_bits_truncate((permissions >> shift) & 0o7)
+	}
+
+	/// Get what can be told about the inode without reading its blocks
+	fn metadata(&self, inode: Inode) -> Metadata {
+		Metadata {
+			inode,
+			inode_type: self.type_and_permissions.inode_type(),
+			permissions: self.type_and_permissions.permissions(),
+			uid: self.user_id,
+			gid: self.group_id,
+			size: self.size_lower as u64,
+			links: self.hard_link_count,
+			sectors: self.sectors_in_use as u64,
+			access_time: self.last_access_time,
+			modification_time: self.last_modification_time,
+			change_time: self.creation_time,
+		}
+	}
+
+	/// Check that the given credentials have all of the wanted access to this inode
+	fn check_access(&self, credentials: &Credentials, wanted: Access) -> Result<(), Ext2Err> {
+		if self.granted(credentials).contains(wanted) {
+			Ok(())
+		} else {
+			Err(PermissionDenied)
+		}
+	}
+}
+
+/// Information about an inode, like the stat of UNIX
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+	/// The inode
+	pub inode: Inode,
+	/// Type of the inode
+	pub inode_type: Type,
+	/// Permission bits
+	pub permissions: u16,
+	/// User that owns the inode
+	pub uid: Uid,
+	/// Group that owns the inode
+	pub gid: Gid,
+	/// Size in bytes
+	pub size: u64,
+	/// Number of directory entries pointing to the inode
+	pub links: u16,
+	/// Disk sectors (512b) in use
+	pub sectors: u64,
+	/// Last access, in seconds since the epoch
+	pub access_time: u32,
+	/// Last modification of the contents, in seconds since the epoch
+	pub modification_time: u32,
+	/// Last change of the inode, in seconds since the epoch
+	pub change_time: u32,
+}
+
+impl Metadata {
+	/// Metadata of something of the type that has no inode, like a pipe or a terminal
+	pub fn anonymous(inode_type: Type) -> Self {
+		Self {
+			inode: 0,
+			inode_type,
+			permissions: 0o600,
+			uid: ROOT_UID,
+			gid: 0,
+			size: 0,
+			links: 1,
+			sectors: 0,
+			access_time: 0,
+			modification_time: 0,
+			change_time: 0,
+		}
+	}
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 /// Literal structure found on disk, the directory entry
@@ -250,8 +377,8 @@ pub struct Directory {
 
 impl Directory {
 	/// Get directory from path
-	pub fn from_path(path: &str) -> Result<Directory, Ext2Err> {
-		let mut file = File::from_path(path, OpenFlags::empty())?;
+	pub fn from_path(path: &str, credentials: &Credentials) -> Result<Directory, Ext2Err> {
+		let mut file = File::from_path(path, OpenFlags::empty(), credentials)?;
 		Directory::read(&mut file)
 	}
 
@@ -359,11 +486,24 @@ impl Entry {
 	fn actual_size(&self) -> usize {
 		size_of::<DirectoryEntry>() + self.name.len() + 1
 	}
+
+	/// The inode this entry points to
+	pub fn inode(&self) -> u32 {
+		self.entry.inode
+	}
+
+	/// The type of the inode this entry points to, as stored in the directory entry
+	pub fn type_indicator(&self) -> u8 {
+		self.entry.type_indicator as u8
+	}
 }
 
 struct Ext2 {
 	super_block: SuperBlock,
 	block_groups: Vec<BlockGroup>,
+	/// How many handles keep each inode from being written, because processes run the file.
+	/// Their pages are loaded from the file as they are touched, so it must not change.
+	text_busy: HashMap<Inode, usize>,
 }
 
 impl Ext2 {
@@ -374,6 +514,10 @@ impl Ext2 {
 		&group.inode_table[index_in_group]
 	}
 
+	fn is_text_busy(&self, inode: Inode) -> bool {
+		self.text_busy.contains_key(&inode)
+	}
+
 	fn get_inode_data_mut(&mut self, inode: Inode) -> &mut InodeData {
 		let group = self.super_block.inode_blockgroup(inode);
 		let index_in_group = self.super_block.inode_index_in_blockgroup(inode);
@@ -410,10 +554,10 @@ impl Ext2 {
 				bytes: blocks,
 			};
 
-			let bytes = Vec::from(block_reader.read_block(descriptor.inode_bitmap_addr)?);
+			let bytes = Vec::from(block_reader.read_block_ref(descriptor.inode_bitmap_addr)?);
 			let inode_bitmap = ExtBitMap { bytes };
 
-			let bytes = Vec::from(block_reader.read_block(descriptor.block_bitmap_addr)?);
+			let bytes = Vec::from(block_reader.read_block_ref(descriptor.block_bitmap_addr)?);
 			let block_bitmap = ExtBitMap { bytes };
 
 			block_groups.push(BlockGroup {
@@ -428,6 +572,7 @@ impl Ext2 {
 		Ok(Ext2 {
 			super_block,
 			block_groups,
+			text_busy: HashMap::new(),
 		})
 	}
 
@@ -605,22 +750,37 @@ impl ExtBitMap {
 	}
 }
 
-/// Add a regular file at a given path
-pub fn add_regular_file(path: &str) -> Result<Inode, Ext2Err> {
+/// Add a regular file at a given path, owned by the given credentials
+pub fn add_regular_file(path: &str, credentials: &Credentials) -> Result<Inode, Ext2Err> {
+	add_file(path, Type::RegularFile, credentials)
+}
+
+/// Add a named pipe at a given path, owned by the given credentials
+pub fn mkfifo(path: &str, credentials: &Credentials) -> Result<Inode, Ext2Err> {
+	add_file(path, Type::Fifo, credentials)
+}
+
+/// Add a unix domain socket at a given path, owned by the given credentials
+pub fn mksocket(path: &str, credentials: &Credentials) -> Result<Inode, Ext2Err> {
+	add_file(path, Type::UnixSocket, credentials)
+}
+
+/// Add an inode without data of the given type at a given path, owned by the given credentials
+fn add_file(path: &str, inode_type: Type, credentials: &Credentials) -> Result<Inode, Ext2Err> {
 	// TODO check that path is okay before doing anything
 	//  - Absolute
-	//  - Parent exists,
 	//  - It doesn't exist
+	check_parent_access(path, credentials)?;
 
 	let inode_data = InodeData {
-		type_and_permissions: TypeAndPermissions::new(Type::RegularFile, 0b000110110110),
-		user_id: 0,
+		type_and_permissions: TypeAndPermissions::new(inode_type, 0o666 & !credentials.umask),
+		user_id: credentials.uid,
 		size_lower: 0,
 		last_access_time: 0,
 		creation_time: 0,
 		last_modification_time: 0,
 		deletion_time: 0,
-		group_id: 0,
+		group_id: credentials.gid,
 		hard_link_count: 0, // will be 1 once linked
 		sectors_in_use: 0,
 		flags: 0,
@@ -636,7 +796,7 @@ pub fn add_regular_file(path: &str) -> Result<Inode, Ext2Err> {
 		os_specific_val2: [0; 12],
 	};
 	let inode = add_inode(inode_data)?;
-	link(path, inode)?;
+	link(path, inode, credentials)?;
 	Ok(inode)
 }
 
@@ -650,12 +810,12 @@ fn add_inode(data: InodeData) -> Result<Inode, Ext2Err> {
 }
 
 /// Create a (hard) link to an Inode. returns the parent inode
-pub fn link(path: &str, inode: Inode) -> Result<Inode, Ext2Err> {
+pub fn link(path: &str, inode: Inode, credentials: &Credentials) -> Result<Inode, Ext2Err> {
 	let index = path.rfind(SEPARATOR).unwrap();
 	let folder_path = &path[..index + 1];
 	let file_name = &path[index + 1..];
 
-	let dir_inode = path_to_inode(folder_path)?;
+	let dir_inode = path_to_inode(folder_path, credentials)?;
 
 	let mut parent_reader = File::new(dir_inode)?;
 	let mut directory = Directory::read(&mut parent_reader)?;
@@ -685,18 +845,20 @@ pub fn link(path: &str, inode: Inode) -> Result<Inode, Ext2Err> {
 	}
 }
 
-/// Create a directory
-pub fn mkdir(path: &str) -> Result<Inode, Ext2Err> {
+/// Create a directory, owned by the given credentials
+pub fn mkdir(path: &str, credentials: &Credentials) -> Result<Inode, Ext2Err> {
 	// TODO check that path is okay before doing anything
+	check_parent_access(path, credentials)?;
+
 	let inode_data = InodeData {
-		type_and_permissions: TypeAndPermissions::new(Type::Directory, 0b000111101101),
-		user_id: 0,
+		type_and_permissions: TypeAndPermissions::new(Type::Directory, 0o777 & !credentials.umask),
+		user_id: credentials.uid,
 		size_lower: 0,
 		last_access_time: 0,
 		creation_time: 0,
 		last_modification_time: 0,
 		deletion_time: 0,
-		group_id: 0,
+		group_id: credentials.gid,
 		hard_link_count: 1, // will be 2 once linked (self, and from parent)
 		sectors_in_use: 0,
 		flags: 0,
@@ -714,7 +876,7 @@ pub fn mkdir(path: &str) -> Result<Inode, Ext2Err> {
 
 	let inode = add_inode(inode_data)?;
 
-	let parent_inode = link(path, inode)?;
+	let parent_inode = link(path, inode, credentials)?;
 
 	let mut directory = Directory::empty();
 	directory.entries.push(Entry {
@@ -753,8 +915,9 @@ pub fn mkdir(path: &str) -> Result<Inode, Ext2Err> {
 }
 
 /// Remov an empty (only . and ..) directory
-pub fn rmdir(path: &str) -> Result<(), Ext2Err> {
-	let inode = path_to_inode(path)?;
+pub fn rmdir(path: &str, credentials: &Credentials) -> Result<(), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	check_parent_access(path, credentials)?;
 
 	let parent_inode;
 	{
@@ -786,7 +949,7 @@ pub fn rmdir(path: &str) -> Result<(), Ext2Err> {
 
 	unlink_inode(inode)?;
 	unlink_inode(parent_inode)?;
-	unlink(path, true)?;
+	unlink(path, true, credentials)?;
 	Ok(())
 }
 
@@ -797,6 +960,9 @@ fn unlink_inode(inode: Inode) -> Result<(), Ext2Err> {
 
 	let mut ext = get_ext!().lock();
 	let device = get_device!();
+	if inode_data.hard_link_count == 1 && ext.is_text_busy(inode) {
+		return Err(TextBusy);
+	}
 	if inode_data.hard_link_count == 1 {
 		// Get rid of inode
 		ext.free_inode(inode)?;
@@ -818,8 +984,9 @@ fn unlink_inode(inode: Inode) -> Result<(), Ext2Err> {
 
 /// Unlink a file, also called removing. If there are multiple hard links to the file, the
 /// other links will continue to be able to access it
-pub fn unlink(path: &str, allow: bool) -> Result<(), Ext2Err> {
-	let inode = path_to_inode(path)?;
+pub fn unlink(path: &str, allow: bool, credentials: &Credentials) -> Result<(), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	check_parent_access(path, credentials)?;
 	let allowed = allow
 		|| get_ext!()
 			.lock()
@@ -837,7 +1004,7 @@ pub fn unlink(path: &str, allow: bool) -> Result<(), Ext2Err> {
 	let folder_path = &path[..index + 1];
 	let file_name = &path[index + 1..];
 
-	let dir_inode = path_to_inode(folder_path)?;
+	let dir_inode = path_to_inode(folder_path, credentials)?;
 
 	let mut parent_reader = File::new(dir_inode)?;
 	let mut directory = Directory::read(&mut parent_reader)?;
@@ -853,7 +1020,63 @@ pub fn unlink(path: &str, allow: bool) -> Result<(), Ext2Err> {
 	Ok(())
 }
 
-fn path_to_inode(mut path: &str) -> Result<Inode, Ext2Err> {
+/// Check that the credentials may add or remove entries in the directory containing path
+fn check_parent_access(path: &str, credentials: &Credentials) -> Result<(), Ext2Err> {
+	let index = path.rfind(SEPARATOR).ok_or(NotAbsolute)?;
+	let folder_path = &path[..index + 1];
+	let dir_inode = path_to_inode(folder_path, credentials)?;
+	get_ext!()
+		.lock()
+		.get_inode_data(dir_inode)
+		.check_access(credentials, Access::WRITE | Access::EXECUTE)
+}
+
+/// Check that the credentials have all of the wanted access to the file at path
+pub fn access(path: &str, credentials: &Credentials, wanted: Access) -> Result<(), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	get_ext!().lock().get_inode_data(inode).check_access(credentials, wanted)
+}
+
+/// Get the inode at the path and its type
+pub fn inode_type(path: &str, credentials: &Credentials) -> Result<(Inode, Type), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	let inode_type = get_ext!().lock().get_inode_data(inode).type_and_permissions.inode_type();
+	Ok((inode, inode_type))
+}
+
+/// Get the metadata of the file at the path
+pub fn metadata(path: &str, credentials: &Credentials) -> Result<Metadata, Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	Ok(get_ext!().lock().get_inode_data(inode).metadata(inode))
+}
+
+/// Change the permission bits of a file. Only its owner (or root) may do this.
+pub fn chmod(path: &str, permissions: u16, credentials: &Credentials) -> Result<(), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	let mut ext = get_ext!().lock();
+	let inode_data = ext.get_inode_data_mut(inode);
+	if credentials.uid != ROOT_UID && credentials.uid != inode_data.user_id {
+		return Err(PermissionDenied);
+	}
+	inode_data.type_and_permissions.set_permissions(permissions);
+	Ok(())
+}
+
+/// Change the owner and group of a file. Only root may do this.
+pub fn chown(path: &str, uid: Uid, gid: Gid, credentials: &Credentials) -> Result<(), Ext2Err> {
+	let inode = path_to_inode(path, credentials)?;
+	if credentials.uid != ROOT_UID {
+		return Err(PermissionDenied);
+	}
+	let mut ext = get_ext!().lock();
+	let inode_data = ext.get_inode_data_mut(inode);
+	inode_data.user_id = uid;
+	inode_data.group_id = gid;
+	Ok(())
+}
+
+/// Find the inode at the path. Every directory on the way must let the credentials search it.
+fn path_to_inode(mut path: &str, credentials: &Credentials) -> Result<Inode, Ext2Err> {
 	if *path == *SEPARATOR {
 		return Ok(ROOT_INODE);
 	}
@@ -877,6 +1100,7 @@ fn path_to_inode(mut path: &str) -> Result<Inode, Ext2Err> {
 
 	for name in split {
 		let mut file_reader = File::new(inode)?;
+		file_reader.inode_data.check_access(credentials, Access::EXECUTE)?;
 		let directory = Directory::read(&mut file_reader)?;
 		// serial_println!("Searching for: {}", name);
 		// serial_println!("Directory : {:#?}", directory);
@@ -924,6 +1148,13 @@ fn get_sub_blocks(b_reader: &mut BlockReader, block: Block) -> Result<&'static m
 	}
 }
 
+/// Read the block pointers in an indirect block, which is not written back
+fn read_sub_blocks(b_reader: &mut BlockReader, block: Block) -> Result<Vec<Block>, IOError> {
+	let slice = b_reader.read_block_ref(block)?;
+	let sub_blocks = unsafe { slice::from_raw_parts(slice.as_ptr() as *const Block, slice.len() / size_of::<Block>()) };
+	Ok(sub_blocks.to_vec())
+}
+
 fn get_indirect_blocks(
 	blocks: &mut Vec<Block>,
 	b_reader: &mut BlockReader,
@@ -934,7 +1165,7 @@ fn get_indirect_blocks(
 	if block == 0 {
 		return Ok(());
 	} else {
-		let mut sub_blocks = &*get_sub_blocks(b_reader, block)?.to_vec();
+		let mut sub_blocks = &*read_sub_blocks(b_reader, block)?;
 
 		let zero_index = sub_blocks.iter().position(|val| *val == 0);
 		if let Some(index) = zero_index {
@@ -957,6 +1188,22 @@ fn get_indirect_blocks(
 	Ok(())
 }
 
+/// The access that opening a file with the flags asks for. Without READ or WRITE it asks for both.
+fn requested_access(flags: OpenFlags) -> Access {
+	let mut access = Access::empty();
+	if flags.contains(OpenFlags::READ) {
+		access |= Access::READ;
+	}
+	if flags.contains(OpenFlags::WRITE) {
+		access |= Access::WRITE;
+	}
+	if access.is_empty() {
+		Access::READ | Access::WRITE
+	} else {
+		access
+	}
+}
+
 /// File handle
 #[derive(Debug)]
 pub struct File {
@@ -966,18 +1213,48 @@ pub struct File {
 	reader: BlockReader,
 	position: usize,
 	blocks: Vec<Block>,
+	/// What this handle is allowed to do with the file
+	access: Access,
+	/// Every write goes to the end of the file
+	append: bool,
+	/// The handle changed the size or the blocks of the file, which are written back when it is
+	/// dropped
+	dirty: bool,
+	/// The handle keeps the file from being written, see [File::deny_write]
+	denies_write: bool,
 }
 
 impl File {
-	/// get file handle from path
-	pub fn from_path(path: &str, flags: OpenFlags) -> Result<Self, Ext2Err> {
-		let inode = path_to_inode(path);
+	/// get file handle from path. The handle may only read or write the file if the permissions
+	/// of the file allow it for the given credentials.
+	pub fn from_path(path: &str, flags: OpenFlags, credentials: &Credentials) -> Result<Self, Ext2Err> {
+		let inode = path_to_inode(path, credentials);
 		match inode {
-			Ok(inode) => File::new(inode),
+			Ok(inode) => {
+				if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) {
+					return Err(FileAlreadyExists);
+				}
+				let mut file = File::new(inode)?;
+				file.access = file.inode_data.granted(credentials) & requested_access(flags);
+				if file.access.is_empty() {
+					return Err(PermissionDenied);
+				}
+				if file.access.contains(Access::WRITE) && get_ext!().lock().is_text_busy(inode) {
+					return Err(TextBusy);
+				}
+				if flags.contains(OpenFlags::TRUNCATE) {
+					file.set_len(0)?;
+				}
+				file.append = flags.contains(OpenFlags::APPEND);
+				Ok(file)
+			}
 			Err(FileNotFound) => {
 				if flags.contains(OpenFlags::CREATE) {
-					let new_inode = add_regular_file(path)?;
-					File::new(new_inode)
+					let new_inode = add_regular_file(path, credentials)?;
+					let mut file = File::new(new_inode)?;
+					file.access &= requested_access(flags);
+					file.append = flags.contains(OpenFlags::APPEND);
+					Ok(file)
 				} else {
 					Err(FileNotFound)
 				}
@@ -986,6 +1263,97 @@ impl File {
 		}
 	}
 
+	/// get a handle that only reads the file at the path, whatever its permissions. For the
+	/// kernel's own reads, like loading an executable the caller was allowed to execute.
+	pub fn open_read(path: &str) -> Result<Self, Ext2Err> {
+		let mut file = File::new(path_to_inode(path, &ROOT_CREDENTIALS)?)?;
+		file.access = Access::READ;
+		Ok(file)
+	}
+
+	/// Keep the file from being opened for writing or removed for as long as the handle is open,
+	/// like an executable that a process runs and loads its pages from
+	pub fn deny_write(&mut self) {
+		if !self.denies_write {
+			*get_ext!().lock().text_busy.entry(self.inode).or_insert(0) += 1;
+			self.denies_write = true;
+		}
+	}
+
+	/// Get the metadata of the file, as this handle sees it
+	pub fn metadata(&self) -> Metadata {
+		self.inode_data.metadata(self.inode)
+	}
+
+	/// Change the size of the file. Shrinking it frees the data blocks past the new end, and the
+	/// indirect blocks that no longer point to anything, back to the block bitmap. Growing it
+	/// fills the new space with zeroes.
+	pub fn set_len(&mut self, len: usize) -> Result<(), Ext2Err> {
+		if !self.access.contains(Access::WRITE) {
+			return Err(PermissionDenied);
+		}
+		self.dirty = true;
+
+		let size = self.inode_data.size_lower as usize;
+		if len > size {
+			let position = self.position;
+			let append = self.append;
+			self.position = size;
+			self.append = false;
+			let zeroes = [0u8; 512];
+			let mut left_to_write = len - size;
+			while left_to_write > 0 {
+				let count = min(left_to_write, zeroes.len());
+				self.write(&zeroes[..count])?;
+				left_to_write -= count;
+			}
+			self.position = position;
+			self.append = append;
+			return Ok(());
+		}
+
+		let block_size = self.reader.slice().len();
+		let blocks_per_block = block_size / size_of::<Block>();
+		let kept_blocks = (len + block_size - 1) / block_size;
+
+		let mut freed = Vec::new();
+		if kept_blocks < self.blocks.len() {
+			for pointer in self.inode_data.direct_block_pointers.iter_mut().skip(kept_blocks) {
+				if *pointer != 0 {
+					freed.push(*pointer);
+					*pointer = 0;
+				}
+			}
+
+			let mut reader = self.reader.clone();
+			let mut remaining = kept_blocks.saturating_sub(self.inode_data.direct_block_pointers.len());
+			let mut capacity = blocks_per_block;
+			let indirect_pointers = [
+				&mut self.inode_data.singly_indirect_pointer,
+				&mut self.inode_data.doubly_indirect_pointer,
+				&mut self.inode_data.triply_indirect_pointer,
+			];
+			for (tier, pointer) in indirect_pointers.into_iter().enumerate() {
+				let kept_in_tier = min(remaining, capacity);
+				truncate_indirect(&mut reader, pointer, tier + 1, kept_in_tier, blocks_per_block, &mut freed)?;
+				remaining -= kept_in_tier;
+				capacity *= blocks_per_block;
+			}
+
+			let mut ext = get_ext!().lock();
+			for block in freed.iter() {
+				ext.free_block(*block)?;
+			}
+			self.blocks.truncate(kept_blocks);
+			self.inode_data.sectors_in_use -= (freed.len() * self.reader.sectors_per_block()) as u32;
+		}
+
+		self.inode_data.size_lower = len as u32;
+		// Files can't have holes, so the position can't stay past the new end
+		self.position = min(self.position, len);
+		Ok(())
+	}
+
 	fn new(inode: u32) -> Result<Self, Ext2Err> {
 		let ext = get_ext!();
 		let device = get_device!();
@@ -1000,12 +1368,19 @@ impl File {
 			reader: block_reader,
 			position: 0,
 			blocks,
+			access: Access::READ | Access::WRITE,
+			append: false,
+			dirty: false,
+			denies_write: false,
 		})
 	}
 }
 impl Read for File {
 	fn read(&mut self, mut buf: &mut [u8]) -> Result<usize, IOError> {
-		let to_read = min(buf.len(), self.inode_data.size_lower as usize - self.position);
+		if !self.access.contains(Access::READ) {
+			return Err(IOError::PermissionDenied);
+		}
+		let to_read = min(buf.len(), (self.inode_data.size_lower as usize).saturating_sub(self.position));
 		let mut left_to_read = to_read;
 
 		let block_size = self.reader.slice().len();
@@ -1014,7 +1389,7 @@ impl Read for File {
 			let offset_in_block = self.position % block_size;
 			let to_read_from_block = min(left_to_read, block_size - offset_in_block);
 			let next_block = self.blocks[self.position / block_size];
-			block = self.reader.read_block(next_block)?;
+			block = self.reader.read_block_ref(next_block)?;
 			buf[..to_read_from_block].copy_from_slice(&block[offset_in_block..offset_in_block + to_read_from_block]);
 			buf = &mut buf[to_read_from_block..];
 			left_to_read -= to_read_from_block;
@@ -1026,19 +1401,20 @@ impl Read for File {
 
 impl Seek for File {
 	fn seek(&mut self, pos: SeekFrom) -> Result<usize, IOError> {
-		match pos {
-			SeekFrom::Start(offset) => {
-				self.position = offset;
-			}
-			SeekFrom::Current(offset) => {
-				self.position = (self.position as isize + offset) as usize;
-			}
-			SeekFrom::End(_offset) => unimplemented!(),
+		let position = match pos {
+			SeekFrom::Start(offset) => offset as isize,
+			SeekFrom::Current(offset) => self.position as isize + offset,
+			SeekFrom::End(offset) => self.inode_data.size_lower as isize + offset,
+		};
+		if position < 0 {
+			return Err(IOError::Other);
 		}
+		self.position = position as usize;
 		// Seeking after the end of a file is allowed, behaviour is to leave whatever data was
 		// there already.
 		if self.position as u32 > self.inode_data.size_lower {
-			self.inode_data.size_lower = self.position as u32
+			self.inode_data.size_lower = self.position as u32;
+			self.dirty = true;
 		}
 		Ok(self.position)
 	}
@@ -1046,6 +1422,13 @@ impl Seek for File {
 
 impl Write for File {
 	fn write(&mut self, mut buf: &[u8]) -> Result<usize, IOError> {
+		if !self.access.contains(Access::WRITE) {
+			return Err(IOError::PermissionDenied);
+		}
+		self.dirty = true;
+		if self.append {
+			self.position = self.inode_data.size_lower as usize;
+		}
 		let old_block_count = self.blocks.len();
 
 		let to_write = buf.len();
@@ -1247,6 +1630,41 @@ fn fill_block_pointers(
 	Ok(vec)
 }
 
+/// Trim the tree of blocks under an indirect pointer, so that only its first `keep` data blocks
+/// stay reachable. The data and indirect blocks that are cut off are pushed to `freed`.
+fn truncate_indirect(
+	reader: &mut BlockReader,
+	pointer: &mut Block,
+	indirectness: usize,
+	keep: usize,
+	blocks_per_block: usize,
+	freed: &mut Vec<Block>,
+) -> Result<(), Ext2Err> {
+	if *pointer == 0 {
+		return Ok(());
+	}
+	if keep == 0 {
+		get_indirect_blocks(freed, reader, *pointer, indirectness, true)?;
+		*pointer = 0;
+		return Ok(());
+	}
+
+	let child_capacity = blocks_per_block.pow(indirectness as u32 - 1);
+	let mut sub_blocks = get_sub_blocks(reader, *pointer)?.to_vec();
+	for (i, sub_block) in sub_blocks.iter_mut().enumerate() {
+		let child_keep = min(keep.saturating_sub(i * child_capacity), child_capacity);
+		if indirectness > 1 {
+			truncate_indirect(reader, sub_block, indirectness - 1, child_keep, blocks_per_block, freed)?;
+		} else if child_keep == 0 && *sub_block != 0 {
+			freed.push(*sub_block);
+			*sub_block = 0;
+		}
+	}
+	get_sub_blocks(reader, *pointer)?.copy_from_slice(&sub_blocks);
+	reader.flush()?;
+	Ok(())
+}
+
 /// Get number of blocks required in next tier
 fn next_tier_blocks(count: usize, tier: usize, bpb: usize) -> usize {
 	let in_inode = if tier == 0 { 12 } else { 1 };
@@ -1259,8 +1677,25 @@ fn next_tier_blocks(count: usize, tier: usize, bpb: usize) -> usize {
 
 impl Drop for File {
 	fn drop(&mut self) {
-		let ext = get_ext!();
-		*ext.lock().get_inode_data_mut(self.inode) = self.inode_data;
+		let mut ext = get_ext!().lock();
+		if self.denies_write {
+			let count = ext.text_busy.get_mut(&self.inode).unwrap();
+			*count -= 1;
+			if *count == 0 {
+				ext.text_busy.remove(&self.inode);
+			}
+		}
+		// Only what the handle changed is written back, the rest of the inode may have changed
+		// since it was opened, like its permissions. A removed inode is left alone.
+		let inode_data = ext.get_inode_data_mut(self.inode);
+		if self.dirty && inode_data.hard_link_count > 0 {
+			inode_data.size_lower = self.inode_data.size_lower;
+			inode_data.sectors_in_use = self.inode_data.sectors_in_use;
+			inode_data.direct_block_pointers = self.inode_data.direct_block_pointers;
+			inode_data.singly_indirect_pointer = self.inode_data.singly_indirect_pointer;
+			inode_data.doubly_indirect_pointer = self.inode_data.doubly_indirect_pointer;
+			inode_data.triply_indirect_pointer = self.inode_data.triply_indirect_pointer;
+		}
 	}
 }
 
@@ -1331,6 +1766,10 @@ pub enum Ext2Err {
 	NoHandle,
 	/// No more entries in this directory
 	EndOfDir,
+	/// The permissions of the file don't allow this operation
+	PermissionDenied,
+	/// The file is an executable that a process runs, so it can't be written or removed
+	TextBusy,
 }
 
 impl From<IOError> for Ext2Err {
@@ -1360,7 +1799,7 @@ pub fn setup() -> Result<(), Ext2Err> {
 
 /// Do some things to the file system
 pub fn test() -> Result<(), Ext2Err> {
-	let inode = path_to_inode("/profile.png")?;
+	let inode = path_to_inode("/profile.png", &ROOT_CREDENTIALS)?;
 	let mut file = File::new(inode)?;
 	let mut vec = Vec::new();
 	file.read_to_end(&mut vec)?;
@@ -1369,10 +1808,10 @@ pub fn test() -> Result<(), Ext2Err> {
 		vec.len()
 	);
 
-	let inode = mkdir("/new_directory")?;
+	let inode = mkdir("/new_directory", &ROOT_CREDENTIALS)?;
 	println!("Directory inode: {}", inode);
 
-	let inode = add_regular_file("/new_directory/new_file.txt")?;
+	let inode = add_regular_file("/new_directory/new_file.txt", &ROOT_CREDENTIALS)?;
 	println!("File inode: {}", inode);
 	let mut writer = File::new(inode)?;
 	writer.write(b"Hello world!\n")?;
@@ -1381,11 +1820,11 @@ pub fn test() -> Result<(), Ext2Err> {
 		writer.write(TEST_DATA)?;
 	}
 
-	add_regular_file("/other_file.txt")?;
-	mkdir("/bar")?;
-	rmdir("/bar")?;
+	add_regular_file("/other_file.txt", &ROOT_CREDENTIALS)?;
+	mkdir("/bar", &ROOT_CREDENTIALS)?;
+	rmdir("/bar", &ROOT_CREDENTIALS)?;
 
-	let inode = path_to_inode("/Documents/alice.txt")?;
+	let inode = path_to_inode("/Documents/alice.txt", &ROOT_CREDENTIALS)?;
 	let mut writer = File::new(inode)?;
 	writer.write(b"hello eran")?;
 	Ok(())
@@ -1393,7 +1832,7 @@ pub fn test() -> Result<(), Ext2Err> {
 
 /// Read entire file into Vec
 pub fn read_file(path: &str) -> Result<Vec<u8>, Ext2Err> {
-	let inode = path_to_inode(path)?;
+	let inode = path_to_inode(path, &ROOT_CREDENTIALS)?;
 	let mut reader = File::new(inode)?;
 	let mut data = Vec::new();
 	reader.read_to_end(&mut data)?;
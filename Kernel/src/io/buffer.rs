@@ -47,6 +47,14 @@ pub fn print_on(s: &str, term: usize) {
 	}
 }
 
+/// Get the width and height of a terminal in characters
+pub fn size_of_term(term: usize) -> (usize, usize) {
+	match unsafe { &TERMINALS } {
+		Some(terminals) => (terminals[term].width, terminals[term].height),
+		None => (0, 0),
+	}
+}
+
 /// Public static terminal.
 pub static mut TERMINALS: Option<[Terminal; TERM_COUNT]> = None;
 /// Public static screen
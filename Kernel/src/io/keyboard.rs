@@ -21,7 +21,9 @@ lazy_static! {
 
 /// setup keyboard
 pub fn setup() {
-	crate::cpu::interrupts::register_callback(1, keyboard_interrupt);
+	if !crate::cpu::interrupts::register_isa_callback(1, keyboard_interrupt) {
+		serial_println!("Can't route the keyboard interrupt");
+	}
 }
 
 fn keyboard_interrupt(_stack_frame: &InterruptStackFrame) {
@@ -46,11 +48,7 @@ fn parse_scan_code(scancode: u8) {
 		if let Some(key) = keyboard.process_keyevent(key_event) {
 			match key {
 				DecodedKey::Unicode(character) => {
-					for (_, process) in process::MAP.lock().iter_mut() {
-						if process.terminal == crate::io::buffer::active_term() && process.blocked_on_input() {
-							process.append_input(character)
-						}
-					}
+					process::input_character(crate::io::buffer::active_term(), character);
 
 					// let fg_pid = process::foreground_process();
 					// process::MAP
@@ -61,7 +59,7 @@ fn parse_scan_code(scancode: u8) {
 				}
 				DecodedKey::RawKey(KeyCode::ArrowLeft) => crate::io::buffer::cycle_terms(1),
 				DecodedKey::RawKey(KeyCode::ArrowRight) => crate::io::buffer::cycle_terms(-1),
-				DecodedKey::RawKey(KeyCode::F1) => crate::process::remove_current_process(),
+				DecodedKey::RawKey(KeyCode::F1) => crate::process::remove_current_process(crate::process::ExitStatus::Killed),
 				DecodedKey::RawKey(key) => serial_println!("{:?}", key),
 			}
 		}
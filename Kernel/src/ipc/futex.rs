@@ -0,0 +1,65 @@
+use crate::{
+	mem::paging,
+	process::{self, wait_queue::WaitQueue, Pid},
+};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Block the thread if the futex still holds the expected value
+pub const FUTEX_WAIT: u64 = 0;
+/// Wake threads blocked on the futex
+pub const FUTEX_WAKE: u64 = 1;
+/// Flag for futexes that are only used by the threads of one process, which saves translating
+/// the address
+pub const FUTEX_PRIVATE: u64 = 128;
+
+/// Identifies a futex
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FutexKey {
+	/// A futex only used inside one process, identified by its virtual address
+	Private(Pid, VirtAddr),
+	/// A futex in memory that might be shared between processes, identified by its physical
+	/// address, which is the same no matter where each process maps it
+	Shared(PhysAddr),
+}
+
+impl FutexKey {
+	/// Get the key of the futex at a virtual address of the running process. Returns [None] if
+	/// the address isn't mapped.
+	pub fn new(addr: VirtAddr, private: bool) -> Option<Self> {
+		let phys = paging::translate_in_current(addr)?;
+		if private {
+			Some(FutexKey::Private(process::running_process(), addr))
+		} else {
+			Some(FutexKey::Shared(phys))
+		}
+	}
+}
+
+lazy_static! {
+	/// Threads blocked on each futex, in the order they started waiting
+	static ref FUTEXES: Mutex<HashMap<FutexKey, WaitQueue>> = Mutex::new(HashMap::new());
+}
+
+/// Add the running thread to the waiters of the futex. The thread must then block with
+/// [process::block_current].
+pub fn wait(key: FutexKey) {
+	FUTEXES.lock().entry(key).or_insert_with(WaitQueue::new).add_current();
+}
+
+/// Wake up to `count` threads blocked on the futex, returns how many were woken
+pub fn wake(key: FutexKey, count: usize) -> usize {
+	let mut futexes = FUTEXES.lock();
+	let mut woken = 0;
+	if let Some(waiters) = futexes.get_mut(&key) {
+		while woken < count && waiters.wake_one(0) {
+			woken += 1;
+		}
+		if waiters.is_empty() {
+			futexes.remove(&key);
+		}
+	}
+	woken
+}
@@ -0,0 +1,8 @@
+/// Fast userspace mutexes, blocking threads on a word of memory
+pub mod futex;
+/// Pipes, buffers connecting a writer to a reader, and the FIFOs that name them
+pub mod pipe;
+/// Named memory regions shared between processes
+pub mod shm;
+/// Local (unix domain) stream sockets, bound to socket files
+pub mod socket;
@@ -0,0 +1,229 @@
+use crate::{
+	process::wait_queue::WaitQueue,
+	util::io::IOError,
+};
+use alloc::{
+	collections::VecDeque,
+	sync::{Arc, Weak},
+};
+use core::{cmp::min, fmt};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// How many bytes a pipe holds before writers block
+const CAPACITY: usize = 4096;
+
+lazy_static! {
+	/// The pipes of the FIFOs that are open, by inode. A pipe lives as long as one of its ends is open.
+	static ref FIFOS: Mutex<HashMap<u32, Weak<Mutex<Pipe>>>> = Mutex::new(HashMap::new());
+}
+
+/// Which end of a pipe a handle is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum End {
+	/// Only reads
+	Read,
+	/// Only writes
+	Write,
+	/// Reads and writes, never waiting for another end to open
+	Both,
+}
+
+impl End {
+	fn reads(self) -> bool {
+		self != End::Write
+	}
+
+	fn writes(self) -> bool {
+		self != End::Read
+	}
+}
+
+/// A buffer that bytes are written into at one end, and read from at the other
+#[derive(Debug)]
+pub struct Pipe {
+	buffer: VecDeque<u8>,
+	readers: usize,
+	writers: usize,
+	/// Threads blocked reading, or with a read end waiting for a writer to open
+	waiting_readers: WaitQueue,
+	/// Threads blocked writing, or with a write end waiting for a reader to open
+	waiting_writers: WaitQueue,
+}
+
+impl Pipe {
+	fn new() -> Self {
+		Self {
+			buffer: VecDeque::new(),
+			readers: 0,
+			writers: 0,
+			waiting_readers: WaitQueue::new(),
+			waiting_writers: WaitQueue::new(),
+		}
+	}
+
+	/// Wake the threads blocked reading from the pipe, they try again
+	fn wake_readers(&mut self) {
+		self.waiting_readers.wake_all(0);
+	}
+
+	/// Wake the threads blocked writing to the pipe, they try again
+	fn wake_writers(&mut self) {
+		self.waiting_writers.wake_all(0);
+	}
+
+	/// The waiters of the side of the pipe that the end is used for
+	fn waiting(&mut self, end: End) -> &mut WaitQueue {
+		if end.reads() {
+			&mut self.waiting_readers
+		} else {
+			&mut self.waiting_writers
+		}
+	}
+}
+
+/// An open end of a pipe. The pipe knows how many ends of each kind are open.
+pub struct PipeEnd {
+	pipe: Arc<Mutex<Pipe>>,
+	end: End,
+}
+
+impl PipeEnd {
+	fn new(pipe: Arc<Mutex<Pipe>>, end: End) -> Self {
+		{
+			// Only the other side waits for this end to open
+			let mut locked = pipe.lock();
+			if end.reads() {
+				locked.readers += 1;
+				locked.wake_writers();
+			}
+			if end.writes() {
+				locked.writers += 1;
+				locked.wake_readers();
+			}
+		}
+		Self { pipe, end }
+	}
+
+	/// Whether the other end of the pipe is open, so using this end won't wait for it
+	pub fn connected(&self) -> bool {
+		let pipe = self.pipe.lock();
+		match self.end {
+			End::Read => pipe.writers > 0,
+			End::Write => pipe.readers > 0,
+			End::Both => true,
+		}
+	}
+
+	/// Add the running thread to the waiters of the pipe, to wait for the other end to open. The
+	/// thread must then block, and check again once it is woken.
+	pub fn wait_for_peer(&self) {
+		self.pipe.lock().waiting(self.end).add_current();
+	}
+
+	/// Read from the pipe into the slice. Returns 0 at the end of the stream, which is once the
+	/// buffer is empty and all the other writers closed, an [End::Both] handle isn't a writer for
+	/// its own reads. If the buffer is empty but there are writers, the running thread is added to
+	/// the waiters of the pipe and [IOError::WouldBlock] is returned, the thread must then block.
+	pub fn read(&self, slice: &mut [u8]) -> Result<usize, IOError> {
+		if !self.end.reads() {
+			return Err(IOError::PermissionDenied);
+		}
+		let mut pipe = self.pipe.lock();
+		if pipe.buffer.is_empty() {
+			let other_writers = if self.end.writes() { pipe.writers - 1 } else { pipe.writers };
+			if other_writers == 0 || slice.is_empty() {
+				return Ok(0);
+			}
+			pipe.waiting_readers.add_current();
+			return Err(IOError::WouldBlock);
+		}
+		let count = min(slice.len(), pipe.buffer.len());
+		for (byte, value) in slice.iter_mut().zip(pipe.buffer.drain(..count)) {
+			*byte = value;
+		}
+		pipe.wake_writers();
+		Ok(count)
+	}
+
+	/// Write from the slice into the pipe, as much as fits. Fails with [IOError::BrokenPipe] if
+	/// there are no readers. If the buffer is full the running thread is added to the waiters of
+	/// the pipe and [IOError::WouldBlock] is returned, the thread must then block.
+	pub fn write(&self, slice: &[u8]) -> Result<usize, IOError> {
+		if !self.end.writes() {
+			return Err(IOError::PermissionDenied);
+		}
+		let mut pipe = self.pipe.lock();
+		if pipe.readers == 0 {
+			return Err(IOError::BrokenPipe);
+		}
+		let space = CAPACITY - pipe.buffer.len();
+		if space == 0 {
+			pipe.waiting_writers.add_current();
+			return Err(IOError::WouldBlock);
+		}
+		let count = min(slice.len(), space);
+		pipe.buffer.extend(slice[..count].iter());
+		pipe.wake_readers();
+		Ok(count)
+	}
+}
+
+impl Drop for PipeEnd {
+	fn drop(&mut self) {
+		// Writers get a broken pipe once the readers close, readers the end of the stream once the
+		// writers close
+		let mut pipe = self.pipe.lock();
+		if self.end.reads() {
+			pipe.readers -= 1;
+			pipe.wake_writers();
+		}
+		if self.end.writes() {
+			pipe.writers -= 1;
+			pipe.wake_readers();
+		}
+	}
+}
+
+impl fmt::Debug for PipeEnd {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("PipeEnd").field("end", &self.end).finish()
+	}
+}
+
+impl fmt::Display for PipeEnd {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let pipe = self.pipe.lock();
+		write!(
+			f,
+			"{:?} end, {} bytes buffered, {} readers, {} writers",
+			self.end,
+			pipe.buffer.len(),
+			pipe.readers,
+			pipe.writers
+		)
+	}
+}
+
+/// Create a pipe that isn't named by a FIFO, returns its read end and its write end
+pub fn anonymous() -> (PipeEnd, PipeEnd) {
+	let pipe = Arc::new(Mutex::new(Pipe::new()));
+	(PipeEnd::new(pipe.clone(), End::Read), PipeEnd::new(pipe, End::Write))
+}
+
+/// Open an end of the pipe of the FIFO with the inode, creating the pipe if the FIFO isn't open
+pub fn open_fifo(inode: u32, end: End) -> PipeEnd {
+	let mut fifos = FIFOS.lock();
+	// Forget the pipes whose ends were all closed
+	fifos.retain(|_, pipe| pipe.strong_count() > 0);
+	let pipe = match fifos.get(&inode).and_then(|pipe| pipe.upgrade()) {
+		Some(pipe) => pipe,
+		None => {
+			let pipe = Arc::new(Mutex::new(Pipe::new()));
+			fifos.insert(inode, Arc::downgrade(&pipe));
+			pipe
+		}
+	};
+	PipeEnd::new(pipe, end)
+}
@@ -0,0 +1,176 @@
+use crate::{
+	mem::{buddy, paging},
+	process::{
+		layout::{SHM_END, SHM_START, USER_END},
+		memory::{Backing, MemoryMap, RegionKind},
+	},
+};
+use alloc::{
+	string::String,
+	sync::{Arc, Weak},
+	vec::Vec,
+};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+	structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTable, PageTableFlags, PhysFrame},
+	VirtAddr,
+};
+
+/// Size of a page (4 KiB)
+const PAGE_SIZE: u64 = 4096;
+/// Biggest shared memory object that can be created
+const MAX_SIZE: u64 = 0x4000000; // 64MiB
+
+lazy_static! {
+	/// Shared memory objects by name. An object is only kept alive by its mappings.
+	static ref OBJECTS: Mutex<HashMap<String, Weak<SharedMemory>>> = Mutex::new(HashMap::new());
+}
+
+/// Named memory that several processes can map. Its frames are freed when the last mapping of
+/// it goes away.
+#[derive(Debug)]
+pub struct SharedMemory {
+	name: String,
+	frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+	/// Allocate zeroed frames for a new object. Returns [None] if there isn't enough memory.
+	fn new(name: &str, pages: usize) -> Option<Self> {
+		let mut allocator = buddy::ALLOCATOR.lock();
+		let mut frames = Vec::with_capacity(pages);
+		for _ in 0..pages {
+			match allocator.allocate_frame() {
+				Some(frame) => {
+					let ptr = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+					unsafe { ptr.write_bytes(0, PAGE_SIZE as usize) };
+					frames.push(frame);
+				}
+				None => {
+					for frame in frames {
+						unsafe { allocator.deallocate_frame(frame) };
+					}
+					return None;
+				}
+			}
+		}
+		Some(Self {
+			name: String::from(name),
+			frames,
+		})
+	}
+
+	/// Name of the object
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Size of the object in bytes
+	pub fn size(&self) -> u64 {
+		self.frames.len() as u64 * PAGE_SIZE
+	}
+}
+
+impl Drop for SharedMemory {
+	fn drop(&mut self) {
+		let mut allocator = buddy::ALLOCATOR.lock();
+		for frame in self.frames.drain(..) {
+			unsafe { allocator.deallocate_frame(frame) };
+		}
+	}
+}
+
+/// Get the shared memory object with the name, creating it with `size` bytes (rounded up to
+/// whole pages) if it doesn't exist. A `size` of 0 only opens an existing object. Fails if the
+/// existing object is smaller than `size`, or there isn't enough memory for a new one.
+pub fn open(name: &str, size: u64) -> Option<Arc<SharedMemory>> {
+	let mut objects = OBJECTS.lock();
+	// Forget the objects that were freed
+	objects.retain(|_, object| object.strong_count() > 0);
+	if let Some(object) = objects.get(name).and_then(|object| object.upgrade()) {
+		return if object.size() >= size { Some(object) } else { None };
+	}
+	if size == 0 || size > MAX_SIZE {
+		return None;
+	}
+	let pages = ((size + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+	let object = Arc::new(SharedMemory::new(name, pages)?);
+	objects.insert(String::from(name), Arc::downgrade(&object));
+	Some(object)
+}
+
+/// A shared memory object mapped in the page table of a process, and recorded as a region of its
+/// memory map. It must be unmapped with [Mapping::unmap], or the page table wiped, before the
+/// mapping is dropped.
+#[derive(Debug)]
+pub struct Mapping {
+	start: Page,
+	object: Arc<SharedMemory>,
+}
+
+impl Mapping {
+	/// Map the object into the page table at `addr`, or at an address picked by the kernel if it
+	/// is [None]. Fails if the address isn't page aligned, or the pages are already used, or are
+	/// in a region of `memory` that isn't mapped yet.
+	pub fn new(
+		object: Arc<SharedMemory>,
+		addr: Option<VirtAddr>,
+		table: &mut PageTable,
+		memory: &mut MemoryMap,
+	) -> Option<Self> {
+		let pages = object.frames.len() as u64;
+		let start = match addr {
+			Some(addr) => {
+				// Stay in the lower half
+				let end = addr.as_u64().checked_add(pages * PAGE_SIZE)?;
+				if end > USER_END || memory.overlaps(addr, pages * PAGE_SIZE) {
+					return None;
+				}
+				Page::from_start_address(addr).ok()?
+			}
+			None => find_free(pages, table, memory)?,
+		};
+		let flags = PageTableFlags::PRESENT
+			| PageTableFlags::WRITABLE
+			| PageTableFlags::USER_ACCESSIBLE
+			| PageTableFlags::NO_EXECUTE;
+		paging::map_frames(start, &object.frames, table, flags).ok()?;
+		memory.add(start.start_address(), pages * PAGE_SIZE, RegionKind::SharedMemory, flags, Backing::Frames);
+		Some(Self { start, object })
+	}
+
+	/// Address the object is mapped at
+	pub fn start(&self) -> VirtAddr {
+		self.start.start_address()
+	}
+
+	/// The mapped object
+	pub fn object(&self) -> &SharedMemory {
+		&self.object
+	}
+
+	/// Remove the mapping from the page table and its region from `memory`
+	pub fn unmap(self, table: &mut PageTable, memory: &mut MemoryMap) {
+		memory.remove(self.start(), RegionKind::SharedMemory);
+		let pages = self.object.frames.len() as u64;
+		paging::unmap_shared(Page::range_inclusive(self.start, self.start + (pages - 1)), table);
+	}
+}
+
+/// Find `pages` free pages between [SHM_START] and [SHM_END], which are neither mapped in
+/// the page table nor in a region of `memory`
+fn find_free(pages: u64, table: &mut PageTable, memory: &MemoryMap) -> Option<Page> {
+	let mut start = Page::containing_address(VirtAddr::new(SHM_START));
+	let end = Page::containing_address(VirtAddr::new(SHM_END));
+	while start + pages <= end {
+		let range = Page::range_inclusive(start, start + (pages - 1));
+		if paging::is_unmapped(range, table) && !memory.overlaps(start.start_address(), pages * PAGE_SIZE) {
+			return Some(start);
+		}
+		// Leave a guard page between mappings
+		start += pages + 1;
+	}
+	None
+}
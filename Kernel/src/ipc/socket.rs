@@ -0,0 +1,198 @@
+use super::pipe::{self, PipeEnd};
+use crate::{
+	fs::ext2::{self, Access, Ext2Err},
+	process::{wait_queue::WaitQueue, Credentials},
+	util::io::IOError,
+};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::fmt;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// The local (unix domain) socket family, the only one there is
+pub const FAMILY_LOCAL: u64 = 1;
+/// Stream sockets, the only kind there is
+pub const KIND_STREAM: u64 = 1;
+
+lazy_static! {
+	/// Listening sockets, by the inode of the socket file they are bound to
+	static ref LISTENERS: Mutex<HashMap<u32, Arc<Mutex<Listener>>>> = Mutex::new(HashMap::new());
+}
+
+/// Error of a socket operation
+#[derive(Debug, Copy, Clone)]
+pub enum SocketErr {
+	/// The socket isn't in the right state for the operation
+	InvalidState,
+	/// Nothing is listening at the path
+	ConnectionRefused,
+	/// No connection is waiting to be accepted yet, the thread must block
+	WouldBlock,
+	/// The socket file couldn't be created or found
+	Fs(Ext2Err),
+}
+
+impl From<Ext2Err> for SocketErr {
+	fn from(e: Ext2Err) -> Self {
+		SocketErr::Fs(e)
+	}
+}
+
+/// A listening socket's queue of connections that weren't accepted yet
+#[derive(Debug)]
+pub struct Listener {
+	pending: VecDeque<Connection>,
+	/// Threads blocked accepting
+	waiting: WaitQueue,
+}
+
+/// One side of a connection, made of two pipes
+#[derive(Debug)]
+pub struct Connection {
+	incoming: PipeEnd,
+	outgoing: PipeEnd,
+}
+
+impl Connection {
+	/// Create both sides of a new connection
+	fn pair() -> (Self, Self) {
+		let (a_read, b_write) = pipe::anonymous();
+		let (b_read, a_write) = pipe::anonymous();
+		(
+			Self {
+				incoming: a_read,
+				outgoing: a_write,
+			},
+			Self {
+				incoming: b_read,
+				outgoing: b_write,
+			},
+		)
+	}
+}
+
+/// A local stream socket
+#[derive(Debug)]
+pub enum Socket {
+	/// Just created
+	Unbound,
+	/// Bound to the socket file with the inode
+	Bound(u32),
+	/// Accepting connections at the socket file with the inode
+	Listening(u32, Arc<Mutex<Listener>>),
+	/// Connected to another socket
+	Connected(Connection),
+}
+
+impl Socket {
+	/// Bind the socket to a path, creating a socket file there. Fails if the path exists.
+	pub fn bind(&mut self, path: &str, credentials: &Credentials) -> Result<(), SocketErr> {
+		if !matches!(self, Socket::Unbound) {
+			return Err(SocketErr::InvalidState);
+		}
+		let inode = ext2::mksocket(path, credentials)?;
+		*self = Socket::Bound(inode);
+		Ok(())
+	}
+
+	/// Start accepting connections
+	pub fn listen(&mut self) -> Result<(), SocketErr> {
+		let inode = match self {
+			Socket::Bound(inode) => *inode,
+			_ => return Err(SocketErr::InvalidState),
+		};
+		let listener = Arc::new(Mutex::new(Listener {
+			pending: VecDeque::new(),
+			waiting: WaitQueue::new(),
+		}));
+		LISTENERS.lock().insert(inode, listener.clone());
+		*self = Socket::Listening(inode, listener);
+		Ok(())
+	}
+
+	/// Take the oldest connection that wasn't accepted yet, as a new connected socket. If there
+	/// is none the running thread is added to the waiters of the socket, and
+	/// [SocketErr::WouldBlock] is returned.
+	pub fn accept(&mut self) -> Result<Socket, SocketErr> {
+		let listener = match self {
+			Socket::Listening(_, listener) => listener,
+			_ => return Err(SocketErr::InvalidState),
+		};
+		let mut listener = listener.lock();
+		match listener.pending.pop_front() {
+			Some(connection) => Ok(Socket::Connected(connection)),
+			None => {
+				listener.waiting.add_current();
+				Err(SocketErr::WouldBlock)
+			}
+		}
+	}
+
+	/// Connect to the socket listening at the path. Connecting doesn't wait for the connection
+	/// to be accepted.
+	pub fn connect(&mut self, path: &str, credentials: &Credentials) -> Result<(), SocketErr> {
+		if !matches!(self, Socket::Unbound | Socket::Bound(_)) {
+			return Err(SocketErr::InvalidState);
+		}
+		let inode = match ext2::inode_type(path, credentials)? {
+			(inode, ext2::Type::UnixSocket) => inode,
+			_ => return Err(SocketErr::ConnectionRefused),
+		};
+		ext2::access(path, credentials, Access::WRITE)?;
+		let listener = LISTENERS
+			.lock()
+			.get(&inode)
+			.cloned()
+			.ok_or(SocketErr::ConnectionRefused)?;
+
+		let (client, server) = Connection::pair();
+		let mut listener = listener.lock();
+		listener.pending.push_back(server);
+		listener.waiting.wake_all(0);
+		*self = Socket::Connected(client);
+		Ok(())
+	}
+
+	/// Read from the connection, see [PipeEnd::read]
+	pub fn read(&self, slice: &mut [u8]) -> Result<usize, IOError> {
+		match self {
+			Socket::Connected(connection) => connection.incoming.read(slice),
+			_ => Err(IOError::Other),
+		}
+	}
+
+	/// Write to the connection, see [PipeEnd::write]
+	pub fn write(&self, slice: &[u8]) -> Result<usize, IOError> {
+		match self {
+			Socket::Connected(connection) => connection.outgoing.write(slice),
+			_ => Err(IOError::Other),
+		}
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		if let Socket::Listening(inode, _) = self {
+			// Connections that weren't accepted are dropped with the listener, so their clients
+			// see the end of the stream
+			LISTENERS.lock().remove(inode);
+		}
+	}
+}
+
+impl fmt::Display for Socket {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Socket::Unbound => write!(f, "Unbound"),
+			Socket::Bound(inode) => write!(f, "Bound to inode {}", inode),
+			Socket::Listening(inode, listener) => write!(
+				f,
+				"Listening at inode {}, {} pending",
+				inode,
+				listener.lock().pending.len()
+			),
+			Socket::Connected(connection) => write!(f, "Connected, incoming {}", connection.incoming),
+		}
+	}
+}
@@ -13,6 +13,7 @@
 #![feature(slice_ptr_get)]
 #![feature(stmt_expr_attributes)]
 #![feature(asm)] // inline asm
+#![feature(global_asm)] // asm outside of functions
 #![feature(slice_ptr_len)]
 #![feature(naked_functions)] // naked functions (no prologue and epilogue)
 #![deny(missing_docs)]
@@ -43,25 +44,44 @@ pub mod util;
 /// Module for dealing with ELF executables
 pub mod process;
 
-use core::panic::PanicInfo;
+/// Communication and synchronization between processes and threads
+pub mod ipc;
+
+use core::{panic::PanicInfo, sync::atomic::Ordering};
 
 const SOUND_ENABLE: bool = false;
 
-/// End the os
-pub fn end() -> ! {
+/// How [end] leaves the os
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+	/// Turn the computer off
+	PowerOff,
+	/// Restart the computer
+	Reboot,
+}
+
+/// End the os, then power off or reboot
+pub fn end(how: Shutdown) -> ! {
 	fs::ext2::cleanup().expect("Failed to cleanup EXT2");
 	serial_println!("Finished cleanup");
 
-	unsafe {
-		process::RUNNING = false;
-	}
+	process::RUNNING.get().store(false, Ordering::Relaxed);
 	x86_64::instructions::interrupts::enable();
 
-	println!("Shutting down...");
+	match how {
+		Shutdown::PowerOff => println!("Shutting down..."),
+		Shutdown::Reboot => println!("Rebooting..."),
+	}
+
+	// The shutdown song waits for timer interrupts, which take the kernel lock
+	cpu::smp::unlock_kernel();
 
 	cpu::pit::play_shutdown_song();
 
-	util::qemu::exit();
+	match how {
+		Shutdown::PowerOff => util::power::power_off(),
+		Shutdown::Reboot => util::power::reboot(),
+	}
 }
 
 /// Panic handler is called automatically when a panic occurs, and prints the information to serial
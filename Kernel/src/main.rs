@@ -6,7 +6,8 @@
 extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use kernel::{
-	cpu::{gdt, interrupts, pit, syscalls},
+	cpu::{gdt, interrupts, lapic, pit, smp, syscalls},
+	drivers::acpi,
 	fs::ext2,
 	io::{buffer, keyboard},
 	mem::{buddy, heap, paging},
@@ -18,13 +19,17 @@ entry_point!(kernel_main);
 /// Entry point for the kernel. Returns [!] because it is never supposed to exit.
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 	if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
+		smp::setup();
 		gdt::setup();
 		paging::setup();
 		buddy::setup(&boot_info.memory_regions);
 		heap::setup(buffer::calc_real_length(framebuffer));
+		acpi::setup(boot_info.rsdp_addr.into_option());
 		interrupts::setup();
+		lapic::setup();
 		syscalls::setup();
 		pit::setup_time();
+		smp::start_application_processors();
 		keyboard::setup();
 		buffer::setup(framebuffer);
 
@@ -34,7 +39,22 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
 		for i in 0..buffer::TERM_COUNT {
 			let s = alloc::format!("{}", i);
-			process::add_process("/bin/shell", &[&s], Some(i)).expect("Failed to add process");
+			// The shell of the first terminal runs as root, for administering the system
+			let credentials = if i == 0 {
+				process::ROOT_CREDENTIALS
+			} else {
+				process::USER_CREDENTIALS
+			};
+			process::add_process(
+				"/bin/shell",
+				&[&s],
+				Some(i),
+				credentials,
+				process::scheduler::DEFAULT_NICE,
+				process::Limits::default(),
+				process::Personality::default(),
+			)
+				.expect("Failed to add process");
 		}
 
 		process::start();
@@ -1,16 +1,19 @@
 use super::buddy;
-use crate::serial_println;
-use alloc::boxed::Box;
+use crate::{cpu::smp, serial_println};
+use alloc::{boxed::Box, vec::Vec};
 use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
 use lazy_static::lazy_static;
 use x86_64::{
 	addr::{PhysAddr, VirtAddr},
-	registers::control::{Cr3, Cr3Flags},
+	registers::{
+		control::{Cr3, Cr3Flags},
+		model_specific::{Efer, EferFlags},
+	},
 	structures::paging::{
-		mapper::{Mapper, OffsetPageTable, Translate},
+		mapper::{Mapper, OffsetPageTable, Translate, TranslateResult},
 		page::PageRangeInclusive,
 		page_table::{PageTableEntry, PageTableFlags},
-		FrameAllocator, FrameDeallocator, PageTable, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+		FrameAllocator, FrameDeallocator, Page, PageTable, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
 	},
 };
 
@@ -48,6 +51,154 @@ pub fn map(range: PageRangeInclusive, table: &mut PageTable, flags: PageTableFla
 	}
 }
 
+/// Flag on pages mapped to frames that the page table doesn't own, like shared memory. Their
+/// frames aren't freed when the page table is wiped.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Map the pages starting from `start` to the given frames, on the given page table. The frames
+/// are owned by the caller, so the pages are marked [SHARED]. If a page is already mapped nothing
+/// is mapped and an error is returned.
+pub fn map_frames(start: Page, frames: &[PhysFrame], table: &mut PageTable, flags: PageTableFlags) -> Result<(), ()> {
+	if frames.is_empty() {
+		return Ok(());
+	}
+	let range = Page::range_inclusive(start, start + (frames.len() as u64 - 1));
+	if !is_unmapped(range, table) {
+		return Err(());
+	}
+	let mut offset_table: OffsetPageTable;
+	unsafe {
+		offset_table = get_offset_page_table(table);
+	}
+	let mut frame_allocator = buddy::ALLOCATOR.lock();
+	for (page, frame) in range.zip(frames.iter()) {
+		unsafe {
+			let result = offset_table.map_to(page, *frame, flags | SHARED, &mut *frame_allocator);
+			match result {
+				Ok(flush) => flush.flush(),
+				Err(e) => serial_println!("Failed to map! {:?}", e),
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Unmap the given range of [SHARED] pages from the given page table, without freeing their frames.
+/// Pages that aren't mapped, or aren't shared, are left alone.
+pub fn unmap_shared(range: PageRangeInclusive, table: &mut PageTable) {
+	let mut offset_table: OffsetPageTable;
+	unsafe {
+		offset_table = get_offset_page_table(table);
+	}
+	for page in range {
+		let shared = matches!(
+			offset_table.translate(page.start_address()),
+			TranslateResult::Mapped { flags, .. } if flags.contains(SHARED)
+		);
+		if shared {
+			if let Ok((_, flush)) = offset_table.unmap(page) {
+				flush.flush();
+			}
+		}
+	}
+	smp::shoot_down(frame_of(table).start_address());
+}
+
+/// Unmap the given range of pages from the given page table, freeing their frames unless they are
+/// [SHARED]. Pages that aren't mapped are left alone.
+pub fn unmap(range: PageRangeInclusive, table: &mut PageTable) {
+	let mut offset_table: OffsetPageTable;
+	unsafe {
+		offset_table = get_offset_page_table(table);
+	}
+	for page in range {
+		let shared = match offset_table.translate(page.start_address()) {
+			TranslateResult::Mapped { flags, .. } => flags.contains(SHARED),
+			_ => continue,
+		};
+		if let Ok((frame, flush)) = offset_table.unmap(page) {
+			flush.flush();
+			if !shared {
+				unsafe {
+					buddy::ALLOCATOR.lock().deallocate_frame(frame);
+				}
+			}
+		}
+	}
+	smp::shoot_down(frame_of(table).start_address());
+}
+
+/// Change the flags of the pages in the range that are mapped in the given page table, keeping
+/// their [SHARED] flag. Pages that aren't mapped are left alone.
+pub fn set_flags(range: PageRangeInclusive, table: &mut PageTable, flags: PageTableFlags) {
+	let mut offset_table: OffsetPageTable;
+	unsafe {
+		offset_table = get_offset_page_table(table);
+	}
+	for page in range {
+		let shared = match offset_table.translate(page.start_address()) {
+			TranslateResult::Mapped { flags, .. } => flags & SHARED,
+			_ => continue,
+		};
+		if let Ok(flush) = unsafe { offset_table.update_flags(page, flags | shared) } {
+			flush.flush();
+		}
+	}
+	smp::shoot_down(frame_of(table).start_address());
+}
+
+/// Check that none of the pages in the range are mapped in the given page table
+pub fn is_unmapped(mut range: PageRangeInclusive, table: &mut PageTable) -> bool {
+	let offset_table = unsafe { get_offset_page_table(table) };
+	range.all(|page| offset_table.translate_addr(page.start_address()).is_none())
+}
+
+/// A run of consecutive mapped pages with the same flags
+#[derive(Debug, Copy, Clone)]
+pub struct MappedRegion {
+	/// Address of the first page
+	pub start: VirtAddr,
+	/// Number of pages
+	pub pages: u64,
+	/// Flags of the pages, without the accessed and dirty bits
+	pub flags: PageTableFlags,
+}
+
+/// Get the userspace (lower half) regions mapped in the given page table, sorted by address
+pub fn user_regions(table: &PageTable) -> Vec<MappedRegion> {
+	let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+	let mut regions: Vec<MappedRegion> = Vec::new();
+	for (l4, l4_entry) in table.iter().enumerate().take(256) {
+		let l3_table = match get_sub_table(l4_entry) {
+			Ok(table) => table,
+			Err(_) => continue,
+		};
+		for (l3, l3_entry) in l3_table.iter().enumerate() {
+			let l2_table = match get_sub_table(l3_entry) {
+				Ok(table) => table,
+				Err(_) => continue,
+			};
+			for (l2, l2_entry) in l2_table.iter().enumerate() {
+				let l1_table = match get_sub_table(l2_entry) {
+					Ok(table) => table,
+					Err(_) => continue,
+				};
+				for (l1, entry) in l1_table.iter().enumerate().filter(|(_, entry)| !entry.is_unused()) {
+					let start = VirtAddr::new(((l4 << 39) | (l3 << 30) | (l2 << 21) | (l1 << 12)) as u64);
+					let flags = entry.flags() - ignored;
+					match regions.last_mut() {
+						Some(region) if region.flags == flags && region.start + region.pages * 4096 == start => {
+							region.pages += 1;
+						}
+						_ => regions.push(MappedRegion { start, pages: 1, flags }),
+					}
+				}
+			}
+		}
+	}
+	regions
+}
+
 /// Set the current page table.
 /// # Safety
 /// This is extremeley unsafe, for many reasons.
@@ -55,22 +206,20 @@ pub fn map(range: PageRangeInclusive, table: &mut PageTable, flags: PageTableFla
 ///  - The page table must live for as long as it is used
 pub unsafe fn set_page_table(page_table: &PageTable) {
 	let flags = Cr3Flags::empty();
+	let frame = frame_of(page_table);
+	x86_64::registers::control::Cr3::write(frame, flags);
+	// NOTE chaing cr3 flushes the tlb, no need to flush it manually
+	smp::set_loaded_table(frame.start_address());
+}
 
-	let virt_addr = VirtAddr::from_ptr(page_table);
-	// let page = Page::containing_address(addr);
-
-	let kernel_table = get_offset_page_table(get_kernel_page_table());
-
-	// let frame = kernel_table
-	// .translate_page(page)
-	// .expect("Page table not mapped in kernel page table");
+/// The frame a page table is in. It may be in the heap or in the physical memory mapping, both
+/// are mapped in the kernel page table.
+fn frame_of(page_table: &PageTable) -> PhysFrame {
+	let kernel_table = unsafe { get_offset_page_table(get_kernel_page_table()) };
 	let phys_addr = kernel_table
-		.translate_addr(virt_addr)
+		.translate_addr(VirtAddr::from_ptr(page_table))
 		.expect("Page table not mapped in kernel page table");
-	let frame = PhysFrame::containing_address(phys_addr);
-
-	x86_64::registers::control::Cr3::write(frame, flags);
-	// NOTE chaing cr3 flushes the tlb, no need to flush it manually
+	PhysFrame::containing_address(phys_addr)
 }
 
 /// Set the current page table to the kernel
@@ -82,14 +231,34 @@ pub unsafe fn set_page_table(page_table: &PageTable) {
 pub unsafe fn set_page_table_to_kernel() {
 	// set_page_table(get_kernel_page_table()); // doesn't work because the kernel referance I use is mapped in the full mapping, not individually
 	Cr3::write(KERNEL_CR3.0, KERNEL_CR3.1);
+	smp::set_loaded_table(KERNEL_CR3.0.start_address());
 }
 
-/// Set up paging. Clean up the page table created by the bootloader.
+/// Identity map a frame in the kernel page table, for code that runs while paging is enabled, like
+/// application processors starting. Undone by [unmap_identity].
+pub fn map_identity(frame: PhysFrame) {
+	let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	map_frames(page, &[frame], get_kernel_page_table(), flags).expect("Identity mapped page already used");
+}
+
+/// Remove the identity mappings from the kernel page table, freeing the page tables they needed.
+/// User page tables copy the kernel page table, so none may exist yet.
+pub fn unmap_identity() {
+	unsafe {
+		wipe_lower_half(get_kernel_page_table());
+		set_page_table_to_kernel();
+	}
+}
+
+/// Set up paging. Clean up the page table created by the bootloader, and allow pages to be
+/// mapped [PageTableFlags::NO_EXECUTE].
 pub fn setup() {
 	let table = get_current_page_table();
 
 	unsafe {
 		wipe_lower_half(table);
+		Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
 	}
 }
 
@@ -152,7 +321,7 @@ unsafe fn wipe_recursive(entry: &mut PageTableEntry, depth: usize) {
 				unreachable!("Tried to wipe entry that is unused");
 			}
 		}
-	} else {
+	} else if !entry.flags().contains(SHARED) {
 		buddy::ALLOCATOR
 			.lock()
 			.deallocate_frame(PhysFrame::<Size4KiB>::from_start_address(entry.addr()).unwrap());
@@ -160,6 +329,13 @@ unsafe fn wipe_recursive(entry: &mut PageTableEntry, depth: usize) {
 	entry.set_unused();
 }
 
+/// Translate a virtual address to the physical address it is mapped to in the current page table.
+/// Returns [None] if it isn't mapped.
+pub fn translate_in_current(virt: VirtAddr) -> Option<PhysAddr> {
+	let offset_table = unsafe { get_offset_page_table(get_current_page_table()) };
+	offset_table.translate_addr(virt)
+}
+
 /// Translate physical address to virtual address by adding constant [PHYSICAL_MAPPING_OFFSET].
 pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
 	VirtAddr::new(phys.as_u64() + PHYSICAL_MAPPING_OFFSET)
@@ -0,0 +1,254 @@
+use super::{running_process, running_thread, MAP, PCB, ROOT_CREDENTIALS, THREADS};
+use crate::{
+	cpu::syscalls::OpenFlags,
+	fs::ext2::{self, Ext2Err, File},
+	mem::paging::{self, MappedRegion},
+	util::io::Write,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::time::Duration;
+use x86_64::structures::{
+	idt::InterruptStackFrame,
+	paging::{PageTableFlags, Translate},
+};
+
+/// Directory core dumps are written to
+const CRASH_DIR: &str = "/crash";
+
+const PAGE_SIZE: u64 = 4096;
+
+// ELF constants
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+
+/// Size of `struct elf_prstatus` on x86_64
+const PRSTATUS_SIZE: usize = 336;
+/// Offset of the registers (`struct user_regs_struct`) in `struct elf_prstatus`
+const PRSTATUS_REGS: usize = 112;
+/// Size of `struct elf_prpsinfo` on x86_64
+const PRPSINFO_SIZE: usize = 136;
+
+/// Indices of registers in `struct user_regs_struct`
+const REG_RIP: usize = 16;
+const REG_CS: usize = 17;
+const REG_EFLAGS: usize = 18;
+const REG_RSP: usize = 19;
+const REG_SS: usize = 20;
+const REG_FS_BASE: usize = 21;
+
+/// Write a core dump of the running process, which crashed with the signal at the given stack
+/// frame, to [CRASH_DIR]. Only the registers in the stack frame are known, the rest are written
+/// as 0. Returns the path of the core file, or [None] if core dumps are disabled for the process.
+pub fn dump_running(signal: u32, stack_frame: &InterruptStackFrame) -> Result<Option<String>, Ext2Err> {
+	let mut map = MAP.lock();
+	match map.get_mut(&running_process()) {
+		Some(pcb) => write_core(pcb, signal, stack_frame),
+		None => Ok(None),
+	}
+}
+
+fn write_core(pcb: &mut PCB, signal: u32, stack_frame: &InterruptStackFrame) -> Result<Option<String>, Ext2Err> {
+	let limit = pcb.limits.core_size;
+	if limit == 0 {
+		return Ok(None);
+	}
+	let fs_base = THREADS
+		.lock()
+		.get(&running_thread())
+		.map(|thread| thread.fs_base.as_u64())
+		.unwrap_or(0);
+
+	let regions = paging::user_regions(&pcb.page_table.0);
+	let notes = notes(pcb, signal, stack_frame, fs_base);
+
+	let program_headers = 1 + regions.len();
+	let notes_offset = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * program_headers;
+	let data_offset = align_up((notes_offset + notes.len()) as u64, PAGE_SIZE);
+	if data_offset > limit {
+		return Ok(None);
+	}
+
+	// Cut off the segments that don't fit under the limit, keeping their headers
+	let mut budget = limit - data_offset;
+	let mut file_sizes = Vec::with_capacity(regions.len());
+	for region in regions.iter() {
+		let size = (region.pages * PAGE_SIZE).min(budget / PAGE_SIZE * PAGE_SIZE);
+		budget -= size;
+		file_sizes.push(size);
+	}
+
+	let mut header = Vec::with_capacity(data_offset as usize);
+	elf_header(&mut header, program_headers as u16);
+	program_header(&mut header, PT_NOTE, 0, notes_offset as u64, 0, notes.len() as u64, 0, 4);
+	let mut offset = data_offset;
+	for (region, file_size) in regions.iter().zip(file_sizes.iter()) {
+		let size = region.pages * PAGE_SIZE;
+		let flags = segment_flags(region);
+		program_header(&mut header, PT_LOAD, flags, offset, region.start.as_u64(), *file_size, size, PAGE_SIZE);
+		offset += file_size;
+	}
+	header.extend_from_slice(&notes);
+	header.resize(data_offset as usize, 0);
+
+	let _ = ext2::mkdir(CRASH_DIR, &ROOT_CREDENTIALS);
+	let name = pcb.command.rsplit('/').next().unwrap_or("core");
+	let path = format!("{}/{}.{}.core", CRASH_DIR, name, running_process());
+	let mut file = File::from_path(
+		&path,
+		OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+		&ROOT_CREDENTIALS,
+	)?;
+	file.write_exact(&header)?;
+
+	let table = unsafe { paging::get_offset_page_table(&mut pcb.page_table.0) };
+	let zeroes = [0u8; PAGE_SIZE as usize];
+	for (region, file_size) in regions.iter().zip(file_sizes.iter()) {
+		for page in 0..file_size / PAGE_SIZE {
+			let addr = region.start + page * PAGE_SIZE;
+			let data = match table.translate_addr(addr) {
+				Some(phys) => unsafe {
+					core::slice::from_raw_parts(paging::phys_to_virt(phys).as_ptr::<u8>(), PAGE_SIZE as usize)
+				},
+				None => &zeroes,
+			};
+			file.write_exact(data)?;
+		}
+	}
+	drop(file);
+	ext2::chown(&path, pcb.credentials.uid, pcb.credentials.gid, &ROOT_CREDENTIALS)?;
+	Ok(Some(path))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+	(value + align - 1) / align * align
+}
+
+fn segment_flags(region: &MappedRegion) -> u32 {
+	let mut flags = PF_R;
+	if region.flags.contains(PageTableFlags::WRITABLE) {
+		flags |= PF_W;
+	}
+	if !region.flags.contains(PageTableFlags::NO_EXECUTE) {
+		flags |= PF_X;
+	}
+	flags
+}
+
+fn elf_header(buffer: &mut Vec<u8>, program_headers: u16) {
+	// Identification: magic, 64 bit, little endian, version 1, System V ABI
+	buffer.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+	buffer.extend_from_slice(&[0; 8]);
+	buffer.extend_from_slice(&ET_CORE.to_le_bytes());
+	buffer.extend_from_slice(&EM_X86_64.to_le_bytes());
+	buffer.extend_from_slice(&1u32.to_le_bytes()); // version
+	buffer.extend_from_slice(&0u64.to_le_bytes()); // entry
+	buffer.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // program headers offset
+	buffer.extend_from_slice(&0u64.to_le_bytes()); // section headers offset
+	buffer.extend_from_slice(&0u32.to_le_bytes()); // flags
+	buffer.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+	buffer.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+	buffer.extend_from_slice(&program_headers.to_le_bytes());
+	buffer.extend_from_slice(&0u16.to_le_bytes()); // section header size
+	buffer.extend_from_slice(&0u16.to_le_bytes()); // section headers
+	buffer.extend_from_slice(&0u16.to_le_bytes()); // section name table index
+}
+
+#[allow(clippy::too_many_arguments)]
+fn program_header(
+	buffer: &mut Vec<u8>,
+	segment_type: u32,
+	flags: u32,
+	offset: u64,
+	addr: u64,
+	file_size: u64,
+	memory_size: u64,
+	align: u64,
+) {
+	buffer.extend_from_slice(&segment_type.to_le_bytes());
+	buffer.extend_from_slice(&flags.to_le_bytes());
+	buffer.extend_from_slice(&offset.to_le_bytes());
+	buffer.extend_from_slice(&addr.to_le_bytes()); // virtual address
+	buffer.extend_from_slice(&0u64.to_le_bytes()); // physical address
+	buffer.extend_from_slice(&file_size.to_le_bytes());
+	buffer.extend_from_slice(&memory_size.to_le_bytes());
+	buffer.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Build the notes segment, with the status (`NT_PRSTATUS`) and info (`NT_PRPSINFO`) of the
+/// process, laid out like Linux does so gdb can read them
+fn notes(pcb: &PCB, signal: u32, stack_frame: &InterruptStackFrame, fs_base: u64) -> Vec<u8> {
+	let pid = running_process() as u32;
+	let tid = running_thread() as u32;
+
+	let mut status = vec![0u8; PRSTATUS_SIZE];
+	put(&mut status, 0, &signal.to_le_bytes()); // si_signo
+	put(&mut status, 12, &(signal as u16).to_le_bytes()); // pr_cursig
+	put(&mut status, 32, &tid.to_le_bytes()); // pr_pid
+	put(&mut status, 40, &pid.to_le_bytes()); // pr_pgrp
+	put(&mut status, 44, &pid.to_le_bytes()); // pr_sid
+	put_time(&mut status, 48, pcb.usage.user_time); // pr_utime
+	put_time(&mut status, 64, pcb.usage.kernel_time); // pr_stime
+	let registers = [
+		(REG_RIP, stack_frame.instruction_pointer.as_u64()),
+		(REG_CS, stack_frame.code_segment),
+		(REG_EFLAGS, stack_frame.cpu_flags),
+		(REG_RSP, stack_frame.stack_pointer.as_u64()),
+		(REG_SS, stack_frame.stack_segment),
+		(REG_FS_BASE, fs_base),
+	];
+	for (index, value) in registers {
+		put(&mut status, PRSTATUS_REGS + index * 8, &value.to_le_bytes());
+	}
+
+	let mut info = vec![0u8; PRPSINFO_SIZE];
+	info[1] = b'R'; // pr_sname
+	info[3] = pcb.nice as u8; // pr_nice
+	put(&mut info, 16, &(pcb.credentials.uid as u32).to_le_bytes()); // pr_uid
+	put(&mut info, 20, &(pcb.credentials.gid as u32).to_le_bytes()); // pr_gid
+	put(&mut info, 24, &pid.to_le_bytes()); // pr_pid
+	put(&mut info, 32, &pid.to_le_bytes()); // pr_pgrp
+	put(&mut info, 36, &pid.to_le_bytes()); // pr_sid
+	let name = pcb.command.rsplit('/').next().unwrap_or("").as_bytes();
+	put(&mut info, 40, &name[..name.len().min(15)]); // pr_fname
+	let mut args = pcb.command.clone();
+	for arg in pcb.args.iter() {
+		args.push(' ');
+		args.push_str(arg);
+	}
+	let args = args.as_bytes();
+	put(&mut info, 56, &args[..args.len().min(79)]); // pr_psargs
+
+	let mut notes = Vec::new();
+	note(&mut notes, NT_PRSTATUS, &status);
+	note(&mut notes, NT_PRPSINFO, &info);
+	notes
+}
+
+fn put(buffer: &mut [u8], offset: usize, bytes: &[u8]) {
+	buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Write a `struct timeval`
+fn put_time(buffer: &mut [u8], offset: usize, time: Duration) {
+	put(buffer, offset, &time.as_secs().to_le_bytes());
+	put(buffer, offset + 8, &(time.subsec_micros() as u64).to_le_bytes());
+}
+
+fn note(buffer: &mut Vec<u8>, note_type: u32, desc: &[u8]) {
+	const NAME: &[u8] = b"CORE\0\0\0\0"; // padded to 4 bytes
+	buffer.extend_from_slice(&5u32.to_le_bytes());
+	buffer.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+	buffer.extend_from_slice(&note_type.to_le_bytes());
+	buffer.extend_from_slice(NAME);
+	buffer.extend_from_slice(desc);
+	buffer.resize((buffer.len() + 3) & !3, 0);
+}
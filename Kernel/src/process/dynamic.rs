@@ -0,0 +1,395 @@
+use super::{
+	elf::{align_up, map_object, ElfErr},
+	layout::{LIBRARY_BASE, LIBRARY_RANDOM_PAGES},
+	memory::MemoryMap,
+	Abi, Credentials,
+};
+use crate::{
+	fs::ext2::{self, Access},
+	serial_println,
+	util::random::random_below,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::{convert::TryInto, str::from_utf8};
+use x86_64::VirtAddr;
+
+/// Directory the shared objects executables need are looked up in
+const LIBRARY_DIR: &str = "/lib";
+
+const PAGE_SIZE: u64 = 4096;
+
+// Dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_STRSZ: u64 = 10;
+const DT_REL: u64 = 17;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+
+// Relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_COPY: u32 = 5;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+// Symbols
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+const DYNAMIC_ENTRY_SIZE: u64 = 16;
+const RELA_SIZE: u64 = 24;
+const SYMBOL_SIZE: u64 = 24;
+
+/// An executable or shared object mapped into a process
+#[derive(Debug)]
+pub struct Object {
+	/// Path of the file it was loaded from
+	pub path: String,
+	/// Difference between the addresses the object is mapped at and the ones it was linked at
+	pub base: u64,
+	/// Address and size of the dynamic section, if the object has one
+	pub dynamic: Option<(VirtAddr, u64)>,
+	/// Entrypoint
+	pub entry: VirtAddr,
+	/// Address of the program headers, if they are in a loaded segment
+	pub program_headers: Option<VirtAddr>,
+	/// Number of program headers
+	pub program_header_count: usize,
+	/// End of the last loaded segment
+	pub end: VirtAddr,
+	/// The syscalls the object makes, which only matters for executables
+	pub abi: Abi,
+	/// Size of the file it was loaded from, no table it points to is bigger
+	pub file_size: u64,
+}
+
+/// What the dynamic section of an object points to
+#[derive(Debug, Default)]
+struct Dynamic {
+	/// Names of the shared objects the object needs
+	needed: Vec<String>,
+	/// The string table
+	strings: Vec<u8>,
+	/// The symbol table
+	symbols: Option<u64>,
+	/// The hash table of the symbols, without which the object's symbols can't be looked up
+	hash: Option<u64>,
+	/// Address, size and entry size of the relocation tables
+	relocations: Vec<(u64, u64, u64)>,
+}
+
+impl Dynamic {
+	/// Get the string at `offset` in the string table
+	fn string(&self, offset: u32) -> Result<&[u8], ElfErr> {
+		let rest = self.strings.get(offset as usize..).ok_or(ElfErr::BadDynamic)?;
+		let len = rest.iter().position(|&byte| byte == 0).ok_or(ElfErr::BadDynamic)?;
+		Ok(&rest[..len])
+	}
+}
+
+/// An entry of a symbol table
+#[derive(Debug, Copy, Clone)]
+struct Symbol {
+	name: u32,
+	binding: u8,
+	section: u16,
+	value: u64,
+	size: u64,
+}
+
+/// Load the shared objects the executable needs, and the ones they need, from [LIBRARY_DIR], then
+/// apply the relocations of all of them. The executable must be the only object, the shared
+/// objects are added after it in the order symbols are looked up in. The memory map's page table
+/// must be loaded.
+pub fn link(
+	objects: &mut Vec<Object>,
+	memory: &mut MemoryMap,
+	randomize: bool,
+	credentials: &Credentials,
+) -> Result<(), ElfErr> {
+	let mut next_base = LIBRARY_BASE;
+	if randomize {
+		next_base += random_below(LIBRARY_RANDOM_PAGES) * PAGE_SIZE;
+	}
+	let mut dynamics = Vec::new();
+	let mut index = 0;
+	while index < objects.len() {
+		let dynamic = read_dynamic(memory, &objects[index])?;
+		for name in dynamic.needed.iter() {
+			let path = format!("{}/{}", LIBRARY_DIR, name);
+			if objects.iter().any(|object| object.path == path) {
+				continue;
+			}
+			if let Err(e) = ext2::access(&path, credentials, Access::READ) {
+				serial_println!("Can't load {} needed by {}: {:?}", path, objects[index].path, e);
+				return Err(e.into());
+			}
+			// A page is left unmapped between shared objects
+			let object = map_object(&path, memory, true, |size| {
+				let base = next_base;
+				next_base += align_up(size) + PAGE_SIZE;
+				base
+			})?;
+			objects.push(object);
+		}
+		dynamics.push(dynamic);
+		index += 1;
+	}
+	// Shared objects are relocated before the objects that need them, so the data copied out of
+	// them is relocated already
+	let (objects, memory): (&[Object], &MemoryMap) = (objects, memory);
+	for (object, dynamic) in objects.iter().zip(dynamics.iter()).rev() {
+		for &(addr, size, entry_size) in dynamic.relocations.iter() {
+			let table = read_table(memory, object, object.base.wrapping_add(addr), size)?;
+			for entry in table.chunks_exact(entry_size as usize) {
+				relocate(objects, &dynamics, memory, object, dynamic, entry)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Read the dynamic section of the object, and the names of the objects it needs
+fn read_dynamic(memory: &MemoryMap, object: &Object) -> Result<Dynamic, ElfErr> {
+	let mut dynamic = Dynamic::default();
+	let (addr, size) = match object.dynamic {
+		Some(dynamic) => dynamic,
+		None => return Ok(dynamic),
+	};
+	let section = read_table(memory, object, addr.as_u64(), size)?;
+	let mut needed = Vec::new();
+	let (mut strings, mut strings_size) = (None, 0);
+	let (mut rela, mut rela_size, mut rela_entry) = (None, 0, RELA_SIZE);
+	let (mut plt, mut plt_size) = (None, 0);
+	for entry in section.chunks_exact(DYNAMIC_ENTRY_SIZE as usize) {
+		let tag = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+		let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+		match tag {
+			DT_NULL => break,
+			DT_NEEDED => needed.push(value),
+			DT_HASH => dynamic.hash = Some(value),
+			DT_STRTAB => strings = Some(value),
+			DT_STRSZ => strings_size = value,
+			DT_SYMTAB => dynamic.symbols = Some(value),
+			DT_RELA => rela = Some(value),
+			DT_RELASZ => rela_size = value,
+			DT_RELAENT => rela_entry = value,
+			DT_JMPREL => plt = Some(value),
+			DT_PLTRELSZ => plt_size = value,
+			// x86_64 only uses relocations with addends
+			DT_REL => return Err(ElfErr::BadDynamic),
+			DT_PLTREL if value != DT_RELA => return Err(ElfErr::BadDynamic),
+			_ => {}
+		}
+	}
+	if rela_entry < RELA_SIZE {
+		return Err(ElfErr::BadDynamic);
+	}
+	if let Some(rela) = rela {
+		dynamic.relocations.push((rela, rela_size, rela_entry));
+	}
+	if let Some(plt) = plt {
+		dynamic.relocations.push((plt, plt_size, RELA_SIZE));
+	}
+	if let Some(strings) = strings {
+		dynamic.strings = read_table(memory, object, object.base.wrapping_add(strings), strings_size)?;
+	}
+	for offset in needed {
+		let name = from_utf8(dynamic.string(offset as u32)?)
+			.map(String::from)
+			.map_err(|_| ElfErr::BadDynamic)?;
+		dynamic.needed.push(name);
+	}
+	Ok(dynamic)
+}
+
+/// Apply a relocation of the object
+fn relocate(
+	objects: &[Object],
+	dynamics: &[Dynamic],
+	memory: &MemoryMap,
+	object: &Object,
+	dynamic: &Dynamic,
+	entry: &[u8],
+) -> Result<(), ElfErr> {
+	let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+	let info = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+	let addend = i64::from_le_bytes(entry[16..24].try_into().unwrap());
+	let target = object.base.wrapping_add(offset);
+	let kind = info as u32;
+	let index = info >> 32;
+	let value = match kind {
+		R_X86_64_NONE => return Ok(()),
+		R_X86_64_RELATIVE => object.base.wrapping_add(addend as u64),
+		R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT | R_X86_64_COPY => {
+			let (value, size) = resolve(objects, dynamics, memory, object, dynamic, index, kind == R_X86_64_COPY)?;
+			match kind {
+				R_X86_64_64 => value.wrapping_add(addend as u64),
+				R_X86_64_COPY => {
+					// The data is copied out of the file of another object
+					if objects.iter().all(|object| size > object.file_size) {
+						return Err(ElfErr::BadDynamic);
+					}
+					let data = read(memory, value, size)?;
+					return write(memory, target, &data);
+				}
+				_ => value,
+			}
+		}
+		other => {
+			serial_println!("Unsupported relocation type {} in {}", other, object.path);
+			return Err(ElfErr::Relocation(other));
+		}
+	};
+	write(memory, target, &value.to_le_bytes())
+}
+
+/// Find the address and size of the symbol at `index` in the object's symbol table. Symbols
+/// that aren't local are looked up in the executable and then the shared objects in load order,
+/// skipping the object itself for copy relocations.
+fn resolve(
+	objects: &[Object],
+	dynamics: &[Dynamic],
+	memory: &MemoryMap,
+	object: &Object,
+	dynamic: &Dynamic,
+	index: u64,
+	copy: bool,
+) -> Result<(u64, u64), ElfErr> {
+	if index == 0 {
+		return Ok((0, 0));
+	}
+	let symbols = dynamic.symbols.ok_or(ElfErr::BadDynamic)?;
+	let symbol = read_symbol(memory, object.base.wrapping_add(symbols), index)?;
+	if symbol.binding == STB_LOCAL {
+		return Ok((address(object, &symbol), symbol.size));
+	}
+	let name = dynamic.string(symbol.name)?;
+	for (other, other_dynamic) in objects.iter().zip(dynamics.iter()) {
+		if copy && core::ptr::eq(other, object) {
+			continue;
+		}
+		if let Some(found) = lookup(memory, other, other_dynamic, name)? {
+			return Ok((address(other, &found), found.size));
+		}
+	}
+	if symbol.binding == STB_WEAK {
+		return Ok((0, 0));
+	}
+	serial_println!(
+		"Undefined symbol {} in {}",
+		from_utf8(name).unwrap_or("(invalid)"),
+		object.path
+	);
+	Err(ElfErr::UndefinedSymbol)
+}
+
+/// Look up a symbol the object defines, by name, in its hash table
+fn lookup(memory: &MemoryMap, object: &Object, dynamic: &Dynamic, name: &[u8]) -> Result<Option<Symbol>, ElfErr> {
+	let (hash, symbols) = match (dynamic.hash, dynamic.symbols) {
+		(Some(hash), Some(symbols)) => (object.base.wrapping_add(hash), object.base.wrapping_add(symbols)),
+		_ => return Ok(None),
+	};
+	let buckets = read_u32(memory, hash)? as u64;
+	let chains = read_u32(memory, hash.wrapping_add(4))? as u64;
+	if buckets == 0 {
+		return Ok(None);
+	}
+	let mut index = read_u32(memory, hash.wrapping_add(8 + 4 * (elf_hash(name) as u64 % buckets)))? as u64;
+	// Every symbol is on the chain at most once, a longer chain is a loop
+	for _ in 0..chains {
+		if index == 0 {
+			break;
+		}
+		if index >= chains {
+			return Err(ElfErr::BadDynamic);
+		}
+		let symbol = read_symbol(memory, symbols, index)?;
+		let defined = symbol.section != SHN_UNDEF && matches!(symbol.binding, STB_GLOBAL | STB_WEAK);
+		if defined && dynamic.string(symbol.name)? == name {
+			return Ok(Some(symbol));
+		}
+		index = read_u32(memory, hash.wrapping_add(8 + 4 * (buckets + index)))? as u64;
+	}
+	Ok(None)
+}
+
+/// The address a symbol of the object is at
+fn address(object: &Object, symbol: &Symbol) -> u64 {
+	if symbol.section == SHN_ABS {
+		symbol.value
+	} else {
+		object.base.wrapping_add(symbol.value)
+	}
+}
+
+/// The hash function of the `DT_HASH` table
+fn elf_hash(name: &[u8]) -> u32 {
+	let mut hash: u32 = 0;
+	for &byte in name {
+		hash = (hash << 4).wrapping_add(byte as u32);
+		let high = hash & 0xF0000000;
+		if high != 0 {
+			hash ^= high >> 24;
+		}
+		hash &= !high;
+	}
+	hash
+}
+
+fn read_symbol(memory: &MemoryMap, symbols: u64, index: u64) -> Result<Symbol, ElfErr> {
+	let entry = read(memory, symbols.wrapping_add(index * SYMBOL_SIZE), SYMBOL_SIZE)?;
+	Ok(Symbol {
+		name: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+		binding: entry[4] >> 4,
+		section: u16::from_le_bytes([entry[6], entry[7]]),
+		value: u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+		size: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+	})
+}
+
+fn read_u32(memory: &MemoryMap, addr: u64) -> Result<u32, ElfErr> {
+	let bytes = read(memory, addr, 4)?;
+	Ok(u32::from_le_bytes(bytes[..].try_into().unwrap()))
+}
+
+/// Read a table the object points to, which its file must be big enough to hold
+fn read_table(memory: &MemoryMap, object: &Object, addr: u64, size: u64) -> Result<Vec<u8>, ElfErr> {
+	if size > object.file_size {
+		return Err(ElfErr::BadDynamic);
+	}
+	read(memory, addr, size)
+}
+
+/// Read from the objects' segments, which don't have to be mapped yet
+fn read(memory: &MemoryMap, addr: u64, size: u64) -> Result<Vec<u8>, ElfErr> {
+	let addr = VirtAddr::try_new(addr).map_err(|_| ElfErr::BadDynamic)?;
+	let mut buffer = vec![0u8; size as usize];
+	if memory.read(addr, &mut buffer) {
+		Ok(buffer)
+	} else {
+		Err(ElfErr::BadDynamic)
+	}
+}
+
+/// Write to the objects' segments, even the read only ones
+fn write(memory: &MemoryMap, addr: u64, bytes: &[u8]) -> Result<(), ElfErr> {
+	let addr = VirtAddr::try_new(addr).map_err(|_| ElfErr::BadDynamic)?;
+	if memory.write(addr, bytes) {
+		Ok(())
+	} else {
+		Err(ElfErr::BadDynamic)
+	}
+}
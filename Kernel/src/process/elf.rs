@@ -1,9 +1,7 @@
-use core::{
-	mem::{align_of, size_of},
-	slice::from_raw_parts_mut,
-	str::from_utf8,
-};
-use elf_rs::{self, Elf, ElfFile, Error, ProgramType};
+use alloc::{string::ToString, sync::Arc, vec, vec::Vec};
+use core::{cmp::max, convert::TryInto, slice::from_raw_parts_mut};
+use elf_rs::{self, Elf, ElfFile, Error, ProgramHeaderFlags, ProgramType};
+use spin::Mutex;
 
 use x86_64::{
 	addr::VirtAddr,
@@ -14,11 +12,72 @@ use x86_64::{
 	},
 };
 
+use super::{
+	dynamic::{self, Object},
+	layout::{
+		HEAP_RANDOM_PAGES, HEAP_SIZE, HEAP_START, MAPPING_BASE, MAPPING_RANDOM_PAGES, PIE_BASE, PIE_RANDOM_PAGES,
+		STACK_RANDOM_PAGES,
+	},
+	memory::{
+		Backing, MemoryMap, RegionKind, DATA_FLAGS, INITIAL_STACK_SIZE, MAX_STACK_SIZE, STACK_END, STACK_GUARD_SIZE,
+	},
+	Abi, Credentials,
+};
 use crate::{
-	fs::ext2::{self, Ext2Err},
+	fs::ext2::{self, Access, Ext2Err, File},
 	mem::paging,
+	util::{
+		io::{Read, Seek, SeekFrom},
+		random::{random_below, random_u64},
+	},
 };
 
+const ELF_HEADER_SIZE: usize = 64;
+const PAGE_SIZE: u64 = 4096;
+
+/// Type of position independent executables and shared objects, which are loaded at a base of
+/// our choosing
+const ET_DYN: u16 = 3;
+
+/// Number of pages the start of the heap of Linux executables is randomized over, past the end
+/// of the executable (32MiB)
+const BREAK_RANDOM_PAGES: u64 = 1 << 13;
+/// Number of random bytes on the stack, for the process to seed its generators with
+const RANDOM_SIZE: usize = 16;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+/// Index of the OS/ABI in the identification of the ELF header
+const EI_OSABI: usize = 7;
+/// The OS/ABI of Linux executables, though most of them leave it as System V
+const ELFOSABI_LINUX: u8 = 3;
+/// Type of the program headers of notes
+const PT_NOTE: u32 = 4;
+/// Name of the note marking executables built for this kernel
+const NATIVE_NOTE: &[u8] = b"GuyOS\0";
+/// Notes segments bigger than this aren't looked at
+const MAX_NOTES_SIZE: u64 = 0x1000;
+/// Native executables that were built before the note existed and are shipped as they are, so
+/// they are recognized by their path
+const PREBUILT_NATIVE: [&str; 3] = ["/bin/a", "/bin/b", "/bin/b_exit"];
+
+// Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+/// Start of the heap. Not a standard key, other programs ignore it.
+const AT_HEAP: u64 = 0x1000;
+
 /// Error relating to reading, parsing, loading, or executing an ELF executable file.
 #[derive(Debug, Copy, Clone)]
 pub enum ElfErr {
@@ -28,6 +87,22 @@ pub enum ElfErr {
 	Elf32,
 	/// Error related to the structure of the elf file
 	Elf(Error),
+	/// The dynamic section, or the tables it points to, are outside of the loaded segments or
+	/// malformed
+	BadDynamic,
+	/// A needed shared object isn't position independent
+	NotShared,
+	/// The executable or a shared object needs a relocation of a type that isn't supported
+	Relocation(u32),
+	/// A symbol that isn't defined by the executable or any of the shared objects it needs
+	UndefinedSymbol,
+	/// A size or an offset in the headers goes past the end of the file, or an address outside of
+	/// userspace
+	OutOfRange,
+	/// The `#!` line of a script doesn't name an interpreter, or is too long
+	BadInterpreter,
+	/// Scripts are run by scripts too many times before an executable runs them
+	ScriptDepth,
 }
 
 impl From<Error> for ElfErr {
@@ -47,23 +122,27 @@ impl From<Ext2Err> for ElfErr {
 pub struct LoadData {
 	/// Entrypoint
 	pub entry: VirtAddr,
-	/// Top of stack
+	/// Top of stack, where the arguement count is
 	pub stack_top: VirtAddr,
-	/// Number of arguements
-	pub argc: usize,
-	/// Pointer to arguemnt vector
-	pub argv: VirtAddr,
+	/// The syscalls the executable makes
+	pub abi: Abi,
 }
 
-/// load an ELF executable
+/// load an ELF executable and the shared objects it needs, recording the regions they map in
+/// `memory`. The stack may grow to `stack_size` bytes. If `randomize` is set, position
+/// independent executables, shared objects, the stack and the heap are placed at random
+/// addresses.
 // pub fn load_elf(path: &str, page_table: &mut PageTable, args: &[&str]) -> Result<(VirtAddr, VirtAddr), ElfErr> {
-pub fn load_elf(path: &str, page_table: &mut PageTable, args: &[&str]) -> Result<LoadData, ElfErr> {
-	let file_data = ext2::read_file(path)?;
-	let elf = Elf::from_bytes(&file_data)?;
-	let elf64 = match elf {
-		Elf::Elf64(elf) => elf,
-		_ => return Err(ElfErr::Elf32),
-	};
+pub fn load_elf(
+	path: &str,
+	page_table: &mut PageTable,
+	memory: &mut MemoryMap,
+	stack_size: u64,
+	randomize: bool,
+	args: &[&str],
+	credentials: &Credentials,
+) -> Result<LoadData, ElfErr> {
+	ext2::access(path, credentials, Access::EXECUTE)?;
 
 	let prev_table = Cr3::read();
 
@@ -72,88 +151,306 @@ pub fn load_elf(path: &str, page_table: &mut PageTable, args: &[&str]) -> Result
 		paging::set_page_table(page_table);
 	}
 
-	for header in elf64.program_header_iter() {
-		if header.ph_type() == ProgramType::LOAD {
-			let addr = VirtAddr::new(header.vaddr());
-			let size = header.memsz() as usize;
-			let start = header.offset() as usize;
-			let filesz = header.filesz() as usize;
-			let end = start + filesz;
-			let data: &[u8] = &file_data[start..end];
-
-			let range = PageRangeInclusive::<Size4KiB> {
-				start: Page::containing_address(addr),
-				end: Page::containing_address(addr + size),
-			};
-			let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+	let result = load_in_table(path, page_table, memory, stack_size, randomize, args, credentials);
+
+	// Switch back to original page table
+	unsafe {
+		Cr3::write(prev_table.0, prev_table.1);
+	}
 
-			paging::map(range, page_table, flags);
+	result
+}
 
-			if header.memsz() > 0 {
-				// If there is data, copy it in
-				unsafe {
-					let target: *mut u8 = addr.as_mut_ptr();
-					target.copy_from(data.as_ptr(), data.len());
+/// The part of [load_elf] that runs with the process's page table loaded
+fn load_in_table(
+	path: &str,
+	page_table: &mut PageTable,
+	memory: &mut MemoryMap,
+	stack_size: u64,
+	randomize: bool,
+	args: &[&str],
+	credentials: &Credentials,
+) -> Result<LoadData, ElfErr> {
+	let random_pages = |pages| if randomize { random_below(pages) * PAGE_SIZE } else { 0 };
+	let executable = map_object(path, memory, false, |_| PIE_BASE + random_pages(PIE_RANDOM_PAGES))?;
+	let entry = executable.entry;
+	let mut aux = vec![
+		(AT_PHENT, PROGRAM_HEADER_SIZE),
+		(AT_PHNUM, executable.program_header_count as u64),
+		(AT_PAGESZ, PAGE_SIZE),
+		(AT_BASE, 0),
+		(AT_ENTRY, entry.as_u64()),
+		(AT_UID, credentials.uid as u64),
+		(AT_EUID, credentials.uid as u64),
+		(AT_GID, credentials.gid as u64),
+		(AT_EGID, credentials.gid as u64),
+		(AT_SECURE, 0),
+	];
+	if let Some(program_headers) = executable.program_headers {
+		aux.push((AT_PHDR, program_headers.as_u64()));
+	}
+	let abi = executable.abi;
+	let program_break = executable.end.align_up(PAGE_SIZE) + random_pages(BREAK_RANDOM_PAGES);
+	let mut objects = vec![executable];
+	dynamic::link(&mut objects, memory, randomize, credentials)?;
 
-					// Pad zeroes (especially important for BSS)
-					let padding_start = target.add(filesz);
-					let padding_len = size - filesz;
-					padding_start.write_bytes(0, padding_len);
-				}
-			}
+	match abi {
+		Abi::Native => {
+			// The heap is zero filled as it is touched
+			let heap = VirtAddr::new(HEAP_START + random_pages(HEAP_RANDOM_PAGES));
+
+			memory.add(heap, HEAP_SIZE, RegionKind::Heap, DATA_FLAGS, Backing::Zero);
+			aux.push((AT_HEAP, heap.as_u64()));
 		}
+		Abi::Linux => {
+			// Linux executables grow their heap with brk, and map more memory with mmap
+			let mappings = VirtAddr::new(MAPPING_BASE + random_pages(MAPPING_RANDOM_PAGES));
+			memory.set_bases(program_break, mappings);
+		}
+	}
+
+	// The stack starts with the strings: random bytes, the path and the arguements. Below them
+	// is the arguement count, the arguement and (empty) environment vectors and the auxiliary
+	// vector, with the count at the 16 byte aligned top of the stack (System V ABI).
+	let mut strings = vec![0u8; RANDOM_SIZE];
+	for chunk in strings.chunks_mut(8) {
+		chunk.copy_from_slice(&random_u64().to_le_bytes());
+	}
+	let mut offsets = Vec::with_capacity(args.len() + 1);
+	for arg in core::iter::once(&path).chain(args.iter()) {
+		offsets.push(strings.len() as u64);
+		strings.extend_from_slice(arg.as_bytes());
+		strings.push(0);
 	}
+	// argc, argv, NULL, envp NULL, auxv and AT_NULL
+	let vector_size = (1 + offsets.len() + 1 + 1 + 2 * (aux.len() + 3)) as u64 * 8;
+
+	// Map the top of the stack, enough for the arguements. The rest is mapped as it grows, up to
+	// the limit, and below that is the guard.
+	let stack_end = STACK_END - random_pages(STACK_RANDOM_PAGES);
+	let strings_start = (stack_end - strings.len() as u64) & !0xF;
+	let stack_top = (strings_start - vector_size) & !0xF;
 
-	// Map stack
-	const STACK_SIZE: u64 = 0x800000; // 8MiB
-	const STACK_TOP: u64 = 0x0000800000000000 - 1; // top of userspace
+	let initial_size = align_up(stack_end - stack_top + INITIAL_STACK_SIZE);
+	let stack_size = align_up(stack_size.clamp(initial_size, MAX_STACK_SIZE));
+	let stack_bottom = stack_end - stack_size;
 
 	let range = PageRangeInclusive::<Size4KiB> {
-		start: Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE)),
-		end: Page::containing_address(VirtAddr::new(STACK_TOP)),
+		start: Page::containing_address(VirtAddr::new(stack_end - initial_size)),
+		end: Page::containing_address(VirtAddr::new(stack_end - 1)),
 	};
-	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
-	paging::map(range, page_table, flags);
+	paging::map(range, page_table, DATA_FLAGS);
+	memory.add(
+		VirtAddr::new(stack_bottom),
+		stack_size,
+		RegionKind::Stack,
+		DATA_FLAGS,
+		Backing::Zero,
+	);
+	memory.add(
+		VirtAddr::new(stack_bottom - STACK_GUARD_SIZE),
+		STACK_GUARD_SIZE,
+		RegionKind::StackGuard,
+		DATA_FLAGS,
+		Backing::Nothing,
+	);
 
-	let mut stack_top: usize = (STACK_TOP as usize) & !(align_of::<&str>() - 1);
+	aux.push((AT_RANDOM, strings_start));
+	aux.push((AT_EXECFN, strings_start + offsets[0]));
+	aux.push((AT_NULL, 0));
+	let mut vector = Vec::with_capacity(vector_size as usize / 8);
+	vector.push(args.len() as u64 + 1);
+	vector.extend(offsets.iter().map(|offset| strings_start + offset));
+	vector.push(0);
+	vector.push(0);
+	for (key, value) in aux {
+		vector.push(key);
+		vector.push(value);
+	}
 
-	let len = args.len();
-	let size = len * size_of::<&str>();
-	let slice: &mut [&str] = unsafe { from_raw_parts_mut((stack_top - size) as *mut &str, len) };
-	slice.copy_from_slice(args);
-	stack_top -= size;
+	unsafe {
+		from_raw_parts_mut(strings_start as *mut u8, strings.len()).copy_from_slice(&strings);
+		from_raw_parts_mut(stack_top as *mut u64, vector.len()).copy_from_slice(&vector);
+	}
 
-	for arg in slice.iter_mut() {
-		let len = arg.len();
-		let slice: &mut [u8] = unsafe { from_raw_parts_mut((stack_top - len) as *mut u8, len) };
-		slice.copy_from_slice(arg.as_bytes());
-		stack_top -= len;
-		*arg = from_utf8(slice).unwrap();
+	Ok(LoadData {
+		entry,
+		stack_top: VirtAddr::new(stack_top),
+		abi,
+	})
+}
+
+/// Record the segments of an executable or shared object in `memory`. Position independent
+/// objects are placed where `place` says, given the size they span, and `shared` objects must be
+/// position independent. Only the headers are read now, the segments are read as their pages are
+/// touched.
+pub(super) fn map_object(
+	path: &str,
+	memory: &mut MemoryMap,
+	shared: bool,
+	place: impl FnOnce(u64) -> u64,
+) -> Result<Object, ElfErr> {
+	// The segments are loaded from the file as they are touched, so it must not change while mapped
+	let mut file = File::open_read(path)?;
+	file.deny_write();
+	let file_size = file.metadata().size;
+	let mut headers = vec![0u8; ELF_HEADER_SIZE];
+	file.read_exact(&mut headers).map_err(Ext2Err::from)?;
+	if let Elf::Elf32(_) = Elf::from_bytes(&headers)? {
+		return Err(ElfErr::Elf32);
 	}
+	let program_headers_offset = u64::from_le_bytes(headers[32..40].try_into().unwrap());
+	let program_header_size = u16::from_le_bytes([headers[54], headers[55]]) as usize;
+	let program_header_count = u16::from_le_bytes([headers[56], headers[57]]) as usize;
+	// The sizes come from the file, so they are checked against it before anything is allocated
+	let headers_end = program_headers_offset
+		.checked_add((program_header_size * program_header_count) as u64)
+		.filter(|&end| end <= file_size)
+		.ok_or(ElfErr::OutOfRange)?;
+	let (program_headers_offset, headers_end) = (program_headers_offset as usize, headers_end as usize);
+	let headers_end = max(ELF_HEADER_SIZE, headers_end);
+	headers.resize(headers_end, 0);
+	file.read_exact(&mut headers[ELF_HEADER_SIZE..]).map_err(Ext2Err::from)?;
+	let elf64 = match Elf::from_bytes(&headers)? {
+		Elf::Elf64(elf) => elf,
+		_ => return Err(ElfErr::Elf32),
+	};
 
-	// Map heap
-	const HEAP_SIZE: u64 = 0x800000; // 8MiB
-	const HEAP_START: u64 = 0x0000400000000000;
+	for header in elf64.program_header_iter() {
+		let in_file = header.offset().checked_add(header.filesz()).filter(|&end| end <= file_size);
+		let in_memory = header.vaddr().checked_add(header.memsz()).filter(|&end| end <= STACK_END);
+		if in_file.is_none() || in_memory.is_none() || header.filesz() > header.memsz() {
+			return Err(ElfErr::OutOfRange);
+		}
+	}
 
-	let range = PageRangeInclusive::<Size4KiB> {
-		start: Page::containing_address(VirtAddr::new(HEAP_START)),
-		end: Page::containing_address(VirtAddr::new(HEAP_START + HEAP_SIZE)),
+	let elf_type = u16::from_le_bytes([headers[16], headers[17]]);
+	let base = if elf_type == ET_DYN {
+		let (mut start, mut end) = (u64::MAX, 0);
+		for header in elf64.program_header_iter() {
+			if header.ph_type() == ProgramType::LOAD {
+				start = start.min(header.vaddr() & !(PAGE_SIZE - 1));
+				end = end.max(header.vaddr() + header.memsz());
+			}
+		}
+		if start > end {
+			return Err(ElfErr::BadDynamic);
+		}
+		place(align_up(end) - start).wrapping_sub(start)
+	} else if shared {
+		return Err(ElfErr::NotShared);
+	} else {
+		// Other executables are linked to run at their addresses
+		0
 	};
-	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
-	paging::map(range, page_table, flags);
+	let abi = if PREBUILT_NATIVE.contains(&path) {
+		Abi::Native
+	} else if headers[EI_OSABI] == ELFOSABI_LINUX || program_header_size < PROGRAM_HEADER_SIZE as usize {
+		Abi::Linux
+	} else {
+		let program_headers = &headers[program_headers_offset..headers_end];
+		find_abi(&mut file, program_headers.chunks_exact(program_header_size))?
+	};
 
-	// Switch back to original page table
-	unsafe {
-		Cr3::write(prev_table.0, prev_table.1);
+	let file = Arc::new(Mutex::new(file));
+	let mut dynamic = None;
+	let mut program_headers = None;
+	let mut end = VirtAddr::zero();
+	for header in elf64.program_header_iter() {
+		// PT_INTERP is ignored, the kernel links executables itself
+		if header.ph_type() == ProgramType::DYNAMIC {
+			dynamic = Some((user_addr(base, header.vaddr(), header.memsz())?, header.memsz()));
+		}
+		if header.ph_type() == ProgramType::LOAD {
+			let headers_offset = program_headers_offset as u64;
+			if headers_offset >= header.offset() && headers_offset - header.offset() < header.filesz() {
+				program_headers = Some(user_addr(base, header.vaddr() + headers_offset - header.offset(), 0)?);
+			}
+			let addr = user_addr(base, header.vaddr(), header.memsz())?;
+			let start = addr.align_down(PAGE_SIZE);
+			end = end.max(addr + header.memsz());
+			let end = (addr + header.memsz()).align_up(PAGE_SIZE);
+			// Segments are mapped with exactly the permissions they declare. Pages can't be write
+			// only, so every segment is readable.
+			let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+			if header.flags().contains(ProgramHeaderFlags::WRITE) {
+				flags |= PageTableFlags::WRITABLE;
+			}
+			let kind = if header.flags().contains(ProgramHeaderFlags::EXECUTE) {
+				RegionKind::Code
+			} else {
+				flags |= PageTableFlags::NO_EXECUTE;
+				RegionKind::Data
+			};
+			// Past the file's part of the segment is BSS, which is zero filled
+			let backing = Backing::File {
+				file: file.clone(),
+				addr,
+				offset: header.offset(),
+				size: header.filesz(),
+			};
+			memory.add(start, end - start, kind, flags, backing);
+		}
 	}
 
-	let entry = elf64.elf_header().entry_point();
-	Ok(LoadData {
-		entry: VirtAddr::new(entry),
-		stack_top: VirtAddr::new(stack_top as u64),
-		argc: args.len(),
-		argv: VirtAddr::from_ptr(slice.as_ptr()),
+	Ok(Object {
+		path: path.to_string(),
+		base,
+		dynamic,
+		entry: user_addr(base, elf64.elf_header().entry_point(), 0)?,
+		program_headers,
+		program_header_count,
+		end,
+		abi,
+		file_size,
 	})
 }
+
+/// The address `vaddr` of an object is loaded at, if it and the `size` bytes from it are in
+/// userspace
+fn user_addr(base: u64, vaddr: u64, size: u64) -> Result<VirtAddr, ElfErr> {
+	// The base is a difference, which wraps for objects linked above where they are loaded
+	let addr = base.wrapping_add(vaddr);
+	match addr.checked_add(size) {
+		Some(end) if end <= STACK_END => VirtAddr::try_new(addr).map_err(|_| ElfErr::OutOfRange),
+		_ => Err(ElfErr::OutOfRange),
+	}
+}
+
+/// Find which syscalls an executable makes from its notes. Executables built for this kernel carry
+/// a note saying so, and the rest are taken to be Linux executables, since most of those don't set
+/// their OS/ABI.
+fn find_abi<'a>(file: &mut File, program_headers: impl Iterator<Item = &'a [u8]>) -> Result<Abi, ElfErr> {
+	let field = |header: &[u8], offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+	for header in program_headers {
+		if u32::from_le_bytes(header[..4].try_into().unwrap()) != PT_NOTE {
+			continue;
+		}
+		let (offset, size) = (field(header, 8), field(header, 32));
+		let mut notes = vec![0u8; size.min(MAX_NOTES_SIZE) as usize];
+		file.seek(SeekFrom::Start(offset as usize)).map_err(Ext2Err::from)?;
+		file.read_exact(&mut notes).map_err(Ext2Err::from)?;
+		// Each note is the sizes of its name and description and its type, then the name and the
+		// description, each padded to 4 bytes
+		let mut position = 0;
+		while position + 12 <= notes.len() {
+			let word = |at: usize| u32::from_le_bytes(notes[at..at + 4].try_into().unwrap()) as usize;
+			let (name_size, description_size) = (word(position), word(position + 4));
+			let name_start = position + 12;
+			let name = notes.get(name_start..name_start.saturating_add(name_size));
+			let description_start = name_start.saturating_add((name_size + 3) & !3);
+			if name == Some(NATIVE_NOTE) {
+				return Ok(Abi::Native);
+			}
+			position = description_start.saturating_add((description_size + 3) & !3);
+		}
+	}
+	Ok(Abi::Linux)
+}
+
+/// Round up to a multiple of the page size
+pub(super) fn align_up(size: u64) -> u64 {
+	(size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
@@ -0,0 +1,99 @@
+use super::{memory::RegionKind, running_process, Pid, MAP};
+use alloc::{format, string::String};
+use core::fmt;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+/// Addresses below this are taken to be a dereferenced null pointer
+const NULL_PAGE_END: u64 = 0x1000;
+
+/// What went wrong when an exception killed a user process
+#[derive(Debug, Copy, Clone)]
+pub struct FaultReport {
+	/// Name of the exception
+	pub exception: &'static str,
+	/// The signal a process is killed with for the exception, as on Linux
+	pub signal: u32,
+	/// Address of the faulting instruction
+	pub instruction_pointer: VirtAddr,
+	/// The accessed address (CR2) and the error code, for page faults
+	pub page_fault: Option<(VirtAddr, PageFaultErrorCode)>,
+	/// The region of the process the accessed address is in, [None] if it is unmapped or the
+	/// exception isn't a page fault
+	pub region: Option<RegionKind>,
+}
+
+impl FaultReport {
+	/// Describe an exception raised by the running process
+	pub fn new(
+		exception: &'static str,
+		instruction_pointer: VirtAddr,
+		page_fault: Option<(VirtAddr, PageFaultErrorCode)>,
+	) -> Self {
+		let region = page_fault.and_then(|(addr, _)| {
+			MAP.lock()
+				.get(&running_process())
+				.and_then(|process| process.region_of(addr))
+		});
+		Self {
+			exception,
+			signal: signal(exception),
+			instruction_pointer,
+			page_fault,
+			region,
+		}
+	}
+
+	/// A multi line report with everything known about the fault, for the terminal and serial
+	pub fn details(&self, command: &str, pid: Pid) -> String {
+		let mut details = format!("\x1b[31m\x1b[4mEXCEPTION:\x1b[0m {} in {} (pid {})\n", self.exception, command, pid);
+		details.push_str(&format!(" - RIP: {:#x}\n", self.instruction_pointer.as_u64()));
+		if let Some((addr, error_code)) = self.page_fault {
+			details.push_str(&format!(" - Accessed Address (CR2): {:#x}\n", addr.as_u64()));
+			details.push_str(&format!(" - Error Code: {:?}\n", error_code));
+			match self.region {
+				Some(region) => details.push_str(&format!(" - Mapping: {}\n", region)),
+				None => details.push_str(" - Mapping: unmapped\n"),
+			}
+		}
+		details.push_str(&format!(" - {}\n", self));
+		details
+	}
+}
+
+/// A short description, like "null pointer write at 0x0"
+impl fmt::Display for FaultReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let (addr, error_code) = match self.page_fault {
+			Some(page_fault) => page_fault,
+			None => return write!(f, "{} at {:#x}", self.exception, self.instruction_pointer.as_u64()),
+		};
+		let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+			"execute"
+		} else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+			"write"
+		} else {
+			"read"
+		};
+		let addr = addr.as_u64();
+		match self.region {
+			_ if addr < NULL_PAGE_END => write!(f, "null pointer {} at {:#x}", access, addr),
+			Some(RegionKind::StackGuard) => write!(f, "stack overflow, {} at {:#x}", access, addr),
+			None => write!(f, "{} of unmapped address {:#x}", access, addr),
+			Some(region) if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) => {
+				write!(f, "{} not permitted to {} at {:#x}", access, region, addr)
+			}
+			Some(region) => write!(f, "{} of {} at {:#x}", access, region, addr),
+		}
+	}
+}
+
+/// The signal a process is killed with for the exception
+fn signal(exception: &str) -> u32 {
+	match exception {
+		"divide error" | "x87 floating point" | "simd floating point" => 8, // SIGFPE
+		"invalid opcode" => 4,                                              // SIGILL
+		"debug" | "breakpoint" => 5,                                        // SIGTRAP
+		"alignment check" => 7,                                             // SIGBUS
+		_ => 11,                                                            // SIGSEGV
+	}
+}
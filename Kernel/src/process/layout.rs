@@ -0,0 +1,81 @@
+use super::memory::{MAX_STACK_SIZE, STACK_END, STACK_GUARD_SIZE};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// End of userspace, the lower half of the address space
+pub const USER_END: u64 = 0x0000800000000000;
+
+/// The heap of native executables starts here, plus a random number of pages
+pub const HEAP_START: u64 = 0x0000400000000000;
+/// Number of pages the start of the heap is randomized over (1TiB)
+pub const HEAP_RANDOM_PAGES: u64 = 1 << 28;
+/// Size of the heap of native executables
+pub const HEAP_SIZE: u64 = 0x800000; // 8MiB
+
+/// Position independent executables are loaded here, plus a random number of pages
+pub const PIE_BASE: u64 = 0x0000555555554000;
+/// Number of pages the base of position independent executables is randomized over (1TiB)
+pub const PIE_RANDOM_PAGES: u64 = 1 << 28;
+/// Room left for a position independent executable and the heap it grows with brk
+const PIE_ROOM: u64 = 0x10000000000; // 1TiB
+
+/// Linux executables map anonymous memory from here up, plus a random number of pages
+pub const MAPPING_BASE: u64 = 0x0000580000000000;
+/// Number of pages the base of anonymous mappings is randomized over (1TiB)
+pub const MAPPING_RANDOM_PAGES: u64 = 1 << 28;
+/// Room left for anonymous mappings. Past it they are only placed where nothing else is.
+const MAPPING_ROOM: u64 = 0x10000000000; // 1TiB
+
+/// The stacks of the threads a process spawns are placed from here up, each above a guard region
+pub const THREAD_STACKS_START: u64 = 0x0000480000000000;
+/// End of the area for the stacks of spawned threads
+pub const THREAD_STACKS_END: u64 = 0x0000500000000000;
+
+/// Where the kernel starts looking for free space when it picks the address of a shared memory
+/// mapping
+pub const SHM_START: u64 = 0x0000600000000000;
+/// Where the kernel stops looking for free space for shared memory mappings
+pub const SHM_END: u64 = 0x0000700000000000;
+
+/// Shared objects are loaded one after another from here, plus a random number of pages
+pub const LIBRARY_BASE: u64 = 0x0000700000000000;
+/// Number of pages the base of the shared objects is randomized over (1TiB)
+pub const LIBRARY_RANDOM_PAGES: u64 = 1 << 28;
+/// Room left for the shared objects
+const LIBRARY_ROOM: u64 = 0x10000000000; // 1TiB
+
+/// Number of pages the end of the stack is randomized over, below [STACK_END] (16GiB)
+pub const STACK_RANDOM_PAGES: u64 = 1 << 22;
+
+/// Lowest and highest address of each area the kernel places things in
+const AREAS: [(u64, u64); 7] = [
+	(HEAP_START, HEAP_START + HEAP_RANDOM_PAGES * PAGE_SIZE + HEAP_SIZE),
+	(THREAD_STACKS_START, THREAD_STACKS_END),
+	(PIE_BASE, PIE_BASE + PIE_RANDOM_PAGES * PAGE_SIZE + PIE_ROOM),
+	(MAPPING_BASE, MAPPING_BASE + MAPPING_RANDOM_PAGES * PAGE_SIZE + MAPPING_ROOM),
+	(SHM_START, SHM_END),
+	(LIBRARY_BASE, LIBRARY_BASE + LIBRARY_RANDOM_PAGES * PAGE_SIZE + LIBRARY_ROOM),
+	(STACK_END - STACK_RANDOM_PAGES * PAGE_SIZE - MAX_STACK_SIZE - STACK_GUARD_SIZE, STACK_END),
+];
+
+// Doesn't compile if the areas overlap or leave userspace, as the array is then the wrong size
+const _: [(); 0] = [(); !disjoint(&AREAS) as usize];
+
+/// Whether the areas are in userspace, and none of them overlap
+const fn disjoint(areas: &[(u64, u64)]) -> bool {
+	let mut i = 0;
+	while i < areas.len() {
+		if areas[i].0 >= areas[i].1 || areas[i].1 > USER_END {
+			return false;
+		}
+		let mut j = i + 1;
+		while j < areas.len() {
+			if areas[i].0 < areas[j].1 && areas[j].0 < areas[i].1 {
+				return false;
+			}
+			j += 1;
+		}
+		i += 1;
+	}
+	true
+}
@@ -0,0 +1,549 @@
+use super::{
+	elf::align_up,
+	layout::{THREAD_STACKS_END, THREAD_STACKS_START},
+};
+use crate::{
+	cpu::smp::{PerCpu, MAX_CPUS},
+	fs::ext2::File,
+	mem::paging,
+	serial_println,
+	util::io::{Read, Seek, SeekFrom},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{cmp::min, fmt};
+use spin::Mutex;
+use x86_64::{
+	structures::paging::{Page, PageTableFlags, Size4KiB},
+	VirtAddr,
+};
+
+/// The stack of the main thread ends at the top of userspace
+pub const STACK_END: u64 = super::layout::USER_END;
+/// How much of the stack is mapped when a process is loaded, on top of its arguements
+pub const INITIAL_STACK_SIZE: u64 = 0x4000; // 16KiB
+/// Size of the region below the stack that is never mapped, so overflowing the stack faults
+pub const STACK_GUARD_SIZE: u64 = 0x10000; // 64KiB
+/// The stack can't be limited to more than this
+pub const MAX_STACK_SIZE: u64 = 0x10000000000; // 1TiB
+/// Flags of the pages of the stack and the heap
+pub const DATA_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+	PageTableFlags::PRESENT.bits()
+		| PageTableFlags::WRITABLE.bits()
+		| PageTableFlags::USER_ACCESSIBLE.bits()
+		| PageTableFlags::NO_EXECUTE.bits(),
+);
+
+const PAGE_SIZE: u64 = 4096;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_RUNNING: Mutex<Option<Arc<Mutex<MemoryMap>>>> = Mutex::new(None);
+
+/// The memory map of the process running on each CPU. Kept apart from [super::MAP] because pages
+/// are mapped in page faults, which can happen in syscalls that hold it.
+static RUNNING: PerCpu<Mutex<Option<Arc<Mutex<MemoryMap>>>>> = PerCpu::new([NOT_RUNNING; MAX_CPUS]);
+
+/// What a region of a process's address space holds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+	/// An executable segment of the program
+	Code,
+	/// A segment of the program that isn't executable
+	Data,
+	/// The heap the process allocates from
+	Heap,
+	/// The stack of a thread, mapped as it grows
+	Stack,
+	/// The unmapped region below a stack
+	StackGuard,
+	/// A shared memory object
+	SharedMemory,
+	/// Anonymous memory the process mapped
+	Mapping,
+}
+
+impl fmt::Display for RegionKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RegionKind::Code => write!(f, "code"),
+			RegionKind::Data => write!(f, "data"),
+			RegionKind::Heap => write!(f, "heap"),
+			RegionKind::Stack => write!(f, "stack"),
+			RegionKind::StackGuard => write!(f, "stack guard"),
+			RegionKind::SharedMemory => write!(f, "shared memory"),
+			RegionKind::Mapping => write!(f, "mapping"),
+		}
+	}
+}
+
+/// Where the contents of the pages of a region come from when they are first touched
+#[derive(Debug, Clone)]
+pub enum Backing {
+	/// The region is never mapped, touching it is a fault
+	Nothing,
+	/// The pages are filled with zeroes
+	Zero,
+	/// The pages were mapped when the region was added, to frames the region doesn't own, like
+	/// those of a shared memory object
+	Frames,
+	/// The pages are read from a file, and filled with zeroes where the file's part ends
+	File {
+		/// The file, shared by the regions mapping it
+		file: Arc<Mutex<File>>,
+		/// The address the file's part starts at, which may be past the start of the region
+		addr: VirtAddr,
+		/// Offset in the file of the part
+		offset: u64,
+		/// Size of the part
+		size: u64,
+	},
+}
+
+/// A range of addresses in a process, and how to map it (a virtual memory area)
+#[derive(Debug, Clone)]
+pub struct Region {
+	/// First address of the region, page aligned
+	pub start: VirtAddr,
+	/// Size of the region in bytes, page aligned
+	pub size: u64,
+	/// What the region holds
+	pub kind: RegionKind,
+	/// Flags the pages of the region are mapped with
+	pub flags: PageTableFlags,
+	/// Where the contents of the pages come from
+	pub backing: Backing,
+}
+
+impl Region {
+	/// Whether the address is in the region
+	pub fn contains(&self, addr: VirtAddr) -> bool {
+		addr >= self.start && addr - self.start < self.size
+	}
+
+	/// Copy the region's part of the file that falls in the page to `buffer`, the contents of
+	/// the page
+	fn read_file_part(&self, page: Page, buffer: &mut [u8]) {
+		if let Backing::File {
+			file,
+			addr,
+			offset,
+			size,
+		} = &self.backing
+		{
+			let page_start = page.start_address().as_u64();
+			let start = page_start.max(addr.as_u64());
+			let end = min(page_start + PAGE_SIZE, addr.as_u64() + size);
+			if start >= end {
+				return;
+			}
+			let position = offset + (start - addr.as_u64());
+			let target = &mut buffer[(start - page_start) as usize..(end - page_start) as usize];
+			let mut file = file.lock();
+			if file.seek(SeekFrom::Start(position as usize)).is_err() || file.read_exact(target).is_err() {
+				serial_println!("Failed to read page {:?} from file", page);
+			}
+		}
+	}
+}
+
+impl fmt::Display for Region {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:#x}-{:#x} {}", self.start.as_u64(), self.start.as_u64() + self.size, self.kind)?;
+		match &self.backing {
+			Backing::Nothing => write!(f, " (never mapped)"),
+			Backing::Zero => Ok(()),
+			Backing::Frames => write!(f, " (frames)"),
+			Backing::File { offset, size, .. } => write!(f, " (file {:#x}+{:#x})", offset, size),
+		}
+	}
+}
+
+/// The regions a process's address space is made of. Their pages are mapped the first time they
+/// are touched.
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+	regions: Vec<Region>,
+	/// Start and end of the heap that grows with brk, if the process has one
+	program_break: Option<(VirtAddr, VirtAddr)>,
+	/// Where the next anonymous mapping is placed, 0 if the process can't map any
+	next_mapping: u64,
+}
+
+impl MemoryMap {
+	/// Create an empty memory map
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Record a region of `size` bytes at `start`
+	pub fn add(&mut self, start: VirtAddr, size: u64, kind: RegionKind, flags: PageTableFlags, backing: Backing) {
+		self.regions.push(Region {
+			start,
+			size,
+			kind,
+			flags,
+			backing,
+		});
+	}
+
+	/// Find the region the address is in
+	pub fn find(&self, addr: VirtAddr) -> Option<&Region> {
+		self.regions.iter().find(|region| region.contains(addr))
+	}
+
+	/// Iterate over the regions, in the order they were added
+	pub fn iter(&self) -> impl Iterator<Item = &Region> {
+		self.regions.iter()
+	}
+
+	/// Remove the region of the kind that starts at `start`, leaving its pages mapped
+	pub fn remove(&mut self, start: VirtAddr, kind: RegionKind) {
+		self.regions.retain(|region| region.start != start || region.kind != kind);
+	}
+
+	/// Whether any region overlaps the `size` bytes from `start`
+	pub fn overlaps(&self, start: VirtAddr, size: u64) -> bool {
+		let (start, end) = (start.as_u64(), start.as_u64() + size);
+		self.regions.iter().any(|region| {
+			let region_start = region.start.as_u64();
+			region_start < end && start < region_start + region.size
+		})
+	}
+
+	/// Whether the `size` bytes from `start` are all in regions the process can read, and write if
+	/// `write`, which the kernel checks before touching memory a process gave it
+	pub fn can_access(&self, start: VirtAddr, size: u64, write: bool) -> bool {
+		let (mut covered, end) = match start.as_u64().checked_add(size) {
+			Some(end) if end <= STACK_END => (start.as_u64(), end),
+			_ => return false,
+		};
+		while covered < end {
+			match self.find(VirtAddr::new(covered)) {
+				Some(region)
+					if !matches!(region.backing, Backing::Nothing)
+						&& region.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+						&& (!write || region.flags.contains(PageTableFlags::WRITABLE)) =>
+				{
+					covered = region.start.as_u64() + region.size;
+				}
+				_ => return false,
+			}
+		}
+		true
+	}
+
+	/// Record a stack of `size` bytes for a spawned thread, above a guard region, between
+	/// [THREAD_STACKS_START] and [THREAD_STACKS_END]. It is mapped as it grows. Returns the top of
+	/// the stack, or [None] if there is no room for it.
+	pub fn add_thread_stack(&mut self, size: u64) -> Option<VirtAddr> {
+		let size = align_up(size);
+		if size == 0 || size > THREAD_STACKS_END - THREAD_STACKS_START - STACK_GUARD_SIZE {
+			return None;
+		}
+		let mut start = THREAD_STACKS_START;
+		while start + STACK_GUARD_SIZE + size <= THREAD_STACKS_END {
+			let end = start + STACK_GUARD_SIZE + size;
+			// Skip past the regions in the way, if there are any
+			let in_the_way = self
+				.regions
+				.iter()
+				.filter(|region| region.start.as_u64() < end && start < region.start.as_u64() + region.size)
+				.map(|region| region.start.as_u64() + region.size)
+				.max();
+			match in_the_way {
+				Some(region_end) => start = region_end,
+				None => {
+					let (guard, stack_bottom) = (VirtAddr::new(start), VirtAddr::new(start + STACK_GUARD_SIZE));
+					self.add(guard, STACK_GUARD_SIZE, RegionKind::StackGuard, DATA_FLAGS, Backing::Nothing);
+					self.add(stack_bottom, size, RegionKind::Stack, DATA_FLAGS, Backing::Zero);
+					return Some(VirtAddr::new(end));
+				}
+			}
+		}
+		None
+	}
+
+	/// Remove the stack with the top from [MemoryMap::add_thread_stack] and its guard, and free its
+	/// pages. The memory map's page table must be loaded.
+	pub fn remove_thread_stack(&mut self, top: VirtAddr) {
+		let index = self
+			.regions
+			.iter()
+			.position(|region| region.kind == RegionKind::Stack && region.start + region.size == top);
+		if let Some(index) = index {
+			let stack = self.regions.remove(index);
+			unmap_pages(stack.start, stack.size);
+			self.remove(stack.start - STACK_GUARD_SIZE, RegionKind::StackGuard);
+		}
+	}
+
+	/// Let the process grow a heap with brk from `program_break`, and map anonymous memory from
+	/// `mappings` up
+	pub fn set_bases(&mut self, program_break: VirtAddr, mappings: VirtAddr) {
+		self.program_break = Some((program_break, program_break));
+		self.next_mapping = mappings.as_u64();
+	}
+
+	/// Move the program break (the end of the heap) to `addr`, growing or shrinking the heap. The
+	/// memory map's page table must be loaded. Returns the break, which stays where it was if it
+	/// can't be moved to `addr`, and is null if the process has no heap that grows with brk.
+	pub fn set_break(&mut self, addr: VirtAddr) -> VirtAddr {
+		let (start, end) = match self.program_break {
+			Some(program_break) => program_break,
+			None => return VirtAddr::zero(),
+		};
+		if addr < start || addr.as_u64() >= STACK_END {
+			return end;
+		}
+		let is_heap = |region: &Region| region.kind == RegionKind::Heap && region.start == start;
+		let old_size = align_up(end - start);
+		let new_size = align_up(addr - start);
+		self.regions.retain(|region| !is_heap(region));
+		if self.overlaps(start, new_size) {
+			if old_size > 0 {
+				self.add(start, old_size, RegionKind::Heap, DATA_FLAGS, Backing::Zero);
+			}
+			return end;
+		}
+		if new_size > 0 {
+			self.add(start, new_size, RegionKind::Heap, DATA_FLAGS, Backing::Zero);
+		}
+		if new_size < old_size {
+			unmap_pages(start + new_size, old_size - new_size);
+		}
+		self.program_break = Some((start, addr));
+		addr
+	}
+
+	/// Record an anonymous mapping of `size` bytes, filled with zeroes as it is touched. Returns
+	/// where it was placed, or [None] if the process can't map that much.
+	pub fn map_anonymous(&mut self, size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+		let size = align_up(size);
+		let start = VirtAddr::try_new(self.next_mapping).ok()?;
+		if start.is_null() || size == 0 || size > STACK_END - start.as_u64() || self.overlaps(start, size) {
+			return None;
+		}
+		self.add(start, size, RegionKind::Mapping, flags, Backing::Zero);
+		self.next_mapping += size;
+		Some(start)
+	}
+
+	/// Remove the anonymous mappings in the `size` bytes from `start`, splitting the ones that are
+	/// partly in the range, and free their pages. The memory map's page table must be loaded.
+	pub fn unmap(&mut self, start: VirtAddr, size: u64) {
+		// The end may be the end of userspace, which isn't a valid address
+		let (start, end) = (start.as_u64(), start.as_u64() + align_up(size));
+		let mut index = 0;
+		while index < self.regions.len() {
+			let region = &self.regions[index];
+			let (region_start, region_end) = (region.start.as_u64(), region.start.as_u64() + region.size);
+			if region.kind != RegionKind::Mapping || region_end <= start || end <= region_start {
+				index += 1;
+				continue;
+			}
+			let region = self.regions.remove(index);
+			let removed_start = region_start.max(start);
+			let removed_end = min(region_end, end);
+			unmap_pages(VirtAddr::new(removed_start), removed_end - removed_start);
+			if region_start < start {
+				let before = Region {
+					size: start - region_start,
+					..region.clone()
+				};
+				self.regions.insert(index, before);
+				index += 1;
+			}
+			if end < region_end {
+				let after = Region {
+					start: VirtAddr::new(end),
+					size: region_end - end,
+					..region
+				};
+				self.regions.insert(index, after);
+				index += 1;
+			}
+		}
+	}
+
+	/// Give the `size` bytes from `start` new flags, splitting the regions that are partly in the
+	/// range, and change the flags of their pages that are mapped already. The memory map's page
+	/// table must be loaded. Returns false without changing anything if part of the range isn't in
+	/// a region that is mapped.
+	pub fn protect(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> bool {
+		// The end may be the end of userspace, which isn't a valid address
+		let (start, end) = (start.as_u64(), start.as_u64() + align_up(size));
+		let mut covered = start;
+		while covered < end {
+			match self.find(VirtAddr::new(covered)) {
+				Some(region) if !matches!(region.backing, Backing::Nothing) => {
+					covered = region.start.as_u64() + region.size;
+				}
+				_ => return false,
+			}
+		}
+		self.split(start);
+		self.split(end);
+		for region in self.regions.iter_mut() {
+			let region_start = region.start.as_u64();
+			if start <= region_start && region_start + region.size <= end {
+				region.flags = flags;
+			}
+		}
+		let table = paging::get_current_page_table();
+		let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+		for page in Page::range_inclusive(Page::containing_address(VirtAddr::new(start)), last) {
+			if let Some(region) = self.find(page.start_address()) {
+				let flags = self.page_flags(region, page.start_address());
+				paging::set_flags(Page::range_inclusive(page, page), table, flags);
+			}
+		}
+		true
+	}
+
+	/// Split the region the address is in, if it isn't at its start
+	fn split(&mut self, addr: u64) {
+		let index = self.regions.iter().position(|region| {
+			let region_start = region.start.as_u64();
+			region_start < addr && addr < region_start + region.size
+		});
+		if let Some(index) = index {
+			let region = &mut self.regions[index];
+			let after = Region {
+				start: VirtAddr::new(addr),
+				size: region.start.as_u64() + region.size - addr,
+				..region.clone()
+			};
+			region.size = addr - region.start.as_u64();
+			self.regions.insert(index + 1, after);
+		}
+	}
+
+	/// The flags of the page at `page_start`, which is in `region`. Segments that don't start or
+	/// end on a page boundary share pages with their neighbours, which get the permissions of both.
+	fn page_flags(&self, region: &Region, page_start: VirtAddr) -> PageTableFlags {
+		let mut flags = region.flags;
+		let sharing = self
+			.regions
+			.iter()
+			.filter(|other| other.start <= page_start && page_start - other.start < other.size);
+		for other in sharing {
+			flags |= other.flags & PageTableFlags::WRITABLE;
+			if !other.flags.contains(PageTableFlags::NO_EXECUTE) {
+				flags.remove(PageTableFlags::NO_EXECUTE);
+			}
+		}
+		flags
+	}
+
+	/// Map the page the address is in, if it is in a region that is mapped and the page isn't
+	/// yet. The memory map's page table must be loaded. Returns whether the page was mapped.
+	fn map_page(&self, addr: VirtAddr) -> bool {
+		let region = match self.find(addr) {
+			Some(region) if matches!(region.backing, Backing::Zero | Backing::File { .. }) => region,
+			_ => return false,
+		};
+		let page = Page::<Size4KiB>::containing_address(addr);
+		let range = Page::range_inclusive(page, page);
+		let table = paging::get_current_page_table();
+		if !paging::is_unmapped(range, table) {
+			return false;
+		}
+		let page_start = page.start_address();
+		let sharing = || {
+			self.regions
+				.iter()
+				.filter(move |region| region.start <= page_start && page_start - region.start < region.size)
+		};
+		paging::map(range, table, self.page_flags(region, page_start));
+		let phys = match paging::translate_in_current(page.start_address()) {
+			Some(phys) => phys,
+			None => return false,
+		};
+		// Frames aren't zeroed when they are allocated. The page is written through the physical
+		// mapping, since it may not be writable.
+		let buffer = unsafe {
+			core::slice::from_raw_parts_mut(paging::phys_to_virt(phys).as_mut_ptr::<u8>(), PAGE_SIZE as usize)
+		};
+		buffer.fill(0);
+		for region in sharing() {
+			region.read_file_part(page, buffer);
+		}
+		true
+	}
+
+	/// Map the page the address is in if it isn't yet, and get the rest of the page from the
+	/// address, through the physical mapping. The memory map's page table must be loaded.
+	fn page_at(&self, addr: VirtAddr) -> Option<&'static mut [u8]> {
+		self.find(addr)?;
+		self.map_page(addr);
+		let phys = paging::translate_in_current(addr)?;
+		let len = (PAGE_SIZE - addr.as_u64() % PAGE_SIZE) as usize;
+		Some(unsafe { core::slice::from_raw_parts_mut(paging::phys_to_virt(phys).as_mut_ptr::<u8>(), len) })
+	}
+
+	/// Read from the regions, mapping the pages that aren't yet. The memory map's page table must
+	/// be loaded. Returns whether all of `buffer` was read.
+	pub fn read(&self, addr: VirtAddr, buffer: &mut [u8]) -> bool {
+		let mut read = 0;
+		while read < buffer.len() {
+			let page = match self.page_at(addr + read) {
+				Some(page) => page,
+				None => return false,
+			};
+			let len = min(buffer.len() - read, page.len());
+			buffer[read..read + len].copy_from_slice(&page[..len]);
+			read += len;
+		}
+		true
+	}
+
+	/// Write to the regions, mapping the pages that aren't yet. Writes through the physical
+	/// mapping, so read only pages can be written, which is needed to relocate them. The memory
+	/// map's page table must be loaded. Returns whether all of `bytes` were written.
+	pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> bool {
+		let mut written = 0;
+		while written < bytes.len() {
+			let page = match self.page_at(addr + written) {
+				Some(page) => page,
+				None => return false,
+			};
+			let len = min(bytes.len() - written, page.len());
+			page[..len].copy_from_slice(&bytes[written..written + len]);
+			written += len;
+		}
+		true
+	}
+}
+
+/// Unmap the pages of the `size` bytes from `start` in the current page table, freeing their frames
+fn unmap_pages(start: VirtAddr, size: u64) {
+	if size == 0 {
+		return;
+	}
+	let range = Page::range_inclusive(Page::containing_address(start), Page::containing_address(start + size - 1u64));
+	paging::unmap(range, paging::get_current_page_table());
+}
+
+/// Set the memory map of the process that is about to run on this CPU
+pub fn set_running(memory: Arc<Mutex<MemoryMap>>) {
+	*RUNNING.get().lock() = Some(memory);
+}
+
+/// Whether the running process can read the `size` bytes from `start`, and write them if `write`
+pub fn running_can_access(start: VirtAddr, size: u64, write: bool) -> bool {
+	match RUNNING.get().lock().clone() {
+		Some(memory) => memory.lock().can_access(start, size, write),
+		None => false,
+	}
+}
+
+/// Map the page of the running process the address is in, if it is in one of its regions and
+/// isn't mapped yet. The running process's page table must be loaded. Returns whether the page
+/// was mapped.
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+	let memory = match RUNNING.get().lock().clone() {
+		Some(memory) => memory,
+		None => return false,
+	};
+	let memory = memory.lock();
+	memory.map_page(addr)
+}
@@ -1,46 +1,255 @@
 use crate::{
 	cpu::{
-		pit::get_time,
-		syscalls::{self, OpenFlags, Registers},
+		pit::{get_ticks, get_time, Ticks, PROC_COUNTER, PROC_QUANTUM},
+		smp::{self, cpu_count, cpu_index, PerCpu, MAX_CPUS},
+		syscalls::{self, DirRecord, OpenFlags, Registers, WaitRecord},
 	},
-	fs::ext2::{Directory, Entry, Ext2Err, File},
+	fs::ext2::{self, Access, Directory, Entry, Ext2Err, File, Metadata},
+	ipc::{
+		pipe::{self, PipeEnd},
+		shm,
+		socket::Socket,
+	},
+	io::buffer::TERM_COUNT,
 	mem::paging::{self, UserPageTable},
-	util::io::{IOError, Read, Write},
+	util::io::{IOError, Read, Seek, SeekFrom, Write},
 };
 use alloc::{
-	collections::VecDeque,
+	boxed::Box,
+	format,
 	string::{String, ToString},
+	sync::Arc,
+	vec,
 	vec::{IntoIter, Vec},
 };
-use core::{fmt, time::Duration};
+use core::{
+	any::Any,
+	fmt,
+	mem::{replace, size_of},
+	ops::AddAssign,
+	sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+	time::Duration,
+};
+use bitflags::bitflags;
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::VirtAddr;
+use wait_queue::{WaitQueue, Waiter};
+use x86_64::{registers::model_specific::FsBase, VirtAddr};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO: AtomicBool = AtomicBool::new(false);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TICKS: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_THREAD: AtomicUsize = AtomicUsize::new(NONE);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAITERS: WaitQueue = WaitQueue::new();
 
-/// Are processes running
-pub static mut RUNNING: bool = false;
+/// Is each CPU running a process, false while it is in the scheduler
+pub static RUNNING: PerCpu<AtomicBool> = PerCpu::new([NO; MAX_CPUS]);
 
 /// An identifier for a process. This is unique per process
 pub type Pid = usize;
 
-lazy_static! {
-	/// Queue of process pids
-	pub static ref QUEUE: Mutex<VecDeque<Pid>> = Mutex::new(VecDeque::new());
+/// An identifier for a thread. Threads share their identifiers with processes, the main thread of
+/// a process has the pid of the process as its tid.
+pub type Tid = usize;
+
+/// An identifier for a user
+pub type Uid = u16;
+
+/// An identifier for a group of users
+pub type Gid = u16;
+
+/// The uid of the superuser, who is allowed to bypass file permissions
+pub const ROOT_UID: Uid = 0;
+
+/// File mode creation mask processes start with
+pub const DEFAULT_UMASK: u16 = 0o022;
+
+/// Credentials of the superuser
+pub const ROOT_CREDENTIALS: Credentials = Credentials {
+	uid: ROOT_UID,
+	gid: 0,
+	umask: DEFAULT_UMASK,
+};
+
+/// Credentials of the normal user that the shells of all terminals but the first run as
+pub const USER_CREDENTIALS: Credentials = Credentials {
+	uid: 1000,
+	gid: 1000,
+	umask: DEFAULT_UMASK,
+};
+
+/// The identity a process acts as when accessing files. Inherited by processes it executes.
+#[derive(Debug, Copy, Clone)]
+pub struct Credentials {
+	/// User id
+	pub uid: Uid,
+	/// Group id
+	pub gid: Gid,
+	/// Permission bits that are set here are cleared from the permissions of new files
+	pub umask: u16,
+}
+
+/// Core dumps of processes are cut off at this size, unless they set a limit of their own
+pub const DEFAULT_CORE_LIMIT: u64 = 0x4000000; // 64MiB
+
+/// The stack of a process's main thread can grow to this size, unless it sets a limit of its own
+pub const DEFAULT_STACK_LIMIT: u64 = 0x800000; // 8MiB
+
+/// Resource limits of a process. Inherited by processes it executes.
+#[derive(Debug, Copy, Clone)]
+pub struct Limits {
+	/// Maximum size of a core dump in bytes, 0 disables core dumps
+	pub core_size: u64,
+	/// Maximum size of the main thread's stack in bytes, taken when a process is loaded
+	pub stack_size: u64,
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			core_size: DEFAULT_CORE_LIMIT,
+			stack_size: DEFAULT_STACK_LIMIT,
+		}
+	}
+}
+
+bitflags! {
+	/// Variations in how a process is run, with the values Linux uses. Inherited by processes it
+	/// executes.
+	#[derive(Default)]
+	pub struct Personality: u32 {
+		/// Load executables, the stack and the heap at fixed addresses, for debugging
+		const NO_RANDOMIZE = 0x0040000;
+	}
+}
+
+/// The syscalls a process makes, picked from its executable when it is loaded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Abi {
+	/// The syscalls of this kernel, made by programs built with the standard library
+	Native,
+	/// The syscalls of Linux on x86-64, made by static Linux executables
+	Linux,
+}
+
+/// How a process ended, reported to the threads that wait for it
+#[derive(Debug, Copy, Clone)]
+pub enum ExitStatus {
+	/// The process exited by itself with the code
+	Exited(i64),
+	/// The process was killed by another process or the user
+	Killed,
+	/// An exception in the process killed it
+	Crashed(FaultReport),
+}
+
+/// CPU time and activity of a process
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Usage {
+	/// Time spent running in user mode
+	pub user_time: Duration,
+	/// Time spent in the kernel handling the process's syscalls
+	pub kernel_time: Duration,
+	/// Number of times the process was switched out
+	pub context_switches: u64,
+	/// Number of syscalls the process made
+	pub syscalls: u64,
+}
+
+impl Usage {
+	/// Total CPU time, user and kernel
+	pub fn cpu_time(&self) -> Duration {
+		self.user_time + self.kernel_time
+	}
+}
+
+impl AddAssign for Usage {
+	fn add_assign(&mut self, rhs: Self) {
+		self.user_time += rhs.user_time;
+		self.kernel_time += rhs.kernel_time;
+		self.context_switches += rhs.context_switches;
+		self.syscalls += rhs.syscalls;
+	}
 }
 
+/// When the process running on each CPU last entered (or was last accounted in) user mode
+static USER_ENTRY: PerCpu<AtomicU64> = PerCpu::new([NO_TICKS; MAX_CPUS]);
+
+/// When the process running on each CPU last entered the kernel, or last resumed in it
+static KERNEL_ENTRY: PerCpu<AtomicU64> = PerCpu::new([NO_TICKS; MAX_CPUS]);
+
+/// When each CPU last switched to the process it runs
+static RUN_START: PerCpu<AtomicU64> = PerCpu::new([NO_TICKS; MAX_CPUS]);
+
+/// [CURRENT] and [CURRENT_PROCESS] of a CPU that is in the scheduler
+const NONE: usize = usize::MAX;
+
+/// The thread each CPU is running
+static CURRENT: PerCpu<AtomicUsize> = PerCpu::new([NO_THREAD; MAX_CPUS]);
+
+/// The process of [CURRENT]
+static CURRENT_PROCESS: PerCpu<AtomicUsize> = PerCpu::new([NO_THREAD; MAX_CPUS]);
+
+/// Set for CPUs whose thread another CPU removed while it was running, they leave it for the
+/// scheduler the next time they enter the kernel
+static REMOVED: PerCpu<AtomicBool> = PerCpu::new([NO; MAX_CPUS]);
+
 lazy_static! {
 	/// Hashmap containg PCBs of processes by Pid
 	pub static ref MAP: Mutex<HashMap<Pid, PCB>> = Mutex::new(HashMap::new());
 }
 
+lazy_static! {
+	/// Hashmap containg TCBs of threads by Tid. When locking both this and [MAP], lock [MAP] first.
+	pub static ref THREADS: Mutex<HashMap<Tid, TCB>> = Mutex::new(HashMap::new());
+}
+
+/// Source of the identifiers of waits, see [Waiter]
+static NEXT_WAIT: AtomicU64 = AtomicU64::new(0);
+
+/// Threads waiting for input, by terminal. When locking both this and [THREADS], lock this first.
+static INPUT_WAITERS: Mutex<[WaitQueue; TERM_COUNT]> = Mutex::new([NO_WAITERS; TERM_COUNT]);
+
 /// Module for working with elf executables
 pub mod elf;
 
+/// Module linking executables with the shared objects they need
+pub mod dynamic;
+
+/// Module running scripts with their interpreters
+pub mod script;
+
+/// Module deciding which process runs next
+pub mod scheduler;
+
+/// Module writing core dumps of crashed processes
+pub mod core_dump;
+
+/// Module describing the exceptions that kill processes
+pub mod fault;
+
+/// Module keeping track of what is mapped where in processes
+pub mod memory;
+
+/// Module placing the areas of the address space of processes, so they don't overlap
+pub mod layout;
+
+/// Module for wait queues, which blocked threads wait on until something wakes them
+pub mod wait_queue;
+use fault::FaultReport;
+use memory::{MemoryMap, RegionKind};
+use scheduler::{Nice, SCHEDULER};
+
 #[derive(Debug)]
 enum BackHandle {
 	File(File),
-	Dir(IntoIter<Entry>),
+	Dir(IntoIter<Entry>, Metadata),
+	Pipe(PipeEnd),
+	Socket(Socket),
 }
 
 impl fmt::Display for BackHandle {
@@ -50,9 +259,17 @@ impl fmt::Display for BackHandle {
 				write!(f, "File, Inode {}", file.inode)?;
 			}
 
-			BackHandle::Dir(_dir) => {
+			BackHandle::Dir(_dir, _) => {
 				write!(f, "Directory")?;
 			}
+
+			BackHandle::Pipe(end) => {
+				write!(f, "Pipe, {}", end)?;
+			}
+
+			BackHandle::Socket(socket) => {
+				write!(f, "Socket, {}", socket)?;
+			}
 		}
 		Ok(())
 	}
@@ -80,6 +297,8 @@ impl fmt::Display for OpenFiles {
 
 unsafe impl Send for PCB {}
 unsafe impl Sync for PCB {}
+unsafe impl Send for TCB {}
+unsafe impl Sync for TCB {}
 
 /// Start process execution loop
 pub fn start() {
@@ -92,10 +311,11 @@ pub fn start() {
 /// File handle
 pub type Handle = u32;
 impl OpenFiles {
-	fn new() -> Self {
+	/// Create an empty table, which gives out handles from `first` up
+	fn new(first: Handle) -> Self {
 		Self {
 			handles: HashMap::new(),
-			next: 0,
+			next: first,
 		}
 	}
 
@@ -104,7 +324,9 @@ impl OpenFiles {
 		let back_handle = self.handles.get_mut(&handle).ok_or(Ext2Err::NoHandle)?;
 		match back_handle {
 			BackHandle::File(file) => Ok(file.read(slice)?),
-			BackHandle::Dir(dir) => {
+			BackHandle::Pipe(end) => Ok(end.read(slice)?),
+			BackHandle::Socket(socket) => Ok(socket.read(slice)?),
+			BackHandle::Dir(dir, _) => {
 				// if dir.is_empty() {
 				// 	return Err(Ext2Err::EndOfDir);
 				// }
@@ -125,33 +347,168 @@ impl OpenFiles {
 		}
 	}
 
+	/// Read as many directory entries as fit into the slice, each one as a [DirRecord] followed by
+	/// its name. Returns the number of bytes written, which is 0 once the directory has been read
+	/// to its end.
+	pub fn read_dir(&mut self, handle: Handle, slice: &mut [u8]) -> Result<usize, Ext2Err> {
+		const HEADER_SIZE: usize = size_of::<DirRecord>();
+		self.read_dir_with(handle, slice, |entry, slice| {
+			let name = entry.name.as_bytes();
+			let record_length = (HEADER_SIZE + name.len() + 3) & !3;
+			if record_length > slice.len() {
+				return None;
+			}
+
+			let record = DirRecord {
+				inode: entry.inode(),
+				record_length: record_length as u16,
+				name_length: name.len() as u8,
+				type_indicator: entry.type_indicator(),
+			};
+			unsafe {
+				(slice.as_mut_ptr() as *mut DirRecord).write_unaligned(record);
+			}
+			slice[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+			slice[HEADER_SIZE + name.len()..record_length].fill(0);
+			Some(record_length)
+		})
+	}
+
+	/// Read as many directory entries as fit into the slice, each one written by `record`. It
+	/// gets the entry and the rest of the slice, and returns the size of what it wrote, or [None]
+	/// if the entry doesn't fit. Returns the number of bytes written, which is 0 once the
+	/// directory has been read to its end.
+	pub fn read_dir_with(
+		&mut self,
+		handle: Handle,
+		slice: &mut [u8],
+		mut record: impl FnMut(&Entry, &mut [u8]) -> Option<usize>,
+	) -> Result<usize, Ext2Err> {
+		let back_handle = self.handles.get_mut(&handle).ok_or(Ext2Err::NoHandle)?;
+		let dir = match back_handle {
+			BackHandle::Dir(dir, _) => dir,
+			BackHandle::File(_) | BackHandle::Pipe(_) | BackHandle::Socket(_) => return Err(Ext2Err::NotADir),
+		};
+
+		let mut written = 0;
+		while let Some(entry) = dir.as_slice().first() {
+			match record(entry, &mut slice[written..]) {
+				Some(record_length) => written += record_length,
+				None if written == 0 => return Err(Ext2Err::IO(IOError::BufferTooSmall)),
+				None => break,
+			}
+			dir.next();
+		}
+		Ok(written)
+	}
+
 	/// Write from the slice to the file handle
 	pub fn write(&mut self, handle: Handle, slice: &[u8]) -> Result<usize, Ext2Err> {
 		let back_handle = self.handles.get_mut(&handle).ok_or(Ext2Err::NoHandle)?;
 		match back_handle {
 			BackHandle::File(file) => Ok(file.write(slice)?),
-			BackHandle::Dir(_) => Err(Ext2Err::NotAFile),
+			BackHandle::Pipe(end) => Ok(end.write(slice)?),
+			BackHandle::Socket(socket) => Ok(socket.write(slice)?),
+			BackHandle::Dir(..) => Err(Ext2Err::NotAFile),
+		}
+	}
+
+	/// Change the size of the file behind the handle
+	pub fn truncate(&mut self, handle: Handle, len: usize) -> Result<(), Ext2Err> {
+		let back_handle = self.handles.get_mut(&handle).ok_or(Ext2Err::NoHandle)?;
+		match back_handle {
+			BackHandle::File(file) => file.set_len(len),
+			BackHandle::Dir(..) | BackHandle::Pipe(_) | BackHandle::Socket(_) => Err(Ext2Err::NotAFile),
 		}
 	}
 
-	/// Open a file, creting a handle
-	pub fn open_file(&mut self, path: &str, flags: OpenFlags) -> Result<Handle, Ext2Err> {
-		let file = File::from_path(path, flags)?;
+	/// Move the position of the file behind the handle. Returns the new position.
+	pub fn seek(&mut self, handle: Handle, pos: SeekFrom) -> Result<usize, Ext2Err> {
+		let back_handle = self.handles.get_mut(&handle).ok_or(Ext2Err::NoHandle)?;
+		match back_handle {
+			BackHandle::File(file) => Ok(file.seek(pos)?),
+			BackHandle::Dir(..) | BackHandle::Pipe(_) | BackHandle::Socket(_) => Err(Ext2Err::NotAFile),
+		}
+	}
+
+	/// Get the metadata of the file behind the handle. Directories have the metadata they had when
+	/// they were opened, and of pipes and sockets only the type is known.
+	pub fn metadata(&self, handle: Handle) -> Result<Metadata, Ext2Err> {
+		let back_handle = self.handles.get(&handle).ok_or(Ext2Err::NoHandle)?;
+		match back_handle {
+			BackHandle::File(file) => Ok(file.metadata()),
+			BackHandle::Dir(_, metadata) => Ok(*metadata),
+			BackHandle::Pipe(_) => Ok(Metadata::anonymous(ext2::Type::Fifo)),
+			BackHandle::Socket(_) => Ok(Metadata::anonymous(ext2::Type::UnixSocket)),
+		}
+	}
+
+	/// Whether the handle is an open directory
+	pub fn is_dir(&self, handle: Handle) -> bool {
+		matches!(self.handles.get(&handle), Some(BackHandle::Dir(..)))
+	}
+
+	/// Open a file, creting a handle. Opening a FIFO connects to its pipe, see
+	/// [OpenFiles::wait_for_peer].
+	pub fn open_file(&mut self, path: &str, flags: OpenFlags, credentials: &Credentials) -> Result<Handle, Ext2Err> {
+		let back_handle = match ext2::inode_type(path, credentials) {
+			Ok((inode, ext2::Type::Fifo)) => {
+				let (end, wanted) = match (flags.contains(OpenFlags::READ), flags.contains(OpenFlags::WRITE)) {
+					(true, false) => (pipe::End::Read, Access::READ),
+					(false, true) => (pipe::End::Write, Access::WRITE),
+					_ => (pipe::End::Both, Access::READ | Access::WRITE),
+				};
+				ext2::access(path, credentials, wanted)?;
+				BackHandle::Pipe(pipe::open_fifo(inode, end))
+			}
+			_ => BackHandle::File(File::from_path(path, flags, credentials)?),
+		};
 		let handle = self.next;
 		self.next += 1;
-		let prev = self.handles.insert(handle, BackHandle::File(file));
+		let prev = self.handles.insert(handle, back_handle);
 		assert!(prev.is_none());
 		Ok(handle)
 	}
 
+	/// If the handle is an end of a pipe whose other end isn't open, add the running thread to the
+	/// threads waiting for it. Returns true if the thread must then block.
+	pub fn wait_for_peer(&self, handle: Handle) -> bool {
+		match self.handles.get(&handle) {
+			Some(BackHandle::Pipe(end)) if !end.connected() => {
+				end.wait_for_peer();
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Create a handle for a socket
+	pub fn add_socket(&mut self, socket: Socket) -> Handle {
+		let handle = self.next;
+		self.next += 1;
+		let prev = self.handles.insert(handle, BackHandle::Socket(socket));
+		assert!(prev.is_none());
+		handle
+	}
+
+	/// Get the socket behind a handle
+	pub fn socket_mut(&mut self, handle: Handle) -> Result<&mut Socket, Ext2Err> {
+		match self.handles.get_mut(&handle) {
+			Some(BackHandle::Socket(socket)) => Ok(socket),
+			Some(_) => Err(Ext2Err::NotAFile),
+			None => Err(Ext2Err::NoHandle),
+		}
+	}
+
 	/// Open a directory, creting a handle
-	pub fn open_dir(&mut self, path: &str) -> Result<Handle, Ext2Err> {
-		let directory = Directory::from_path(path)?;
+	pub fn open_dir(&mut self, path: &str, credentials: &Credentials) -> Result<Handle, Ext2Err> {
+		let directory = Directory::from_path(path, credentials)?;
+		let metadata = ext2::metadata(path, credentials)?;
 		let handle = self.next;
 		self.next += 1;
 		let prev = self
 			.handles
-			.insert(handle, BackHandle::Dir(directory.entries.into_iter()));
+			.insert(handle, BackHandle::Dir(directory.entries.into_iter(), metadata));
 		assert!(prev.is_none());
 		Ok(handle)
 	}
@@ -168,15 +525,29 @@ impl OpenFiles {
 }
 
 #[derive(Debug)]
-/// State of process
+/// State of a thread
 pub enum State {
 	/// new process
 	New(elf::LoadData),
+	/// new thread of an existing process
+	NewThread {
+		/// Address the thread starts executing at
+		entry: VirtAddr,
+		/// Top of the stack the thread was given
+		stack_top: VirtAddr,
+		/// Arguement passed to the entry function
+		arg: usize,
+	},
 	/// process stopped by syscall
 	Syscall {
 		/// saved registers
 		registers: Registers,
 	},
+	/// thread blocked in a syscall, asleep on its kernel stack
+	Kernel {
+		/// saved kernel stack pointer, the preserved registers and where to return are there
+		rsp: u64,
+	},
 	/// process stopped by timer
 	Timer {
 		/// saved registers
@@ -188,51 +559,36 @@ pub enum State {
 	},
 }
 
-/// Data needed when unblocking process
-#[derive(Debug)]
-pub enum BlockData {
-	/// data for input syscall
-	Input {
-		/// slice to write to
-		slice: *mut [u8],
-	},
-	/// Waiting for aprocess to finish
-	Wait(Pid),
-}
-
-impl fmt::Display for BlockData {
-	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-		match self {
-			BlockData::Input { slice: _ } => {
-				write!(f, "Input")?;
-			}
-
-			BlockData::Wait(pid) => {
-				write!(f, "Process {} termination", pid)?;
-			}
-		}
-		Ok(())
-	}
-}
-
-unsafe impl Sync for BlockData {}
-unsafe impl Send for BlockData {}
-
 #[derive(Debug)]
 enum BlockState {
-	Blocked { still: bool, data: BlockData },
+	Blocked {
+		still: bool,
+		/// What the thread waits for
+		reason: String,
+		/// When the thread stops waiting, see [wait_queue::TIMED_OUT]
+		deadline: Option<Duration>,
+		result: i64,
+	},
+	/// Woken after adding itself to a wait queue, but before it blocked. The next block returns
+	/// the result right away.
+	Woken(i64),
 	Ready,
 }
 
 impl fmt::Display for BlockState {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		match self {
-			BlockState::Ready => {
+			BlockState::Ready | BlockState::Woken(_) => {
 				write!(f, "Ready")?;
 			}
-			BlockState::Blocked { still, data } => {
+			BlockState::Blocked {
+				still, reason, deadline, ..
+			} => {
 				let qualifier = if *still { "Blocked on" } else { "Just unblocked from" };
-				write!(f, "{} - {}", qualifier, data)?;
+				write!(f, "{} - {}", qualifier, reason)?;
+				if let Some(deadline) = deadline {
+					write!(f, " (until {:?})", deadline)?;
+				}
 			}
 		}
 		Ok(())
@@ -242,8 +598,8 @@ impl fmt::Display for BlockState {
 impl BlockState {
 	fn ready(&self) -> bool {
 		match self {
-			BlockState::Ready => true,
-			BlockState::Blocked { still: false, data: _ } => true,
+			BlockState::Ready | BlockState::Woken(_) => true,
+			BlockState::Blocked { still: false, .. } => true,
 			_ => false,
 		}
 	}
@@ -253,30 +609,65 @@ impl BlockState {
 #[derive(Debug)]
 pub struct PCB {
 	pid: Pid,
-	state: State,
-	block_state: BlockState,
+	/// Threads of this process
+	pub threads: Vec<Tid>,
 	page_table: UserPageTable,
+	/// Regions of the address space, mapped in the page table as they are touched
+	memory: Arc<Mutex<MemoryMap>>,
+	/// Shared memory mapped in the page table. Dropped after the page table is wiped.
+	shared_memory: Vec<shm::Mapping>,
 	/// Input buffer for the process
 	pub input_buffer: String,
 	/// This processes open files
 	pub open_files: OpenFiles,
-	waiting_processes: Vec<Pid>,
+	/// Threads waiting for this process to exit
+	waiting_threads: WaitQueue,
+	/// How the processes that threads of this process waited for ended, by waiting thread
+	exit_records: HashMap<Tid, WaitRecord>,
 	/// Terminal this process prints to
 	pub terminal: usize,
+	/// User and group this process acts as
+	pub credentials: Credentials,
+	/// Niceness of this process, inherited by processes it executes
+	pub nice: Nice,
+	/// Resource limits of this process
+	pub limits: Limits,
+	/// How executables are run by this process
+	pub personality: Personality,
+	/// The syscalls this process makes
+	pub abi: Abi,
+	/// CPU time and activity of this process
+	pub usage: Usage,
+	/// Accumulated usage of the processes this process has waited for
+	pub children_usage: Usage,
 	start_time: Duration,
 	/// Command called to crate this process
 	pub command: String,
+	/// Arguements the process was started with
+	pub args: Vec<String>,
 }
 
 impl fmt::Display for PCB {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		writeln!(f, "Command: {}", self.command)?;
 		writeln!(f, "Terminal: {}", self.terminal)?;
-		if self.pid == running_process() {
-			// this process is running
-			writeln!(f, "State: Running")?;
-		} else {
-			writeln!(f, "State: {}", self.block_state)?;
+		writeln!(
+			f,
+			"User: {} Group: {} Umask: {:#o}",
+			self.credentials.uid, self.credentials.gid, self.credentials.umask
+		)?;
+		writeln!(f, "Nice: {}", self.nice)?;
+		writeln!(
+			f,
+			"Core Size Limit: {} bytes Stack Size Limit: {} bytes",
+			self.limits.core_size, self.limits.stack_size
+		)?;
+		writeln!(f, "Personality: {:?} ABI: {:?}", self.personality, self.abi)?;
+		writeln!(f, "Threads:")?;
+		let threads = THREADS.lock();
+		for tid in self.threads.iter() {
+			let thread = threads.get(tid).expect("thread of process not in hashmap");
+			writeln!(f, "\t{} - {}", tid, thread)?;
 		}
 		writeln!(
 			f,
@@ -284,72 +675,485 @@ impl fmt::Display for PCB {
 			self.start_time,
 			get_time() - self.start_time
 		)?;
+		writeln!(
+			f,
+			"CPU Time: {:?} user, {:?} kernel",
+			self.usage.user_time, self.usage.kernel_time
+		)?;
+		writeln!(
+			f,
+			"Context Switches: {} Syscalls: {}",
+			self.usage.context_switches, self.usage.syscalls
+		)?;
 		writeln!(f, "Open Files: {}", self.open_files)?;
-		writeln!(f, "Waiting Processes: {:?}", self.waiting_processes)?;
+		writeln!(f, "Memory Map:")?;
+		for region in self.memory.lock().iter() {
+			writeln!(f, "\t{}", region)?;
+		}
+		writeln!(f, "Shared Memory:")?;
+		for mapping in self.shared_memory.iter() {
+			writeln!(
+				f,
+				"\t{:?} - {} ({} bytes)",
+				mapping.start(),
+				mapping.object().name(),
+				mapping.object().size()
+			)?;
+		}
+		writeln!(f, "Waiting Threads: {}", self.waiting_threads)?;
 		writeln!(f, "Input Buffer: {:?}", self.input_buffer)?;
 		Ok(())
 	}
 }
 
-/// Get currenty running process
+/// Size of the stack each thread runs syscalls on
+const KERNEL_STACK_SIZE: usize = 4096 * 8;
+
+/// The stack a thread runs on in the kernel. A syscall that blocks keeps its frames here until
+/// the thread is woken.
+struct KernelStack(Box<[u8]>);
+
+impl fmt::Debug for KernelStack {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "KernelStack({:?})", self.top())
+	}
+}
+
+impl KernelStack {
+	fn new() -> Self {
+		Self(vec![0; KERNEL_STACK_SIZE].into_boxed_slice())
+	}
+
+	/// The address the stack grows down from
+	fn top(&self) -> VirtAddr {
+		(VirtAddr::from_ptr(self.0.as_ptr()) + KERNEL_STACK_SIZE).align_down(16u64)
+	}
+}
+
+/// Memory of removed threads and processes that CPUs may still be using, with a mask of those
+/// CPUs. It is freed once all of them went back to the scheduler.
+static RETIRED: Mutex<Vec<(u64, Box<dyn Any + Send>)>> = Mutex::new(Vec::new());
+
+/// Free `memory` once none of the CPUs in the mask use it
+fn retire(cpus: u64, memory: Box<dyn Any + Send>) {
+	if cpus != 0 {
+		RETIRED.lock().push((cpus, memory));
+	}
+}
+
+/// Mask of the CPUs that are running the thread
+fn cpus_running(tid: Tid) -> u64 {
+	(0..cpu_count())
+		.filter(|cpu| CURRENT.of(*cpu).load(Ordering::SeqCst) == tid)
+		.fold(0, |mask, cpu| mask | 1 << cpu)
+}
+
+/// Mask of the CPUs that are running threads of the process
+fn cpus_in(pid: Pid) -> u64 {
+	(0..cpu_count())
+		.filter(|cpu| CURRENT_PROCESS.of(*cpu).load(Ordering::SeqCst) == pid)
+		.fold(0, |mask, cpu| mask | 1 << cpu)
+}
+
+/// Remove a thread, keeping its kernel stack until it is surely not in use. The other CPUs
+/// running it leave it the next time they enter the kernel.
+fn retire_thread(thread: TCB) {
+	let cpus = cpus_running(thread.tid);
+	for cpu in (0..cpu_count()).filter(|cpu| cpus & 1 << cpu != 0 && *cpu != cpu_index()) {
+		REMOVED.of(cpu).store(true, Ordering::SeqCst);
+	}
+	retire(cpus, Box::new(thread.kernel_stack));
+}
+
+/// Thread control block
+#[derive(Debug)]
+pub struct TCB {
+	tid: Tid,
+	/// The process this thread belongs to
+	pub process: Pid,
+	state: State,
+	block_state: BlockState,
+	kernel_stack: KernelStack,
+	/// Base address of the FS segment, used for thread local storage
+	pub fs_base: VirtAddr,
+	/// Threads waiting for this thread to exit
+	joining_threads: WaitQueue,
+	/// Which wait the thread is in, or will be in the next time it blocks. See [Waiter].
+	wait: u64,
+	/// Whether the thread may sleep while it waits for the disk, see [with_blocking_io]
+	blocking_io: bool,
+	/// Top of the stack the kernel mapped for the thread when it was spawned, freed when it exits
+	stack_top: Option<VirtAddr>,
+}
+
+impl fmt::Display for TCB {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		if let Some(cpu) = (0..cpu_count()).find(|cpu| CURRENT.of(*cpu).load(Ordering::SeqCst) == self.tid) {
+			write!(f, "Running on CPU {}", cpu)?;
+		} else {
+			write!(f, "{}", self.block_state)?;
+		}
+		if !self.fs_base.is_null() {
+			write!(f, " (FS base {:?})", self.fs_base)?;
+		}
+		Ok(())
+	}
+}
+
+impl TCB {
+	fn new(tid: Tid, process: Pid, state: State) -> Self {
+		Self {
+			tid,
+			process,
+			state,
+			block_state: BlockState::Ready,
+			kernel_stack: KernelStack::new(),
+			fs_base: VirtAddr::zero(),
+			joining_threads: WaitQueue::new(),
+			wait: next_wait(),
+			blocking_io: false,
+			stack_top: None,
+		}
+	}
+
+	/// Mark the thread as no longer blocked, and let the scheduler know
+	fn unblock(&mut self) {
+		if let BlockState::Blocked { still, .. } = &mut self.block_state {
+			*still = false;
+			SCHEDULER.lock().unblock(self.tid);
+		}
+	}
+
+	/// Append a thread to the threads waiting for this one to exit
+	pub fn append_joining(&mut self, waiter: Waiter) {
+		self.joining_threads.add(waiter);
+	}
+}
+
+/// Get the process running on this CPU
 pub fn running_process() -> Pid {
-	QUEUE.lock()[0]
+	CURRENT_PROCESS.get().load(Ordering::SeqCst)
 }
 
-/// Block the currently running process
-pub fn block_current(data: BlockData) {
-	let pid = running_process();
+/// Get the thread running on this CPU
+pub fn running_thread() -> Tid {
+	CURRENT.get().load(Ordering::SeqCst)
+}
+
+/// Check if any CPU is running the thread
+pub fn running_anywhere(tid: Tid) -> bool {
+	cpus_running(tid) != 0
+}
+
+/// Leave the running thread for the scheduler if another CPU removed it while it ran. Called when
+/// entering the kernel for the running thread.
+pub fn leave_if_removed() {
+	if REMOVED.get().swap(false, Ordering::SeqCst) {
+		run_next_process();
+	}
+}
+
+/// Get the credentials of the currently running process
+pub fn running_credentials() -> Credentials {
 	MAP.lock()
-		.get_mut(&pid)
-		.expect("running process not in queue")
-		.block_state = BlockState::Blocked { still: true, data };
+		.get(&running_process())
+		.expect("running process not in hashmap")
+		.credentials
 }
 
-impl PCB {
-	/// append input to this processes input buffer
-	pub fn append_input(&mut self, character: char) {
-		self.input_buffer.push(character);
-		match &mut self.block_state {
+/// Get the syscalls the currently running process makes
+pub fn running_abi() -> Abi {
+	MAP.lock().get(&running_process()).expect("running process not in hashmap").abi
+}
+
+/// Charge the time since the running process last entered user mode to its user time. Called
+/// whenever the kernel is entered from user mode.
+pub fn account_user_time() {
+	let now = get_ticks();
+	let time: Duration = (now - Ticks(USER_ENTRY.get().load(Ordering::Relaxed))).into();
+	USER_ENTRY.get().store(now.0, Ordering::Relaxed);
+	KERNEL_ENTRY.get().store(now.0, Ordering::Relaxed);
+	if let Some(pcb) = MAP.lock().get_mut(&running_process()) {
+		pcb.usage.user_time += time;
+	}
+}
+
+/// Charge the time since the running process entered the kernel to its kernel time
+fn account_kernel_time() {
+	let now = get_ticks();
+	let time: Duration = (now - Ticks(KERNEL_ENTRY.get().load(Ordering::Relaxed))).into();
+	KERNEL_ENTRY.get().store(now.0, Ordering::Relaxed);
+	if let Some(pcb) = MAP.lock().get_mut(&running_process()) {
+		pcb.usage.kernel_time += time;
+	}
+}
+
+/// Charge the time spent in the kernel to the running process, and count the syscall it made
+pub fn account_syscall() {
+	account_kernel_time();
+	if let Some(pcb) = MAP.lock().get_mut(&running_process()) {
+		pcb.usage.syscalls += 1;
+	}
+}
+
+/// Block the running thread until it is woken, sleeping on its kernel stack. The thread should
+/// have added itself to a [WaitQueue] first. Returns the result it was woken with, or
+/// [wait_queue::TIMED_OUT] if it is still asleep at `deadline`, a time from [get_time]. No locks
+/// may be held, other threads run in the meantime.
+pub fn block_current(reason: impl Into<String>, deadline: Option<Duration>) -> i64 {
+	let tid = running_thread();
+	let waiter = {
+		let mut threads = THREADS.lock();
+		let thread = threads.get_mut(&tid).expect("running thread not in hashmap");
+		if let BlockState::Woken(result) = thread.block_state {
+			thread.block_state = BlockState::Ready;
+			thread.wait = next_wait();
+			return result;
+		}
+		thread.block_state = BlockState::Blocked {
+			still: true,
+			reason: reason.into(),
+			deadline,
+			result: 0,
+		};
+		Waiter { tid, wait: thread.wait }
+	};
+	if let Some(deadline) = deadline {
+		wait_queue::add_deadline(deadline, waiter);
+	}
+	// Time asleep isn't spent in the kernel
+	account_kernel_time();
+	switch_from_kernel_stack();
+	KERNEL_ENTRY.get().store(get_ticks().0, Ordering::Relaxed);
+
+	let mut threads = THREADS.lock();
+	let thread = threads.get_mut(&tid).expect("running thread not in hashmap");
+	// Whoever still holds on to this wait can no longer wake the thread
+	thread.wait = next_wait();
+	match replace(&mut thread.block_state, BlockState::Ready) {
+		BlockState::Blocked { result, .. } => result,
+		BlockState::Woken(_) | BlockState::Ready => panic!("thread {} woke without being blocked", tid),
+	}
+}
+
+/// Save the preserved registers of the running thread on its kernel stack, and leave it for the
+/// scheduler. Returns once the thread is run again.
+#[naked]
+extern "C" fn switch_from_kernel_stack() {
+	unsafe {
+		asm!(
+			"
+			push rbp
+			push rbx
+			push r12
+			push r13
+			push r14
+			push r15",
+			"mov rdi, rsp", // C calling convention first variable
+			"sub rsp, 8",   // Align the stack for the call
+			"call switch_from_kernel_stack_inner",
+			options(noreturn)
+		);
+	}
+}
+
+#[allow(dead_code)] // called from asm
+#[no_mangle] // called from asm
+extern "C" fn switch_from_kernel_stack_inner(rsp: u64) -> ! {
+	context_switch(State::Kernel { rsp })
+}
+
+/// The wait the next time a thread blocks
+fn next_wait() -> u64 {
+	NEXT_WAIT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The running thread, for the next time it blocks
+fn current_waiter() -> Waiter {
+	let tid = running_thread();
+	let wait = THREADS.lock().get(&tid).expect("running thread not in hashmap").wait;
+	Waiter { tid, wait }
+}
+
+/// Wake a thread blocked in a syscall if it is still in the wait, making [block_current] return
+/// `result`. A thread that is in the wait but hasn't blocked yet, because it dropped its locks
+/// first, doesn't block at all. Returns whether it was woken.
+fn wake(waiter: Waiter, result: i64) -> bool {
+	let mut threads = THREADS.lock();
+	match threads.get_mut(&waiter.tid) {
+		Some(thread) if thread.wait == waiter.wait => match &mut thread.block_state {
 			BlockState::Blocked {
-				still,
-				data: BlockData::Input { slice: _ },
+				still: true,
+				result: woken_with,
+				..
 			} => {
-				*still = false;
+				*woken_with = result;
+				thread.unblock();
+				true
+			}
+			BlockState::Ready => {
+				thread.block_state = BlockState::Woken(result);
+				true
 			}
-			_ => {}
+			_ => false,
+		},
+		_ => false,
+	}
+}
+
+/// Whether the thread is still in the wait, and hasn't been woken from it
+fn still_waiting(waiter: Waiter) -> bool {
+	match THREADS.lock().get(&waiter.tid) {
+		Some(thread) if thread.wait == waiter.wait => {
+			!matches!(
+				thread.block_state,
+				BlockState::Blocked { still: false, .. } | BlockState::Woken(_)
+			)
 		}
+		_ => false,
 	}
+}
 
-	/// Check if process is blocked on input
-	pub fn blocked_on_input(&self) -> bool {
-		match &self.block_state {
-			BlockState::Blocked {
-				still: _,
-				data: BlockData::Input { slice: _ },
-			} => true,
-			_ => false,
+/// Run `f` with the running thread allowed to sleep while it waits for the disk, instead of
+/// waiting on the CPU. Other threads change the file system in the meantime, so `f` may only read
+/// it, and may not hold any locks.
+pub fn with_blocking_io<T>(f: impl FnOnce() -> T) -> T {
+	set_blocking_io(true);
+	let result = f();
+	set_blocking_io(false);
+	result
+}
+
+fn set_blocking_io(blocking_io: bool) {
+	THREADS
+		.lock()
+		.get_mut(&running_thread())
+		.expect("running thread not in hashmap")
+		.blocking_io = blocking_io;
+}
+
+/// Whether the running thread may sleep while it waits for the disk, see [with_blocking_io]
+pub fn can_block_io() -> bool {
+	THREADS
+		.lock()
+		.get(&running_thread())
+		.map_or(false, |thread| thread.blocking_io)
+}
+
+/// Block the running thread until a character is typed on the terminal
+pub fn wait_for_input(terminal: usize) {
+	INPUT_WAITERS.lock()[terminal].add_current();
+	block_current("Input", None);
+}
+
+/// Block the running thread until the other end of the pipe behind the handle opens, if the
+/// handle is an end of a pipe. See [OpenFiles::wait_for_peer].
+pub fn wait_for_peer(handle: Handle) {
+	loop {
+		let waiting = MAP
+			.lock()
+			.get(&running_process())
+			.expect("running process not in hashmap")
+			.open_files
+			.wait_for_peer(handle);
+		if !waiting {
+			return;
 		}
+		block_current(format!("Other end of pipe {}", handle), None);
 	}
+}
 
-	/// Append a process to waiting processes
-	pub fn append_waiting(&mut self, pid: Pid) {
-		self.waiting_processes.push(pid);
+/// Give a typed character to the processes of the terminal that are waiting for input
+pub fn input_character(terminal: usize, character: char) {
+	let mut processes = MAP.lock();
+	let mut input_waiters = INPUT_WAITERS.lock();
+	let waiters = &mut input_waiters[terminal];
+	let waiting: Vec<Tid> = waiters.waiting().collect();
+	let threads = THREADS.lock();
+	let mut fed: Vec<Pid> = Vec::new();
+	for pid in waiting.iter().filter_map(|tid| threads.get(tid)).map(|thread| thread.process) {
+		if !fed.contains(&pid) {
+			if let Some(process) = processes.get_mut(&pid) {
+				process.input_buffer.push(character);
+			}
+			fed.push(pid);
+		}
 	}
+	drop(threads);
+	waiters.wake_all(0);
+}
 
-	fn run_proc(&mut self) {
-		unsafe {
-			RUNNING = true;
-			crate::cpu::pit::PROC_COUNTER = 0;
-		};
+impl PCB {
+	/// Append a thread to the threads waiting for this process to exit
+	pub fn append_waiting(&mut self, waiter: Waiter) {
+		self.waiting_threads.add(waiter);
+	}
+
+	/// Take how the process that the thread waited for ended, if it has
+	pub fn take_exit_record(&mut self, tid: Tid) -> Option<WaitRecord> {
+		self.exit_records.remove(&tid)
+	}
+
+	/// Map a shared memory object into the address space of the process, at `addr` or at an
+	/// address picked by the kernel. Returns where it was mapped.
+	pub fn map_shared_memory(&mut self, object: Arc<shm::SharedMemory>, addr: Option<VirtAddr>) -> Option<VirtAddr> {
+		let mapping = shm::Mapping::new(object, addr, &mut self.page_table.0, &mut self.memory.lock())?;
+		let start = mapping.start();
+		self.shared_memory.push(mapping);
+		Some(start)
+	}
+
+	/// The regions of the address space of this process. Don't touch the process's memory while
+	/// holding its lock, page faults need it.
+	pub fn memory(&self) -> Arc<Mutex<MemoryMap>> {
+		self.memory.clone()
+	}
+
+	/// Find what the address is mapped to in this process, [None] if it is unmapped
+	pub fn region_of(&self, addr: VirtAddr) -> Option<RegionKind> {
+		self.memory.lock().find(addr).map(|region| region.kind)
+	}
+
+	/// Unmap the shared memory object mapped at `addr`. Returns false if nothing is mapped there.
+	pub fn unmap_shared_memory(&mut self, addr: VirtAddr) -> bool {
+		match self.shared_memory.iter().position(|mapping| mapping.start() == addr) {
+			Some(index) => {
+				self.shared_memory.remove(index).unmap(&mut self.page_table.0, &mut self.memory.lock());
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+impl TCB {
+	fn run(&mut self, process: &mut PCB) {
+		RUNNING.get().store(true, Ordering::Relaxed);
+		PROC_COUNTER.get().store(0, Ordering::Relaxed);
+		let now = get_ticks().0;
+		USER_ENTRY.get().store(now, Ordering::Relaxed);
+		RUN_START.get().store(now, Ordering::Relaxed);
 
 		// Switch to process page table
 		unsafe {
-			paging::set_page_table(&self.page_table.0);
+			paging::set_page_table(&process.page_table.0);
 		}
+		memory::set_running(process.memory.clone());
+		FsBase::write(self.fs_base);
+		smp::set_kernel_stack(self.kernel_stack.top());
+
+		// Other CPUs may enter the kernel once this one returns to user mode. Nothing the kernel
+		// lock protects, like this thread, is touched after it is released.
 		match self.state {
 			State::New(data) => unsafe {
+				smp::unlock_kernel();
 				// serial_println!("Going to ring3 - start: {:?} stack: {:?}", start, stack);
-				syscalls::go_to_ring3(data.entry, data.stack_top, data.argc, data.argv.as_u64() as usize);
+				// Everything the process starts with is on its stack
+				syscalls::go_to_ring3(data.entry, data.stack_top, 0, 0, 0);
+			},
+			State::NewThread { entry, stack_top, arg } => unsafe {
+				smp::unlock_kernel();
+				syscalls::go_to_ring3(entry, stack_top, arg, 0, 0);
 			},
 			State::Timer {
 				mut registers,
@@ -357,6 +1161,7 @@ impl PCB {
 				rflags,
 			} => {
 				// serial_println!("restoring {:?}", registers);
+				smp::unlock_kernel();
 				unsafe {
 					use crate::cpu::gdt::GDT;
 					let cs_idx: u16 = GDT.1.user_code_selector.0;
@@ -407,30 +1212,32 @@ impl PCB {
 					pop rcx
 					pop rax
 					pop rsp
+					swapgs
 					iretq",
 
 					addr = in(reg) start_addr,
 						);
 				}
 			}
+			State::Kernel { rsp } => unsafe {
+				// Return from switch_from_kernel_stack into the syscall that blocked
+				asm!(
+					"mov rsp, {rsp}",
+					"
+					pop r15
+					pop r14
+					pop r13
+					pop r12
+					pop rbx
+					pop rbp",
+					"ret",
+					rsp = in(reg) rsp,
+					options(noreturn)
+				);
+			},
 			State::Syscall { registers } => {
 				// serial_println!("restoring {:?}", registers);
-
-				if let BlockState::Blocked {
-					still: false,
-					data: BlockData::Input { slice },
-				} = self.block_state
-				{
-					let slice = unsafe { slice.as_mut().unwrap() };
-					use core::cmp::min;
-
-					let buffer: &mut String = &mut self.input_buffer;
-
-					let amount_to_take = min(buffer.len(), slice.len());
-					slice[..amount_to_take].copy_from_slice(&buffer.as_bytes()[..amount_to_take]);
-
-					buffer.drain(0..amount_to_take);
-				}
+				smp::unlock_kernel();
 
 				unsafe {
 					let start_addr: *const Registers = &registers;
@@ -456,6 +1263,7 @@ impl PCB {
 					pop rcx
 					pop rax
 					pop rsp",
+					"swapgs",
 					"sysretq",
 					addr = in(reg) start_addr,
 					options(noreturn)
@@ -506,115 +1314,244 @@ impl PCB {
 	}
 }
 
-/// Run the next process in the queue
+/// Size of the stack the scheduler runs on
+const SCHEDULER_STACK_SIZE: usize = 4096 * 8;
+
+/// Stack the scheduler of each CPU runs on, so that it never runs on the kernel stack of a removed
+/// thread
+static mut SCHEDULER_STACKS: [[u8; SCHEDULER_STACK_SIZE]; MAX_CPUS] = [[0; SCHEDULER_STACK_SIZE]; MAX_CPUS];
+
+/// Run the next process in the queue of this CPU. The kernel lock must be held.
 pub fn run_next_process() -> ! {
+	RUNNING.get().store(false, Ordering::Relaxed);
+	unsafe {
+		let stack = &SCHEDULER_STACKS[cpu_index()];
+		let stack_top = (VirtAddr::from_ptr(stack.as_ptr()) + SCHEDULER_STACK_SIZE).align_down(16u64);
+		asm!(
+			"mov rsp, {stack}",
+			"call {schedule}",
+			stack = in(reg) stack_top.as_u64(),
+			schedule = in(reg) schedule as extern "C" fn() -> !,
+			options(noreturn)
+		);
+	}
+}
+
+/// Run the next thread that is ready on this CPU, on its scheduler stack. Between threads the
+/// CPU waits for interrupts without the kernel lock.
+extern "C" fn schedule() -> ! {
+	// Stop using what the thread that ran here used, so it can be freed if it was removed
 	unsafe {
-		RUNNING = false;
+		paging::set_page_table_to_kernel();
+	}
+	CURRENT.get().store(NONE, Ordering::SeqCst);
+	CURRENT_PROCESS.get().store(NONE, Ordering::SeqCst);
+	REMOVED.get().store(false, Ordering::SeqCst);
+	{
+		let cpu = 1 << cpu_index();
+		let mut retired = RETIRED.lock();
+		for (cpus, _) in retired.iter_mut() {
+			*cpus &= !cpu;
+		}
+		retired.retain(|(cpus, _)| *cpus != 0);
 	}
+
 	loop {
 		x86_64::instructions::interrupts::disable();
-		let len = QUEUE.lock().len();
-		for _ in 0..len {
-			let pid = QUEUE.lock()[0];
-			let mut lock = MAP.lock();
-			// serial_print!("{} ", pid);
-			let process = lock.get_mut(&pid).expect("process from queue not in hashmap");
-			if process.block_state.ready() {
-				unsafe {
-					MAP.force_unlock();
-				}
-				process.run_proc();
-			} else {
-				// serial_println!("blocked");
+		smp::lock_kernel();
+		let next = {
+			let mut scheduler = SCHEDULER.lock();
+			scheduler.next(cpu_index()).map(|tid| (tid, scheduler.quantum(tid)))
+		};
+		if let Some((tid, quantum)) = next {
+			let mut processes = MAP.lock();
+			let mut threads = THREADS.lock();
+			// serial_print!("{} ", tid);
+			let thread = threads.get_mut(&tid).expect("scheduled thread not in hashmap");
+			let process = processes
+				.get_mut(&thread.process)
+				.expect("thread's process not in hashmap");
+			CURRENT.get().store(tid, Ordering::SeqCst);
+			CURRENT_PROCESS.get().store(thread.process, Ordering::SeqCst);
+			PROC_QUANTUM.get().store(quantum, Ordering::Relaxed);
+			unsafe {
+				THREADS.force_unlock();
+				MAP.force_unlock();
 			}
-			cycle();
+			thread.run(process);
 		}
+		smp::unlock_kernel();
 		x86_64::instructions::interrupts::enable();
 		x86_64::instructions::hlt();
 	}
 }
 
-/// Remvoe the currently running process
-pub fn remove_current_process() -> ! {
-	let removing_pid: Pid = *QUEUE.lock().front().expect("No processes");
-	remove_process(removing_pid);
+/// Remvoe the currently running process, which ended with the status
+pub fn remove_current_process(status: ExitStatus) -> ! {
+	let removing_pid: Pid = running_process();
+	remove_process(removing_pid, status);
 
 	run_next_process();
 }
 
-/// Remove a process from running
-pub fn remove_process(removing_pid: Pid) {
-	let mut queue = QUEUE.lock();
-	if let Some((index, _)) = queue.iter().enumerate().find(|(_, p)| **p == removing_pid) {
-		queue.remove(index);
-		{
-			let mut lock = MAP.lock();
-			let prev_value = lock.remove(&removing_pid);
-			assert!(prev_value.is_some());
-			let pcb = prev_value.unwrap();
-			let time = get_time();
-
-			serial_println!("Process lasted: {:?}", time - pcb.start_time);
-
-			for pid in pcb.waiting_processes {
-				let process = lock.get_mut(&pid).unwrap();
-				match &mut process.block_state {
-					BlockState::Blocked {
-						still,
-						data: BlockData::Wait(waiting_pid),
-					} if *waiting_pid == removing_pid => {
-						*still = false;
-					}
-					_ => {}
-				}
+/// Remove a process from running, telling the threads waiting for it how it ended
+pub fn remove_process(removing_pid: Pid, status: ExitStatus) {
+	let mut lock = MAP.lock();
+	if let Some(mut pcb) = lock.remove(&removing_pid) {
+		let mut threads = THREADS.lock();
+		for tid in pcb.threads.iter() {
+			if let Some(thread) = threads.remove(tid) {
+				retire_thread(thread);
 			}
+			SCHEDULER.lock().remove(*tid);
+		}
+		drop(threads);
+		let time = get_time();
+
+		serial_println!("Process lasted: {:?}", time - pcb.start_time);
 
-			if lock.is_empty() {
-				crate::end();
+		let mut total_usage = pcb.usage;
+		total_usage += pcb.children_usage;
+		// Each thread still waiting gets the record, which it takes once it runs
+		let waiting: Vec<Tid> = pcb.waiting_threads.waiting().collect();
+		let threads = THREADS.lock();
+		for tid in waiting.iter() {
+			if let Some(process) = threads.get(tid).and_then(|thread| lock.get_mut(&thread.process)) {
+				process.exit_records.insert(*tid, WaitRecord::from(&status));
+				process.children_usage += total_usage;
 			}
 		}
+		drop(threads);
+		pcb.waiting_threads.wake_all(0);
+		// The page table goes before the shared memory, CPUs running threads of the process may
+		// still have it loaded
+		retire(cpus_in(removing_pid), Box::new((pcb.page_table, pcb.shared_memory)));
+
+		if lock.is_empty() {
+			crate::end(crate::Shutdown::PowerOff);
+		}
 	} else {
 		serial_println!("fuck");
 	}
 }
 
-fn cycle() {
+/// Remove the currently running thread. If it is the last thread of its process, the whole
+/// process is removed.
+pub fn remove_current_thread() -> ! {
+	let removing_tid = running_thread();
+	let pid = running_process();
 	{
-		let mut lock = QUEUE.lock();
-		let popped = lock.pop_front().expect("No processes in queue");
-		lock.push_back(popped);
+		let mut processes = MAP.lock();
+		let process = processes.get_mut(&pid).expect("running process not in hashmap");
+		if process.threads.len() > 1 {
+			process.threads.retain(|tid| *tid != removing_tid);
+			let mut threads = THREADS.lock();
+			let mut thread = threads.remove(&removing_tid).expect("running thread not in hashmap");
+			SCHEDULER.lock().remove(removing_tid);
+			// The thread is on its kernel stack now, so its stack in userspace can go
+			if let Some(stack_top) = thread.stack_top {
+				process.memory.lock().remove_thread_stack(stack_top);
+			}
+			let mut joining = replace(&mut thread.joining_threads, WaitQueue::new());
+			retire_thread(thread);
+			drop(threads);
+			drop(processes);
+			joining.wake_all(0);
+			run_next_process();
+		}
 	}
+	remove_current_process(ExitStatus::Exited(0));
 }
 
-/// Context switch to next process
+/// Context switch to next thread
 pub fn context_switch(state: State) -> ! {
 	{
-		let pid: Pid = QUEUE.lock()[0];
-		let mut lock = MAP.lock();
-		let mut process = lock.get_mut(&pid).expect("process from queue not in hashmap");
-		process.state = state;
+		let tid: Tid = running_thread();
+		let ran: Duration = (get_ticks() - Ticks(RUN_START.get().load(Ordering::Relaxed))).into();
+		let preempted = matches!(state, State::Timer { .. });
+		let mut processes = MAP.lock();
+		let mut threads = THREADS.lock();
+		// Another CPU may have removed the thread while this one was entering the kernel
+		if let (Some(process), Some(thread)) = (processes.get_mut(&running_process()), threads.get_mut(&tid)) {
+			process.usage.context_switches += 1;
+			thread.state = state;
+			if thread.block_state.ready() {
+				SCHEDULER.lock().requeue(tid, ran, preempted);
+			} else {
+				SCHEDULER.lock().block(tid, ran);
+			}
+		}
 	}
-	cycle();
 
 	run_next_process()
 }
 
-/// Add a new process to the queue
-pub fn add_process(executable_path: &str, args: &[&str], term: Option<usize>) -> Result<Pid, elf::ElfErr> {
+/// Add a new process to the scheduler, running as the given credentials, niceness and personality
+pub fn add_process(
+	executable_path: &str,
+	args: &[&str],
+	term: Option<usize>,
+	credentials: Credentials,
+	nice: Nice,
+	limits: Limits,
+	personality: Personality,
+) -> Result<Pid, elf::ElfErr> {
 	let new_pid = get_new_pid();
-	let process = create_process(executable_path, args, term, new_pid)?;
+	let (process, data) = create_process(
+		executable_path,
+		args,
+		term,
+		new_pid,
+		credentials,
+		nice,
+		limits,
+		personality,
+	)?;
 
-	QUEUE.lock().push_back(new_pid);
 	let prev_key = MAP.lock().insert(new_pid, process);
 	assert!(prev_key.is_none());
+	let prev_key = THREADS
+		.lock()
+		.insert(new_pid, TCB::new(new_pid, new_pid, State::New(data)));
+	assert!(prev_key.is_none());
+	SCHEDULER.lock().add(new_pid, nice);
 	Ok(new_pid)
 }
 
+/// Add a new thread to the running process, starting at `entry` with `arg` as the first
+/// arguement. It gets a stack of `stack_size` bytes with a guard region below it, which is freed
+/// when it exits. Returns [None] if there is no room for the stack.
+pub fn add_thread(entry: VirtAddr, stack_size: u64, arg: usize) -> Option<Tid> {
+	let new_tid = get_new_pid();
+	let pid = running_process();
+	let (nice, stack_top) = {
+		let mut processes = MAP.lock();
+		let process = processes.get_mut(&pid).expect("running process not in hashmap");
+		let stack_top = process.memory.lock().add_thread_stack(stack_size)?;
+		process.threads.push(new_tid);
+		(process.nice, stack_top)
+	};
+	// The stack must be 16 byte aligned before the call that would have pushed the return address
+	let state = State::NewThread {
+		entry,
+		stack_top: stack_top - 8u64,
+		arg,
+	};
+	let mut thread = TCB::new(new_tid, pid, state);
+	thread.stack_top = Some(stack_top);
+	let prev_key = THREADS.lock().insert(new_tid, thread);
+	assert!(prev_key.is_none());
+	SCHEDULER.lock().add(new_tid, nice);
+	Some(new_tid)
+}
+
+/// Get an identifier that isn't used by any process or thread
 fn get_new_pid() -> Pid {
 	let mut pid = 0;
-	let lock = MAP.lock();
+	let processes = MAP.lock();
+	let threads = THREADS.lock();
 	loop {
-		if !lock.contains_key(&pid) {
+		if !processes.contains_key(&pid) && !threads.contains_key(&pid) {
 			break;
 		}
 		pid += 1;
@@ -622,20 +1559,56 @@ fn get_new_pid() -> Pid {
 	pid
 }
 
-fn create_process(executable_path: &str, args: &[&str], term: Option<usize>, pid: Pid) -> Result<PCB, elf::ElfErr> {
+#[allow(clippy::too_many_arguments)]
+fn create_process(
+	executable_path: &str,
+	args: &[&str],
+	term: Option<usize>,
+	pid: Pid,
+	credentials: Credentials,
+	nice: Nice,
+	limits: Limits,
+	personality: Personality,
+) -> Result<(PCB, elf::LoadData), elf::ElfErr> {
 	let terminal = term.unwrap_or_else(|| crate::io::buffer::active_term());
+	let (executable_path, args) = script::resolve(executable_path, args, &credentials)?;
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
 	let mut page_table = paging::get_new_user_table();
-	let data = elf::load_elf(executable_path, &mut page_table.0, args)?;
-	Ok(PCB {
-		state: State::New(data),
+	let mut memory = MemoryMap::new();
+	let data = elf::load_elf(
+		&executable_path,
+		&mut page_table.0,
+		&mut memory,
+		limits.stack_size,
+		!personality.contains(Personality::NO_RANDOMIZE),
+		&args,
+		&credentials,
+	)?;
+	let pcb = PCB {
+		threads: vec![pid],
 		input_buffer: String::new(),
-		block_state: BlockState::Ready,
-		open_files: OpenFiles::new(),
-		waiting_processes: Vec::new(),
+		// Linux executables expect the terminal at the first three file descriptors
+		open_files: OpenFiles::new(match data.abi {
+			Abi::Native => 0,
+			Abi::Linux => 3,
+		}),
+		waiting_threads: WaitQueue::new(),
+		exit_records: HashMap::new(),
 		start_time: get_time(),
-		command: executable_path.to_string(),
+		command: executable_path,
+		args: args.iter().map(|arg| arg.to_string()).collect(),
 		pid,
 		page_table,
+		memory: Arc::new(Mutex::new(memory)),
+		shared_memory: Vec::new(),
 		terminal,
-	})
+		credentials,
+		nice,
+		limits,
+		personality,
+		abi: data.abi,
+		usage: Usage::default(),
+		children_usage: Usage::default(),
+	};
+	Ok((pcb, data))
 }
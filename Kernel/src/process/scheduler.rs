@@ -0,0 +1,454 @@
+use super::{Tid, MAP, THREADS};
+use crate::cpu::{
+	pit::{get_pit_count, TIMER_MILLIS},
+	smp::{cpu_count, MAX_CPUS},
+};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::time::Duration;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Niceness of a process. Higher is nicer to other processes, meaning a lower priority
+pub type Nice = i8;
+
+/// Highest priority a process can have
+pub const MIN_NICE: Nice = -20;
+/// Lowest priority a process can have
+pub const MAX_NICE: Nice = 19;
+/// Niceness of processes started by the kernel
+pub const DEFAULT_NICE: Nice = 0;
+
+/// Clamp a requested niceness to the valid range
+pub fn clamp_nice(nice: i64) -> Nice {
+	nice.clamp(MIN_NICE as i64, MAX_NICE as i64) as Nice
+}
+
+lazy_static! {
+	/// The run queues deciding which thread runs next on each CPU
+	pub static ref SCHEDULER: Mutex<RunQueues> = Mutex::new(RunQueues::new(Policy::Mlfq));
+}
+
+/// A scheduling policy, deciding which of the ready threads runs next and for how long. Threads
+/// get the niceness of their process.
+///
+/// Threads are either ready, blocked or running. The running thread isn't in any of the
+/// scheduler's sets, it is handed back with [Scheduler::requeue] or [Scheduler::block] when it
+/// stops running.
+pub trait Scheduler: Send {
+	/// Name of the policy
+	fn name(&self) -> &'static str;
+
+	/// Add a new thread that is ready to run
+	fn add(&mut self, tid: Tid, nice: Nice);
+
+	/// Forget about a thread
+	fn remove(&mut self, tid: Tid);
+
+	/// Take the thread that should run next out of the ready set
+	fn next(&mut self) -> Option<Tid>;
+
+	/// How many timer interrupts the thread may run for before it is preempted
+	fn quantum(&self, tid: Tid) -> usize;
+
+	/// The running thread stopped after running for `ran`, and is still ready to run.
+	/// `preempted` is set if it was stopped because its quantum ran out.
+	fn requeue(&mut self, tid: Tid, ran: Duration, preempted: bool);
+
+	/// The running thread stopped after running for `ran`, because it blocked
+	fn block(&mut self, tid: Tid, ran: Duration);
+
+	/// A blocked thread became ready to run
+	fn unblock(&mut self, tid: Tid);
+
+	/// Set the niceness of a thread in any state, without changing its state
+	fn set_nice(&mut self, tid: Tid, nice: Nice);
+
+	/// Number of threads that are ready to run
+	fn ready(&self) -> usize;
+
+	/// Forget about the ready thread that would run last, so that another CPU can run it. Returns
+	/// it with its niceness.
+	fn steal(&mut self) -> Option<(Tid, Nice)>;
+}
+
+/// The available scheduling policies
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Policy {
+	/// [RoundRobin]
+	RoundRobin = 0,
+	/// [Mlfq]
+	Mlfq = 1,
+}
+
+impl Policy {
+	/// Get a policy by its number
+	pub fn from_number(number: u64) -> Option<Self> {
+		match number {
+			0 => Some(Policy::RoundRobin),
+			1 => Some(Policy::Mlfq),
+			_ => None,
+		}
+	}
+
+	fn create(self) -> Box<dyn Scheduler> {
+		match self {
+			Policy::RoundRobin => Box::new(RoundRobin::new()),
+			Policy::Mlfq => Box::new(Mlfq::new()),
+		}
+	}
+}
+
+/// A scheduler of the same policy for each CPU, each with its own threads. New threads go to the
+/// CPU with the fewest threads, and a CPU that has nothing ready to run steals a thread from the
+/// CPU with the most ready threads.
+///
+/// Threads stay on the CPU they were given until they are stolen, the methods that take a [Tid]
+/// pass it on to the scheduler of its CPU.
+pub struct RunQueues {
+	queues: Vec<Box<dyn Scheduler>>,
+	/// The CPU each thread is on
+	cpus: HashMap<Tid, usize>,
+}
+
+impl RunQueues {
+	fn new(policy: Policy) -> Self {
+		Self {
+			queues: (0..MAX_CPUS).map(|_| policy.create()).collect(),
+			cpus: HashMap::new(),
+		}
+	}
+
+	fn queue(&mut self, tid: Tid) -> &mut Box<dyn Scheduler> {
+		let cpu = *self.cpus.get(&tid).unwrap_or(&0);
+		&mut self.queues[cpu]
+	}
+
+	/// Name of the policy
+	pub fn name(&self) -> &'static str {
+		self.queues[0].name()
+	}
+
+	/// Add a new thread that is ready to run, on the CPU with the fewest threads
+	pub fn add(&mut self, tid: Tid, nice: Nice) {
+		let mut threads = [0; MAX_CPUS];
+		for cpu in self.cpus.values() {
+			threads[*cpu] += 1;
+		}
+		let cpu = (0..cpu_count()).min_by_key(|cpu| threads[*cpu]).unwrap_or(0);
+		self.add_to(cpu, tid, nice);
+	}
+
+	fn add_to(&mut self, cpu: usize, tid: Tid, nice: Nice) {
+		self.cpus.insert(tid, cpu);
+		self.queues[cpu].add(tid, nice);
+	}
+
+	/// Forget about a thread
+	pub fn remove(&mut self, tid: Tid) {
+		self.queue(tid).remove(tid);
+		self.cpus.remove(&tid);
+	}
+
+	/// Take the thread that should run next on the CPU out of the ready set, stealing one from
+	/// the busiest CPU if it has none
+	pub fn next(&mut self, cpu: usize) -> Option<Tid> {
+		if self.queues[cpu].ready() == 0 {
+			let busiest = (0..cpu_count())
+				.filter(|other| *other != cpu)
+				.max_by_key(|other| self.queues[*other].ready())?;
+			if let Some((tid, nice)) = self.queues[busiest].steal() {
+				self.add_to(cpu, tid, nice);
+			}
+		}
+		self.queues[cpu].next()
+	}
+
+	/// How many timer interrupts the thread may run for before it is preempted
+	pub fn quantum(&mut self, tid: Tid) -> usize {
+		self.queue(tid).quantum(tid)
+	}
+
+	/// The running thread stopped after running for `ran`, and is still ready to run
+	pub fn requeue(&mut self, tid: Tid, ran: Duration, preempted: bool) {
+		self.queue(tid).requeue(tid, ran, preempted);
+	}
+
+	/// The running thread stopped after running for `ran`, because it blocked
+	pub fn block(&mut self, tid: Tid, ran: Duration) {
+		self.queue(tid).block(tid, ran);
+	}
+
+	/// A blocked thread became ready to run
+	pub fn unblock(&mut self, tid: Tid) {
+		self.queue(tid).unblock(tid);
+	}
+
+	/// Set the niceness of a thread in any state, without changing its state
+	pub fn set_nice(&mut self, tid: Tid, nice: Nice) {
+		self.queue(tid).set_nice(tid, nice);
+	}
+}
+
+/// Replace the schedulers with new ones of the given policy, moving all threads over to them.
+/// Threads stay on their CPUs.
+pub fn set_policy(policy: Policy) {
+	let processes = MAP.lock();
+	let threads = THREADS.lock();
+	let mut run_queues = SCHEDULER.lock();
+	let mut new = RunQueues::new(policy);
+	for (tid, thread) in threads.iter() {
+		let nice = processes
+			.get(&thread.process)
+			.expect("thread's process not in hashmap")
+			.nice;
+		let cpu = *run_queues.cpus.get(tid).unwrap_or(&0);
+		let scheduler = &mut new.queues[cpu];
+		if super::running_anywhere(*tid) {
+			scheduler.set_nice(*tid, nice);
+		} else if thread.block_state.ready() {
+			scheduler.add(*tid, nice);
+		} else {
+			scheduler.set_nice(*tid, nice);
+			scheduler.block(*tid, Duration::ZERO);
+		}
+		new.cpus.insert(*tid, cpu);
+	}
+	*run_queues = new;
+}
+
+/// Number of timer interrupts a thread with a niceness of 0 runs for under [RoundRobin]
+const ROUND_ROBIN_QUANTUM: usize = 5;
+
+/// Plain round robin over the ready threads. Nicer threads get shorter quanta.
+pub struct RoundRobin {
+	ready: VecDeque<Tid>,
+	blocked: HashSet<Tid>,
+	nice: HashMap<Tid, Nice>,
+}
+
+impl RoundRobin {
+	fn new() -> Self {
+		Self {
+			ready: VecDeque::new(),
+			blocked: HashSet::new(),
+			nice: HashMap::new(),
+		}
+	}
+}
+
+impl Scheduler for RoundRobin {
+	fn name(&self) -> &'static str {
+		"Round Robin"
+	}
+
+	fn add(&mut self, tid: Tid, nice: Nice) {
+		self.nice.insert(tid, nice);
+		self.ready.push_back(tid);
+	}
+
+	fn remove(&mut self, tid: Tid) {
+		self.nice.remove(&tid);
+		self.blocked.remove(&tid);
+		self.ready.retain(|p| *p != tid);
+	}
+
+	fn next(&mut self) -> Option<Tid> {
+		self.ready.pop_front()
+	}
+
+	fn quantum(&self, tid: Tid) -> usize {
+		let nice = *self.nice.get(&tid).unwrap_or(&DEFAULT_NICE) as isize;
+		// -20 => 9, 0 => 5, 19 => 2
+		(ROUND_ROBIN_QUANTUM as isize - nice / 5) as usize
+	}
+
+	fn requeue(&mut self, tid: Tid, _ran: Duration, _preempted: bool) {
+		self.ready.push_back(tid);
+	}
+
+	fn block(&mut self, tid: Tid, _ran: Duration) {
+		self.blocked.insert(tid);
+	}
+
+	fn unblock(&mut self, tid: Tid) {
+		if self.blocked.remove(&tid) {
+			self.ready.push_back(tid);
+		}
+	}
+
+	fn set_nice(&mut self, tid: Tid, nice: Nice) {
+		self.nice.insert(tid, nice);
+	}
+
+	fn ready(&self) -> usize {
+		self.ready.len()
+	}
+
+	fn steal(&mut self) -> Option<(Tid, Nice)> {
+		let tid = self.ready.pop_back()?;
+		let nice = self.nice.remove(&tid).unwrap_or(DEFAULT_NICE);
+		Some((tid, nice))
+	}
+}
+
+/// Number of priority levels in the [Mlfq]
+const LEVELS: usize = 8;
+/// How often (in timer interrupts) all threads are boosted back to their starting level
+const BOOST_INTERVAL: usize = 100;
+
+/// Scheduling information the [Mlfq] keeps on a thread
+struct MlfqEntry {
+	nice: Nice,
+	/// How many levels the thread has been demoted since the last boost
+	demotion: usize,
+	/// CPU time used at the current level
+	used: Duration,
+}
+
+impl MlfqEntry {
+	fn level(&self) -> usize {
+		// -20 => 0, 0 => 2, 19 => 4
+		let start = ((self.nice - MIN_NICE) / 8) as usize;
+		(start + self.demotion).min(LEVELS - 1)
+	}
+}
+
+/// Multi level feedback queue. The first ready thread of the highest priority level runs.
+///
+/// A thread starts at a level set by its niceness, and is demoted a level every time it uses
+/// up the quantum of its level, whether in one go or over many short runs. Processes that block
+/// a lot (like shells waiting for input) stay at high priority, while ones that keep computing
+/// sink down, where they get longer quanta. Every [BOOST_INTERVAL] all threads are boosted
+/// back to their starting level, so no thread starves.
+pub struct Mlfq {
+	levels: [VecDeque<Tid>; LEVELS],
+	blocked: HashSet<Tid>,
+	entries: HashMap<Tid, MlfqEntry>,
+	last_boost: usize,
+}
+
+impl Mlfq {
+	fn new() -> Self {
+		Self {
+			levels: Default::default(),
+			blocked: HashSet::new(),
+			entries: HashMap::new(),
+			last_boost: get_pit_count(),
+		}
+	}
+
+	fn level_quantum(level: usize) -> usize {
+		2 * (level + 1)
+	}
+
+	fn entry(&mut self, tid: Tid) -> &mut MlfqEntry {
+		self.entries.entry(tid).or_insert(MlfqEntry {
+			nice: DEFAULT_NICE,
+			demotion: 0,
+			used: Duration::ZERO,
+		})
+	}
+
+	/// Account the time the thread ran, demoting it if it used up its quantum
+	fn charge(&mut self, tid: Tid, ran: Duration, preempted: bool) {
+		let entry = self.entry(tid);
+		entry.used += ran;
+		let quantum = Duration::from_millis((Self::level_quantum(entry.level()) as u64) * TIMER_MILLIS);
+		if preempted || entry.used >= quantum {
+			entry.demotion += 1;
+			entry.used = Duration::ZERO;
+		}
+	}
+
+	fn push_ready(&mut self, tid: Tid) {
+		let level = self.entry(tid).level();
+		self.levels[level].push_back(tid);
+	}
+
+	fn boost(&mut self) {
+		for entry in self.entries.values_mut() {
+			entry.demotion = 0;
+			entry.used = Duration::ZERO;
+		}
+		let mut ready = VecDeque::new();
+		for level in self.levels.iter_mut() {
+			ready.append(level);
+		}
+		for tid in ready {
+			self.push_ready(tid);
+		}
+	}
+}
+
+impl Scheduler for Mlfq {
+	fn name(&self) -> &'static str {
+		"Multi Level Feedback Queue"
+	}
+
+	fn add(&mut self, tid: Tid, nice: Nice) {
+		self.set_nice(tid, nice);
+		self.push_ready(tid);
+	}
+
+	fn remove(&mut self, tid: Tid) {
+		self.entries.remove(&tid);
+		self.blocked.remove(&tid);
+		for level in self.levels.iter_mut() {
+			level.retain(|p| *p != tid);
+		}
+	}
+
+	fn next(&mut self) -> Option<Tid> {
+		let now = get_pit_count();
+		if now - self.last_boost >= BOOST_INTERVAL {
+			self.last_boost = now;
+			self.boost();
+		}
+		self.levels.iter_mut().find_map(|level| level.pop_front())
+	}
+
+	fn quantum(&self, tid: Tid) -> usize {
+		let level = self.entries.get(&tid).map(|entry| entry.level()).unwrap_or(0);
+		Self::level_quantum(level)
+	}
+
+	fn requeue(&mut self, tid: Tid, ran: Duration, preempted: bool) {
+		self.charge(tid, ran, preempted);
+		self.push_ready(tid);
+	}
+
+	fn block(&mut self, tid: Tid, ran: Duration) {
+		self.charge(tid, ran, false);
+		self.blocked.insert(tid);
+	}
+
+	fn unblock(&mut self, tid: Tid) {
+		if self.blocked.remove(&tid) {
+			self.push_ready(tid);
+		}
+	}
+
+	fn set_nice(&mut self, tid: Tid, nice: Nice) {
+		let old_level = self.entry(tid).level();
+		self.entry(tid).nice = nice;
+		let new_level = self.entry(tid).level();
+		if old_level != new_level {
+			let queue = &mut self.levels[old_level];
+			if let Some(index) = queue.iter().position(|p| *p == tid) {
+				queue.remove(index);
+				self.levels[new_level].push_back(tid);
+			}
+		}
+	}
+
+	fn ready(&self) -> usize {
+		self.levels.iter().map(VecDeque::len).sum()
+	}
+
+	fn steal(&mut self) -> Option<(Tid, Nice)> {
+		let tid = self.levels.iter_mut().rev().find_map(|level| level.pop_back())?;
+		let nice = self.entries.remove(&tid).map(|entry| entry.nice).unwrap_or(DEFAULT_NICE);
+		Some((tid, nice))
+	}
+}
@@ -0,0 +1,63 @@
+use super::{elf::ElfErr, Credentials};
+use crate::{
+	fs::ext2::{self, Access, Ext2Err, File},
+	util::io::Read,
+};
+use alloc::{
+	string::{String, ToString},
+	vec::Vec,
+};
+use core::str::from_utf8;
+
+/// How many scripts can be run by each other before the executable that runs them, like Linux
+const MAX_SCRIPT_DEPTH: usize = 4;
+/// The `#!` line can't be longer than this, including the newline
+const MAX_LINE_SIZE: usize = 256;
+
+/// Find the executable that runs the file at `path`. Scripts starting with `#!` are run by the
+/// interpreter named on that line, which gets the optional arguement after it, the path of the
+/// script and then the arguements of the script. Interpreters may be scripts themselves, up to
+/// [MAX_SCRIPT_DEPTH]. Returns the path of the executable and its arguements.
+pub fn resolve(path: &str, args: &[&str], credentials: &Credentials) -> Result<(String, Vec<String>), ElfErr> {
+	let mut path = path.to_string();
+	let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+	for _ in 0..=MAX_SCRIPT_DEPTH {
+		ext2::access(&path, credentials, Access::EXECUTE)?;
+		let mut file = File::open_read(&path)?;
+		let mut buffer = [0u8; MAX_LINE_SIZE];
+		let mut len = 0;
+		while len < buffer.len() {
+			match file.read(&mut buffer[len..]).map_err(Ext2Err::from)? {
+				0 => break,
+				read => len += read,
+			}
+		}
+		if !buffer[..len].starts_with(b"#!") {
+			return Ok((path, args));
+		}
+		// A script without a newline is just the line
+		let end = match buffer[..len].iter().position(|&byte| byte == b'\n') {
+			Some(end) => end,
+			None if len < buffer.len() => len,
+			None => return Err(ElfErr::BadInterpreter),
+		};
+		let line = from_utf8(&buffer[2..end]).map_err(|_| ElfErr::BadInterpreter)?.trim();
+		let (interpreter, arg) = match line.split_once(|c: char| c == ' ' || c == '\t') {
+			Some((interpreter, arg)) => (interpreter, arg.trim()),
+			None => (line, ""),
+		};
+		if interpreter.is_empty() {
+			return Err(ElfErr::BadInterpreter);
+		}
+		// Like Linux, everything after the interpreter is a single arguement
+		let mut interpreter_args = Vec::with_capacity(args.len() + 2);
+		if !arg.is_empty() {
+			interpreter_args.push(arg.to_string());
+		}
+		interpreter_args.push(path);
+		interpreter_args.append(&mut args);
+		path = interpreter.to_string();
+		args = interpreter_args;
+	}
+	Err(ElfErr::ScriptDepth)
+}
@@ -0,0 +1,119 @@
+use super::Tid;
+use crate::cpu::pit::get_time;
+use alloc::vec::Vec;
+use core::{fmt, time::Duration};
+use spin::Mutex;
+
+/// What [super::block_current] returns when the wait reached its deadline
+pub const TIMED_OUT: i64 = -1;
+
+/// A thread waiting for something, for one of the times it blocks. Once that wait is over, waking
+/// it does nothing, so waiters that were already woken some other way are harmless.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Waiter {
+	/// The waiting thread
+	pub tid: Tid,
+	/// Which of the thread's waits, unique among all waits of all threads
+	pub(super) wait: u64,
+}
+
+impl Waiter {
+	/// The running thread, for the next time it blocks
+	pub fn current() -> Self {
+		super::current_waiter()
+	}
+
+	/// Wake the thread if it is still in the same wait, making [super::block_current] return
+	/// `result`. Returns whether it was woken.
+	pub fn wake(self, result: i64) -> bool {
+		super::wake(self, result)
+	}
+
+	/// Whether the thread hasn't been woken from the wait yet
+	pub fn waiting(self) -> bool {
+		super::still_waiting(self)
+	}
+}
+
+/// Threads waiting for something to happen, which whoever makes it happen wakes. A thread adds
+/// itself and then blocks with [super::block_current], after dropping its locks. Threads may be
+/// woken for other reasons too, so they check that what they wait for happened once they are
+/// woken, and wait again if it didn't.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+	waiters: Vec<Waiter>,
+}
+
+impl WaitQueue {
+	/// An empty wait queue
+	pub const fn new() -> Self {
+		Self { waiters: Vec::new() }
+	}
+
+	/// Add a waiter to the end of the queue
+	pub fn add(&mut self, waiter: Waiter) {
+		self.waiters.push(waiter);
+	}
+
+	/// Add the running thread to the end of the queue. [super::THREADS] must not be locked.
+	pub fn add_current(&mut self) {
+		self.add(Waiter::current());
+	}
+
+	/// Wake the first thread in the queue that is still waiting. Returns whether there was one.
+	pub fn wake_one(&mut self, result: i64) -> bool {
+		while !self.waiters.is_empty() {
+			if self.waiters.remove(0).wake(result) {
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Wake all the threads in the queue, returns how many were still waiting
+	pub fn wake_all(&mut self, result: i64) -> usize {
+		self.waiters.drain(..).filter(|waiter| waiter.wake(result)).count()
+	}
+
+	/// The threads in the queue that are still waiting
+	pub fn waiting(&mut self) -> impl Iterator<Item = Tid> + '_ {
+		self.waiters.retain(|waiter| waiter.waiting());
+		self.waiters.iter().map(|waiter| waiter.tid)
+	}
+
+	/// Whether no thread in the queue is still waiting
+	pub fn is_empty(&mut self) -> bool {
+		self.waiting().next().is_none()
+	}
+}
+
+impl fmt::Display for WaitQueue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list().entries(self.waiters.iter().map(|waiter| waiter.tid)).finish()
+	}
+}
+
+/// Waits that give up at a deadline
+static DEADLINES: Mutex<Vec<(Duration, Waiter)>> = Mutex::new(Vec::new());
+
+/// Wake the waiter with [TIMED_OUT] at `deadline`, a time from [get_time]
+pub(super) fn add_deadline(deadline: Duration, waiter: Waiter) {
+	DEADLINES.lock().push((deadline, waiter));
+}
+
+/// Wake the waiters whose deadline passed. Called on every timer interrupt.
+pub fn check_deadlines() {
+	let mut deadlines = DEADLINES.lock();
+	if deadlines.is_empty() {
+		return;
+	}
+	let now = get_time();
+	deadlines.retain(|(deadline, waiter)| {
+		if *deadline <= now {
+			waiter.wake(TIMED_OUT);
+			false
+		} else {
+			true
+		}
+	});
+}
@@ -15,6 +15,12 @@ pub enum IOError {
 	NoData,
 	/// Buffer is not big enough
 	BufferTooSmall,
+	/// The stream doesn't allow this operation
+	PermissionDenied,
+	/// The operation can't make progress yet, it has to wait for the other side of the stream
+	WouldBlock,
+	/// Nobody is reading from the other side of the stream
+	BrokenPipe,
 }
 
 /// Trait allowing reading from a stream
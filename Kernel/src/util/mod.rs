@@ -1,6 +1,9 @@
 /// Module for IO, similair to std::io, which is not available in a no-std project
 pub mod io;
 
+/// Module for random numbers
+pub mod random;
+
 /// Module for dealing with QEMU
 pub mod qemu {
 
@@ -9,13 +12,15 @@ pub mod qemu {
 	// Failed = 0x11,
 	// }
 
-	/// exit QEMU
-	pub fn exit() -> ! {
+	/// exit QEMU, if it has the isa-debug-exit device. Returns if it doesn't
+	pub fn exit() {
 		use x86_64::instructions::port::Port;
 		unsafe {
 			let mut port = Port::new(0xf4);
 			port.write(0x10 as u32);
 		}
-		loop {} // this shouldn't be reached, because the last command shutds down computer, but it proves to the compiler I never return
 	}
 }
+
+/// Module for powering off and rebooting the computer
+pub mod power;
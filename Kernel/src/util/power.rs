@@ -0,0 +1,122 @@
+use super::qemu;
+use crate::{
+	cpu::pit,
+	drivers::acpi::fadt::{self, Register},
+	mem::paging,
+	serial_println,
+};
+use core::time::Duration;
+use x86_64::{
+	instructions::{
+		hlt, interrupts,
+		port::{Port, PortReadOnly, PortWriteOnly},
+		tables::lidt,
+	},
+	structures::DescriptorTablePointer,
+	VirtAddr,
+};
+
+/// Bit of the PM1 control register that is set while the machine is in ACPI mode
+const SCI_ENABLE: u16 = 1 << 0;
+/// Bits of the PM1 control register holding the sleep type
+const SLEEP_TYPE_SHIFT: u16 = 10;
+/// Bit of the PM1 control register that enters the sleep type
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+// The keyboard controller, which can pulse the CPU reset line
+const KEYBOARD_STATUS: u16 = 0x64;
+const KEYBOARD_COMMAND: u16 = 0x64;
+/// Status bit set while the controller hasn't taken the last command yet
+const INPUT_FULL: u8 = 1 << 1;
+/// Command that pulses the reset line
+const PULSE_RESET: u8 = 0xFE;
+
+/// How long to give each way of powering off or resetting before trying the next one
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Turn the computer off. Tries ACPI soft off (S5), then exiting QEMU through its debug exit
+/// device. If both fail, the CPU halts.
+pub fn power_off() -> ! {
+	if let Some(fadt) = fadt::read() {
+		match fadt.s5_sleep_types {
+			Some((type_a, type_b)) => unsafe {
+				enable_acpi(&fadt);
+				let (pm1a, pm1b) = fadt.pm1_control;
+				write_sleep_type(pm1a, type_a);
+				if pm1b != 0 {
+					write_sleep_type(pm1b, type_b);
+				}
+				pit::wait(TIMEOUT);
+				serial_println!("ACPI: the machine didn't power off");
+			},
+			None => serial_println!("ACPI: the DSDT has no S5 sleep type"),
+		}
+	}
+	qemu::exit();
+	serial_println!("Can't power off, halting");
+	interrupts::disable();
+	loop {
+		hlt();
+	}
+}
+
+/// Restart the computer. Tries the ACPI reset register, then the keyboard controller, and as a
+/// last resort makes the CPU triple fault.
+pub fn reboot() -> ! {
+	if let Some(fadt) = fadt::read() {
+		if let Some(register) = fadt.reset_register {
+			unsafe {
+				match register {
+					Register::Io(port) => PortWriteOnly::<u8>::new(port).write(fadt.reset_value),
+					Register::Memory(addr) => {
+						let register = paging::phys_to_virt(addr).as_mut_ptr::<u8>();
+						register.write_volatile(fadt.reset_value);
+					}
+				}
+			}
+			pit::wait(TIMEOUT);
+			serial_println!("ACPI: the reset register didn't reset the machine");
+		}
+	}
+
+	unsafe {
+		let mut status = PortReadOnly::<u8>::new(KEYBOARD_STATUS);
+		while status.read() & INPUT_FULL != 0 {}
+		PortWriteOnly::<u8>::new(KEYBOARD_COMMAND).write(PULSE_RESET);
+	}
+	pit::wait(TIMEOUT);
+	serial_println!("The keyboard controller didn't reset the machine, triple faulting");
+
+	// With an empty interrupt descriptor table, the breakpoint can't be handled, and neither can
+	// the faults that follow
+	interrupts::disable();
+	unsafe {
+		lidt(&DescriptorTablePointer {
+			limit: 0,
+			base: VirtAddr::zero(),
+		});
+		asm!("int3", options(noreturn));
+	}
+}
+
+/// Switch to ACPI mode, if the firmware left the machine in legacy mode
+unsafe fn enable_acpi(fadt: &fadt::Fadt) {
+	let mut control = Port::<u16>::new(fadt.pm1_control.0);
+	if control.read() & SCI_ENABLE != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+		return;
+	}
+	PortWriteOnly::<u8>::new(fadt.smi_command).write(fadt.acpi_enable);
+	for _ in 0..(TIMEOUT.as_millis() as u64 / pit::TIMER_MILLIS) {
+		if control.read() & SCI_ENABLE != 0 {
+			return;
+		}
+		pit::wait_for_pit();
+	}
+}
+
+/// Enter a sleep type through a PM1 control register
+unsafe fn write_sleep_type(port: u16, sleep_type: u8) {
+	let mut control = Port::<u16>::new(port);
+	let value = control.read() & !(0b111 << SLEEP_TYPE_SHIFT);
+	control.write(value | (sleep_type as u16) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+}
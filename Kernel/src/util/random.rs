@@ -0,0 +1,29 @@
+use crate::cpu::pit::get_ticks;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::random::RdRand;
+
+/// State of the fallback generator, seeded from the time stamp counter on first use
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Get a random number. Uses RDRAND when the CPU has it, and otherwise a xorshift generator
+/// seeded from the time stamp counter, which is good enough to randomize addresses but not for
+/// anything secret.
+pub fn random_u64() -> u64 {
+	if let Some(value) = RdRand::new().and_then(RdRand::get_u64) {
+		return value;
+	}
+	let mut state = STATE.load(Ordering::Relaxed);
+	if state == 0 {
+		state = get_ticks().0 | 1;
+	}
+	state ^= state << 13;
+	state ^= state >> 7;
+	state ^= state << 17;
+	STATE.store(state, Ordering::Relaxed);
+	state
+}
+
+/// Get a random number below `bound`, which must not be 0
+pub fn random_below(bound: u64) -> u64 {
+	random_u64() % bound
+}
@@ -18,12 +18,10 @@ pub extern "C" fn main() -> isize {
 		let dest = args[1];
 
 		let mut source = File::open(source).unwrap();
-		let mut dest = File::open(dest).unwrap();
+		let mut dest = File::open_with(dest, OpenFlags::APPEND).unwrap();
 
 		let mut buf = Vec::new();
 		source.read_to_end(&mut buf).unwrap();
-		let mut trash = Vec::new();
-		dest.read_to_end(&mut trash).unwrap();
 		dest.write(&buf).unwrap();
 
 		return 0;
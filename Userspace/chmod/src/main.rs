@@ -0,0 +1,29 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.len() == 2 {
+		match u16::from_str_radix(args[0], 8) {
+			Ok(permissions) => match chmod(args[1], permissions) {
+				Ok(_) => return 0,
+				Err(()) => {
+					println!("Failed to change permissions of {}", args[1]);
+					return -1;
+				}
+			},
+			Err(_) => {
+				println!("Permissions must be an octal number!");
+				return -1;
+			}
+		}
+	} else {
+		println!("Usage: chmod <octal permissions> <path>");
+		return -1;
+	}
+}
@@ -0,0 +1,32 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.len() == 2 {
+		let mut split = args[0].split(':');
+		let uid = split.next().map(|s| s.parse());
+		let gid = split.next().map(|s| s.parse());
+		match (uid, gid) {
+			(Some(Ok(uid)), Some(Ok(gid))) => match chown(args[1], uid, gid) {
+				Ok(_) => return 0,
+				Err(()) => {
+					println!("Failed to change owner of {}", args[1]);
+					return -1;
+				}
+			},
+			_ => {
+				println!("Owner must be of the form uid:gid");
+				return -1;
+			}
+		}
+	} else {
+		println!("Usage: chown <uid>:<gid> <path>");
+		return -1;
+	}
+}
@@ -0,0 +1,8 @@
+use std::env;
+
+fn main() {
+	// libgreeting.so is built next to the executables before this one, as greeting is a dependency
+	let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+	let profile = env::var("PROFILE").unwrap();
+	println!("cargo:rustc-link-search=native={}/../target/x86_64-custom/{}", manifest_dir, profile);
+}
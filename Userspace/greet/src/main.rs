@@ -0,0 +1,27 @@
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+use core::str::from_utf8;
+use standard::{get_args, println};
+
+// Linked when the program is loaded, from /lib/libgreeting.so
+#[link(name = "greeting")]
+extern "C" {
+	fn greeting(name: *const u8, name_len: usize, buffer: *mut u8, capacity: usize) -> usize;
+	fn greeting_count() -> usize;
+}
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+	let names = if args.is_empty() { &["world"][..] } else { args };
+	let mut buffer = [0u8; 128];
+	for name in names {
+		let written = unsafe { greeting(name.as_ptr(), name.len(), buffer.as_mut_ptr(), buffer.len()) };
+		println!("{}", from_utf8(&buffer[..written]).unwrap_or("?"));
+	}
+	println!("{} greetings", unsafe { greeting_count() });
+
+	return 0;
+}
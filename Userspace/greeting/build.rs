@@ -0,0 +1,4 @@
+fn main() {
+	// Executables name the libraries they need by their soname, and the kernel looks for them in /lib
+	println!("cargo:rustc-cdylib-link-arg=-soname=libgreeting.so");
+}
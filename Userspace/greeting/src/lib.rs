@@ -0,0 +1,43 @@
+#![no_std]
+
+use core::{
+	slice,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+const GREETING: &[u8] = b"Hello, ";
+
+/// How many greetings were written, in the writable data of the library
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Write a greeting of the `name_len` bytes at `name` into the buffer, cut at `capacity` bytes.
+/// Returns how many bytes were written.
+///
+/// # Safety
+/// `name` must point to `name_len` readable bytes, and `buffer` to `capacity` writable ones.
+#[no_mangle]
+pub unsafe extern "C" fn greeting(name: *const u8, name_len: usize, buffer: *mut u8, capacity: usize) -> usize {
+	let name = slice::from_raw_parts(name, name_len);
+	let buffer = slice::from_raw_parts_mut(buffer, capacity);
+	let mut written = 0;
+	for (byte, value) in buffer.iter_mut().zip(GREETING.iter().chain(name).chain(b"!")) {
+		*byte = *value;
+		written += 1;
+	}
+	COUNT.fetch_add(1, Ordering::Relaxed);
+	written
+}
+
+/// How many greetings were written by the process
+#[no_mangle]
+pub extern "C" fn greeting_count() -> usize {
+	COUNT.load(Ordering::Relaxed)
+}
+
+// The library can't use the standard library of the executables, it would get its own heap
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+	loop {
+		core::hint::spin_loop();
+	}
+}
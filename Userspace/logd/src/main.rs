@@ -0,0 +1,59 @@
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+use standard::{
+	io::{Read, Write},
+	net::{UnixListener, UnixStream},
+	syscalls::unlink,
+	*,
+};
+
+/// Where the daemon listens
+const SOCKET_PATH: &str = "/logd.sock";
+
+/// A logging daemon. Run `logd &` to start it, and `logd <message>` to log a message through it.
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.is_empty() {
+		serve()
+	} else {
+		let mut message = args.join(" ");
+		message.push('\n');
+		match UnixStream::connect(SOCKET_PATH) {
+			Ok(mut stream) => match stream.write_exact(message.as_bytes()) {
+				Ok(_) => 0,
+				Err(_) => {
+					println!("Failed to send message");
+					-1
+				}
+			},
+			Err(_) => {
+				println!("logd isn't running");
+				-1
+			}
+		}
+	}
+}
+
+fn serve() -> isize {
+	// The socket file of a previous run is left behind
+	let _ = unlink(SOCKET_PATH);
+	let listener = match UnixListener::bind(SOCKET_PATH) {
+		Ok(listener) => listener,
+		Err(_) => {
+			println!("Failed to listen at {}", SOCKET_PATH);
+			return -1;
+		}
+	};
+	for mut stream in listener.incoming() {
+		let mut buf = Vec::new();
+		if stream.read_to_end(&mut buf).is_ok() {
+			print!("[logd] {}", String::from_utf8_lossy(&buf));
+		}
+	}
+	return 0;
+}
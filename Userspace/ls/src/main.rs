@@ -1,7 +1,10 @@
 #![no_main]
 #![no_std]
 
-use standard::{get_args, println, syscalls::Dir};
+use standard::{
+	get_args, println,
+	syscalls::{Dir, FileType},
+};
 extern crate alloc;
 use alloc::string::String;
 
@@ -14,7 +17,10 @@ pub extern "C" fn main() -> isize {
 		Ok(dir) => {
 			let mut string = String::new();
 			for entry in dir {
-				string.push_str(&entry);
+				string.push_str(&entry.name);
+				if entry.file_type == FileType::Directory {
+					string.push('/');
+				}
 				string.push_str("  ");
 			}
 			println!("{}", string);
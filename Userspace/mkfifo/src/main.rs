@@ -0,0 +1,22 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.is_empty() {
+		println!("Usage: mkfifo <path>...");
+		return -1;
+	}
+	for path in args {
+		if mkfifo(path).is_err() {
+			println!("Failed to create FIFO {}", path);
+			return -1;
+		}
+	}
+	return 0;
+}
@@ -0,0 +1,51 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+use alloc::string::ToString;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.len() < 2 {
+		println!("Usage: nice <increment> <command> [args...]");
+		return -1;
+	}
+
+	let increment: i8 = match args[0].parse() {
+		Ok(increment) => increment,
+		Err(_) => {
+			println!("Increment must be a number!");
+			return -1;
+		}
+	};
+
+	// The niceness is inherited, so the command runs with the niceness of this process
+	let pid = getpid();
+	let current = get_nice(pid).unwrap();
+	if set_nice(pid, current.saturating_add(increment)).is_err() {
+		println!("Failed to set niceness, only root can lower it");
+		return -1;
+	}
+
+	let path = if args[1].starts_with("/") {
+		args[1].to_string()
+	} else {
+		let mut path = "/bin/".to_string();
+		path.push_str(args[1]);
+		path
+	};
+
+	match exec(&path, &args[2..]) {
+		Ok(pid) => {
+			wait(pid);
+			0
+		}
+		Err(_) => {
+			println!("Failed to run {}", path);
+			-1
+		}
+	}
+}
@@ -0,0 +1,32 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.len() == 2 {
+		match (args[0].parse(), args[1].parse()) {
+			(Ok(nice), Ok(pid)) => match set_nice(pid, nice) {
+				Ok(old) => {
+					println!("{}: old niceness {}, new niceness {}", pid, old, nice);
+					return 0;
+				}
+				Err(()) => {
+					println!("Failed to set niceness of {}", pid);
+					return -1;
+				}
+			},
+			_ => {
+				println!("Niceness and pid must be numbers!");
+				return -1;
+			}
+		}
+	} else {
+		println!("Usage: renice <niceness> <pid>");
+		return -1;
+	}
+}
@@ -2,96 +2,207 @@
 #![no_std]
 
 extern crate alloc;
-use alloc::{string::ToString, vec::Vec};
+use alloc::{
+	string::{String, ToString},
+	vec::Vec,
+};
 use standard::{
-	get_args, print, println,
-	syscalls::{exec, file_exists, info, kill, quit, read_line, wait},
+	get_args,
+	io::Read,
+	print, println,
+	syscalls::{
+		exec, file_exists, info, kill, limit, personality, quit, read_line, reboot, set_scheduler, wait,
+		ExitStatus, File, Resource, SchedulerPolicy, ADDR_NO_RANDOMIZE,
+	},
 };
 
 #[no_mangle]
 pub extern "C" fn main() -> isize {
 	let args = get_args();
+	// Run as the interpreter of a script, which is passed as the first arguement
+	if let Some(path) = args.first().filter(|arg| arg.starts_with('/')) {
+		return run_script(path);
+	}
 	println!("TTY: {}", args[0]);
 
 	loop {
 		print!("GuyOS > ");
 		let input = read_line();
-		match input.as_str() {
-			"exit" => {
-				break;
-			}
-			"quit" => {
-				quit();
-			}
-			"ps" => {
-				info(0, None);
-			}
-			"mem" => {
-				info(4, None);
+		if !run(&input) {
+			break;
+		}
+	}
+	return 0;
+}
+
+/// Run the commands of a script, one per line. Lines starting with `#` are comments.
+fn run_script(path: &str) -> isize {
+	let mut buf = Vec::new();
+	match File::open(path).map(|mut file| file.read_to_end(&mut buf)) {
+		Ok(Ok(_)) => {}
+		_ => {
+			println!("Failed to read {}", path);
+			return -1;
+		}
+	}
+	let script = match String::from_utf8(buf) {
+		Ok(script) => script,
+		Err(_) => {
+			println!("{} is not UTF8", path);
+			return -1;
+		}
+	};
+	for line in script.lines().map(str::trim) {
+		if !line.starts_with('#') && !run(line) {
+			break;
+		}
+	}
+	return 0;
+}
+
+/// Run a command. Returns false if the shell should exit.
+fn run(input: &str) -> bool {
+	match input {
+		"exit" => {
+			return false;
+		}
+		"quit" | "poweroff" => {
+			if quit().is_err() {
+				println!("Only root can power off the computer");
 			}
-			"superblock" => {
-				info(2, None);
+		}
+		"reboot" => {
+			if reboot().is_err() {
+				println!("Only root can reboot the computer");
 			}
-			s if s.starts_with("kill ") => match s.split_whitespace().nth(1) {
-				None => println!("Requires extra arguement: pid"),
-				Some(s) => match s.parse() {
-					Ok(pid) => kill(pid),
-					Err(_) => println!("Pid must be a number!"),
-				},
+		}
+		"ps" => {
+			info(0, None);
+		}
+		"mem" => {
+			info(4, None);
+		}
+		"superblock" => {
+			info(2, None);
+		}
+		s if s.starts_with("kill ") => match s.split_whitespace().nth(1) {
+			None => println!("Requires extra arguement: pid"),
+			Some(s) => match s.parse() {
+				Ok(pid) => {
+					if kill(pid).is_err() {
+						println!("Can't kill process {}", pid);
+					}
+				}
+				Err(_) => println!("Pid must be a number!"),
 			},
-			s if s.starts_with("pcb ") => match s.split_whitespace().nth(1) {
-				None => println!("Requires extra arguement: pid"),
-				Some(s) => match s.parse() {
-					Ok(pid) => info(1, Some(pid)),
-					Err(_) => println!("Pid must be a number!"),
-				},
+		},
+		s if s.starts_with("pcb ") => match s.split_whitespace().nth(1) {
+			None => println!("Requires extra arguement: pid"),
+			Some(s) => match s.parse() {
+				Ok(pid) => info(1, Some(pid)),
+				Err(_) => println!("Pid must be a number!"),
 			},
-			s if s.starts_with("inode ") => match s.split_whitespace().nth(1) {
-				None => println!("Requires extra arguement: Inode"),
-				Some(s) => match s.parse() {
-					Ok(inode) => info(3, Some(inode)),
-					Err(_) => println!("Inode must be a number!"),
+		},
+		s if s.starts_with("sched ") => {
+			let policy = match s.split_whitespace().nth(1) {
+				Some("rr") => SchedulerPolicy::RoundRobin,
+				Some("mlfq") => SchedulerPolicy::Mlfq,
+				_ => {
+					println!("Scheduler must be one of: rr, mlfq");
+					return true;
+				}
+			};
+			if set_scheduler(policy).is_err() {
+				println!("Only root can change the scheduler");
+			}
+		}
+		s if s.starts_with("ulimit ") => {
+			let mut words = s.split_whitespace().skip(1);
+			let resource = match words.next() {
+				Some("core") => Resource::CoreSize,
+				Some("stack") => Resource::StackSize,
+				_ => {
+					println!("Resource must be one of: core, stack");
+					return true;
+				}
+			};
+			match words.next().map(str::parse) {
+				None => match limit(resource, None) {
+					Ok(value) => println!("{}", value),
+					Err(_) => println!("Can't read limit"),
 				},
+				Some(Ok(value)) => {
+					let _ = limit(resource, Some(value));
+				}
+				Some(Err(_)) => println!("Limit must be a number!"),
+			}
+		}
+		s if s == "aslr" || s.starts_with("aslr ") => {
+			let old = match personality(None) {
+				Ok(old) => old,
+				Err(_) => {
+					println!("Can't read personality");
+					return true;
+				}
+			};
+			match s.split_whitespace().nth(1) {
+				None => println!("{}", if old & ADDR_NO_RANDOMIZE == 0 { "on" } else { "off" }),
+				Some("on") => {
+					let _ = personality(Some(old & !ADDR_NO_RANDOMIZE));
+				}
+				Some("off") => {
+					let _ = personality(Some(old | ADDR_NO_RANDOMIZE));
+				}
+				Some(_) => println!("Must be one of: on, off"),
+			}
+		}
+		s if s.starts_with("inode ") => match s.split_whitespace().nth(1) {
+			None => println!("Requires extra arguement: Inode"),
+			Some(s) => match s.parse() {
+				Ok(inode) => info(3, Some(inode)),
+				Err(_) => println!("Inode must be a number!"),
 			},
-			"" => {}
-			command => match shell_words::split(command) {
-				Ok(v) => {
-					let mut tokens = v.as_slice();
-					let exec_path = &v[0];
-					let mut path = "/bin/".to_string();
+		},
+		"" => {}
+		command => match shell_words::split(command) {
+			Ok(v) => {
+				let mut tokens = v.as_slice();
+				let exec_path = &v[0];
+				let mut path = "/bin/".to_string();
 
-					tokens = &tokens[1..];
+				tokens = &tokens[1..];
 
-					let should_wait = match tokens.last() {
-						Some(s) if s == "&" => {
-							tokens = &tokens[..tokens.len() - 1];
-							false
-						}
-						_ => true,
-					};
+				let should_wait = match tokens.last() {
+					Some(s) if s == "&" => {
+						tokens = &tokens[..tokens.len() - 1];
+						false
+					}
+					_ => true,
+				};
 
-					let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
-					path.push_str(&exec_path);
-					if file_exists(&path) {
-						let pid = exec(&path, &args);
-						if should_wait {
-							match pid {
-								Ok(pid) => {
-									// println!("{}", pid);
-									wait(pid)
+				let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
+				path.push_str(&exec_path);
+				if file_exists(&path) {
+					let pid = exec(&path, &args);
+					if should_wait {
+						match pid {
+							Ok(pid) => {
+								// println!("{}", pid);
+								if let Some(status @ ExitStatus::Crashed { .. }) = wait(pid) {
+									println!("{}", status);
 								}
-								_ => {}
 							}
+							_ => {}
 						}
-					} else {
-						println!("{} does not exist", path);
 					}
+				} else {
+					println!("{} does not exist", path);
 				}
-				Err(e) => {
-					println!("Failed to parse command: {}", e)
-				}
-			},
-		}
+			}
+			Err(e) => {
+				println!("Failed to parse command: {}", e)
+			}
+		},
 	}
-	return 0;
+	true
 }
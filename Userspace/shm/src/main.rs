@@ -0,0 +1,84 @@
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+use alloc::{format, string::String};
+use core::sync::atomic::{AtomicU32, Ordering};
+use standard::{
+	syscalls::{exec, futex_wait, futex_wake, shm_map, shm_unmap, wait},
+	*,
+};
+
+/// Name of the shared memory object the producer and consumer talk through
+const NAME: &str = "shm-demo";
+
+/// A single message slot, shared between the producer and the consumer
+#[repr(C)]
+struct Channel {
+	/// 1 while the slot holds a message the consumer hasn't read, used as a futex
+	full: AtomicU32,
+	/// Length of the message, 0 when the producer is done
+	len: u32,
+	data: [u8; 252],
+}
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+	let consumer = args.get(0) == Some(&"consume");
+	let size = if consumer { 0 } else { core::mem::size_of::<Channel>() };
+
+	let ptr = match shm_map(NAME, size, None) {
+		Ok(ptr) => ptr,
+		Err(_) => {
+			println!("Can't map shared memory {}", NAME);
+			return 1;
+		}
+	};
+	let channel = unsafe { &mut *(ptr as *mut Channel) };
+
+	if consumer {
+		loop {
+			// Wait for a message
+			while channel.full.load(Ordering::Acquire) == 0 {
+				let _ = futex_wait(&channel.full, 0, None, false);
+			}
+			let len = channel.len as usize;
+			if len == 0 {
+				break;
+			}
+			let message = core::str::from_utf8(&channel.data[..len]).unwrap_or("?");
+			println!("Consumer got: {}", message);
+			channel.full.store(0, Ordering::Release);
+			futex_wake(&channel.full, 1, false);
+		}
+	} else {
+		let pid = match exec("/bin/shm", &["consume"]) {
+			Ok(pid) => pid,
+			Err(_) => {
+				println!("Can't start consumer");
+				return 1;
+			}
+		};
+		let count: usize = args.get(0).and_then(|s| s.parse().ok()).unwrap_or(5);
+		for i in 0..=count {
+			// Wait for the slot to be free
+			while channel.full.load(Ordering::Acquire) == 1 {
+				let _ = futex_wait(&channel.full, 1, None, false);
+			}
+			let message = if i == count {
+				String::new()
+			} else {
+				format!("message number {}", i)
+			};
+			channel.data[..message.len()].copy_from_slice(message.as_bytes());
+			channel.len = message.len() as u32;
+			channel.full.store(1, Ordering::Release);
+			futex_wake(&channel.full, 1, false);
+		}
+		wait(pid);
+	}
+
+	let _ = shm_unmap(ptr);
+	return 0;
+}
@@ -3,12 +3,20 @@
 #![feature(const_for)] // for loops in const functions
 #![feature(const_mut_refs)] // mutable references inside const functions
 #![feature(alloc_error_handler)] // error handler for alloc failiures
+#![feature(naked_functions)] // _start gets the stack pointer before a prologue touches it
 
 pub mod syscalls;
 
 pub mod io;
 
+pub mod thread;
+
+pub mod sync;
+
+pub mod net;
+
 extern crate alloc;
+use alloc::vec::Vec;
 
 macro_rules! syscall {
     ($($name:ident($a:ident, $($b:ident, $($c:ident, $($d:ident, $($e:ident, $($f:ident, )?)?)?)?)?);)+) => {
@@ -63,16 +71,15 @@ use linked_list_allocator::LockedHeap;
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
-fn init_heap() {
-	let heap_start = 0x0000400000000000;
+fn init_heap(heap_start: usize) {
 	let heap_size = 0x800000; // 8MiB
 	unsafe {
 		ALLOCATOR.lock().init(heap_start, heap_size);
 	}
 }
 
-pub fn init() {
-	init_heap();
+pub fn init(heap_start: usize) {
+	init_heap(heap_start);
 }
 
 #[alloc_error_handler]
@@ -90,15 +97,65 @@ pub fn get_args() -> &'static [&'static str] {
 	unsafe { ARGS }
 }
 
+/// End of the auxiliary vector
+const AT_NULL: usize = 0;
+/// Start of the heap, which the kernel passes in the auxiliary vector
+const AT_HEAP: usize = 0x1000;
+
+/// Entrypoint. The kernel puts the arguement count, the arguement and environment vectors and
+/// the auxiliary vector on the stack (System V ABI), `start` gets a pointer to them.
+#[naked]
 #[no_mangle]
-pub extern "C" fn _start(argc: usize, argv: *const &'static str) {
-	init();
-	// println!("argc: {}", argc);
-	// println!("argv: {:?}", argv);
-	let args: &[&str] = unsafe { core::slice::from_raw_parts(argv, argc) };
-	unsafe { ARGS = args };
-	// println!("args: {:?}", args);
-
-	let result = unsafe { main() };
+pub unsafe extern "C" fn _start() -> ! {
+	asm!(
+		// A note marking the executable as one of ours, so the kernel gives it our syscalls and
+		// not the ones of Linux
+		".pushsection .note.guyos, \"a\", @note",
+		".balign 4",
+		".long 6", // Size of the name
+		".long 0", // Size of the description
+		".long 1", // Type
+		".asciz \"GuyOS\"",
+		".balign 4",
+		".popsection",
+		"mov rdi, rsp",
+		"call {}",
+		sym start,
+		options(noreturn)
+	);
+}
+
+unsafe extern "C" fn start(stack: *const usize) -> ! {
+	let argc = *stack;
+	let argv = stack.add(1) as *const *const u8;
+	// The environment is after the arguements, and the auxiliary vector after it
+	let mut environment = argv.add(argc + 1) as *const usize;
+	while *environment != 0 {
+		environment = environment.add(1);
+	}
+	let mut aux = environment.add(1);
+	let mut heap_start = 0;
+	while *aux != AT_NULL {
+		if *aux == AT_HEAP {
+			heap_start = *aux.add(1);
+		}
+		aux = aux.add(2);
+	}
+	init(heap_start);
+
+	// The first arguement is the path of the program
+	let args: Vec<&'static str> = (1..argc).map(|i| c_str(*argv.add(i))).collect();
+	ARGS = args.leak();
+
+	let result = main();
 	syscalls::exit(result);
 }
+
+/// A string the kernel put on the stack, which is valid UTF8
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+	let mut len = 0;
+	while *ptr.add(len) != 0 {
+		len += 1;
+	}
+	core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+}
@@ -0,0 +1,73 @@
+use crate::{
+	io::{IOError, Read, Write},
+	syscalls::{accept, bind, close, connect, listen, read, socket, write, FAMILY_LOCAL, KIND_STREAM},
+};
+
+/// A local socket accepting connections at a path
+pub struct UnixListener(u32);
+
+impl UnixListener {
+	/// Create a socket file at the path and listen on it. Fails if the path exists.
+	pub fn bind(path: &str) -> Result<Self, ()> {
+		let listener = UnixListener(socket(FAMILY_LOCAL, KIND_STREAM)?);
+		bind(listener.0, path)?;
+		listen(listener.0)?;
+		Ok(listener)
+	}
+
+	/// Wait for a connection
+	pub fn accept(&self) -> Result<UnixStream, ()> {
+		Ok(UnixStream(accept(self.0)?))
+	}
+
+	/// Iterate over the connections as they come in
+	pub fn incoming(&self) -> impl Iterator<Item = UnixStream> + '_ {
+		core::iter::from_fn(move || self.accept().ok())
+	}
+}
+
+impl Drop for UnixListener {
+	fn drop(&mut self) {
+		close(self.0)
+	}
+}
+
+/// A connected local stream socket
+pub struct UnixStream(u32);
+
+impl UnixStream {
+	/// Connect to the socket listening at the path
+	pub fn connect(path: &str) -> Result<Self, ()> {
+		let stream = UnixStream(socket(FAMILY_LOCAL, KIND_STREAM)?);
+		connect(stream.0, path)?;
+		Ok(stream)
+	}
+}
+
+impl Read for UnixStream {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+		match read(buf, self.0) {
+			count if count >= 0 => Ok(count as usize),
+			_ => Err(IOError::Other),
+		}
+	}
+}
+
+impl Write for UnixStream {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+		match write(buf, self.0) {
+			count if count >= 0 => Ok(count as usize),
+			_ => Err(IOError::Other),
+		}
+	}
+	fn flush(&mut self) -> Result<(), IOError> {
+		// No need to flush
+		Ok(())
+	}
+}
+
+impl Drop for UnixStream {
+	fn drop(&mut self) {
+		close(self.0)
+	}
+}
@@ -0,0 +1,209 @@
+use crate::syscalls::{futex_wait, futex_wake};
+use core::{
+	cell::UnsafeCell,
+	ops::{Deref, DerefMut},
+	sync::atomic::{AtomicU32, Ordering},
+	time::Duration,
+};
+
+const UNLOCKED: u32 = 0;
+/// Locked, and no thread is waiting for it
+const LOCKED: u32 = 1;
+/// Locked, and threads might be waiting for it
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock that blocks waiting threads in the kernel instead of spinning
+pub struct Mutex<T> {
+	state: AtomicU32,
+	data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+	/// Create a new unlocked mutex
+	pub const fn new(data: T) -> Self {
+		Self {
+			state: AtomicU32::new(UNLOCKED),
+			data: UnsafeCell::new(data),
+		}
+	}
+
+	/// Lock the mutex, blocking until it is available
+	pub fn lock(&self) -> MutexGuard<T> {
+		if self
+			.state
+			.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+			.is_err()
+		{
+			self.lock_contended();
+		}
+		MutexGuard { mutex: self }
+	}
+
+	/// Lock the mutex only if it is available right now
+	pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+		self.state
+			.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+			.ok()
+			.map(|_| MutexGuard { mutex: self })
+	}
+
+	fn lock_contended(&self) {
+		// Mark the mutex as contended so whoever unlocks it wakes us
+		while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+			let _ = futex_wait(&self.state, CONTENDED, None, true);
+		}
+	}
+
+	fn unlock(&self) {
+		if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+			futex_wake(&self.state, 1, true);
+		}
+	}
+
+	/// Get the data without locking, as the mutable borrow guarantees no one else has it
+	pub fn get_mut(&mut self) -> &mut T {
+		self.data.get_mut()
+	}
+
+	/// Consume the mutex, returning the data
+	pub fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+}
+
+impl<T: Default> Default for Mutex<T> {
+	fn default() -> Self {
+		Self::new(T::default())
+	}
+}
+
+/// Access to the data of a locked [Mutex], which is unlocked when the guard is dropped
+pub struct MutexGuard<'a, T> {
+	mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		unsafe { &*self.mutex.data.get() }
+	}
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.mutex.data.get() }
+	}
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+	fn drop(&mut self) {
+		self.mutex.unlock();
+	}
+}
+
+/// A condition variable, letting threads wait for a change to the data behind a [Mutex]
+pub struct Condvar {
+	/// Incremented on every notify, so waiters can tell if they missed one
+	sequence: AtomicU32,
+}
+
+impl Condvar {
+	/// Create a new condition variable
+	pub const fn new() -> Self {
+		Self {
+			sequence: AtomicU32::new(0),
+		}
+	}
+
+	/// Unlock the mutex and block until notified, then lock it again. Can wake up spuriously,
+	/// so the condition should be checked in a loop.
+	pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+		self.wait_timeout(guard, None).0
+	}
+
+	/// Like [Condvar::wait], but gives up after the timeout. Also returns whether it timed out.
+	pub fn wait_timeout<'a, T>(
+		&self,
+		guard: MutexGuard<'a, T>,
+		timeout: Option<Duration>,
+	) -> (MutexGuard<'a, T>, bool) {
+		let mutex = guard.mutex;
+		// Read the sequence before unlocking, so a notify between the unlock and the wait isn't lost
+		let sequence = self.sequence.load(Ordering::Relaxed);
+		drop(guard);
+		let timed_out = futex_wait(&self.sequence, sequence, timeout, true).is_err()
+			&& self.sequence.load(Ordering::Relaxed) == sequence;
+		(mutex.lock(), timed_out)
+	}
+
+	/// Wake one waiting thread
+	pub fn notify_one(&self) {
+		self.sequence.fetch_add(1, Ordering::Relaxed);
+		futex_wake(&self.sequence, 1, true);
+	}
+
+	/// Wake all waiting threads
+	pub fn notify_all(&self) {
+		self.sequence.fetch_add(1, Ordering::Relaxed);
+		futex_wake(&self.sequence, usize::MAX, true);
+	}
+}
+
+impl Default for Condvar {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const COMPLETE: u32 = 2;
+
+/// Runs a function exactly once, even when called from many threads
+pub struct Once {
+	state: AtomicU32,
+}
+
+impl Once {
+	/// Create a new `Once` that hasn't run yet
+	pub const fn new() -> Self {
+		Self {
+			state: AtomicU32::new(INCOMPLETE),
+		}
+	}
+
+	/// Whether the function has finished running
+	pub fn is_completed(&self) -> bool {
+		self.state.load(Ordering::Acquire) == COMPLETE
+	}
+
+	/// Run `f` if no call has run yet. If another thread is running its function, wait for it
+	/// to finish.
+	pub fn call_once<F: FnOnce()>(&self, f: F) {
+		match self
+			.state
+			.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+		{
+			Ok(_) => {
+				f();
+				self.state.store(COMPLETE, Ordering::Release);
+				futex_wake(&self.state, usize::MAX, true);
+			}
+			Err(_) => {
+				while self.state.load(Ordering::Acquire) == RUNNING {
+					let _ = futex_wait(&self.state, RUNNING, None, true);
+				}
+			}
+		}
+	}
+}
+
+impl Default for Once {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -1,7 +1,8 @@
 use crate::io::{IOError, Read, Write};
 #[allow(unused_imports)]
 use crate::{syscall0, syscall1, syscall2, syscall3, syscall4, syscall5};
-use alloc::string::String;
+use alloc::{string::String, vec, vec::Vec};
+use core::{mem::size_of, sync::atomic::AtomicU32, time::Duration};
 use bitflags::bitflags;
 
 pub fn print_a(s: &str) {
@@ -33,13 +34,19 @@ pub fn exit(status: isize) -> ! {
 	// This is unreachable but makes compiler happy
 	loop {}
 }
-pub fn kill(pid: Pid) {
-	unsafe {
-		syscall1(14, pid as usize);
+/// Kill the process with pid. Fails if it doesn't exist, or belongs to another user and the caller
+/// isn't root.
+pub fn kill(pid: Pid) -> Result<(), ()> {
+	let res = unsafe { syscall1(14, pid as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
 	}
 }
 
 type Pid = usize;
+pub type Tid = usize;
 
 pub fn exec(path: &str, args: &[&str]) -> Result<Pid, ()> {
 	let pid = unsafe {
@@ -58,10 +65,68 @@ pub fn exec(path: &str, args: &[&str]) -> Result<Pid, ()> {
 	}
 }
 
-pub fn wait(pid: Pid) {
-	unsafe {
-		syscall1(10, pid as usize);
+#[repr(C)]
+struct WaitRecord {
+	kind: u64,
+	code: i64,
+	description_len: u64,
+	description: [u8; 120],
+}
+
+/// How a process ended
+#[derive(Debug, Clone)]
+pub enum ExitStatus {
+	/// The process exited by itself with the code
+	Exited(isize),
+	/// The process was killed
+	Killed,
+	/// An exception killed the process. The signal is as on Linux, the description says what
+	/// went wrong, like "null pointer write at 0x0".
+	Crashed { signal: u32, description: String },
+}
+
+impl core::fmt::Display for ExitStatus {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			ExitStatus::Exited(code) => write!(f, "Exited with code {}", code),
+			ExitStatus::Killed => write!(f, "Killed"),
+			ExitStatus::Crashed { signal, description } => {
+				let name = match signal {
+					4 => "Illegal instruction",
+					5 => "Trace/breakpoint trap",
+					7 => "Bus error",
+					8 => "Floating point exception",
+					_ => "Segmentation fault",
+				};
+				write!(f, "{} ({})", name, description)
+			}
+		}
+	}
+}
+
+/// Wait for a process to end. Returns [None] if there is no such process.
+pub fn wait(pid: Pid) -> Option<ExitStatus> {
+	let mut record = WaitRecord {
+		kind: 0,
+		code: 0,
+		description_len: 0,
+		description: [0; 120],
+	};
+	let res = unsafe { syscall2(10, pid as usize, &mut record as *mut WaitRecord as usize) };
+	if res < 0 {
+		return None;
 	}
+	Some(match record.kind {
+		0 => ExitStatus::Exited(record.code as isize),
+		1 => ExitStatus::Killed,
+		_ => {
+			let len = (record.description_len as usize).min(record.description.len());
+			ExitStatus::Crashed {
+				signal: record.code as u32,
+				description: String::from_utf8_lossy(&record.description[..len]).into_owned(),
+			}
+		}
+	})
 }
 
 pub fn open_file(path: &str, flags: OpenFlags) -> Result<Handle, ()> {
@@ -141,12 +206,20 @@ pub fn write(buffer: &[u8], handle: Handle) -> i64 {
 	unsafe { syscall3(8, buffer.as_ptr() as usize, buffer.len(), handle as usize) }
 }
 
-pub fn quit() -> ! {
+/// End the os and power off the computer. Only returns if the caller isn't root.
+pub fn quit() -> Result<(), ()> {
 	unsafe {
-		syscall0(11);
+		syscall1(11, 0);
 	}
-	// This is unreachable but makes compiler happy
-	loop {}
+	Err(())
+}
+
+/// End the os and reboot the computer. Only returns if the caller isn't root.
+pub fn reboot() -> Result<(), ()> {
+	unsafe {
+		syscall1(11, 1);
+	}
+	Err(())
 }
 
 pub fn rmdir(path: &str) -> Result<(), ()> {
@@ -167,6 +240,325 @@ pub fn mkdir(path: &str) -> Result<(), ()> {
 	}
 }
 
+/// Create a FIFO (named pipe) at the path. Opening it connects to a pipe shared by everyone who
+/// opened it.
+pub fn mkfifo(path: &str) -> Result<(), ()> {
+	let res = unsafe { syscall2(37, path.as_ptr() as usize, path.len()) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Family of local (unix domain) sockets
+pub const FAMILY_LOCAL: usize = 1;
+/// Kind of stream sockets
+pub const KIND_STREAM: usize = 1;
+
+/// Create a socket, returns a handle to it
+pub fn socket(family: usize, kind: usize) -> Result<Handle, ()> {
+	let res = unsafe { syscall2(38, family, kind) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(res as Handle)
+	}
+}
+
+/// Bind a socket to a path, creating a socket file there
+pub fn bind(handle: Handle, path: &str) -> Result<(), ()> {
+	let res = unsafe { syscall3(39, handle as usize, path.as_ptr() as usize, path.len()) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Start accepting connections on a bound socket
+pub fn listen(handle: Handle) -> Result<(), ()> {
+	let res = unsafe { syscall1(40, handle as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Wait for a connection on a listening socket, returns a handle to a socket for it
+pub fn accept(handle: Handle) -> Result<Handle, ()> {
+	let res = unsafe { syscall1(41, handle as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(res as Handle)
+	}
+}
+
+/// Connect a socket to the socket listening at the path
+pub fn connect(handle: Handle, path: &str) -> Result<(), ()> {
+	let res = unsafe { syscall3(42, handle as usize, path.as_ptr() as usize, path.len()) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+pub fn chmod(path: &str, permissions: u16) -> Result<(), ()> {
+	let res = unsafe { syscall3(18, path.as_ptr() as usize, path.len(), permissions as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+pub fn chown(path: &str, uid: u16, gid: u16) -> Result<(), ()> {
+	let res = unsafe { syscall4(19, path.as_ptr() as usize, path.len(), uid as usize, gid as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Set the file mode creation mask, returns the previous mask
+pub fn umask(mask: u16) -> u16 {
+	unsafe { syscall1(20, mask as usize) as u16 }
+}
+
+pub fn truncate(path: &str, len: usize) -> Result<(), ()> {
+	let res = unsafe { syscall3(21, path.as_ptr() as usize, path.len(), len) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+pub fn ftruncate(handle: Handle, len: usize) -> Result<(), ()> {
+	let res = unsafe { syscall2(22, handle as usize, len) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Whose resource usage to get
+#[derive(Debug, Copy, Clone)]
+pub enum UsageTarget {
+	/// The calling process
+	Current = 0,
+	/// All the processes the calling process has waited for, and their own waited for processes
+	Children = 1,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct UsageRecord {
+	user_nanos: u64,
+	kernel_nanos: u64,
+	context_switches: u64,
+	syscalls: u64,
+}
+
+/// CPU time and activity of a process
+#[derive(Debug, Copy, Clone)]
+pub struct Usage {
+	pub user_time: Duration,
+	pub kernel_time: Duration,
+	pub context_switches: u64,
+	pub syscalls: u64,
+}
+
+pub fn usage(target: UsageTarget) -> Result<Usage, ()> {
+	let mut record = UsageRecord::default();
+	let res = unsafe { syscall2(24, target as usize, &mut record as *mut UsageRecord as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(Usage {
+			user_time: Duration::from_nanos(record.user_nanos),
+			kernel_time: Duration::from_nanos(record.kernel_nanos),
+			context_switches: record.context_switches,
+			syscalls: record.syscalls,
+		})
+	}
+}
+
+/// Set the niceness of a process, returns the previous niceness
+pub fn set_nice(pid: Pid, nice: i8) -> Result<i8, ()> {
+	let res = unsafe { syscall2(25, pid, nice as isize as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		// The kernel returns the niceness offset by the minimal niceness, -20
+		Ok((res - 20) as i8)
+	}
+}
+
+pub fn get_nice(pid: Pid) -> Result<i8, ()> {
+	let res = unsafe { syscall1(28, pid) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok((res - 20) as i8)
+	}
+}
+
+pub fn getpid() -> Pid {
+	unsafe { syscall0(26) as Pid }
+}
+
+/// Scheduling policies the kernel can use
+#[derive(Debug, Copy, Clone)]
+pub enum SchedulerPolicy {
+	RoundRobin = 0,
+	Mlfq = 1,
+}
+
+pub fn set_scheduler(policy: SchedulerPolicy) -> Result<(), ()> {
+	let res = unsafe { syscall1(27, policy as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Resources of a process that are limited
+#[derive(Debug, Copy, Clone)]
+pub enum Resource {
+	/// Maximum size of a core dump in bytes, 0 disables core dumps
+	CoreSize = 0,
+	/// Maximum size of the main thread's stack in bytes, for processes executed after it is set
+	StackSize = 1,
+}
+
+/// Set a resource limit of this process and the processes it executes, or just read it if
+/// `value` is [None]. Returns the old value.
+pub fn limit(resource: Resource, value: Option<u64>) -> Result<u64, ()> {
+	let res = unsafe { syscall2(43, resource as usize, value.unwrap_or(u64::MAX) as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(res as u64)
+	}
+}
+
+/// Personality flag that loads executables, the stack and the heap at fixed addresses
+pub const ADDR_NO_RANDOMIZE: u32 = 0x0040000;
+
+/// Set the personality of this process and the processes it executes, or just read it if
+/// `value` is [None]. Returns the old personality.
+pub fn personality(value: Option<u32>) -> Result<u32, ()> {
+	let value = value.map(|value| value as usize).unwrap_or(u64::MAX as usize);
+	let res = unsafe { syscall1(44, value) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(res as u32)
+	}
+}
+
+/// Start a new thread at `entry`, with `arg` as its first arguement. The kernel gives it a stack
+/// of `stack_size` bytes with a guard region below it, and frees the stack once the thread exits.
+pub fn thread_create(entry: extern "C" fn(usize) -> !, stack_size: usize, arg: usize) -> Result<Tid, ()> {
+	let tid = unsafe { syscall3(29, entry as usize, stack_size, arg) };
+	if tid >= 0 {
+		Ok(tid as Tid)
+	} else {
+		Err(())
+	}
+}
+
+/// Exit the calling thread. If it is the last thread of the process, the process exits. Once the
+/// thread is gone, 1 is stored in `exited` and the threads waiting on it as a private futex are
+/// woken.
+pub fn thread_exit(exited: Option<&AtomicU32>) -> ! {
+	let exited = exited.map_or(0, |exited| exited as *const AtomicU32 as usize);
+	unsafe {
+		syscall1(30, exited);
+	}
+	// This is unreachable but makes compiler happy
+	loop {}
+}
+
+/// Wait for a thread of this process to exit. Fails if it doesn't exist (or already exited).
+pub fn thread_join(tid: Tid) -> Result<(), ()> {
+	let res = unsafe { syscall1(31, tid) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Set the base address of the FS segment of the calling thread, for thread local storage
+pub fn set_fs_base(base: usize) -> Result<(), ()> {
+	let res = unsafe { syscall1(32, base) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_PRIVATE: usize = 128;
+
+/// Block while the futex holds `expected`, until woken or until the timeout passes. A private
+/// futex is only used by the threads of this process. Fails if the futex didn't hold `expected`
+/// or the wait timed out.
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>, private: bool) -> Result<(), ()> {
+	let op = if private { FUTEX_WAIT | FUTEX_PRIVATE } else { FUTEX_WAIT };
+	// 0 means no timeout, so round short timeouts up
+	let timeout = timeout.map(|timeout| timeout.as_millis().max(1) as usize).unwrap_or(0);
+	let res = unsafe { syscall4(34, futex as *const AtomicU32 as usize, op, expected as usize, timeout) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Wake up to `count` threads blocked on the futex, returns how many were woken
+pub fn futex_wake(futex: &AtomicU32, count: usize, private: bool) -> usize {
+	let op = if private { FUTEX_WAKE | FUTEX_PRIVATE } else { FUTEX_WAKE };
+	let res = unsafe { syscall3(34, futex as *const AtomicU32 as usize, op, count) };
+	res.max(0) as usize
+}
+
+/// Map the shared memory object with the name, creating it with `size` bytes if it doesn't exist.
+/// A size of 0 only opens an existing object. It is mapped at `addr` if given, otherwise the kernel
+/// picks an address. The object is freed once every process unmapped it (or exited).
+pub fn shm_map(name: &str, size: usize, addr: Option<usize>) -> Result<*mut u8, ()> {
+	let res = unsafe { syscall4(35, name.as_ptr() as usize, name.len(), size, addr.unwrap_or(0)) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(res as *mut u8)
+	}
+}
+
+/// Unmap the shared memory object mapped at `ptr`
+pub fn shm_unmap(ptr: *mut u8) -> Result<(), ()> {
+	let res = unsafe { syscall1(36, ptr as usize) };
+	if res < 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+pub fn gettid() -> Tid {
+	unsafe { syscall0(33) as Tid }
+}
+
 pub fn info(info_type: usize, arg0: Option<usize>) {
 	unsafe {
 		syscall2(16, info_type, arg0.unwrap_or(0));
@@ -182,26 +574,105 @@ pub fn unlink(path: &str) -> Result<(), ()> {
 	}
 }
 
-pub struct Dir(Handle);
+pub fn read_dir(buffer: &mut [u8], handle: Handle) -> i64 {
+	unsafe { syscall3(23, buffer.as_ptr() as usize, buffer.len(), handle as usize) }
+}
+
+/// Header of a record written by the read_dir syscall, followed by the name
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct DirRecord {
+	inode: u32,
+	record_length: u16,
+	name_length: u8,
+	type_indicator: u8,
+}
+
+/// Type of the inode a directory entry points to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+	RegularFile,
+	Directory,
+	CharacterDevice,
+	BlockDevice,
+	Fifo,
+	UnixSocket,
+	SymbolicLink,
+	Other,
+}
+
+impl From<u8> for FileType {
+	fn from(type_indicator: u8) -> Self {
+		match type_indicator {
+			1 => FileType::RegularFile,
+			2 => FileType::Directory,
+			3 => FileType::CharacterDevice,
+			4 => FileType::BlockDevice,
+			5 => FileType::Fifo,
+			6 => FileType::UnixSocket,
+			7 => FileType::SymbolicLink,
+			_ => FileType::Other,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+	pub name: String,
+	pub inode: u32,
+	pub file_type: FileType,
+}
+
+pub struct Dir {
+	handle: Handle,
+	buffer: Vec<u8>,
+	/// Offset of the next unread record in the buffer
+	position: usize,
+	/// Number of valid bytes in the buffer
+	filled: usize,
+}
+
 impl Dir {
 	pub fn open(path: &str) -> Result<Self, ()> {
 		let handle = open_dir(path)?;
-		Ok(Dir(handle))
+		Ok(Dir {
+			handle,
+			buffer: vec![0; 1024],
+			position: 0,
+			filled: 0,
+		})
 	}
 }
 
 impl Iterator for Dir {
-	type Item = String;
+	type Item = DirEntry;
 	fn next(&mut self) -> Option<Self::Item> {
-		let mut buf = [0; 512];
-		let res = read(&mut buf, self.0);
-		match res {
-			count if count > 0 => {
-				let slice = &buf[..count as usize];
-				String::from_utf8(slice.to_vec()).ok()
+		if self.position >= self.filled {
+			let res = read_dir(&mut self.buffer, self.handle);
+			if res <= 0 {
+				return None;
 			}
-			_ => None,
+			self.position = 0;
+			self.filled = res as usize;
 		}
+
+		let bytes = &self.buffer[self.position..self.filled];
+		let record = unsafe { (bytes.as_ptr() as *const DirRecord).read_unaligned() };
+		let name_start = size_of::<DirRecord>();
+		let name = &bytes[name_start..name_start + record.name_length as usize];
+		self.position += record.record_length as usize;
+
+		Some(DirEntry {
+			name: String::from_utf8_lossy(name).into_owned(),
+			inode: record.inode,
+			file_type: FileType::from(record.type_indicator),
+		})
+	}
+}
+
+impl Drop for Dir {
+	fn drop(&mut self) {
+		close(self.handle)
 	}
 }
 
@@ -209,14 +680,24 @@ pub struct File(Handle);
 
 impl File {
 	pub fn create(path: &str) -> Result<Self, ()> {
-		let a = open_file(path, OpenFlags::CREATE)?;
+		let a = open_file(path, OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE)?;
 		Ok(File(a))
 	}
 
 	pub fn open(path: &str) -> Result<Self, ()> {
-		let a = open_file(path, OpenFlags::empty())?;
+		let a = open_file(path, OpenFlags::READ)?;
+		Ok(File(a))
+	}
+
+	pub fn open_with(path: &str, flags: OpenFlags) -> Result<Self, ()> {
+		let a = open_file(path, flags)?;
 		Ok(File(a))
 	}
+
+	/// Truncate or extend the file to `len` bytes
+	pub fn set_len(&self, len: usize) -> Result<(), ()> {
+		ftruncate(self.0, len)
+	}
 }
 
 impl Read for File {
@@ -254,5 +735,13 @@ bitflags! {
 		const CREATE = 0b0001;
 		/// Truncate file
 		const TRUNCATE = 0b0010;
+		/// Every write goes to the end of the file
+		const APPEND = 0b0100;
+		/// Fail if the file already exists (used with CREATE)
+		const EXCLUSIVE = 0b1000;
+		/// Only open for reading. Opening a FIFO like this waits for a writer.
+		const READ = 0b1_0000;
+		/// Only open for writing. Opening a FIFO like this waits for a reader.
+		const WRITE = 0b10_0000;
 	}
 }
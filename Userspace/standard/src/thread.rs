@@ -0,0 +1,95 @@
+use crate::syscalls::{futex_wait, thread_create, thread_exit, Tid};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+	cell::UnsafeCell,
+	sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Size of the stack every spawned thread gets, the kernel puts a guard region below it
+const STACK_SIZE: usize = 0x10000; // 64KiB
+
+/// Where a thread puts its result for whoever joins it
+struct Packet<T> {
+	result: UnsafeCell<Option<T>>,
+}
+
+// The result is only written by the thread before it exits, and only read after joining it
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+/// What a spawned thread starts with
+struct Start {
+	main: Box<dyn FnOnce()>,
+	/// The flag the kernel sets once the thread has exited
+	exited: *const AtomicU32,
+}
+
+/// Handle to a spawned thread, used to wait for it and get its result
+pub struct JoinHandle<T> {
+	tid: Tid,
+	packet: Arc<Packet<T>>,
+	/// Set to 1 by the kernel once the thread has exited, it can only be freed after that
+	exited: Option<Box<AtomicU32>>,
+}
+
+impl<T> JoinHandle<T> {
+	/// Id of the thread
+	pub fn tid(&self) -> Tid {
+		self.tid
+	}
+
+	/// Wait for the thread to finish, and get its result
+	pub fn join(mut self) -> T {
+		let exited = self.exited.take().expect("thread joined twice");
+		while exited.load(Ordering::Acquire) == 0 {
+			// Fails if the flag was set in the meantime, which the loop checks
+			let _ = futex_wait(&exited, 0, None, true);
+		}
+		unsafe { (*self.packet.result.get()).take() }.expect("joined thread has no result")
+	}
+}
+
+impl<T> Drop for JoinHandle<T> {
+	fn drop(&mut self) {
+		// The kernel sets the flag once the thread exits, which might not have happened yet
+		if let Some(exited) = self.exited.take() {
+			Box::leak(exited);
+		}
+	}
+}
+
+extern "C" fn thread_start(start: usize) -> ! {
+	let Start { main, exited } = *unsafe { Box::from_raw(start as *mut Start) };
+	main();
+	thread_exit(Some(unsafe { &*exited }));
+}
+
+/// Spawn a new thread running `f`, sharing the memory and open files of this process
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	let packet = Arc::new(Packet {
+		result: UnsafeCell::new(None),
+	});
+	let their_packet = packet.clone();
+	let main: Box<dyn FnOnce()> = Box::new(move || {
+		let result = f();
+		unsafe {
+			*their_packet.result.get() = Some(result);
+		}
+	});
+	let exited = Box::new(AtomicU32::new(0));
+	let start = Box::into_raw(Box::new(Start {
+		main,
+		exited: &*exited,
+	}));
+
+	let tid = thread_create(thread_start, STACK_SIZE, start as usize).expect("failed to spawn thread");
+
+	JoinHandle {
+		tid,
+		packet,
+		exited: Some(exited),
+	}
+}
@@ -0,0 +1,39 @@
+#![no_main]
+#![no_std]
+
+use standard::{sync::Mutex, syscalls::gettid, thread, *};
+extern crate alloc;
+use alloc::{sync::Arc, vec::Vec};
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+	let count: usize = args.get(0).and_then(|s| s.parse().ok()).unwrap_or(4);
+
+	let total = Arc::new(Mutex::new(0u64));
+
+	let handles: Vec<thread::JoinHandle<u64>> = (0..count)
+		.map(|i| {
+			let total = total.clone();
+			thread::spawn(move || {
+				let mut sum: u64 = 0;
+				for n in 0..(1_000_000 * (i as u64 + 1)) {
+					sum = sum.wrapping_add(n);
+				}
+				let mut total = total.lock();
+				*total = total.wrapping_add(sum);
+				drop(total);
+				println!("Thread {} done", gettid());
+				sum
+			})
+		})
+		.collect();
+
+	for handle in handles {
+		let tid = handle.tid();
+		let sum = handle.join();
+		println!("Joined thread {}, sum: {}", tid, sum);
+	}
+	println!("Total: {}", *total.lock());
+	return 0;
+}
@@ -0,0 +1,51 @@
+#![no_main]
+#![no_std]
+
+use standard::{syscalls::*, *};
+extern crate alloc;
+use alloc::string::ToString;
+use core::time::Duration;
+
+#[no_mangle]
+pub extern "C" fn main() -> isize {
+	let args = get_args();
+
+	if args.len() == 0 {
+		println!("Usage: time <command> [args...]");
+		return -1;
+	}
+
+	let path = if args[0].starts_with("/") {
+		args[0].to_string()
+	} else {
+		let mut path = "/bin/".to_string();
+		path.push_str(args[0]);
+		path
+	};
+
+	let before = usage(UsageTarget::Children).unwrap();
+	match exec(&path, &args[1..]) {
+		Ok(pid) => {
+			wait(pid);
+		}
+		Err(_) => {
+			println!("Failed to run {}", path);
+			return -1;
+		}
+	}
+	let after = usage(UsageTarget::Children).unwrap();
+
+	println!();
+	println!("user\t{}", format_time(after.user_time - before.user_time));
+	println!("sys\t{}", format_time(after.kernel_time - before.kernel_time));
+	println!(
+		"{} context switches, {} syscalls",
+		after.context_switches - before.context_switches,
+		after.syscalls - before.syscalls
+	);
+	return 0;
+}
+
+fn format_time(duration: Duration) -> alloc::string::String {
+	alloc::format!("{}.{:06}s", duration.as_secs(), duration.subsec_micros())
+}
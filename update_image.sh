@@ -2,6 +2,12 @@ sudo losetup -D
 sudo losetup -f -P image.img
 sudo mount /dev/loop0p1 Mountpoint
 sudo rsync -rvu --delete -L "FileSystem/" "Mountpoint"
+# Everything belongs to the normal user (uid 1000), except for /bin and the shared objects in /lib
+# which only root may change
+sudo mkdir -p Mountpoint/lib
+sudo chown -R 1000:1000 Mountpoint
+sudo chown -R 0:0 Mountpoint/bin Mountpoint/lib
+sudo chmod -R go-w Mountpoint/bin Mountpoint/lib
 sudo umount /dev/loop0p1
 sudo losetup -D
 cp image.img disk.img